@@ -7,17 +7,17 @@ use std::io;
 use std::sync::mpsc::channel;
 use u2fhid::U2FManager;
 
-#[macro_use] extern crate log;
+extern crate log;
 extern crate env_logger;
 
-fn u2f_get_key_handle_from_register_response(register_response: &Vec<u8>) -> io::Result<Vec<u8>>
+fn u2f_get_key_handle_from_register_response(register_response: &[u8]) -> io::Result<Vec<u8>>
 {
     if register_response[0] != 0x05 {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "Reserved byte not set correctly"));
     }
 
     let key_handle_len = register_response[66] as usize;
-    let mut public_key = register_response.clone();
+    let mut public_key = register_response.to_vec();
     let mut key_handle = public_key.split_off(67);
     let _attestation = key_handle.split_off(key_handle_len);
 
@@ -3,18 +3,44 @@ use std::collections::HashMap;
 use std::{ptr, slice};
 
 use ::U2FManager;
+use u2ftypes::{DeviceInfo, Transport};
 
 type U2FResult = HashMap<u8, Vec<u8>>;
 type U2FCallback = extern "C" fn (u64, *mut U2FResult);
+type U2FApduCallback = extern "C" fn (u64, *mut U2FResult, u16);
 
 const RESBUF_ID_REGISTRATION : u8 = 0;
 const RESBUF_ID_KEYHANDLE : u8 = 1;
 const RESBUF_ID_SIGNATURE : u8 = 2;
+const RESBUF_ID_APDU_RESPONSE : u8 = 3;
 
 unsafe fn from_raw(ptr: *const u8, len: usize) -> Vec<u8> {
     slice::from_raw_parts(ptr, len).to_vec()
 }
 
+// Puts every device into its own buffer, numbered from zero: the transport
+// (0 for USB HID, 1 for NFC), vendor and product ID (big endian, zero if
+// unknown), the capabilities, then the serial number as UTF-8, if any.
+// There are buffer IDs for at most 256 devices.
+fn device_list_result(devices: &[DeviceInfo]) -> U2FResult {
+    let mut result = U2FResult::new();
+    for (bid, info) in devices.iter().take(256).enumerate() {
+        let transport = match info.transport {
+            Transport::UsbHid => 0,
+            Transport::Nfc => 1
+        };
+        let vid = info.vendor_id.unwrap_or(0);
+        let pid = info.product_id.unwrap_or(0);
+
+        let mut buf = vec![transport, (vid >> 8) as u8, vid as u8, (pid >> 8) as u8, pid as u8, info.capabilities];
+        if let Some(ref serial) = info.serial_number {
+            buf.extend(serial.as_bytes());
+        }
+        result.insert(bid as u8, buf);
+    }
+    result
+}
+
 #[no_mangle]
 pub extern "C" fn rust_u2f_mgr_new() -> *mut U2FManager
 {
@@ -25,14 +51,20 @@ pub extern "C" fn rust_u2f_mgr_new() -> *mut U2FManager
     }
 }
 
+/// # Safety
+///
+/// `mgr` must be null or come from rust_u2f_mgr_new(), and not be used after.
 #[no_mangle]
 pub unsafe extern "C" fn rust_u2f_mgr_free(mgr: *mut U2FManager)
 {
     if !mgr.is_null() {
-        Box::from_raw(mgr);
+        drop(Box::from_raw(mgr));
     }
 }
 
+/// # Safety
+///
+/// `res` must be null or a live result, `len` must be writable.
 #[no_mangle]
 pub unsafe extern "C" fn rust_u2f_resbuf_length(res: *const U2FResult,
                                                 bid: u8,
@@ -50,6 +82,10 @@ pub unsafe extern "C" fn rust_u2f_resbuf_length(res: *const U2FResult,
     false
 }
 
+/// # Safety
+///
+/// `res` must be null or a live result, `dst` must have room for the
+/// buffer's length, see rust_u2f_resbuf_length().
 #[no_mangle]
 pub unsafe extern "C" fn rust_u2f_resbuf_copy(res: *const U2FResult,
                                               bid: u8,
@@ -67,14 +103,21 @@ pub unsafe extern "C" fn rust_u2f_resbuf_copy(res: *const U2FResult,
     false
 }
 
+/// # Safety
+///
+/// `res` must be null or a result that wasn't freed yet, and not be used after.
 #[no_mangle]
 pub unsafe extern "C" fn rust_u2f_res_free(res: *mut U2FResult)
 {
     if !res.is_null() {
-        Box::from_raw(res);
+        drop(Box::from_raw(res));
     }
 }
 
+/// # Safety
+///
+/// `mgr` must be null or a live manager, the pointers must point to
+/// their lengths of readable bytes.
 #[no_mangle]
 pub unsafe extern "C" fn rust_u2f_mgr_register(mgr: *mut U2FManager,
                                                tid: u64,
@@ -105,6 +148,10 @@ pub unsafe extern "C" fn rust_u2f_mgr_register(mgr: *mut U2FManager,
     res.is_ok()
 }
 
+/// # Safety
+///
+/// `mgr` must be null or a live manager, the pointers must point to
+/// their lengths of readable bytes.
 #[no_mangle]
 pub unsafe extern "C" fn rust_u2f_mgr_sign(mgr: *mut U2FManager,
                                            tid: u64,
@@ -140,6 +187,66 @@ pub unsafe extern "C" fn rust_u2f_mgr_sign(mgr: *mut U2FManager,
     res.is_ok()
 }
 
+// Sends an APDU to the first device that answers it, see
+// `U2FManager::send_apdu()`. Returns false if it couldn't be started.
+// Otherwise `callback` is called once, on one of the manager's threads,
+// with the response data in a buffer and the status word, or with a null
+// result and status word zero if no device answered in time or
+// rust_u2f_mgr_cancel() was called. The data is copied before this returns,
+// the result belongs to the callback and must be freed with
+// rust_u2f_res_free().
+//
+//     void on_apdu(uint64_t tid, rust_u2f_res* res, uint16_t sw) {
+//       size_t len;
+//       if (res && rust_u2f_resbuf_length(res, U2F_RESBUF_ID_APDU_RESPONSE, &len)) {
+//         uint8_t* buf = (uint8_t*) malloc(len);
+//         rust_u2f_resbuf_copy(res, U2F_RESBUF_ID_APDU_RESPONSE, buf);
+//         /* sw is 0x9000 on success */
+//         free(buf);
+//       }
+//       rust_u2f_res_free(res);
+//     }
+//
+//     rust_u2f_mgr_send_apdu(mgr, tid, 3000, on_apdu, 0x40, 0x00, data, data_len);
+/// # Safety
+///
+/// `mgr` must be null or a live manager, `data_ptr` must point to
+/// `data_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rust_u2f_mgr_send_apdu(mgr: *mut U2FManager,
+                                               tid: u64,
+                                               timeout: u64,
+                                               callback: U2FApduCallback,
+                                               ins: u8,
+                                               p1: u8,
+                                               data_ptr: *const u8,
+                                               data_len: usize) -> bool
+{
+    if mgr.is_null() || (data_ptr.is_null() && data_len > 0) {
+        return false;
+    }
+
+    let data = if data_len > 0 { from_raw(data_ptr, data_len) } else { Vec::new() };
+
+    let res = (*mgr).send_apdu(timeout, ins, p1, data, move |rv| {
+        if let Ok((response, status_word)) = rv {
+            let mut result = U2FResult::new();
+            result.insert(RESBUF_ID_APDU_RESPONSE, response);
+            callback(tid, Box::into_raw(Box::new(result)), status_word);
+        } else {
+            callback(tid, ptr::null_mut(), 0);
+        };
+    });
+
+    res.is_ok()
+}
+
+// Cancels the ongoing operation, whose callback then gets a null result.
+// Fine to call at any time: without an operation, e.g. once its callback
+// was called, this does nothing.
+/// # Safety
+///
+/// `mgr` must be null or a live manager.
 #[no_mangle]
 pub unsafe extern "C" fn rust_u2f_mgr_cancel(mgr: *mut U2FManager)
 {
@@ -148,3 +255,89 @@ pub unsafe extern "C" fn rust_u2f_mgr_cancel(mgr: *mut U2FManager)
         let _ = (*mgr).cancel();
     }
 }
+
+// Lists the attached devices and sets `count` to their number, see
+// device_list_result(). Blocks for at most a second. Returns null on failure,
+// the result must be freed with rust_u2f_res_free() otherwise.
+//
+//     size_t count;
+//     rust_u2f_res* res = rust_u2f_mgr_list_devices(mgr, &count);
+//     for (size_t i = 0; res && i < count; i++) {
+//       size_t len;
+//       if (rust_u2f_resbuf_length(res, i, &len)) {
+//         uint8_t* buf = (uint8_t*) malloc(len);
+//         rust_u2f_resbuf_copy(res, i, buf);
+//         /* buf[0] is the transport, ... */
+//         free(buf);
+//       }
+//     }
+//     rust_u2f_res_free(res);
+/// # Safety
+///
+/// `mgr` must be null or a live manager, `count` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn rust_u2f_mgr_list_devices(mgr: *mut U2FManager,
+                                                   count: *mut size_t) -> *mut U2FResult
+{
+    if mgr.is_null() || count.is_null() {
+        return ptr::null_mut();
+    }
+
+    match (*mgr).list_devices() {
+        Ok(devices) => {
+            let result = device_list_result(&devices);
+            *count = result.len();
+            Box::into_raw(Box::new(result))
+        }
+        Err(_) => ptr::null_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{U2FResult, device_list_result, rust_u2f_mgr_cancel, rust_u2f_mgr_free, rust_u2f_mgr_new, rust_u2f_mgr_send_apdu};
+    use std::ptr;
+    use u2ftypes::{DeviceInfo, Transport};
+
+    extern "C" fn ignore(_: u64, _: *mut U2FResult, _: u16) {}
+
+    #[test]
+    fn test_send_apdu_and_cancel() {
+        let mgr = rust_u2f_mgr_new();
+        assert!(!mgr.is_null());
+        unsafe {
+            // Nothing to cancel, yet.
+            rust_u2f_mgr_cancel(mgr);
+
+            // Data must be there if it has a length.
+            assert!(!rust_u2f_mgr_send_apdu(mgr, 1, 1, ignore, 0x40, 0x00, ptr::null(), 4));
+            assert!(!rust_u2f_mgr_send_apdu(ptr::null_mut(), 1, 1, ignore, 0x40, 0x00, ptr::null(), 0));
+
+            // And fit into a message.
+            let data = vec![0u8; 20000];
+            assert!(!rust_u2f_mgr_send_apdu(mgr, 1, 1, ignore, 0x40, 0x00, data.as_ptr(), data.len()));
+
+            let data = [1u8, 2, 3, 4];
+            assert!(rust_u2f_mgr_send_apdu(mgr, 2, 1, ignore, 0x40, 0x00, data.as_ptr(), data.len()));
+            rust_u2f_mgr_cancel(mgr);
+            // Again, after the operation is gone.
+            rust_u2f_mgr_cancel(mgr);
+            rust_u2f_mgr_cancel(ptr::null_mut());
+            rust_u2f_mgr_free(mgr);
+        }
+    }
+
+    #[test]
+    fn test_device_list_result() {
+        let mut token = DeviceInfo::new(Transport::UsbHid);
+        token.vendor_id = Some(0x1050);
+        token.product_id = Some(0x0407);
+        token.capabilities = 0x05;
+        token.serial_number = Some("4711".to_owned());
+
+        let result = device_list_result(&[token, DeviceInfo::new(Transport::Nfc)]);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[&0], vec![0, 0x10, 0x50, 0x04, 0x07, 0x05, b'4', b'7', b'1', b'1']);
+        assert_eq!(result[&1], vec![1, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,262 @@
+use std::io;
+
+// A minimal CBOR (RFC 7049) codec, covering what CTAP2 messages use. Floats,
+// indefinite lengths and tags other than being skipped aren't supported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Unsigned(u64),
+    Negative(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null
+}
+
+impl Value {
+    // Looks up `key` if this is a map.
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        match *self {
+            Value::Map(ref entries) => {
+                entries.iter().find(|entry| entry.0 == *key).map(|entry| &entry.1)
+            }
+            _ => None
+        }
+    }
+}
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+const SIMPLE_FALSE: u8 = 20;
+const SIMPLE_TRUE: u8 = 21;
+const SIMPLE_NULL: u8 = 22;
+const SIMPLE_UNDEFINED: u8 = 23;
+
+// Don't let a device make us recurse forever.
+const MAX_DEPTH: usize = 16;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn encode_header(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+
+    if value < 24 {
+        out.push(major | value as u8);
+    } else if value <= 0xff {
+        out.push(major | 24);
+        out.push(value as u8);
+    } else if value <= 0xffff {
+        out.push(major | 25);
+        out.extend(&[(value >> 8) as u8, value as u8]);
+    } else if value <= 0xffff_ffff {
+        out.push(major | 26);
+        out.extend((0..4).rev().map(|i| (value >> (i * 8)) as u8));
+    } else {
+        out.push(major | 27);
+        out.extend((0..8).rev().map(|i| (value >> (i * 8)) as u8));
+    }
+}
+
+fn encode_into(out: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::Unsigned(n) => encode_header(out, MAJOR_UNSIGNED, n),
+        Value::Negative(n) => encode_header(out, MAJOR_NEGATIVE, (-1 - n) as u64),
+        Value::Bytes(ref bytes) => {
+            encode_header(out, MAJOR_BYTES, bytes.len() as u64);
+            out.extend(bytes);
+        }
+        Value::Text(ref text) => {
+            encode_header(out, MAJOR_TEXT, text.len() as u64);
+            out.extend(text.as_bytes());
+        }
+        Value::Array(ref values) => {
+            encode_header(out, MAJOR_ARRAY, values.len() as u64);
+            for value in values {
+                encode_into(out, value);
+            }
+        }
+        Value::Map(ref entries) => {
+            encode_header(out, MAJOR_MAP, entries.len() as u64);
+            for (key, value) in entries {
+                encode_into(out, key);
+                encode_into(out, value);
+            }
+        }
+        Value::Bool(false) => out.push(MAJOR_SIMPLE << 5 | SIMPLE_FALSE),
+        Value::Bool(true) => out.push(MAJOR_SIMPLE << 5 | SIMPLE_TRUE),
+        Value::Null => out.push(MAJOR_SIMPLE << 5 | SIMPLE_NULL)
+    }
+}
+
+// Encodes `value`. Map entries are written in the given order, so callers
+// have to sort them if they need canonical CBOR.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(&mut out, value);
+    out
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(invalid("CBOR data too short"));
+        }
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    // Returns the major type and the argument of the next item.
+    fn header(&mut self) -> io::Result<(u8, u8, u64)> {
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+
+        if info < 24 {
+            return Ok((major, info, info as u64));
+        }
+
+        let len = match info {
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(invalid("unsupported CBOR item"))
+        };
+
+        let bytes = self.take(len)?;
+        let value = bytes.iter().fold(0u64, |value, b| (value << 8) | *b as u64);
+        Ok((major, info, value))
+    }
+
+    fn length(&self, value: u64) -> io::Result<usize> {
+        // Every item takes at least one byte, so this also bounds allocations.
+        if value > (self.data.len() - self.pos) as u64 {
+            return Err(invalid("CBOR data too short"));
+        }
+        Ok(value as usize)
+    }
+
+    fn value(&mut self, depth: usize) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("CBOR data nested too deeply"));
+        }
+
+        let (major, info, arg) = self.header()?;
+        match major {
+            MAJOR_UNSIGNED => Ok(Value::Unsigned(arg)),
+            MAJOR_NEGATIVE => {
+                if arg > i64::MAX as u64 {
+                    return Err(invalid("CBOR integer out of range"));
+                }
+                Ok(Value::Negative(-1 - arg as i64))
+            }
+            MAJOR_BYTES => {
+                let len = self.length(arg)?;
+                Ok(Value::Bytes(self.take(len)?.to_vec()))
+            }
+            MAJOR_TEXT => {
+                let len = self.length(arg)?;
+                let bytes = self.take(len)?.to_vec();
+                String::from_utf8(bytes).map(Value::Text)
+                                        .map_err(|_| invalid("invalid CBOR text string"))
+            }
+            MAJOR_ARRAY => {
+                let len = self.length(arg)?;
+                let mut values = Vec::with_capacity(len);
+                for _ in 0..len {
+                    values.push(self.value(depth + 1)?);
+                }
+                Ok(Value::Array(values))
+            }
+            MAJOR_MAP => {
+                let len = self.length(arg)?;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let key = self.value(depth + 1)?;
+                    let value = self.value(depth + 1)?;
+                    entries.push((key, value));
+                }
+                Ok(Value::Map(entries))
+            }
+            // We don't care about tags, return the tagged item.
+            MAJOR_TAG => self.value(depth + 1),
+            _ => {
+                match info {
+                    SIMPLE_FALSE => Ok(Value::Bool(false)),
+                    SIMPLE_TRUE => Ok(Value::Bool(true)),
+                    SIMPLE_NULL | SIMPLE_UNDEFINED => Ok(Value::Null),
+                    _ => Err(invalid("unsupported CBOR item"))
+                }
+            }
+        }
+    }
+}
+
+// Decodes a single item that has to span all of `data`.
+pub fn decode(data: &[u8]) -> io::Result<Value> {
+    let mut decoder = Decoder { data, pos: 0 };
+    let value = decoder.value(0)?;
+
+    if decoder.pos != data.len() {
+        return Err(invalid("trailing CBOR data"));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Value};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&Value::Unsigned(10)), vec![0x0a]);
+        assert_eq!(encode(&Value::Unsigned(500)), vec![0x19, 0x01, 0xf4]);
+        assert_eq!(encode(&Value::Negative(-7)), vec![0x26]);
+        assert_eq!(encode(&Value::Text("pin".to_owned())), vec![0x63, 0x70, 0x69, 0x6e]);
+
+        let map = Value::Map(vec![(Value::Unsigned(1), Value::Unsigned(1)),
+                                  (Value::Unsigned(2), Value::Bool(true))]);
+        assert_eq!(encode(&map), vec![0xa2, 0x01, 0x01, 0x02, 0xf5]);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let value = Value::Map(vec![
+            (Value::Unsigned(1), Value::Array(vec![Value::Text("FIDO_2_0".to_owned())])),
+            (Value::Unsigned(3), Value::Bytes(vec![0xaa; 16])),
+            (Value::Negative(-25), Value::Unsigned(0x1_0000_0000)),
+            (Value::Text("rk".to_owned()), Value::Bool(false)),
+            (Value::Unsigned(7), Value::Null)
+        ]);
+        assert_eq!(decode(&encode(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        // Truncated, trailing data, indefinite length, too deep.
+        assert!(decode(&[0x19, 0x01]).is_err());
+        assert!(decode(&[0x43, 0x01, 0x02]).is_err());
+        assert!(decode(&[0x01, 0x02]).is_err());
+        assert!(decode(&[0x9f, 0xff]).is_err());
+        assert!(decode(&[0x81; 32]).is_err());
+        // Claims far more entries than there are bytes.
+        assert!(decode(&[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use util::sha256;
+
+// Appends `value` to `out` as a JSON string literal.
+fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+// Builds a WebAuthn-style clientDataJSON and its SHA-256 hash, which is what
+// gets passed as the challenge parameter. `kind` is e.g. "webauthn.create" or
+// "webauthn.get", `challenge` is base64url-encoded. The fields are always
+// written in the same order, without whitespace.
+pub fn build_client_data(kind: &str, challenge: &str, origin: &str) -> (String, [u8; 32]) {
+    let mut json = String::from("{");
+    for (i, &(key, value)) in [("type", kind), ("challenge", challenge), ("origin", origin)].iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        push_json_string(&mut json, key);
+        json.push(':');
+        push_json_string(&mut json, value);
+    }
+    json.push('}');
+
+    let hash = sha256(json.as_bytes());
+    (json, hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_client_data;
+
+    #[test]
+    fn test_build_client_data() {
+        let (json, hash) = build_client_data("webauthn.get", "1vQ9mxionq0ngCnjD-wTsv1zUSrGRtFqG2xP09SbZ70", "https://demo.yubico.com");
+        assert_eq!(json, r#"{"type":"webauthn.get","challenge":"1vQ9mxionq0ngCnjD-wTsv1zUSrGRtFqG2xP09SbZ70","origin":"https://demo.yubico.com"}"#);
+        assert_eq!(hash, [0xbd, 0xe6, 0xb9, 0xe5, 0x34, 0x33, 0x0d, 0x0b, 0xc8, 0xa7, 0xce, 0x9f, 0xff, 0x3c, 0x68, 0xaa,
+                          0x2d, 0x45, 0x0a, 0xc1, 0x54, 0x47, 0x2e, 0xbf, 0xe0, 0x2f, 0x55, 0x07, 0x71, 0xae, 0x54, 0x3f]);
+    }
+
+    #[test]
+    fn test_build_client_data_escapes() {
+        let (json, _) = build_client_data("webauthn.create", "abc", "https://a\"b\\c\u{1}");
+        assert_eq!(json, r#"{"type":"webauthn.create","challenge":"abc","origin":"https://a\"b\\c\u0001"}"#);
+    }
+}
@@ -1,13 +1,23 @@
 // Allow dead code in this module, since it's all packet consts anyways.
 #![allow(dead_code)]
 
-pub const HID_RPT_SIZE : usize = 64;
-pub const U2FAPDUHEADER_SIZE : usize = 7;
-pub const CID_BROADCAST : [u8; 4] = [0xff, 0xff, 0xff, 0xff];
-pub const TYPE_MASK : u8 = 0x80;
-pub const TYPE_INIT : u8 = 0x80;
-pub const TYPE_CONT : u8 = 0x80;
+// The constants of the protocols we speak, named after the specs:
+//
+// U2FHID: https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-hid-protocol.html
+// U2F messages: https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-raw-message-formats.html
+// CTAP2: https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html
 
+// U2FHID framing, section 2.4
+pub const HID_RPT_SIZE : usize = 64;	// Size of a HID report, without the report ID
+pub const U2FAPDUHEADER_SIZE : usize = 7;	// Extended length APDU header: CLA INS P1 P2 and 3 length bytes
+pub const CID_BROADCAST : [u8; 4] = [0xff, 0xff, 0xff, 0xff];	// Channel for U2FHID_INIT, before we have one
+pub const TYPE_MASK : u8 = 0x80;	// Frame type bit of the command byte
+pub const TYPE_INIT : u8 = 0x80;	// Initialization frame
+pub const TYPE_CONT : u8 = 0x00;	// Continuation frame
+pub const MAX_MESSAGE_SIZE : usize = 7609;	// An init frame's 57 bytes plus 128 continuation frames of 59
+pub const MAX_APDU_DATA_SIZE : usize = MAX_MESSAGE_SIZE - U2FAPDUHEADER_SIZE - 2;	// What's left of a message for APDU data, after the header and Le
+
+// Size of the challenge and application parameters, both SHA-256 hashes.
 pub const PARAMETER_SIZE : usize = 32;
 
 pub const FIDO_USAGE_PAGE     : u16 =    0xf1d0;	// FIDO alliance HID usage page
@@ -15,41 +25,72 @@ pub const FIDO_USAGE_U2FHID   : u16  =   0x01;	// U2FHID usage for top-level col
 pub const FIDO_USAGE_DATA_IN  : u8  =   0x20;	// Raw IN data report
 pub const FIDO_USAGE_DATA_OUT : u8  =   0x21;	// Raw OUT data report
 
-// General pub constants
+// General constants, U2FHID section 4
 
 pub const U2FHID_IF_VERSION    : u32 =  2;	// Current interface implementation version
 pub const U2FHID_FRAME_TIMEOUT : u32 =  500;	// Default frame timeout in ms
 pub const U2FHID_TRANS_TIMEOUT : u32 =  3000;	// Default message timeout in ms
 
-// U2FHID native commands
-pub const U2FHID_PING         : u8 = (TYPE_INIT | 0x01);  // Echo data through local processor only
-pub const U2FHID_MSG          : u8 = (TYPE_INIT | 0x03);  // Send U2F message frame
-pub const U2FHID_LOCK         : u8 = (TYPE_INIT | 0x04);  // Send lock channel command
-pub const U2FHID_INIT         : u8 = (TYPE_INIT | 0x06);  // Channel initialization
-pub const U2FHID_WINK         : u8 = (TYPE_INIT | 0x08);  // Send device identification wink
-pub const U2FHID_ERROR        : u8 = (TYPE_INIT | 0x3f);  // Error response
-
-// U2FHID_MSG commands
-pub const U2F_VENDOR_FIRST : u8 = (TYPE_INIT | 0x40); // First vendor defined command
-pub const U2F_VENDOR_LAST  : u8 = (TYPE_INIT | 0x7f); // Last vendor defined command
+// U2FHID native commands, U2FHID sections 4.1 and 4.2, and CTAP2 section 8.1.9
+pub const U2FHID_PING         : u8 = TYPE_INIT | 0x01;  // Echo data through local processor only
+pub const U2FHID_MSG          : u8 = TYPE_INIT | 0x03;  // Send U2F message frame
+pub const U2FHID_LOCK         : u8 = TYPE_INIT | 0x04;  // Send lock channel command
+pub const U2FHID_INIT         : u8 = TYPE_INIT | 0x06;  // Channel initialization
+pub const U2FHID_WINK         : u8 = TYPE_INIT | 0x08;  // Send device identification wink
+pub const U2FHID_CBOR         : u8 = TYPE_INIT | 0x10;  // Send CTAP2 CBOR message
+pub const U2FHID_CANCEL       : u8 = TYPE_INIT | 0x11;  // Abort a pending request
+pub const U2FHID_KEEPALIVE    : u8 = TYPE_INIT | 0x3b;  // Sent while processing a request
+pub const U2FHID_ERROR        : u8 = TYPE_INIT | 0x3f;  // Error response
+pub const U2FHID_VENDOR_FIRST : u8 = TYPE_INIT | 0x40;  // First vendor defined command
+pub const U2FHID_VENDOR_LAST  : u8 = TYPE_INIT | 0x7f;  // Last vendor defined command
+
+// U2FHID_MSG commands, the INS byte of U2F messages, section 3
+pub const U2F_VENDOR_FIRST : u8 = 0x40;  // First vendor defined command
+pub const U2F_VENDOR_LAST  : u8 = 0xbf;  // Last vendor defined command
 pub const U2F_REGISTER     : u8 = 0x01;  // Registration command
 pub const U2F_AUTHENTICATE : u8 = 0x02;  // Authenticate/sign command
 pub const U2F_VERSION      : u8 = 0x03;  // Read version string command
 
-// U2F_REGISTER command defines
+// U2F_REGISTER command defines, U2F messages section 4.3
 pub const U2F_REGISTER_ID      : u8 = 0x05;  // Version 2 registration identifier
 pub const U2F_REGISTER_HASH_ID : u8 = 0x00;  // Version 2 hash identintifier
 
-// U2F_AUTHENTICATE command defines
+// U2F_AUTHENTICATE command defines, the P1 byte, U2F messages section 5.1
 pub const U2F_REQUEST_USER_PRESENCE : u8 = 0x03; // Verify user presence and sign
 pub const U2F_CHECK_IS_REGISTERED   : u8 = 0x07; // Check if the key handle is registered
 
-// U2FHID_INIT command defines
+// U2FHID_INIT command defines, U2FHID section 4.1.3
 pub const INIT_NONCE_SIZE     : usize =    8;	// Size of channel initialization challenge
 pub const CAPFLAG_WINK        : u8 =    0x01;	// Device supports WINK command
 pub const CAPFLAG_LOCK        : u8 =    0x02;	// Device supports LOCK command
+pub const CAPFLAG_CBOR        : u8 =    0x04;	// Device supports CBOR command
+pub const CAPFLAG_NMSG        : u8 =    0x08;	// Device doesn't support MSG command
+
+// CTAP2 commands, sent with U2FHID_CBOR, CTAP2 section 6.1
+pub const CTAP2_MAKE_CREDENTIAL : u8 = 0x01;  // Create a new credential
+pub const CTAP2_GET_ASSERTION : u8 = 0x02;  // Authenticate with a credential
+pub const CTAP2_GET_INFO      : u8 = 0x04;  // Query device capabilities
+pub const CTAP2_CLIENT_PIN    : u8 = 0x06;  // PIN related subcommands
+pub const CTAP2_CREDENTIAL_MANAGEMENT : u8 = 0x0a;  // Manage discoverable credentials
+pub const CTAP2_CREDENTIAL_MANAGEMENT_PREVIEW : u8 = 0x41;  // The same, before CTAP 2.1
+
+// CTAP2_CLIENT_PIN subcommands, CTAP2 section 5.5
+pub const CLIENT_PIN_GET_RETRIES : u64 = 0x01;  // Remaining PIN attempts
+pub const CLIENT_PIN_GET_KEY_AGREEMENT : u64 = 0x02;  // Token's ECDH public key
+pub const CLIENT_PIN_GET_PIN_TOKEN : u64 = 0x05;  // Trade the PIN for a pinToken
+
+// CTAP2_CREDENTIAL_MANAGEMENT subcommands, CTAP 2.1 section 6.8
+pub const CRED_MGMT_ENUMERATE_CREDENTIALS_BEGIN : u64 = 0x04;  // First credential of an RP
+pub const CRED_MGMT_ENUMERATE_CREDENTIALS_NEXT : u64 = 0x05;  // The ones after that
+
+// CTAP2 status codes, CTAP2 section 6.3
+pub const CTAP2_OK            : u8 = 0x00;  // Success
+pub const CTAP2_ERR_UNSUPPORTED_OPTION : u8 = 0x2b;  // Option not supported
+pub const CTAP2_ERR_INVALID_OPTION : u8 = 0x2c;  // Option not valid for this command
+pub const CTAP2_ERR_NO_CREDENTIALS : u8 = 0x2e;  // No valid credential found
+pub const CTAP2_ERR_USER_ACTION_TIMEOUT : u8 = 0x2f;  // User didn't respond in time
 
-// Low-level error codes. Return as negatives.
+// U2FHID_ERROR codes, U2FHID section 4.1.4
 
 pub const ERR_NONE            : u8 =    0x00;	// No error
 pub const ERR_INVALID_CMD     : u8 =    0x01;	// Invalid command
@@ -62,8 +103,61 @@ pub const ERR_LOCK_REQUIRED   : u8 =    0x0a;	// Command requires channel lock
 pub const ERR_INVALID_CID     : u8 =    0x0b;	// Command not allowed on this cid
 pub const ERR_OTHER           : u8 =    0x7f;	// Other unspecified error
 
-// These are ISO 7816-4 defined response status words.
-pub const SW_NO_ERROR : [u8; 2] = [0x90, 0x00];
-pub const SW_CONDITIONS_NOT_SATISFIED : [u8; 2] = [0x69, 0x85];
-pub const SW_WRONG_DATA : [u8; 2] = [0x6A, 0x80];
-pub const SW_WRONG_LENGTH : [u8; 2] = [0x67, 0x00];
+// What a U2FHID_ERROR frame with the given code means, for error messages.
+pub fn ctaphid_error_description(code: u8) -> &'static str {
+    match code {
+        ERR_NONE => "no error",
+        ERR_INVALID_CMD => "invalid command",
+        ERR_INVALID_PAR => "invalid parameter",
+        ERR_INVALID_LEN => "invalid message length",
+        ERR_INVALID_SEQ => "invalid message sequencing",
+        ERR_MSG_TIMEOUT => "message timed out",
+        ERR_CHANNEL_BUSY => "channel busy",
+        ERR_LOCK_REQUIRED => "command requires channel lock",
+        ERR_INVALID_CID => "command not allowed on this channel",
+        _ => "unspecified error"
+    }
+}
+
+// These are ISO 7816-4 defined response status words, U2F messages
+// section 3.3.
+pub const SW_NO_ERROR : [u8; 2] = [0x90, 0x00];  // The command completed
+pub const SW_CONDITIONS_NOT_SATISFIED : [u8; 2] = [0x69, 0x85];  // User presence is needed
+pub const SW_WRONG_DATA : [u8; 2] = [0x6A, 0x80];  // Invalid key handle, say
+pub const SW_WRONG_LENGTH : [u8; 2] = [0x67, 0x00];  // The request length is invalid
+pub const SW_CLA_NOT_SUPPORTED : [u8; 2] = [0x6E, 0x00];  // The class byte isn't supported
+pub const SW_INS_NOT_SUPPORTED : [u8; 2] = [0x6D, 0x00];  // The instruction isn't supported
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ctaphid_error_description() {
+        assert_eq!(ctaphid_error_description(ERR_CHANNEL_BUSY), "channel busy");
+        assert_eq!(ctaphid_error_description(0x06), "channel busy");
+        assert_eq!(ctaphid_error_description(ERR_OTHER), "unspecified error");
+        assert_eq!(ctaphid_error_description(0x42), "unspecified error");
+    }
+
+    // Callers build on these, they must not go away.
+    #[test]
+    fn test_constants() {
+        let _ = (HID_RPT_SIZE, U2FAPDUHEADER_SIZE, CID_BROADCAST, TYPE_MASK, TYPE_INIT, TYPE_CONT, MAX_MESSAGE_SIZE, MAX_APDU_DATA_SIZE, PARAMETER_SIZE);
+        let _ = (FIDO_USAGE_PAGE, FIDO_USAGE_U2FHID, FIDO_USAGE_DATA_IN, FIDO_USAGE_DATA_OUT);
+        let _ = (U2FHID_IF_VERSION, U2FHID_FRAME_TIMEOUT, U2FHID_TRANS_TIMEOUT);
+        let _ = (U2FHID_PING, U2FHID_MSG, U2FHID_LOCK, U2FHID_INIT, U2FHID_WINK, U2FHID_CBOR, U2FHID_CANCEL, U2FHID_KEEPALIVE, U2FHID_ERROR, U2FHID_VENDOR_FIRST, U2FHID_VENDOR_LAST);
+        let _ = (U2F_VENDOR_FIRST, U2F_VENDOR_LAST, U2F_REGISTER, U2F_AUTHENTICATE, U2F_VERSION);
+        let _ = (U2F_REGISTER_ID, U2F_REGISTER_HASH_ID, U2F_REQUEST_USER_PRESENCE, U2F_CHECK_IS_REGISTERED);
+        let _ = (INIT_NONCE_SIZE, CAPFLAG_WINK, CAPFLAG_LOCK, CAPFLAG_CBOR, CAPFLAG_NMSG);
+        let _ = (CTAP2_MAKE_CREDENTIAL, CTAP2_GET_ASSERTION, CTAP2_GET_INFO, CTAP2_CLIENT_PIN, CTAP2_CREDENTIAL_MANAGEMENT, CTAP2_CREDENTIAL_MANAGEMENT_PREVIEW);
+        let _ = (CLIENT_PIN_GET_RETRIES, CLIENT_PIN_GET_KEY_AGREEMENT, CLIENT_PIN_GET_PIN_TOKEN, CRED_MGMT_ENUMERATE_CREDENTIALS_BEGIN, CRED_MGMT_ENUMERATE_CREDENTIALS_NEXT);
+        let _ = (CTAP2_OK, CTAP2_ERR_UNSUPPORTED_OPTION, CTAP2_ERR_INVALID_OPTION, CTAP2_ERR_NO_CREDENTIALS, CTAP2_ERR_USER_ACTION_TIMEOUT);
+        let _ = (ERR_NONE, ERR_INVALID_CMD, ERR_INVALID_PAR, ERR_INVALID_LEN, ERR_INVALID_SEQ, ERR_MSG_TIMEOUT, ERR_CHANNEL_BUSY, ERR_LOCK_REQUIRED, ERR_INVALID_CID, ERR_OTHER);
+        let _ = (SW_NO_ERROR, SW_CONDITIONS_NOT_SATISFIED, SW_WRONG_DATA, SW_WRONG_LENGTH, SW_CLA_NOT_SUPPORTED, SW_INS_NOT_SUPPORTED);
+
+        // Frames are either kind.
+        assert_eq!(U2FHID_INIT & TYPE_MASK, TYPE_INIT);
+        assert_eq!(0x05 & TYPE_MASK, TYPE_CONT);
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::io;
+
+use u2ftypes::KeyHandle;
+
+// Reads the counter from a raw authenticate response: a user presence byte,
+// followed by the counter in big endian byte order and the signature.
+pub fn sign_counter(response: &[u8]) -> Option<u32> {
+    if response.len() < 5 {
+        return None;
+    }
+    Some(response[1..5].iter().fold(0, |num, b| (num << 8) | (*b as u32)))
+}
+
+// What a new counter value means, compared to the ones seen before.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterCheck {
+    First,
+    Increased,
+    // The token might have been cloned, or its counter wrapped around.
+    NotIncreased { last: u32, current: u32 }
+}
+
+// Remembers the highest counter seen per device and key handle. Tokens
+// without a serial number share a single entry per key handle.
+#[derive(Default)]
+pub struct SignCounters {
+    last: HashMap<(Option<String>, KeyHandle), u32>
+}
+
+impl SignCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Records the counter in the response to a sign request, as passed to
+    // the `U2FManager::sign()` callback.
+    pub fn check(&mut self, serial_number: Option<&str>, key_handle: &KeyHandle, response: &[u8]) -> io::Result<CounterCheck> {
+        let current = sign_counter(response).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "sign response too short")
+        })?;
+
+        let key = (serial_number.map(String::from), key_handle.clone());
+        let check = match self.last.get(&key) {
+            None => CounterCheck::First,
+            Some(&last) if current > last => CounterCheck::Increased,
+            Some(&last) => CounterCheck::NotIncreased { last, current }
+        };
+
+        // Keep the highest value, so that a clone keeps being noticed.
+        let last = self.last.entry(key).or_insert(current);
+        if current > *last {
+            *last = current;
+        }
+
+        if let CounterCheck::NotIncreased { last, current } = check {
+            warn!("signature counter didn't increase ({} after {})", current, last);
+        }
+        Ok(check)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign_counter, CounterCheck, SignCounters};
+    use u2ftypes::KeyHandle;
+
+    fn response(counter: u32) -> Vec<u8> {
+        let mut response = vec![0x01];
+        response.extend(&[(counter >> 24) as u8, (counter >> 16) as u8, (counter >> 8) as u8, counter as u8]);
+        response.extend(&[0x30, 0x44, 0x90, 0x00]);
+        response
+    }
+
+    #[test]
+    fn test_sign_counter() {
+        assert_eq!(sign_counter(&response(0x01020304)), Some(0x01020304));
+        assert_eq!(sign_counter(&[0x01, 0x00, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn test_counter_not_increased() {
+        let key_handle = KeyHandle::from(vec![0x33; 64]);
+        let mut counters = SignCounters::new();
+
+        assert_eq!(counters.check(Some("0123"), &key_handle, &response(5)).unwrap(), CounterCheck::First);
+        assert_eq!(counters.check(Some("0123"), &key_handle, &response(6)).unwrap(), CounterCheck::Increased);
+        assert_eq!(counters.check(Some("0123"), &key_handle, &response(6)).unwrap(),
+                   CounterCheck::NotIncreased { last: 6, current: 6 });
+        assert_eq!(counters.check(Some("0123"), &key_handle, &response(2)).unwrap(),
+                   CounterCheck::NotIncreased { last: 6, current: 2 });
+
+        // Other devices are tracked separately.
+        assert_eq!(counters.check(Some("4567"), &key_handle, &response(1)).unwrap(), CounterCheck::First);
+        assert!(counters.check(None, &key_handle, &[0x01]).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::error::Error;
+use std::{fmt, io};
+
+use u2fprotocol::{status_word, status_word_error};
+
+// What went wrong, for callers that need to tell failures apart. Register,
+// sign and send_apdu fail with these; everything else still deals in
+// `io::Error`s, and `U2FError::from()` converts between the two. Variants
+// are only ever added.
+#[derive(Debug)]
+pub enum U2FError {
+    // The operation ran out of time, e.g. nobody touched a device.
+    Timeout,
+    // The operation was cancelled, or the manager shut down.
+    Cancelled,
+    // A device answered with this status word, e.g. 0x6a80 for a key
+    // handle it doesn't know.
+    ApduStatus(u16),
+    // Another operation is in flight and the manager was asked not to
+    // cancel it, see `U2FManagerBuilder::reject_if_busy()`.
+    Busy,
+    // The operation was still queued when the deadline for the whole queue
+    // passed, see `U2FManager::set_queue_deadline()`.
+    QueueDeadline,
+    // Anything else, e.g. the device was unplugged.
+    DeviceError(io::Error),
+    // The arguments were turned down before the operation started, e.g. a
+    // challenge that isn't 32 bytes long.
+    InvalidInput(io::Error)
+}
+
+// What the status words of U2F devices mean, as far as we know them.
+pub fn status_reason(status_word: u16) -> Option<&'static str> {
+    match status_word {
+        0x9000 => Some("no error"),
+        0x6985 => Some("test of user presence required"),
+        0x6a80 => Some("bad key handle or data"),
+        0x6700 => Some("wrong length"),
+        0x6e00 => Some("class not supported"),
+        0x6d00 => Some("instruction not supported"),
+        _ => None
+    }
+}
+
+// Error payload for the errors we raise ourselves, so that they survive the
+// trip through an `io::Error`. A device's errors of the same kind, e.g. a
+// read that timed out, are told apart by not having one.
+#[derive(Clone, Copy, Debug)]
+enum Raised {
+    Timeout,
+    Cancelled,
+    Busy,
+    QueueDeadline
+}
+
+impl Raised {
+    fn kind(self) -> io::ErrorKind {
+        match self {
+            Raised::Timeout | Raised::QueueDeadline => io::ErrorKind::TimedOut,
+            Raised::Cancelled => io::ErrorKind::Interrupted,
+            Raised::Busy => io::ErrorKind::WouldBlock
+        }
+    }
+}
+
+impl fmt::Display for Raised {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Raised::Timeout => write!(f, "timed out"),
+            Raised::Cancelled => write!(f, "cancelled"),
+            Raised::Busy => write!(f, "operation already in progress"),
+            Raised::QueueDeadline => write!(f, "queue deadline elapsed")
+        }
+    }
+}
+
+impl Error for Raised {}
+
+fn raised(raised: Raised) -> io::Error {
+    io::Error::new(raised.kind(), raised)
+}
+
+// The error an operation fails with when its deadline passed.
+pub fn timed_out() -> io::Error {
+    raised(Raised::Timeout)
+}
+
+// The error an operation fails with when it was cancelled.
+pub fn cancelled() -> io::Error {
+    raised(Raised::Cancelled)
+}
+
+impl U2FError {
+    // The status word the device answered with, if it did.
+    pub fn status_word(&self) -> Option<u16> {
+        match *self {
+            U2FError::ApduStatus(status_word) => Some(status_word),
+            _ => None
+        }
+    }
+
+    // Why the device turned the request down, readable for a known status
+    // word. `None` for unknown ones and for other errors.
+    pub fn reason(&self) -> Option<&'static str> {
+        self.status_word().and_then(status_reason)
+    }
+}
+
+impl From<io::Error> for U2FError {
+    fn from(e: io::Error) -> Self {
+        // Status words first: 0x6985 comes as a TimedOut error, but the
+        // device did answer.
+        if let Some(status_word) = status_word(&e) {
+            return U2FError::ApduStatus(status_word);
+        }
+        let raised = e.get_ref().and_then(|inner| inner.downcast_ref::<Raised>()).cloned();
+        match raised {
+            Some(Raised::Timeout) => U2FError::Timeout,
+            Some(Raised::Cancelled) => U2FError::Cancelled,
+            Some(Raised::Busy) => U2FError::Busy,
+            Some(Raised::QueueDeadline) => U2FError::QueueDeadline,
+            None => U2FError::DeviceError(e)
+        }
+    }
+}
+
+impl From<U2FError> for io::Error {
+    fn from(e: U2FError) -> Self {
+        match e {
+            U2FError::Timeout => raised(Raised::Timeout),
+            U2FError::Cancelled => raised(Raised::Cancelled),
+            U2FError::Busy => raised(Raised::Busy),
+            U2FError::QueueDeadline => raised(Raised::QueueDeadline),
+            U2FError::ApduStatus(status_word) => status_word_error(status_word),
+            U2FError::DeviceError(e) | U2FError::InvalidInput(e) => e
+        }
+    }
+}
+
+impl fmt::Display for U2FError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            U2FError::Timeout => write!(f, "timed out"),
+            U2FError::Cancelled => write!(f, "cancelled"),
+            U2FError::Busy => write!(f, "operation already in progress"),
+            U2FError::QueueDeadline => write!(f, "queue deadline elapsed"),
+            U2FError::ApduStatus(status_word) => match status_reason(status_word) {
+                Some(reason) => write!(f, "status {:#06x}: {}", status_word, reason),
+                None => write!(f, "status {:#06x}", status_word)
+            },
+            U2FError::DeviceError(ref e) | U2FError::InvalidInput(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl Error for U2FError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            U2FError::DeviceError(ref e) | U2FError::InvalidInput(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{U2FError, cancelled, status_reason, timed_out};
+    use consts::{U2FHID_MSG, U2F_AUTHENTICATE, U2F_REQUEST_USER_PRESENCE};
+    use std::io;
+    use testdevice::{apdu, TestDevice};
+    use u2fprotocol::{U2FDevice, status_word, u2f_sign};
+    use u2ftypes::KeyHandle;
+
+    #[test]
+    fn test_u2f_error() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let key_handle = KeyHandle::from(vec![0x33; 4]);
+        let mut data = challenge.clone();
+        data.extend(&application);
+        data.push(4);
+        data.extend(key_handle.as_bytes());
+
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_AUTHENTICATE, U2F_REQUEST_USER_PRESENCE, &data));
+        device.add_message_read(U2FHID_MSG, &[0x6a, 0x80]);
+        let err = u2f_sign(&mut device, &challenge, &application, &key_handle).unwrap_err();
+        assert_eq!(err.status_word(), Some(0x6a80));
+        assert_eq!(err.reason(), Some("bad key handle or data"));
+        assert_eq!(err.to_string(), "status 0x6a80: bad key handle or data");
+
+        // And back, for code that deals in io::Errors.
+        let err: io::Error = err.into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(status_word(&err), Some(0x6a80));
+
+        match U2FError::from(timed_out()) {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        match U2FError::from(cancelled()) {
+            U2FError::Cancelled => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        match U2FError::from(io::Error::from(U2FError::Busy)) {
+            U2FError::Busy => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(io::Error::from(U2FError::Busy).kind(), io::ErrorKind::WouldBlock);
+
+        // Only ours, a device's read that timed out or was interrupted is
+        // a device error.
+        for kind in &[io::ErrorKind::TimedOut, io::ErrorKind::Interrupted, io::ErrorKind::WouldBlock] {
+            match U2FError::from(io::Error::new(*kind, "read failed")) {
+                U2FError::DeviceError(e) => assert_eq!(e.kind(), *kind),
+                other => panic!("unexpected {:?}", other)
+            }
+        }
+        // Still a timeout for code that only looks at the kind.
+        let err = io::Error::from(U2FError::QueueDeadline);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "queue deadline elapsed");
+        match U2FError::from(err) {
+            U2FError::QueueDeadline => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        match U2FError::from(io::Error::new(io::ErrorKind::NotFound, "unplugged")) {
+            U2FError::DeviceError(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            other => panic!("unexpected {:?}", other)
+        }
+
+        assert_eq!(status_reason(0x6985), Some("test of user presence required"));
+        assert_eq!(status_reason(0x6f00), None);
+        assert_eq!(U2FError::ApduStatus(0x6f00).to_string(), "status 0x6f00");
+    }
+}
@@ -0,0 +1,265 @@
+use std::io;
+
+use crypto::aessafe::{AesSafe256Decryptor, AesSafe256Encryptor};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+use rand::Rng;
+
+use cbor::Value;
+use p256;
+use u2ftypes::HmacSecretSalts;
+use util::sha256;
+
+const BLOCK_SIZE: usize = 16;
+
+// The authenticator data flag that says extension outputs follow.
+const FLAG_EXTENSION_DATA: u8 = 0x80;
+
+fn invalid_key() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid key agreement key")
+}
+
+// A key shared with a token, as agreed on with CTAP2 PIN protocol 1: ECDH on
+// P-256 with a throwaway key of ours, then SHA-256 over the x coordinate.
+// Encrypts the hmac-secret salts and the PIN, and decrypts what the token
+// sends back.
+#[derive(Clone)]
+pub struct SharedSecret {
+    key: [u8; 32],
+    // Our public key, for the token to do its half.
+    x: [u8; 32],
+    y: [u8; 32]
+}
+
+impl SharedSecret {
+    // Agrees on a key with the token's key agreement key, the big endian x and
+    // y coordinates of a P-256 point.
+    pub fn new<R>(token_x: &[u8], token_y: &[u8], rng: &mut R) -> io::Result<Self>
+        where R: Rng + ?Sized
+    {
+        if !p256::is_on_curve(token_x, token_y) {
+            return Err(invalid_key());
+        }
+
+        // Almost every 32-byte number is a valid key.
+        let mut private_key = [0u8; 32];
+        loop {
+            rng.fill_bytes(&mut private_key);
+            if let Some((x, y)) = p256::public_key(&private_key) {
+                let shared = p256::ecdh(&private_key, token_x, token_y).ok_or_else(invalid_key)?;
+                return Ok(Self { key: sha256(&shared), x, y });
+            }
+        }
+    }
+
+    // Same, for a COSE_Key as returned by clientPin(getKeyAgreement).
+    pub(crate) fn from_cose_key<R>(key_agreement: &Value, rng: &mut R) -> io::Result<Self>
+        where R: Rng + ?Sized
+    {
+        let coordinate = |key: i64| {
+            match key_agreement.get(&Value::Negative(key)) {
+                Some(Value::Bytes(bytes)) => Ok(bytes),
+                _ => Err(invalid_key())
+            }
+        };
+        Self::new(coordinate(-2)?, coordinate(-3)?, rng)
+    }
+
+    // Our public key as a COSE_Key, as the token expects it.
+    pub(crate) fn cose_key(&self) -> Value {
+        // kty: EC2, alg: ECDH-ES+HKDF-256, crv: P-256
+        Value::Map(vec![(Value::Unsigned(1), Value::Unsigned(2)),
+                        (Value::Unsigned(3), Value::Negative(-25)),
+                        (Value::Negative(-1), Value::Unsigned(1)),
+                        (Value::Negative(-2), Value::Bytes(self.x.to_vec())),
+                        (Value::Negative(-3), Value::Bytes(self.y.to_vec()))])
+    }
+
+    // pinHashEnc for clientPin(getPinToken): the first half of the PIN's
+    // SHA-256 hash, encrypted.
+    pub(crate) fn encrypt_pin_hash(&self, pin: &str) -> Vec<u8> {
+        self.encrypt(&sha256(pin.as_bytes())[..16])
+    }
+
+    // The pinToken from a clientPin(getPinToken) response.
+    pub(crate) fn decrypt_pin_token(&self, pin_token_enc: &[u8]) -> io::Result<Vec<u8>> {
+        self.decrypt(pin_token_enc)
+    }
+
+    // AES-256-CBC with an all zero IV and no padding. `data` must be a
+    // multiple of the block size.
+    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let aes = AesSafe256Encryptor::new(&self.key);
+        let mut previous = [0u8; BLOCK_SIZE];
+        let mut encrypted = Vec::with_capacity(data.len());
+        for block in data.chunks(BLOCK_SIZE) {
+            let mut input = [0u8; BLOCK_SIZE];
+            for i in 0..BLOCK_SIZE {
+                input[i] = block[i] ^ previous[i];
+            }
+            aes.encrypt_block(&input, &mut previous);
+            encrypted.extend(&previous);
+        }
+        encrypted
+    }
+
+    fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid encrypted data"));
+        }
+
+        let aes = AesSafe256Decryptor::new(&self.key);
+        let mut previous = &[0u8; BLOCK_SIZE][..];
+        let mut decrypted = Vec::with_capacity(data.len());
+        for block in data.chunks(BLOCK_SIZE) {
+            let mut output = [0u8; BLOCK_SIZE];
+            aes.decrypt_block(block, &mut output);
+            decrypted.extend(output.iter().zip(previous).map(|(a, b)| a ^ b));
+            previous = block;
+        }
+        Ok(decrypted)
+    }
+
+    fn authenticate(&self, data: &[u8]) -> Vec<u8> {
+        authenticate(&self.key, data)
+    }
+}
+
+// The first 16 bytes of HMAC-SHA-256 over `data`, as PIN protocol 1 checks
+// requests. `key` is the shared secret, or a pinToken.
+pub fn authenticate(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result().code()[..16].to_vec()
+}
+
+// The hmac-secret extension input for getAssertion: our public key, the
+// encrypted salts, and their MAC.
+pub fn extension_input(secret: &SharedSecret, salts: &HmacSecretSalts) -> Value {
+    let mut salt = salts.salt1.to_vec();
+    if let Some(ref salt2) = salts.salt2 {
+        salt.extend(salt2);
+    }
+
+    let salt_enc = secret.encrypt(&salt);
+    let salt_auth = secret.authenticate(&salt_enc);
+    Value::Map(vec![(Value::Unsigned(1), secret.cose_key()),
+                    (Value::Unsigned(2), Value::Bytes(salt_enc)),
+                    (Value::Unsigned(3), Value::Bytes(salt_auth))])
+}
+
+// The decrypted hmac-secret outputs from the authenticator data of a
+// getAssertion response, one 32-byte secret per salt. Returns `None` if the
+// token didn't include any.
+pub fn extension_output(secret: &SharedSecret, auth_data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    // rpIdHash, flags, signCount, then the extensions if the flag says so.
+    if auth_data.len() < 37 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid authenticator data"));
+    }
+    if auth_data[32] & FLAG_EXTENSION_DATA == 0 {
+        return Ok(None);
+    }
+
+    let extensions = ::cbor::decode(&auth_data[37..])?;
+    match extensions.get(&Value::Text("hmac-secret".to_owned())) {
+        Some(Value::Bytes(output)) if output.len() == 32 || output.len() == 64 => {
+            secret.decrypt(output).map(Some)
+        }
+        None => Ok(None),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid hmac-secret output"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authenticate, extension_input, extension_output, SharedSecret};
+    use cbor::{encode, Value};
+    use testdevice::{token_key, CountingRng, OUTPUT_ENC, PIN_HASH_ENC, PIN_TOKEN_ENC, TOKEN_X, TOKEN_Y};
+    use u2ftypes::HmacSecretSalts;
+
+    // Ours, for the private key 0x0001...1f that CountingRng gives us.
+    const PLATFORM_X: [u8; 32] = [
+        0x7a, 0x59, 0x31, 0x80, 0x86, 0x0c, 0x40, 0x37, 0xc8, 0x3c, 0x12, 0x74, 0x98, 0x45, 0xc8, 0xee,
+        0x14, 0x24, 0xdd, 0x29, 0x7f, 0xad, 0xcb, 0x89, 0x5e, 0x35, 0x82, 0x55, 0xd2, 0xc7, 0xd2, 0xb2];
+    const PLATFORM_Y: [u8; 32] = [
+        0xa8, 0xca, 0x25, 0x58, 0x0f, 0x26, 0x26, 0xfe, 0x57, 0x90, 0x62, 0xff, 0x1b, 0x99, 0xff, 0x91,
+        0xc2, 0x4a, 0x0d, 0xa0, 0x6f, 0xb3, 0x2b, 0x5b, 0xe2, 0x01, 0x48, 0xc9, 0x24, 0x9f, 0x56, 0x50];
+
+    // The salts 0x71...71 and 0x72...72, encrypted with the shared secret.
+    const SALT_ENC: [u8; 64] = [
+        0x53, 0x2d, 0x27, 0x8d, 0x27, 0x90, 0x58, 0x19, 0xc9, 0x20, 0x29, 0x57, 0x7e, 0x04, 0x00, 0x76,
+        0xcc, 0x13, 0xe2, 0x01, 0x56, 0xe0, 0x35, 0xdf, 0x54, 0x79, 0x72, 0xde, 0x05, 0xeb, 0x96, 0xc4,
+        0x8a, 0x6e, 0x37, 0xf5, 0x02, 0x2d, 0x9f, 0x98, 0xa7, 0x2a, 0x76, 0x8b, 0x73, 0xdb, 0xde, 0xf4,
+        0x9e, 0x47, 0x9b, 0x5d, 0xb9, 0xd1, 0x3f, 0x66, 0x94, 0x82, 0x64, 0x61, 0xe0, 0x5b, 0x6f, 0x93];
+    const SALT_AUTH: [u8; 16] = [
+        0xae, 0x5b, 0x55, 0x7c, 0xee, 0xc5, 0x9d, 0x8f, 0x80, 0xee, 0x9b, 0xdf, 0x01, 0xfa, 0xf2, 0x2f];
+
+    fn salts() -> HmacSecretSalts {
+        HmacSecretSalts { salt1: [0x71; 32], salt2: Some([0x72; 32]) }
+    }
+
+    #[test]
+    fn test_extension_input() {
+        let secret = SharedSecret::from_cose_key(&token_key(), &mut CountingRng(0)).unwrap();
+        let input = extension_input(&secret, &salts());
+
+        let cose_key = input.get(&Value::Unsigned(1)).unwrap();
+        assert_eq!(cose_key.get(&Value::Negative(-2)), Some(&Value::Bytes(PLATFORM_X.to_vec())));
+        assert_eq!(cose_key.get(&Value::Negative(-3)), Some(&Value::Bytes(PLATFORM_Y.to_vec())));
+        assert_eq!(input.get(&Value::Unsigned(2)), Some(&Value::Bytes(SALT_ENC.to_vec())));
+        assert_eq!(input.get(&Value::Unsigned(3)), Some(&Value::Bytes(SALT_AUTH.to_vec())));
+
+        // A single salt is just the first block.
+        let input = extension_input(&secret, &HmacSecretSalts { salt1: [0x71; 32], salt2: None });
+        assert_eq!(input.get(&Value::Unsigned(2)), Some(&Value::Bytes(SALT_ENC[..32].to_vec())));
+    }
+
+    #[test]
+    fn test_extension_output() {
+        let secret = SharedSecret::from_cose_key(&token_key(), &mut CountingRng(0)).unwrap();
+
+        let mut auth_data = vec![0x44; 32];
+        auth_data.extend(&[0x81, 0, 0, 0, 1]);
+        let extensions = Value::Map(vec![(Value::Text("hmac-secret".to_owned()), Value::Bytes(OUTPUT_ENC.to_vec()))]);
+        auth_data.extend(encode(&extensions));
+
+        let output = extension_output(&secret, &auth_data).unwrap().unwrap();
+        assert_eq!(&output[..32], &[0x81; 32][..]);
+        assert_eq!(&output[32..], &[0x82; 32][..]);
+
+        // Nothing without the flag, or without the extension.
+        assert_eq!(extension_output(&secret, &auth_data[..37]).unwrap_err().kind(), ::std::io::ErrorKind::InvalidData);
+        auth_data[32] = 0x01;
+        assert_eq!(extension_output(&secret, &auth_data).unwrap(), None);
+    }
+
+    #[test]
+    fn test_pin_token() {
+        let secret = SharedSecret::from_cose_key(&token_key(), &mut CountingRng(0)).unwrap();
+        assert_eq!(secret.encrypt_pin_hash("1234"), PIN_HASH_ENC.to_vec());
+        assert_eq!(secret.decrypt_pin_token(&PIN_TOKEN_ENC).unwrap(), vec![0x91; 32]);
+        assert!(secret.decrypt_pin_token(&PIN_TOKEN_ENC[..31]).is_err());
+
+        // enumerateCredentialsBegin for the RP ID hash 0x44...44.
+        let mut data = vec![0x04, 0xa1, 0x01, 0x58, 0x20];
+        data.extend(&[0x44; 32]);
+        assert_eq!(authenticate(&[0x91; 32], &data), vec![0xbe, 0xfd, 0x0a, 0x05, 0x3a, 0x60, 0x0e, 0x4c, 0xe2, 0x6b, 0x99, 0x26, 0x63, 0x67, 0xa0, 0xa5]);
+    }
+
+    #[test]
+    fn test_invalid_key_agreement() {
+        let mut key = token_key();
+        if let Value::Map(ref mut entries) = key {
+            entries[4].1 = Value::Bytes(vec![0x00; 32]);
+        }
+        assert!(SharedSecret::from_cose_key(&key, &mut CountingRng(0)).is_err());
+        assert!(SharedSecret::from_cose_key(&Value::Null, &mut CountingRng(0)).is_err());
+        assert!(SharedSecret::new(&TOKEN_X, &TOKEN_Y[..31], &mut CountingRng(0)).is_err());
+
+        // The same key as its coordinates.
+        let secret = SharedSecret::new(&TOKEN_X, &TOKEN_Y, &mut CountingRng(0)).unwrap();
+        assert_eq!(secret.encrypt_pin_hash("1234"), PIN_HASH_ENC.to_vec());
+    }
+}
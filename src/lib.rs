@@ -1,21 +1,24 @@
 #[macro_use]
 mod util;
 
-#[cfg(any(target_os = "linux"))]
+#[cfg(target_os = "linux")]
 extern crate libudev;
 
-#[cfg(any(target_os = "linux"))]
+#[cfg(all(target_os = "linux", feature = "libusb"))]
+extern crate libusb;
+
+#[cfg(target_os = "linux")]
 #[path="linux/mod.rs"]
 pub mod platform;
 
-#[cfg(any(target_os = "macos"))]
+#[cfg(target_os = "macos")]
 extern crate core_foundation_sys;
 
-#[cfg(any(target_os = "macos"))]
+#[cfg(target_os = "macos")]
 #[path="macos/mod.rs"]
 pub mod platform;
 
-#[cfg(any(target_os = "windows"))]
+#[cfg(target_os = "windows")]
 #[path="windows/mod.rs"]
 pub mod platform;
 
@@ -23,15 +26,54 @@ pub mod platform;
 extern crate rand;
 extern crate libc;
 extern crate boxfnonce;
+extern crate crypto;
+#[cfg(feature = "futures")]
+extern crate futures;
 
-mod consts;
+mod cbor;
+mod clientdata;
+pub mod consts;
+mod counter;
+mod error;
+mod hmacsecret;
 mod manager;
+mod metrics;
 mod runloop;
+mod p256;
+mod platformmanager;
+mod registry;
+mod session;
+mod statemachine;
+mod stats;
+#[cfg(feature = "futures")]
+mod stream;
+mod u2ftypes;
+mod verify;
+mod warnings;
+mod webauthn;
+
+#[cfg(test)]
+mod testdevice;
 
 // TODO
 pub mod u2fprotocol;
 pub use u2fprotocol::*;
+pub use u2ftypes::*;
+pub use clientdata::*;
+pub use counter::*;
+pub use error::{status_reason, U2FError};
+pub use hmacsecret::SharedSecret;
+pub use verify::*;
+pub use webauthn::*;
+pub use util::{from_base64url, to_base64url};
+pub use metrics::{MetricEvent, OperationKind, Outcome};
+pub use warnings::Warning;
 pub use manager::U2FManager as U2FManager;
+pub use manager::U2FManagerBuilder;
+pub use session::DeviceSession;
+pub use stats::DeviceStats;
+#[cfg(feature = "futures")]
+pub use stream::DeviceEventStream;
 
 mod capi;
 pub use capi::*;
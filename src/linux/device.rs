@@ -1,39 +1,390 @@
 extern crate libc;
 
+use std::collections::HashSet;
 use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::fmt;
 use std::io;
 use std::io::{Read, Write};
 use std::os::unix::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use consts::CID_BROADCAST;
 use platform::hidraw;
+#[cfg(test)]
+use platform::testbench::{TestHandle, TEST_PATH_PREFIX};
+#[cfg(feature = "libusb")]
+use platform::usb::UsbDevice;
 use util::{from_unix_result, to_io_err};
 use u2fprotocol::U2FDevice;
+use u2ftypes::{DeviceInfo, FrameObserver, OperationContext, Transport};
 
+// The kernel exposes the USB serial number string of a HID device as
+// HID_UNIQ in its uevent file. It's empty if there is none.
+fn parse_hid_uniq(uevent: &str) -> Option<String> {
+    uevent.lines()
+          .filter(|line| line.starts_with("HID_UNIQ="))
+          .map(|line| line["HID_UNIQ=".len()..].trim().to_owned())
+          .find(|value| !value.is_empty())
+}
+
+// HID_ID=<bus>:<vendor ID>:<product ID>, all in hex.
+fn parse_hid_id(uevent: &str) -> Option<(u16, u16, u16)> {
+    let value = match uevent.lines().find(|line| line.starts_with("HID_ID=")) {
+        Some(line) => &line["HID_ID=".len()..],
+        None => return None
+    };
+
+    let ids: Vec<u32> = value.trim().split(':').filter_map(|id| {
+        u32::from_str_radix(id, 16).ok()
+    }).collect();
+
+    if ids.len() != 3 {
+        return None;
+    }
+    Some((ids[0] as u16, ids[1] as u16, ids[2] as u16))
+}
+
+// HID_PHYS=<bus path>, e.g. "usb-0000:00:14.0-1/input1".
+fn parse_hid_phys(uevent: &str) -> Option<String> {
+    uevent.lines()
+          .filter(|line| line.starts_with("HID_PHYS="))
+          .map(|line| line["HID_PHYS=".len()..].trim().to_owned())
+          .find(|value| !value.is_empty())
+}
+
+// Bus types from linux/input.h.
+const BUS_USB: u16 = 0x03;
+
+// Best effort, there's no bus type for NFC. Anything we don't know (e.g.
+// Bluetooth, which we'd talk to over hidraw like USB) counts as USB HID.
+fn bus_transport(bus: u16) -> Transport {
+    match bus {
+        BUS_USB => Transport::UsbHid,
+        _ => Transport::default()
+    }
+}
+
+// What the uevent file tells about a device.
+fn parse_device_info(uevent: &str) -> DeviceInfo {
+    let mut info = DeviceInfo { serial_number: parse_hid_uniq(uevent), ..DeviceInfo::default() };
+    if let Some((bus, vid, pid)) = parse_hid_id(uevent) {
+        info.transport = bus_transport(bus);
+        info.vendor_id = Some(vid);
+        info.product_id = Some(pid);
+    }
+    info
+}
+
+// Whether reading or writing failed because the device was unplugged. hidraw
+// fails writes with ENODEV and reads with EIO once the device is gone, and
+// the character device itself may be gone already (ENXIO).
+pub fn is_disconnect_error(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENODEV) | Some(libc::ENXIO) | Some(libc::EIO))
+}
+
+// Reads the uevent file of the hidraw device at `path` from sysfs.
+fn read_uevent(path: &OsString) -> Option<String> {
+    let uevent = match hidraw::sysfs_device_path(path) {
+        Some(dir) => dir.join("uevent"),
+        None => return None
+    };
+
+    let mut contents = String::new();
+    match File::open(uevent).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => Some(contents),
+        Err(_) => None
+    }
+}
+
+// Reads a sysfs attribute that holds a hex byte, like bInterfaceNumber.
+fn read_hex_attribute(path: &Path) -> Option<u8> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).ok()?;
+    u8::from_str_radix(contents.trim(), 16).ok()
+}
+
+// Reads an endpoint's polling interval, which the kernel derives from the
+// descriptor's bInterval and the bus speed, e.g. "8ms" or "125us".
+fn read_interval(path: &Path) -> Option<Duration> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).ok()?;
+    let contents = contents.trim();
+    if let Some(ms) = contents.strip_suffix("ms") {
+        ms.parse().ok().map(Duration::from_millis)
+    } else if let Some(us) = contents.strip_suffix("us") {
+        us.parse().ok().map(Duration::from_micros)
+    } else {
+        None
+    }
+}
+
+// Fills in the USB interface number, the endpoint addresses and the IN
+// endpoint's polling interval from `dir`, the sysfs directory of the
+// interface, with an ep_XX directory for each endpoint. The control
+// endpoint, ep_00, doesn't count.
+fn read_usb_interface(dir: &Path, info: &mut DeviceInfo) {
+    info.usb_interface = read_hex_attribute(&dir.join("bInterfaceNumber"));
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !entry.file_name().to_string_lossy().starts_with("ep_") {
+            continue;
+        }
+        match read_hex_attribute(&entry.path().join("bEndpointAddress")) {
+            Some(0) | None => {}
+            Some(address) if address & 0x80 != 0 => {
+                info.usb_endpoint_in = Some(address);
+                info.usb_poll_interval = read_interval(&entry.path().join("interval"));
+            }
+            Some(address) => info.usb_endpoint_out = Some(address)
+        }
+    }
+}
+
+// Identifies the physical device behind `path` across backends: its bus path,
+// or else its serial number. Every interface of a device has its own hidraw
+// node, with "/input<N>" appended to the bus path, so that's left out.
+pub fn stable_id(path: &OsString) -> Option<String> {
+    read_uevent(path).and_then(|uevent| uevent_stable_id(&uevent))
+}
+
+fn uevent_stable_id(uevent: &str) -> Option<String> {
+    parse_hid_phys(uevent).map(|phys| {
+        match phys.rfind("/input") {
+            Some(index) if phys[index + "/input".len()..].chars().all(|c| c.is_ascii_digit()) => phys[..index].to_owned(),
+            _ => phys
+        }
+    }).or_else(|| parse_hid_uniq(uevent))
+}
+
+// How we talk to a device.
 #[derive(Debug)]
+enum Handle {
+    Hidraw(libc::c_int),
+    #[cfg(feature = "libusb")]
+    Usb(UsbDevice),
+    #[cfg(test)]
+    Test(TestHandle)
+}
+
+fn open_hidraw(path: &OsString) -> io::Result<Handle> {
+    let cstr = CString::new(path.as_bytes()).map_err(to_io_err)?;
+    let fd = unsafe { libc::open(cstr.as_ptr(), libc::O_RDWR) };
+    Ok(Handle::Hidraw(from_unix_result(fd)?))
+}
+
+impl Handle {
+    // The hidraw node's file descriptor, other backends have none.
+    fn hidraw_fd(&self) -> Option<libc::c_int> {
+        match *self {
+            Handle::Hidraw(fd) => Some(fd),
+            #[cfg(feature = "libusb")]
+            Handle::Usb(_) => None,
+            #[cfg(test)]
+            Handle::Test(_) => None
+        }
+    }
+
+    // What the backend tells about the device at `path`.
+    fn device_info(&self, path: &OsString) -> DeviceInfo {
+        match *self {
+            Handle::Hidraw(_) => {
+                let mut info = read_uevent(path).map_or_else(DeviceInfo::default, |uevent| {
+                    parse_device_info(&uevent)
+                });
+                // The HID device's parent is the USB interface, if it's on USB.
+                if let Some(dir) = hidraw::sysfs_device_path(path) {
+                    read_usb_interface(&dir.join(".."), &mut info);
+                }
+                info
+            }
+            #[cfg(feature = "libusb")]
+            Handle::Usb(ref dev) => dev.device_info(),
+            #[cfg(test)]
+            Handle::Test(ref dev) => dev.device().info.clone()
+        }
+    }
+}
+
+#[cfg(feature = "libusb")]
+fn open_usb(path: &OsString) -> io::Result<Handle> {
+    UsbDevice::open(path).map(Handle::Usb)
+}
+
+#[cfg(not(feature = "libusb"))]
+fn open_usb(_: &OsString) -> io::Result<Handle> {
+    Err(::util::io_err("built without libusb support"))
+}
+
+// Opens the U2F device at `path`, e.g. /dev/hidraw0.
+pub fn open(path: &str) -> io::Result<Device> {
+    let dev = Device::new(OsString::from(path))?;
+    if !dev.is_u2f() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a U2F device"));
+    }
+    Ok(dev)
+}
+
+// Devices libusb found have paths like "usb:1:4:1", see `usb::enumerate()`.
+// All others are hidraw nodes.
+pub const USB_PATH_PREFIX: &str = "usb:";
+
+// The backend that found the device at `path`, and so has to open it.
+fn found_by(path: &OsString) -> &'static str {
+    #[cfg(test)]
+    {
+        if path.as_bytes().starts_with(TEST_PATH_PREFIX.as_bytes()) {
+            return "test";
+        }
+    }
+    if path.as_bytes().starts_with(USB_PATH_PREFIX.as_bytes()) { "libusb" } else { "hidraw" }
+}
+
+// Opens `path` with the backend that found it. If the caller asked for a
+// particular backend, the devices the other one found are refused.
+// The devices of a `TestBench` only open when the "test" backend is asked
+// for.
+fn open_handle(path: &OsString, backend: Option<&str>) -> io::Result<Handle> {
+    let found_by = found_by(path);
+    match backend {
+        Some(backend) if !super::backends().contains(&backend) => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown backend {:?}", backend)))
+        }
+        Some(backend) if backend != found_by => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("not a {} device", backend)))
+        }
+        #[cfg(test)]
+        None if found_by == "test" => Err(io::Error::new(io::ErrorKind::InvalidInput, "only opened for the test backend")),
+        #[cfg(test)]
+        _ if found_by == "test" => TestHandle::open(path).map(Handle::Test),
+        _ if found_by == "libusb" => open_usb(path),
+        _ => open_hidraw(path)
+    }
+}
+
+// The hidraw fds of an operation's devices, so that they can be let go of
+// should its thread be detached, see `RunLoop::on_detach()`.
+#[derive(Clone, Default)]
+pub struct Releaser {
+    fds: Arc<Mutex<HashSet<libc::c_int>>>
+}
+
+impl Releaser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&self, fd: libc::c_int) {
+        if let Ok(mut fds) = self.fds.lock() {
+            fds.insert(fd);
+        }
+    }
+
+    // Closes `fd`, unless it's released. Locked so that `release()` can't
+    // point a number at /dev/null that was already reused.
+    fn close(&self, fd: libc::c_int) {
+        let fds = self.fds.lock();
+        let _ = unsafe { libc::close(fd) };
+        if let Ok(mut fds) = fds {
+            fds.remove(&fd);
+        }
+    }
+
+    // Points the fds at /dev/null, which closes their devices. A device a
+    // read is stuck on is only closed once the read returns. The fds stay
+    // valid, the devices close them when they're dropped as usual.
+    pub fn release(&self) {
+        let fds = match self.fds.lock() {
+            Ok(fds) => fds,
+            Err(_) => return
+        };
+        let null = unsafe { libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR) };
+        if null < 0 {
+            return;
+        }
+        for &fd in fds.iter() {
+            let _ = unsafe { libc::dup2(null, fd) };
+        }
+        let _ = unsafe { libc::close(null) };
+    }
+}
+
 pub struct Device {
     path: OsString,
-    fd: libc::c_int,
+    handle: Handle,
+    releaser: Option<Releaser>,
     cid: [u8; 4],
+    info: DeviceInfo,
+    observer: Option<FrameObserver>,
+    context: Option<OperationContext>,
+    init_failures: u32,
+    disconnected: bool
 }
 
 impl Device {
     pub fn new(path: OsString) -> io::Result<Self> {
-        let cstr = CString::new(path.as_bytes()).map_err(to_io_err)?;
-        let fd = unsafe { libc::open(cstr.as_ptr(), libc::O_RDWR) };
-        let fd = from_unix_result(fd)?;
-        Ok(Self { path, fd, cid: CID_BROADCAST })
+        Self::with_backend(path, None)
+    }
+
+    // Like `new()`, but refuses a device that another backend than the
+    // given one found.
+    pub fn with_backend(path: OsString, backend: Option<&str>) -> io::Result<Self> {
+        let handle = open_handle(&path, backend)?;
+        let mut info = handle.device_info(&path);
+        info.path = Some(path.to_string_lossy().into_owned());
+        Ok(Self { path, handle, releaser: None, cid: CID_BROADCAST, info, observer: None, context: None, init_failures: 0, disconnected: false })
+    }
+
+    // Lets `releaser` close the device, see `Releaser::release()`.
+    pub fn set_releaser(&mut self, releaser: Releaser) {
+        if let Some(fd) = self.handle.hidraw_fd() {
+            releaser.insert(fd);
+        }
+        self.releaser = Some(releaser);
+    }
+
+    pub fn serial_number(&self) -> Option<String> {
+        self.info.serial_number.clone()
     }
 
     pub fn is_u2f(&self) -> bool {
-        hidraw::is_u2f_device(self.fd)
+        match self.handle {
+            Handle::Hidraw(fd) => hidraw::is_u2f_device(fd),
+            #[cfg(feature = "libusb")]
+            Handle::Usb(ref dev) => dev.is_u2f(),
+            #[cfg(test)]
+            Handle::Test(_) => true
+        }
     }
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
         // Close the fd, ignore any errors.
-        let _ = unsafe { libc::close(self.fd) };
+        if let Some(fd) = self.handle.hidraw_fd() {
+            match self.releaser {
+                Some(ref releaser) => releaser.close(fd),
+                None => { let _ = unsafe { libc::close(fd) }; }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Device {
+    // Leaves out the observer, closures aren't Debug.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Device")
+           .field("path", &self.path)
+           .field("handle", &self.handle)
+           .field("cid", &self.cid)
+           .field("info", &self.info)
+           .finish()
     }
 }
 
@@ -45,17 +396,33 @@ impl PartialEq for Device {
 
 impl Read for Device {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let bufp = buf.as_mut_ptr() as *mut libc::c_void;
-        let rv = unsafe { libc::read(self.fd, bufp, buf.len()) };
-        from_unix_result(rv as usize)
+        match self.handle {
+            Handle::Hidraw(fd) => {
+                let bufp = buf.as_mut_ptr() as *mut libc::c_void;
+                let rv = unsafe { libc::read(fd, bufp, buf.len()) };
+                from_unix_result(rv as usize)
+            }
+            #[cfg(feature = "libusb")]
+            Handle::Usb(ref mut dev) => dev.read(buf),
+            #[cfg(test)]
+            Handle::Test(ref mut dev) => dev.read(buf)
+        }
     }
 }
 
 impl Write for Device {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let bufp = buf.as_ptr() as *const libc::c_void;
-        let rv = unsafe { libc::write(self.fd, bufp, buf.len()) };
-        from_unix_result(rv as usize)
+        match self.handle {
+            Handle::Hidraw(fd) => {
+                let bufp = buf.as_ptr() as *const libc::c_void;
+                let rv = unsafe { libc::write(fd, bufp, buf.len()) };
+                from_unix_result(rv as usize)
+            }
+            #[cfg(feature = "libusb")]
+            Handle::Usb(ref mut dev) => dev.write(buf),
+            #[cfg(test)]
+            Handle::Test(ref mut dev) => dev.write(buf)
+        }
     }
 
     // USB HID writes don't buffer, so this will be a nop.
@@ -66,10 +433,194 @@ impl Write for Device {
 
 impl U2FDevice for Device {
     fn get_cid(&self) -> [u8; 4] {
-        self.cid.clone()
+        self.cid
     }
 
     fn set_cid(&mut self, cid: &[u8; 4]) {
         self.cid.clone_from(cid);
     }
+
+    fn get_device_info(&self) -> DeviceInfo {
+        self.info.clone()
+    }
+
+    fn set_device_info(&mut self, info: DeviceInfo) {
+        self.info = info;
+    }
+    fn frame_observer(&self) -> Option<&FrameObserver> {
+        self.observer.as_ref()
+    }
+
+    fn set_frame_observer(&mut self, observer: Option<FrameObserver>) {
+        self.observer = observer;
+    }
+    fn context(&self) -> Option<&OperationContext> {
+        self.context.as_ref()
+    }
+    fn set_context(&mut self, context: OperationContext) {
+        self.context = Some(context);
+    }
+
+    fn init_failures(&self) -> u32 {
+        self.init_failures
+    }
+
+    fn set_init_failures(&mut self, failures: u32) {
+        self.init_failures = failures;
+    }
+    fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+    fn set_disconnected(&mut self) {
+        self.disconnected = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{libc, open_handle, parse_device_info, parse_hid_id, parse_hid_phys, parse_hid_uniq, read_uevent, read_usb_interface, uevent_stable_id, Releaser};
+    use std::{env, process};
+    use std::ffi::OsString;
+    use std::fs;
+    use std::io;
+    use std::os::unix::io::IntoRawFd;
+    use std::time::Duration;
+    use u2ftypes::{DeviceInfo, Transport};
+
+    #[test]
+    fn test_open_handle() {
+        let hidraw = OsString::from("/dev/nonexistent");
+        let usb = OsString::from("usb:1:4:1");
+        assert_eq!(open_handle(&hidraw, None).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(open_handle(&hidraw, Some("hidraw")).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        // Devices are opened with the backend that found them.
+        let err = open_handle(&usb, Some("hidraw")).unwrap_err();
+        assert_eq!((err.kind(), err.to_string()), (io::ErrorKind::InvalidInput, "not a hidraw device".to_owned()));
+        let err = open_handle(&hidraw, Some("libusb")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(open_handle(&usb, None).is_err());
+
+        let err = open_handle(&hidraw, Some("bogus")).unwrap_err();
+        assert_eq!((err.kind(), err.to_string()), (io::ErrorKind::InvalidInput, "unknown backend \"bogus\"".to_owned()));
+    }
+
+    #[test]
+    fn test_parse_hid_uniq() {
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_NAME=Yubico YubiKey OTP+FIDO+CCID\nHID_PHYS=usb-0000:00:14.0-1/input1\nHID_UNIQ=4711\nMODALIAS=hid:b0003g0001v00001050p00000407\n";
+        assert_eq!(parse_hid_uniq(uevent), Some("4711".to_owned()));
+    }
+
+    #[test]
+    fn test_parse_hid_uniq_none() {
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_UNIQ=\nMODALIAS=hid:b0003g0001v00001050p00000407\n";
+        assert_eq!(parse_hid_uniq(uevent), None);
+        assert_eq!(parse_hid_uniq("DRIVER=hid-generic\n"), None);
+    }
+
+    #[test]
+    fn test_parse_hid_phys() {
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_PHYS=usb-0000:00:14.0-1/input1\nHID_UNIQ=\n";
+        assert_eq!(parse_hid_phys(uevent), Some("usb-0000:00:14.0-1/input1".to_owned()));
+        assert_eq!(parse_hid_phys("DRIVER=hid-generic\n"), None);
+    }
+
+    #[test]
+    fn test_uevent_stable_id() {
+        // Both interfaces of a key with a keyboard interface next to FIDO.
+        let fido = "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_NAME=Yubico YubiKey OTP+FIDO+CCID\nHID_PHYS=usb-0000:00:14.0-1/input1\nHID_UNIQ=\n";
+        let keyboard = "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_NAME=Yubico YubiKey OTP+FIDO+CCID\nHID_PHYS=usb-0000:00:14.0-1/input0\nHID_UNIQ=\n";
+        assert_eq!(uevent_stable_id(fido), Some("usb-0000:00:14.0-1".to_owned()));
+        assert_eq!(uevent_stable_id(fido), uevent_stable_id(keyboard));
+
+        // Another port is another device.
+        let other = "HID_ID=0003:00001050:00000407\nHID_PHYS=usb-0000:00:14.0-2/input1\n";
+        assert_eq!(uevent_stable_id(other), Some("usb-0000:00:14.0-2".to_owned()));
+
+        // Bus paths without an interface are kept as they are, and without
+        // one the serial number is used.
+        assert_eq!(uevent_stable_id("HID_PHYS=bluetooth-xx\n"), Some("bluetooth-xx".to_owned()));
+        assert_eq!(uevent_stable_id("HID_PHYS=\nHID_UNIQ=0123\n"), Some("0123".to_owned()));
+        assert_eq!(uevent_stable_id("DRIVER=hid-generic\n"), None);
+    }
+
+    #[test]
+    fn test_parse_hid_id() {
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_UNIQ=\n";
+        assert_eq!(parse_hid_id(uevent), Some((0x0003, 0x1050, 0x0407)));
+        assert_eq!(parse_hid_id("HID_ID=0003:1050\n"), None);
+        assert_eq!(parse_hid_id("DRIVER=hid-generic\n"), None);
+    }
+
+    #[test]
+    fn test_parse_device_info() {
+        let info = parse_device_info("HID_ID=0003:00001050:00000407\nHID_UNIQ=0123\n");
+        assert_eq!(info.transport, Transport::UsbHid);
+        assert_eq!(info.serial_number, Some("0123".to_owned()));
+        assert_eq!((info.vendor_id, info.product_id), (Some(0x1050), Some(0x0407)));
+
+        // Unknown buses don't keep us from using the device.
+        assert_eq!(parse_device_info("HID_ID=0005:00001050:00000407\n").transport, Transport::UsbHid);
+        assert_eq!(parse_device_info(""), DeviceInfo::default());
+    }
+
+    #[test]
+    fn test_read_usb_interface() {
+        let dir = env::temp_dir().join(format!("u2fhid-test-interface-{}", process::id()));
+        for &(ep, address) in &[("ep_00", "00"), ("ep_84", "84"), ("ep_05", "05")] {
+            fs::create_dir_all(dir.join(ep)).unwrap();
+            fs::write(dir.join(ep).join("bEndpointAddress"), format!("{}\n", address)).unwrap();
+        }
+        fs::write(dir.join("bInterfaceNumber"), "01\n").unwrap();
+        // bInterval 255 on a full speed device.
+        fs::write(dir.join("ep_84").join("interval"), "255ms\n").unwrap();
+
+        let mut info = DeviceInfo::default();
+        read_usb_interface(&dir, &mut info);
+        assert_eq!(info.usb_interface, Some(1));
+        assert_eq!(info.usb_endpoint_in, Some(0x84));
+        assert_eq!(info.usb_endpoint_out, Some(0x05));
+        assert_eq!(info.usb_poll_interval, Some(Duration::from_millis(255)));
+
+        fs::write(dir.join("ep_84").join("interval"), "125us\n").unwrap();
+        read_usb_interface(&dir, &mut info);
+        assert_eq!(info.usb_poll_interval, Some(Duration::from_micros(125)));
+        fs::write(dir.join("ep_84").join("interval"), "bogus\n").unwrap();
+        read_usb_interface(&dir, &mut info);
+        assert_eq!(info.usb_poll_interval, None);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Nothing is known about devices that aren't on USB.
+        let mut info = DeviceInfo::default();
+        read_usb_interface(&dir, &mut info);
+        assert_eq!(info, DeviceInfo::default());
+    }
+
+    #[test]
+    fn test_releaser() {
+        let path = env::temp_dir().join(format!("u2fhid-test-releaser-{}", process::id()));
+        fs::write(&path, b"report").unwrap();
+        let fd = fs::File::open(&path).unwrap().into_raw_fd();
+        let releaser = Releaser::new();
+        releaser.insert(fd);
+
+        // Reads end up at /dev/null, the file is closed.
+        releaser.release();
+        let mut buf = [0u8; 6];
+        let rv = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        assert_eq!(rv, 0);
+        releaser.close(fd);
+        assert!(releaser.fds.lock().unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_uevent() {
+        // The serial number a key's uevent file gives us.
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_NAME=Yubico YubiKey OTP+FIDO+CCID\nHID_PHYS=usb-0000:00:14.0-1/input1\nHID_UNIQ=4711 \n";
+        assert_eq!(parse_device_info(uevent).serial_number, Some("4711".to_owned()));
+        assert_eq!(parse_device_info("HID_UNIQ=\n").serial_number, None);
+
+        assert_eq!(read_uevent(&OsString::from("/dev/nonexistent")), None);
+    }
 }
@@ -1,8 +1,10 @@
 extern crate libc;
 
+use std::ffi::OsString;
 use std::io;
 use std::mem;
 use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 
 use consts::{FIDO_USAGE_PAGE, FIDO_USAGE_U2FHID};
 use util::from_unix_result;
@@ -27,8 +29,8 @@ const READ: u8 = 2;
 const SIZEBITS: u8 = 14;
 
 const NRSHIFT: u32 = 0;
-const TYPESHIFT: u32 = NRSHIFT + NRBITS as u32;
-const SIZESHIFT: u32 = TYPESHIFT + TYPEBITS as u32;
+const TYPESHIFT: u32 = NRSHIFT + NRBITS;
+const SIZESHIFT: u32 = TYPESHIFT + TYPEBITS;
 const DIRSHIFT: u32 = SIZESHIFT + SIZEBITS as u32;
 
 macro_rules! ioctl {
@@ -50,7 +52,9 @@ ioctl!(READ, hidiocgrdesc, b'H', 0x02; /*struct*/ ReportDescriptor);
 
 enum Data {
     UsagePage { data: u32 },
-    Usage { data: u32 }
+    Usage { data: u32 },
+    // A four byte usage, with its usage page in the high half.
+    ExtendedUsage { data: u32 }
 }
 
 struct ReportDescriptorIterator {
@@ -68,7 +72,8 @@ impl Iterator for ReportDescriptorIterator {
     type Item = Data;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let value_len = self.desc.value.len();
+        // Only the first `size` bytes were filled in.
+        let value_len = ::std::cmp::min(::std::cmp::max(self.desc.size, 0) as usize, self.desc.value.len());
         if self.pos >= value_len {
             return None;
         }
@@ -81,7 +86,7 @@ impl Iterator for ReportDescriptorIterator {
 
         // Determine length.
         let data_len = match key & 0x3 {
-            s @ 0 ... 2 => s as usize,
+            s @ 0 ..= 2 => s as usize,
             _ => 4 /* key & 0x3 == 3 */
         };
 
@@ -96,6 +101,7 @@ impl Iterator for ReportDescriptorIterator {
 
         match key & 0xfc {
             0x4 => Some(Data::UsagePage { data }),
+            0x8 if data_len == 4 => Some(Data::ExtendedUsage { data }),
             0x8 => Some(Data::Usage { data }),
             _ => self.next()
         }
@@ -109,9 +115,38 @@ fn read_uint_le(buf: &[u8]) -> u32 {
 }
 
 pub fn is_u2f_device(fd: RawFd) -> bool {
-    match read_report_descriptor(fd) {
+    check_report_descriptor(read_report_descriptor(fd))
+}
+
+// The sysfs directory of the HID device behind the hidraw node at `path`.
+pub fn sysfs_device_path(path: &OsString) -> Option<PathBuf> {
+    Path::new(path).file_name().map(|name| {
+        Path::new("/sys/class/hidraw").join(name).join("device")
+    })
+}
+
+// Like `is_u2f_device()`, for a report descriptor read some other way, e.g.
+// through libusb.
+#[cfg(feature = "libusb")]
+pub fn is_u2f_report_descriptor(bytes: io::Result<Vec<u8>>) -> bool {
+    check_report_descriptor(bytes.map(|bytes| {
+        let mut desc = ReportDescriptor { size: 0, value: [0; 4096] };
+        let size = ::std::cmp::min(bytes.len(), desc.value.len());
+        desc.value[..size].clone_from_slice(&bytes[..size]);
+        desc.size = size as ::libc::c_int;
+        desc
+    }))
+}
+
+// Some devices work fine with the default 64-byte reports, but their report
+// descriptor can't be read. Don't exclude those, give them a try.
+fn check_report_descriptor(desc: io::Result<ReportDescriptor>) -> bool {
+    match desc {
         Ok(desc) => has_fido_usage(desc),
-        Err(_) => false // Upon failure, just say it's not a U2F device.
+        Err(e) => {
+            debug!("Couldn't read report descriptor, using defaults: {}", e);
+            true
+        }
     }
 }
 
@@ -122,22 +157,76 @@ fn read_report_descriptor(fd: RawFd) -> io::Result<ReportDescriptor> {
     Ok(desc)
 }
 
+// Whether any usage in the descriptor is U2FHID's, not just the first one:
+// composite devices may put a keyboard collection before it. Usages are on
+// the usage page last set, unless they carry their own.
 fn has_fido_usage(desc: ReportDescriptor) -> bool {
     let mut usage_page = None;
-    let mut usage = None;
 
     for data in desc.iter() {
-        match data {
-            Data::UsagePage { data } => usage_page = Some(data),
-            Data::Usage { data } => usage = Some(data)
-        }
-
-        // Check the values we found.
-        if let (Some(usage_page), Some(usage)) = (usage_page, usage) {
-            return usage_page == FIDO_USAGE_PAGE as u32 &&
-                   usage == FIDO_USAGE_U2FHID as u32;
+        let usage = match data {
+            Data::UsagePage { data } => { usage_page = Some(data); continue }
+            Data::Usage { data } => (usage_page, data),
+            Data::ExtendedUsage { data } => (Some(data >> 16), data & 0xffff)
+        };
+        if usage == (Some(FIDO_USAGE_PAGE as u32), FIDO_USAGE_U2FHID as u32) {
+            return true;
         }
     }
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_report_descriptor, ReportDescriptor};
+    use std::io;
+
+    fn descriptor(bytes: &[u8]) -> ReportDescriptor {
+        let mut desc = ReportDescriptor { size: bytes.len() as ::libc::c_int, value: [0; 4096] };
+        desc.value[..bytes.len()].clone_from_slice(bytes);
+        desc
+    }
+
+    #[test]
+    fn test_check_report_descriptor() {
+        // Usage Page (FIDO Alliance), Usage (U2F Authenticator Device)
+        assert!(check_report_descriptor(Ok(descriptor(&[0x06, 0xd0, 0xf1, 0x09, 0x01]))));
+        // Usage Page (Generic Desktop), Usage (Keyboard)
+        assert!(!check_report_descriptor(Ok(descriptor(&[0x05, 0x01, 0x09, 0x06]))));
+        // Usage Page (Generic Desktop), Usage (Mouse), Collection, End Collection
+        assert!(!check_report_descriptor(Ok(descriptor(&[0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0xc0]))));
+        // Another usage on the FIDO page.
+        assert!(!check_report_descriptor(Ok(descriptor(&[0x06, 0xd0, 0xf1, 0x09, 0x02]))));
+    }
+
+    #[test]
+    fn test_composite_report_descriptor() {
+        // A keyboard collection, then the FIDO one.
+        let mut bytes = vec![0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x85, 0x01, 0xc0];
+        bytes.extend(&[0x06, 0xd0, 0xf1, 0x09, 0x01, 0xa1, 0x01, 0x85, 0x02, 0xc0]);
+        assert!(check_report_descriptor(Ok(descriptor(&bytes))));
+
+        // An extended usage brings its own usage page.
+        assert!(check_report_descriptor(Ok(descriptor(&[0x05, 0x01, 0x0b, 0x01, 0x00, 0xd0, 0xf1]))));
+        assert!(!check_report_descriptor(Ok(descriptor(&[0x06, 0xd0, 0xf1, 0x0b, 0x01, 0x00, 0x01, 0x00]))));
+    }
+
+    #[test]
+    fn test_report_descriptor_size() {
+        // Bytes beyond the descriptor's size aren't part of it.
+        let mut desc = descriptor(&[0x05, 0x01, 0x09, 0x06]);
+        desc.value[4..9].clone_from_slice(&[0x06, 0xd0, 0xf1, 0x09, 0x01]);
+        assert!(!check_report_descriptor(Ok(desc)));
+
+        // Items may end right at the end.
+        let desc = descriptor(&[0x06, 0xd0, 0xf1, 0x0a, 0x01, 0x00]);
+        assert!(check_report_descriptor(Ok(desc)));
+    }
+
+    #[test]
+    fn test_unreadable_report_descriptor() {
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "ioctl failed");
+        assert!(check_report_descriptor(Err(err)));
+    }
+}
@@ -6,20 +6,23 @@ use libc::{c_int, c_short, c_ulong};
 use std::ffi::OsString;
 use std::io;
 use std::os::unix::io::AsRawFd;
-use std::sync::mpsc::{channel, Receiver, TryIter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
 
+use platform::device;
 use runloop::RunLoop;
-use util::to_io_err;
+use util::{merge_backends, to_io_err, EventIter, EventQueue};
 
-const UDEV_SUBSYSTEM: &'static str = "hidraw";
+const UDEV_SUBSYSTEM: &str = "hidraw";
 const POLLIN: c_short = 0x0001;
 const POLL_TIMEOUT: c_int = 100;
 
-fn poll(fds: &mut Vec<::libc::pollfd>) -> io::Result<()> {
+fn poll(fds: &mut [::libc::pollfd]) -> io::Result<()> {
     let nfds = fds.len() as c_ulong;
 
     let rv = unsafe {
-        ::libc::poll((&mut fds[..]).as_mut_ptr(), nfds, POLL_TIMEOUT)
+        ::libc::poll(fds[..].as_mut_ptr(), nfds, POLL_TIMEOUT)
     };
 
     if rv < 0 {
@@ -29,9 +32,12 @@ fn poll(fds: &mut Vec<::libc::pollfd>) -> io::Result<()> {
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum Event {
     Add(OsString),
-    Remove(OsString)
+    Remove(OsString),
+    // All devices currently present, after a refresh was requested.
+    Snapshot(Vec<OsString>)
 }
 
 impl Event {
@@ -48,29 +54,54 @@ impl Event {
     }
 }
 
+// Lists all devices, each once: the hidraw nodes, and with libusb the FIDO
+// interfaces that have none. A libusb failure doesn't keep us from using
+// the hidraw nodes. Tests add the devices on their `TestBench`.
+fn enumerate(ctx: &libudev::Context) -> io::Result<Vec<OsString>> {
+    #[allow(unused_mut)]
+    let mut backends = vec![enumerate_hidraw(ctx)?];
+    #[cfg(feature = "libusb")]
+    match ::platform::usb::enumerate() {
+        Ok(paths) => backends.push(paths),
+        Err(e) => debug!("Couldn't enumerate USB devices: {}", e)
+    }
+    #[cfg(test)]
+    backends.push(::platform::testbench::enumerate());
+    Ok(merge_backends(backends, device::stable_id))
+}
+
+// Lists the devnodes of all hidraw devices.
+fn enumerate_hidraw(ctx: &libudev::Context) -> io::Result<Vec<OsString>> {
+    let mut enumerator = libudev::Enumerator::new(ctx)?;
+    enumerator.match_subsystem(UDEV_SUBSYSTEM)?;
+
+    let devices = enumerator.scan_devices()?;
+    Ok(devices.filter_map(|dev| {
+        dev.devnode().map(|p| p.to_owned().into_os_string())
+    }).collect())
+}
+
 pub struct Monitor {
     // Receive events from the thread.
-    rx: Receiver<Event>,
+    events: EventQueue<Event>,
     // Handle to the thread loop.
-    thread: RunLoop
+    thread: RunLoop,
+    // Set to have the thread send a fresh snapshot of all devices.
+    refresh: Arc<AtomicBool>
 }
 
 impl Monitor {
     pub fn new() -> io::Result<Self> {
         let (tx, rx) = channel();
+        let refresh = Arc::new(AtomicBool::new(false));
+        let refresh_ = refresh.clone();
 
         let thread = RunLoop::new(move |alive| -> io::Result<()> {
             let ctx = libudev::Context::new()?;
-            let mut enumerator = libudev::Enumerator::new(&ctx)?;
-            enumerator.match_subsystem(UDEV_SUBSYSTEM)?;
 
             // Iterate all existing devices.
-            for dev in enumerator.scan_devices()? {
-                if let Some(path) = dev.devnode().map(|p| {
-                    p.to_owned().into_os_string()
-                }) {
-                    tx.send(Event::Add(path)).map_err(to_io_err)?;
-                }
+            for path in enumerate(&ctx)? {
+                tx.send(Event::Add(path)).map_err(to_io_err)?;
             }
 
             let mut monitor = libudev::Monitor::new(&ctx)?;
@@ -84,6 +115,11 @@ impl Monitor {
 
             // Loop until we're stopped by the controlling thread, or fail.
             while alive() {
+                // Look at all devices again, in case we missed events.
+                if refresh_.swap(false, Ordering::SeqCst) {
+                    tx.send(Event::Snapshot(enumerate(&ctx)?)).map_err(to_io_err)?;
+                }
+
                 // Wait for new events, break on failure.
                 poll(&mut fds)?;
 
@@ -99,11 +135,22 @@ impl Monitor {
 
         // TODO what if dlopen() failed?
 
-        Ok(Self { rx, thread })
+        Ok(Self { events: EventQueue::new(rx), thread, refresh })
+    }
+
+    pub fn events<'a>(&'a self) -> EventIter<'a, Event> {
+        self.events.try_iter()
+    }
+
+    // The events that haven't been handled yet, for diagnostics. They're
+    // still handed out by `events()`.
+    pub fn pending(&self) -> Vec<Event> {
+        self.events.pending()
     }
 
-    pub fn events<'a>(&'a self) -> TryIter<'a, Event> {
-        self.rx.try_iter()
+    // Asks for an Event::Snapshot of all devices currently present.
+    pub fn refresh(&self) {
+        self.refresh.store(true, Ordering::SeqCst);
     }
 }
 
@@ -0,0 +1,144 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use testdevice::TestDevice;
+
+// Paths of the devices attached to the bench, e.g. "test:0".
+pub const TEST_PATH_PREFIX: &str = "test:";
+
+struct Attached {
+    path: String,
+    device: Arc<Mutex<TestDevice>>,
+    // Cloned by every open handle.
+    handles: Arc<()>
+}
+
+static ATTACHED: Mutex<Vec<Attached>> = Mutex::new(Vec::new());
+static OPENED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static BENCH: Mutex<()> = Mutex::new(());
+
+fn lock<T>(mutex: &'static Mutex<T>) -> MutexGuard<'static, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Plugs TestDevices in, so that tests can run whole operations against them.
+// The monitor finds them next to the hidraw nodes, but only DeviceFilters
+// with the "test" backend open them. Other tests never see them, and the
+// tests that use a bench run one at a time.
+pub struct TestBench {
+    _running: MutexGuard<'static, ()>
+}
+
+impl TestBench {
+    pub fn new() -> Self {
+        let running = lock(&BENCH);
+        lock(&ATTACHED).clear();
+        lock(&OPENED).clear();
+        Self { _running: running }
+    }
+
+    // Monitors started from now on find it, running ones with their next
+    // snapshot. Returns the device, to check what it was sent.
+    pub fn attach(&self, path: &str, device: TestDevice) -> Arc<Mutex<TestDevice>> {
+        let device = Arc::new(Mutex::new(device));
+        lock(&ATTACHED).push(Attached { path: path.to_owned(), device: device.clone(), handles: Arc::new(()) });
+        device
+    }
+
+    // Monitors don't find it from now on. Handles that are open stay usable.
+    pub fn detach(&self, path: &str) {
+        lock(&ATTACHED).retain(|attached| attached.path != path);
+    }
+
+    // Whether a DeviceMap holds the device at `path` right now.
+    pub fn is_open(&self, path: &str) -> bool {
+        lock(&ATTACHED).iter().any(|attached| attached.path == path && Arc::strong_count(&attached.handles) > 1)
+    }
+
+    // The paths of the devices opened so far, in order.
+    pub fn opened(&self) -> Vec<String> {
+        lock(&OPENED).clone()
+    }
+}
+
+impl Default for TestBench {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestBench {
+    fn drop(&mut self) {
+        lock(&ATTACHED).clear();
+    }
+}
+
+// What the monitor lists next to the hidraw nodes.
+pub fn enumerate() -> Vec<OsString> {
+    lock(&ATTACHED).iter().map(|attached| OsString::from(&attached.path)).collect()
+}
+
+// An attached device, as `Device` talks to it.
+pub struct TestHandle {
+    device: Arc<Mutex<TestDevice>>,
+    _handle: Arc<()>
+}
+
+impl TestHandle {
+    pub fn open(path: &OsString) -> io::Result<Self> {
+        let path = path.to_string_lossy();
+        let handle = lock(&ATTACHED).iter().find(|attached| attached.path == path).map(|attached| {
+            TestHandle { device: attached.device.clone(), _handle: attached.handles.clone() }
+        });
+        match handle {
+            Some(handle) => {
+                lock(&OPENED).push(path.into_owned());
+                Ok(handle)
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "not attached"))
+        }
+    }
+
+    pub fn device(&self) -> MutexGuard<'_, TestDevice> {
+        self.device.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for TestHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TestHandle").field("info", &self.device().info).finish()
+    }
+}
+
+impl Read for TestHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.device().read(buf)
+    }
+}
+
+impl Write for TestHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.device().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Waits for an operation running on another thread to get to `condition`.
+// Panics if it doesn't within a few seconds.
+pub fn wait_until<F>(condition: F)
+    where F: Fn() -> bool
+{
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < Duration::from_secs(5), "timed out waiting");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,335 @@
+// Talks to U2F devices through libusb, for FIDO interfaces that have no
+// hidraw node, e.g. on kernels built without hidraw. Interfaces with a node
+// are left to hidraw: taking one over would detach the kernel's HID driver,
+// so the node would go away until we're done, and come back after. The
+// monitor would take that for the device being unplugged and plugged in.
+//
+// udev doesn't tell about these interfaces, so they're only found when an
+// operation starts or `refresh_devices()` is called. Opening one needs write
+// access to its /dev/bus/usb node.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ::libusb;
+use libc;
+use consts::U2FHID_TRANS_TIMEOUT;
+use platform::device::USB_PATH_PREFIX;
+use platform::hidraw;
+use u2ftypes::{DeviceInfo, Transport};
+use util::{io_err, to_io_err};
+
+// Where the kernel lists USB devices and their interfaces.
+const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+const USB_CLASS_HID: u8 = 0x03;
+const USB_REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const HID_REPORT_DESCRIPTOR: u8 = 0x22;
+
+// Transfers to an unplugged device fail like hidraw ones would, see
+// `device::is_disconnect_error()`.
+fn transfer_err(err: libusb::Error) -> io::Error {
+    match err {
+        libusb::Error::NoDevice => io::Error::from_raw_os_error(libc::ENODEV),
+        err => to_io_err(err)
+    }
+}
+
+// Reads a sysfs attribute, without the trailing newline.
+fn read_attribute(path: &Path) -> io::Result<String> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents.trim().to_owned())
+}
+
+// "usb:<bus>:<address>:<interface>", all decimal.
+fn usb_path(bus: u8, address: u8, interface: u8) -> OsString {
+    OsString::from(format!("{}{}:{}:{}", USB_PATH_PREFIX, bus, address, interface))
+}
+
+fn parse_usb_path(path: &OsString) -> Option<(u8, u8, u8)> {
+    let path = path.to_str()?;
+    if !path.starts_with(USB_PATH_PREFIX) {
+        return None;
+    }
+
+    let parts: Vec<u8> = path[USB_PATH_PREFIX.len()..].split(':').filter_map(|part| part.parse().ok()).collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    Some((parts[0], parts[1], parts[2]))
+}
+
+// The directory of `interface` of the USB device at `bus` and `address`
+// below `root`, e.g. /sys/bus/usb/devices/1-2:1.1 for the device at 1-2.
+fn sysfs_interface(root: &Path, bus: u8, address: u8, interface: u8) -> Option<PathBuf> {
+    let entries: Vec<PathBuf> = fs::read_dir(root).ok()?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    let matches = |dir: &Path, name: &str, value: u8| {
+        read_attribute(&dir.join(name)).ok().and_then(|v| v.parse().ok()) == Some(value)
+    };
+
+    let device = entries.iter().find(|dir| matches(dir, "busnum", bus) && matches(dir, "devnum", address))?;
+    let prefix = format!("{}:", device.file_name()?.to_string_lossy());
+    entries.into_iter().find(|dir| {
+        let name = dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        name.starts_with(&prefix) && read_attribute(&dir.join("bInterfaceNumber")).ok()
+                                                       .and_then(|n| u8::from_str_radix(&n, 16).ok()) == Some(interface)
+    })
+}
+
+// The HID devices the kernel made of an interface, e.g. 0003:1050:0407.0001.
+// There are none if no HID driver is bound to it.
+fn hid_devices(interface: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(interface) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|dir| dir.join("report_descriptor").is_file()).collect()
+}
+
+// Whether hidraw has a node for the interface.
+fn exposed_by_hidraw(interface: &Path) -> bool {
+    hid_devices(interface).iter().any(|dir| dir.join("hidraw").is_dir())
+}
+
+// Paths of the HID interfaces that hidraw doesn't expose, for `open()`.
+// Those that aren't FIDO ones are sorted out once they're opened.
+pub fn enumerate() -> io::Result<Vec<OsString>> {
+    let context = libusb::Context::new().map_err(to_io_err)?;
+    let devices = context.devices().map_err(to_io_err)?;
+    let root = Path::new(SYSFS_USB_DEVICES);
+    let mut paths = Vec::new();
+
+    for device in devices.iter() {
+        let config = match device.active_config_descriptor() {
+            Ok(config) => config,
+            Err(_) => continue
+        };
+
+        let (bus, address) = (device.bus_number(), device.address());
+        for iface in config.interfaces() {
+            if !iface.descriptors().any(|desc| desc.class_code() == USB_CLASS_HID) {
+                continue;
+            }
+            match sysfs_interface(root, bus, address, iface.number()) {
+                Some(ref dir) if !exposed_by_hidraw(dir) => paths.push(usb_path(bus, address, iface.number())),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+// Finds the interrupt IN and OUT endpoints of the given interface.
+fn find_endpoints(device: &libusb::Device, interface: u8) -> io::Result<(u8, u8)> {
+    let config = device.active_config_descriptor().map_err(to_io_err)?;
+    let mut ep_in = None;
+    let mut ep_out = None;
+
+    for iface in config.interfaces().filter(|iface| iface.number() == interface) {
+        for desc in iface.descriptors() {
+            for ep in desc.endpoint_descriptors() {
+                if ep.transfer_type() != libusb::TransferType::Interrupt {
+                    continue;
+                }
+
+                match ep.direction() {
+                    libusb::Direction::In => ep_in = Some(ep.address()),
+                    libusb::Direction::Out => ep_out = Some(ep.address())
+                }
+            }
+        }
+    }
+
+    match (ep_in, ep_out) {
+        (Some(ep_in), Some(ep_out)) => Ok((ep_in, ep_out)),
+        _ => Err(io_err("no interrupt endpoints"))
+    }
+}
+
+pub struct UsbDevice {
+    // Borrows `_context`, which is why it's dropped first.
+    handle: libusb::DeviceHandle<'static>,
+    interface: u8,
+    ep_in: u8,
+    ep_out: u8,
+    vendor_id: u16,
+    product_id: u16,
+    // Whether the report descriptor has U2FHID's usage.
+    is_u2f: bool,
+    // Whether to give the interface back to the kernel when we're done.
+    reattach: bool,
+    // Every device has a context of its own, they're cheap.
+    _context: Box<libusb::Context>
+}
+
+impl UsbDevice {
+    // Opens the USB interface at `path`, as `enumerate()` lists it.
+    pub fn open(path: &OsString) -> io::Result<Self> {
+        let (bus, address, interface) = parse_usb_path(path).ok_or_else(|| io_err("invalid device path"))?;
+        let dir = sysfs_interface(Path::new(SYSFS_USB_DEVICES), bus, address, interface)
+                      .ok_or_else(|| io_err("USB device not found"))?;
+        // hidraw might have come up since.
+        if exposed_by_hidraw(&dir) {
+            return Err(io_err("use the hidraw node"));
+        }
+
+        let context = Box::new(libusb::Context::new().map_err(to_io_err)?);
+        // The box keeps the context in place while we move it into the
+        // device, and the device drops the handle before the context.
+        let ctx: &'static libusb::Context = unsafe { &*(&*context as *const libusb::Context) };
+
+        let devices = ctx.devices().map_err(to_io_err)?;
+        let device = devices.iter()
+                            .find(|dev| dev.bus_number() == bus && dev.address() == address)
+                            .ok_or_else(|| io_err("USB device not found"))?;
+        let desc = device.device_descriptor().map_err(to_io_err)?;
+
+        let (ep_in, ep_out) = find_endpoints(&device, interface)?;
+        let mut handle = device.open().map_err(to_io_err)?;
+
+        // A HID driver may be bound without making a hidraw node. Only
+        // detach it from FIDO interfaces, e.g. not from a keyboard. Its
+        // report descriptor is in sysfs then.
+        let reattach = handle.kernel_driver_active(interface).unwrap_or(false);
+        let mut is_u2f = None;
+        if reattach {
+            let desc = hid_devices(&dir).first().ok_or_else(|| io_err("not a HID device"))
+                                        .and_then(|hid| fs::read(hid.join("report_descriptor")));
+            if !hidraw::is_u2f_report_descriptor(desc) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a U2F device"));
+            }
+            is_u2f = Some(true);
+            handle.detach_kernel_driver(interface).map_err(to_io_err)?;
+        }
+
+        // Drop hands the interface back, even if claiming it fails.
+        let (vendor_id, product_id) = (desc.vendor_id(), desc.product_id());
+        let mut dev = Self { handle, interface, ep_in, ep_out, vendor_id, product_id, is_u2f: false, reattach, _context: context };
+        dev.handle.claim_interface(interface).map_err(to_io_err)?;
+        dev.is_u2f = is_u2f.unwrap_or_else(|| hidraw::is_u2f_report_descriptor(dev.read_report_descriptor()));
+        Ok(dev)
+    }
+
+    // Asks the device itself, there's no driver that read it for us.
+    fn read_report_descriptor(&self) -> io::Result<Vec<u8>> {
+        let request_type = libusb::request_type(libusb::Direction::In, libusb::RequestType::Standard, libusb::Recipient::Interface);
+        let mut buf = vec![0; 4096];
+        let len = self.handle.read_control(request_type, USB_REQUEST_GET_DESCRIPTOR, (HID_REPORT_DESCRIPTOR as u16) << 8,
+                                           self.interface as u16, &mut buf, Self::timeout()).map_err(transfer_err)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    pub fn is_u2f(&self) -> bool {
+        self.is_u2f
+    }
+
+    // What the descriptors tell about the device.
+    pub fn device_info(&self) -> DeviceInfo {
+        let mut info = DeviceInfo::new(Transport::UsbHid);
+        info.vendor_id = Some(self.vendor_id);
+        info.product_id = Some(self.product_id);
+        info.usb_interface = Some(self.interface);
+        info.usb_endpoint_in = Some(self.ep_in);
+        info.usb_endpoint_out = Some(self.ep_out);
+        info
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_millis(U2FHID_TRANS_TIMEOUT as u64)
+    }
+}
+
+impl Drop for UsbDevice {
+    fn drop(&mut self) {
+        // Ignore errors, the device might be gone already.
+        let _ = self.handle.release_interface(self.interface);
+        if self.reattach {
+            if let Err(e) = self.handle.attach_kernel_driver(self.interface) {
+                debug!("Couldn't give interface {} back to the kernel: {}", self.interface, e);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for UsbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UsbDevice {{ interface: {}, ep_in: {:#x}, ep_out: {:#x} }}",
+               self.interface, self.ep_in, self.ep_out)
+    }
+}
+
+impl Read for UsbDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.handle.read_interrupt(self.ep_in, buf, Self::timeout()).map_err(transfer_err)
+    }
+}
+
+impl Write for UsbDevice {
+    // Like with hidraw, the first byte is the report ID. FIDO devices don't
+    // use report IDs, so it's not sent.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let report = &buf[1..];
+        let written = self.handle.write_interrupt(self.ep_out, report, Self::timeout()).map_err(transfer_err)?;
+        Ok(written + 1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exposed_by_hidraw, parse_usb_path, sysfs_interface, usb_path};
+    use std::{env, process};
+    use std::ffi::OsString;
+    use std::fs;
+
+    #[test]
+    fn test_usb_path() {
+        assert_eq!(usb_path(1, 4, 0), OsString::from("usb:1:4:0"));
+        assert_eq!(parse_usb_path(&usb_path(1, 4, 0)), Some((1, 4, 0)));
+        assert_eq!(parse_usb_path(&OsString::from("/dev/hidraw0")), None);
+        assert_eq!(parse_usb_path(&OsString::from("usb:1:4")), None);
+    }
+
+    #[test]
+    fn test_sysfs_interface() {
+        // Device 4 on bus 1 at port 2, with a keyboard on interface 0 that
+        // hidraw exposes and a FIDO interface 1 that it doesn't.
+        let root = env::temp_dir().join(format!("u2fhid-test-sysfs-{}", process::id()));
+        fs::create_dir_all(root.join("1-2")).unwrap();
+        fs::write(root.join("1-2").join("busnum"), "1\n").unwrap();
+        fs::write(root.join("1-2").join("devnum"), "4\n").unwrap();
+        for &(name, number, hid) in &[("1-2:1.0", "00", "0003:1050:0407.0001"), ("1-2:1.1", "01", "0003:1050:0407.0002")] {
+            fs::create_dir_all(root.join(name).join(hid)).unwrap();
+            fs::write(root.join(name).join("bInterfaceNumber"), format!("{}\n", number)).unwrap();
+            fs::write(root.join(name).join(hid).join("report_descriptor"), [0x05, 0x01]).unwrap();
+        }
+        fs::create_dir_all(root.join("1-2:1.0").join("0003:1050:0407.0001").join("hidraw")).unwrap();
+
+        let keyboard = sysfs_interface(&root, 1, 4, 0).unwrap();
+        assert!(keyboard.ends_with("1-2:1.0"));
+        assert!(exposed_by_hidraw(&keyboard));
+        let fido = sysfs_interface(&root, 1, 4, 1).unwrap();
+        assert!(fido.ends_with("1-2:1.1"));
+        assert!(!exposed_by_hidraw(&fido));
+
+        // Other devices and interfaces aren't there.
+        assert_eq!(sysfs_interface(&root, 1, 5, 0), None);
+        assert_eq!(sysfs_interface(&root, 1, 4, 2), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
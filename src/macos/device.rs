@@ -0,0 +1,311 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::io::{Read, Write};
+use std::io;
+use std::ptr;
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use libc;
+use libc::{c_char, c_void};
+use core_foundation_sys::base::*;
+use core_foundation_sys::number::*;
+use core_foundation_sys::string::*;
+
+use super::iokit::*;
+
+use u2fprotocol::U2FDevice;
+use u2ftypes::{DeviceInfo, FrameObserver, OperationContext, Transport};
+use consts::HID_RPT_SIZE;
+
+const READ_TIMEOUT: u64 = 15;
+
+pub struct Report {
+    pub data: [u8; HID_RPT_SIZE],
+}
+unsafe impl Send for Report {}
+unsafe impl Sync for Report {}
+
+// Whether reading or writing failed because the device was unplugged. Setting
+// a report fails with one of these, and once the DeviceMap dropped the sender
+// for input reports, reads end too.
+pub fn is_disconnect_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(kIOReturnNoDevice) | Some(kIOReturnNotAttached) | Some(kIOReturnOffline) => true,
+        _ => err.kind() == io::ErrorKind::UnexpectedEof
+    }
+}
+
+// Devices are only known by their IOHIDDeviceRef here, and need the HID
+// manager's run loop to receive input reports.
+pub fn open(_: &str) -> io::Result<Device> {
+    Err(io::Error::other("opening devices by path isn't supported"))
+}
+
+// Would let go of an operation's devices should its thread be detached, see
+// `RunLoop::on_detach()`. A handle that another thread is reading from can't
+// be closed safely here, so the devices are kept until the thread exits.
+#[derive(Clone, Default)]
+pub struct Releaser;
+
+impl Releaser {
+    pub fn new() -> Self {
+        Releaser
+    }
+
+    pub fn release(&self) {}
+}
+
+pub struct Device {
+    pub device_ref: IOHIDDeviceRef,
+    // Channel ID for U2F HID communication. Needed to implement U2FDevice
+    // trait.
+    pub cid: [u8; 4],
+    pub report_recv: Receiver<Report>,
+    pub report_send_void: *mut libc::c_void,
+    pub info: DeviceInfo,
+    pub observer: Option<FrameObserver>,
+    pub context: Option<OperationContext>,
+    pub init_failures: u32,
+    pub disconnected: bool,
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InternalDevice(ref:{:?}, cid: {:02x}{:02x}{:02x}{:02x})",
+               self.device_ref, self.cid[0], self.cid[1], self.cid[2], self.cid[3])
+    }
+}
+
+impl Device {
+    pub fn serial_number(&self) -> Option<String> {
+        self.info.serial_number.clone()
+    }
+}
+
+impl PartialEq for Device {
+    fn eq(&self, other_device: &Device) -> bool {
+        self.device_ref == other_device.device_ref
+    }
+}
+
+impl Read for Device {
+    fn read(&mut self, mut bytes: &mut [u8]) -> io::Result<usize> {
+        let timeout = Duration::from_secs(READ_TIMEOUT);
+        let report_data = match self.report_recv.recv_timeout(timeout) {
+            Ok(v) => v,
+            Err(e) => {
+                if e == RecvTimeoutError::Timeout {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+                }
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, e));
+            },
+        };
+        let len = bytes.write(&report_data.data).unwrap();
+        Ok(len)
+    }
+}
+
+impl Write for Device {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        unsafe { set_report(self.device_ref, kIOHIDReportTypeOutput, bytes) }
+    }
+
+    // USB HID writes don't buffer, so this will be a nop.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl U2FDevice for Device {
+    fn get_cid(&self) -> [u8; 4] {
+        self.cid
+    }
+    fn set_cid(&mut self, cid: &[u8; 4]) {
+        self.cid.clone_from(cid);
+    }
+    fn get_device_info(&self) -> DeviceInfo {
+        self.info.clone()
+    }
+    fn set_device_info(&mut self, info: DeviceInfo) {
+        self.info = info;
+    }
+    fn frame_observer(&self) -> Option<&FrameObserver> {
+        self.observer.as_ref()
+    }
+    fn set_frame_observer(&mut self, observer: Option<FrameObserver>) {
+        self.observer = observer;
+    }
+    fn context(&self) -> Option<&OperationContext> {
+        self.context.as_ref()
+    }
+    fn set_context(&mut self, context: OperationContext) {
+        self.context = Some(context);
+    }
+    fn init_failures(&self) -> u32 {
+        self.init_failures
+    }
+    fn set_init_failures(&mut self, failures: u32) {
+        self.init_failures = failures;
+    }
+    fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+    fn set_disconnected(&mut self) {
+        self.disconnected = true;
+    }
+}
+
+// Reads the device's SerialNumber property, if it has one.
+pub fn serial_number(device_ref: IOHIDDeviceRef) -> Option<String> {
+    string_property(device_ref, kIOHIDSerialNumberKey())
+}
+
+// Best effort, based on the Transport property, e.g. "USB" or "Bluetooth".
+// Nothing reports NFC, anything we don't know counts as USB HID.
+pub fn transport(device_ref: IOHIDDeviceRef) -> Transport {
+    match string_property(device_ref, kIOHIDTransportKey()) {
+        Some(ref transport) if transport == "USB" => Transport::UsbHid,
+        _ => Transport::default()
+    }
+}
+
+// Reads a string property of the device. Empty strings count as missing.
+fn string_property(device_ref: IOHIDDeviceRef, key: *const c_char) -> Option<String> {
+    unsafe {
+        let key = CFStringCreateWithCString(kCFAllocatorDefault, key, kCFStringEncodingUTF8);
+        // The property is owned by the device, don't release it.
+        let value = IOHIDDeviceGetProperty(device_ref, key);
+        CFRelease(key as *mut libc::c_void);
+
+        if value.is_null() || CFGetTypeID(value) != CFStringGetTypeID() {
+            return None;
+        }
+
+        let mut buf = [0 as c_char; 256];
+        let rv = CFStringGetCString(value as CFStringRef,
+                                    buf.as_mut_ptr(),
+                                    buf.len() as CFIndex,
+                                    kCFStringEncodingUTF8);
+        if rv == 0 {
+            return None;
+        }
+
+        let value = CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned();
+        if value.is_empty() { None } else { Some(value) }
+    }
+}
+
+// Reads an integer property of the device.
+fn int_property(device_ref: IOHIDDeviceRef, key: *const c_char) -> Option<i32> {
+    unsafe {
+        let key = CFStringCreateWithCString(kCFAllocatorDefault, key, kCFStringEncodingUTF8);
+        // The property is owned by the device, don't release it.
+        let value = IOHIDDeviceGetProperty(device_ref, key);
+        CFRelease(key as *mut libc::c_void);
+
+        if value.is_null() || CFGetTypeID(value) != CFNumberGetTypeID() {
+            return None;
+        }
+
+        let mut number: i32 = 0;
+        let nptr = &mut number as *mut i32 as *mut libc::c_void;
+        if CFNumberGetValue(value as CFNumberRef, kCFNumberSInt32Type, nptr) {
+            Some(number)
+        } else {
+            None
+        }
+    }
+}
+
+// The ID of the device's IOService in the I/O Registry. It's unique while
+// the device is attached, so it tells identical tokens apart.
+pub fn registry_id(device_ref: IOHIDDeviceRef) -> Option<u64> {
+    unsafe {
+        // The service is owned by the device, don't release it.
+        let service = IOHIDDeviceGetService(device_ref);
+        if service == MACH_PORT_NULL {
+            return None;
+        }
+
+        let mut id: u64 = 0;
+        if IORegistryEntryGetRegistryEntryID(service, &mut id) == KERN_SUCCESS {
+            Some(id)
+        } else {
+            None
+        }
+    }
+}
+
+// Returns the vendor and product IDs of the device.
+pub fn vendor_product_id(device_ref: IOHIDDeviceRef) -> Option<(u16, u16)> {
+    let vid = int_property(device_ref, kIOHIDVendorIDKey());
+    let pid = int_property(device_ref, kIOHIDProductIDKey());
+
+    match (vid, pid) {
+        (Some(vid), Some(pid)) => Some((vid as u16, pid as u16)),
+        _ => None
+    }
+}
+
+unsafe fn set_report(device_ref: IOHIDDeviceRef,
+                     report_type: IOHIDReportType,
+                     bytes: &[u8])
+                     -> io::Result<usize> {
+    let report_id = bytes[0] as i64;
+    let mut data = bytes.as_ptr();
+    let mut length = bytes.len() as CFIndex;
+
+    if report_id == 0x0 {
+        // Not using numbered reports, so don't send the report number
+        length = length - 1;
+        data = data.offset(1);
+    }
+
+    let result = IOHIDDeviceSetReport(device_ref, report_type, report_id, data, length);
+    if result != 0 {
+        warn!("set_report sending failure = {0:X}", result);
+
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    trace!("set_report sending success = {0:X}", result);
+
+    Ok(length as usize)
+}
+
+
+// This is called from the RunLoop thread
+pub extern "C" fn read_new_data_cb(context: *mut c_void,
+                               _: IOReturn,
+                               _: *mut c_void,
+                               report_type: IOHIDReportType,
+                               report_id: u32,
+                               report: *mut u8,
+                               report_len: CFIndex) {
+    unsafe {
+        let tx: &mut Sender<Report> = &mut *(context as *mut Sender<Report>);
+
+        trace!("read_new_data_cb type={} id={} report={:?} len={}",
+                 report_type,
+                 report_id,
+                 report,
+                 report_len);
+
+        let mut report_obj = Report { data: [0; HID_RPT_SIZE] };
+
+        if report_len as usize <= HID_RPT_SIZE {
+            ptr::copy(report, report_obj.data.as_mut_ptr(), report_len as usize);
+        } else {
+            warn!("read_new_data_cb got too much data! {} > {}",
+                     report_len,
+                     HID_RPT_SIZE);
+        }
+
+        if let Err(e) = tx.send(report_obj) {
+            // TOOD: This happens when the channel closes before this thread
+            // does. This is pretty common, but let's deal with stopping
+            // properly later.
+            warn!("Problem returning read_new_data_cb data for thread: {}", e);
+        };
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::hash_map::ValuesMut;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::mpsc::channel;
+
+use core_foundation_sys::base::*;
+use libc;
+
+use consts::{CID_BROADCAST, HID_RPT_SIZE};
+use u2ftypes::{DeviceEvent, DeviceFilter, DeviceInfo, FrameObserver, OperationContext};
+use util::{diff_devices, disconnected, newest_first};
+
+use super::iohid::IOHIDDeviceID;
+use super::iokit::*;
+use super::monitor::Event;
+use super::device::{Device, Releaser, Report, read_new_data_cb, registry_id, serial_number, transport, vendor_product_id};
+
+pub struct DeviceMap {
+    map: HashMap<IOHIDDeviceRef, Device>,
+    // When each device was added, to favor new ones.
+    added: HashMap<IOHIDDeviceRef, u64>,
+    next: u64,
+    filter: DeviceFilter,
+    // Handed to every device we add.
+    observer: Option<FrameObserver>,
+    // Handed to every device we add, if set.
+    context: Option<OperationContext>
+}
+
+impl DeviceMap {
+    pub fn new(filter: DeviceFilter, observer: Option<FrameObserver>) -> Self {
+        Self { map: HashMap::new(), added: HashMap::new(), next: 0, filter, observer, context: None }
+    }
+
+    // Devices are kept until a detached thread exits, see `Releaser`.
+    pub fn set_releaser(&mut self, _: Releaser) {}
+
+    // Hands `context` to the devices added from now on.
+    pub fn set_context(&mut self, context: OperationContext) {
+        self.context = Some(context);
+    }
+
+    pub fn values_mut(&mut self) -> ValuesMut<IOHIDDeviceRef, Device> {
+        self.map.values_mut()
+    }
+
+    // At most `max` devices, the most recently added ones first. Leaves out
+    // the ones added outside of `generations`, see `generation()`.
+    pub fn newest_first(&mut self, generations: Range<u64>, max: usize) -> Vec<&mut Device> {
+        newest_first(&mut self.map, &self.added, generations, max)
+    }
+
+    // Devices added from now on are newer than this.
+    pub fn generation(&self) -> u64 {
+        self.next
+    }
+
+    // IOKit devices don't have paths we could hand out.
+    pub fn paths(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Devices can't be opened by path here, see `paths()`, so they're all
+    // found by the monitor as usual.
+    pub fn seed(&mut self, _paths: Vec<String>) -> Vec<DeviceEvent> {
+        Vec::new()
+    }
+
+    // Returns the devices that were actually added or removed.
+    pub fn process_event(&mut self, event: Event) -> Vec<DeviceEvent> {
+        match event {
+            Event::Add(device_id) => self.add(device_id.as_ref()).map(DeviceEvent::Added).into_iter().collect(),
+            Event::Remove(device_id) => self.remove(device_id.as_ref()).map(DeviceEvent::Removed).into_iter().collect(),
+            Event::Snapshot(device_ids) => self.reconcile(device_ids)
+        }
+    }
+
+    // Drops the devices that were unplugged while we talked to them. Returns
+    // the ones that were removed.
+    pub fn remove_disconnected(&mut self) -> Vec<DeviceEvent> {
+        disconnected(&self.map).into_iter().filter_map(|key| self.remove(key)).map(DeviceEvent::Removed).collect()
+    }
+
+    // Adds and removes devices so that we know exactly the given ones.
+    fn reconcile(&mut self, device_ids: Vec<IOHIDDeviceID>) -> Vec<DeviceEvent> {
+        let known: Vec<IOHIDDeviceRef> = self.map.keys().cloned().collect();
+        let current: Vec<IOHIDDeviceRef> = device_ids.iter().map(|id| id.as_ref()).collect();
+        let (removed, added) = diff_devices(&known, &current);
+
+        let mut events = Vec::new();
+        for device_ref in removed {
+            events.extend(self.remove(device_ref).map(DeviceEvent::Removed));
+        }
+        for device_ref in added {
+            events.extend(self.add(device_ref).map(DeviceEvent::Added));
+        }
+        events
+    }
+
+    fn add(&mut self, device_ref: IOHIDDeviceRef) -> Option<DeviceInfo> {
+        if self.map.contains_key(&device_ref) {
+            return None;
+        }
+
+        let mut info = DeviceInfo::new(transport(device_ref));
+        if let Some((vid, pid)) = vendor_product_id(device_ref) {
+            info.vendor_id = Some(vid);
+            info.product_id = Some(pid);
+        }
+
+        // Skip devices on transports the caller didn't ask for, and blocked
+        // models.
+        if !self.filter.matches(&info) {
+            return None;
+        }
+        info.serial_number = serial_number(device_ref);
+        info.registry_id = registry_id(device_ref);
+
+        let scratch_buf = [0; HID_RPT_SIZE];
+        let (report_tx, report_rx) = channel::<Report>();
+
+        let boxed_report_tx = Box::new(report_tx);
+        // report_tx_ptr is deallocated by remove()
+        let report_tx_ptr = Box::into_raw(boxed_report_tx) as *mut libc::c_void;
+
+        let dev = Device {
+            device_ref: device_ref,
+            cid: CID_BROADCAST,
+            report_recv: report_rx,
+            report_send_void: report_tx_ptr,
+            info: info.clone(),
+            observer: self.observer.clone(),
+            context: self.context.clone(),
+            init_failures: 0,
+            disconnected: false,
+        };
+
+        unsafe { IOHIDDeviceRegisterInputReportCallback(device_ref,
+                                                        scratch_buf.as_ptr(),
+                                                        scratch_buf.len() as CFIndex,
+                                                        read_new_data_cb,
+                                                        report_tx_ptr) };
+
+        // The channel is allocated once the device is first used.
+        op_log!(debug, self.context.as_ref(), "added U2F device {} (serial number: {:?})", dev, dev.serial_number());
+        self.added.insert(device_ref, self.next);
+        self.next += 1;
+        self.map.insert(device_ref, dev);
+        Some(info)
+    }
+
+    fn remove(&mut self, device_ref: IOHIDDeviceRef) -> Option<DeviceInfo> {
+        let _ = self.added.remove(&device_ref);
+        match self.map.remove(&device_ref) {
+            Some(dev) => {
+                op_log!(debug, self.context.as_ref(), "removing U2F device {}", dev);
+                // Re-allocate this raw pointer for destruction
+                let _ = unsafe { Box::from_raw(dev.report_send_void) };
+                Some(dev.info)
+            },
+            None => { op_log!(warn, self.context.as_ref(), "Couldn't remove {:?}", device_ref); None },
+        }
+    }
+}
@@ -12,6 +12,7 @@ extern crate libc;
 
 use ::consts::{FIDO_USAGE_PAGE, FIDO_USAGE_U2FHID};
 
+#[derive(Clone, Copy, Debug)]
 pub struct IOHIDDeviceID {
     pub device_id: u64 // TODO: Does this work on non-64-bit systems?
 }
@@ -9,7 +9,7 @@ use core_foundation_sys::string::CFStringRef;
 use core_foundation_sys::runloop::CFRunLoopRef;
 
 use platform::iokit::ioreturn::IOReturn;
-use platform::iokit::IOOptionBits;
+use platform::iokit::{IOOptionBits, io_service_t};
 
 pub type IOHIDReportType = IOOptionBits;
 pub const kIOHIDReportTypeInput: IOHIDReportType   = 0;
@@ -34,6 +34,7 @@ pub type IOHIDCallback = extern fn(context: *mut c_void, result: IOReturn, sende
 
 extern "C" {
     pub fn IOHIDDeviceGetProperty(device: IOHIDDeviceRef, key: CFStringRef) -> CFTypeRef;
+    pub fn IOHIDDeviceGetService(device: IOHIDDeviceRef) -> io_service_t;
     pub fn IOHIDDeviceScheduleWithRunLoop(device: IOHIDDeviceRef, runLoop: CFRunLoopRef, runLoopMode: CFStringRef);
     pub fn IOHIDDeviceSetReport(device: IOHIDDeviceRef, reportType: IOHIDReportType, reportID: CFIndex, report: *const u8, reportLength: CFIndex) -> IOReturn;
     pub fn IOHIDDeviceRegisterInputReportCallback(device: IOHIDDeviceRef, report: *const u8, reportLength: CFIndex, callback: IOHIDReportCallback, context: *mut c_void);
@@ -15,6 +15,19 @@ use libc::c_char;
 
 pub type IOOptionBits = u32;
 
+// exports from <IOKit/IOKitLib.h>
+pub type kern_return_t = libc::c_int;
+pub type io_object_t = libc::c_uint;
+pub type io_registry_entry_t = io_object_t;
+pub type io_service_t = io_object_t;
+
+pub const MACH_PORT_NULL: io_object_t = 0;
+pub const KERN_SUCCESS: kern_return_t = 0;
+
+extern "C" {
+    pub fn IORegistryEntryGetRegistryEntryID(entry: io_registry_entry_t, entryID: *mut u64) -> kern_return_t;
+}
+
 // exports from <IOKit/usb/IOUSBLib.h>
 pub fn kIOUSBDeviceClassName() -> *const c_char {
     b"IOUSBDevice\0".as_ptr() as *const c_char
@@ -40,5 +53,11 @@ pub fn kIOHIDVendorIDKey() -> *const c_char {
     b"VendorID\0".as_ptr() as *const c_char
 }
 pub fn kIOHIDProductIDKey() -> *const c_char {
-    b"Product\0".as_ptr() as *const c_char
+    b"ProductID\0".as_ptr() as *const c_char
+}
+pub fn kIOHIDSerialNumberKey() -> *const c_char {
+    b"SerialNumber\0".as_ptr() as *const c_char
+}
+pub fn kIOHIDTransportKey() -> *const c_char {
+    b"Transport\0".as_ptr() as *const c_char
 }
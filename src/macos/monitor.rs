@@ -1,38 +1,67 @@
 use std::io;
-use std::sync::mpsc::{channel, Sender, Receiver, TryIter};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
 use std::thread;
 
 use super::iohid::*;
 use super::iokit::*;
+use core_foundation_sys::base::*;
 use core_foundation_sys::runloop::*;
+use core_foundation_sys::set::*;
 use runloop::RunLoop;
+use util::{EventIter, EventQueue};
 
 extern crate log;
 extern crate libc;
 use libc::c_void;
 
+#[derive(Clone, Debug)]
 pub enum Event {
     Add(IOHIDDeviceID),
-    Remove(IOHIDDeviceID)
+    Remove(IOHIDDeviceID),
+    // All devices currently present, after a refresh was requested.
+    Snapshot(Vec<IOHIDDeviceID>)
+}
+
+// Lists all devices the HID manager currently matches.
+unsafe fn copy_devices(manager: IOHIDManagerRef) -> Vec<IOHIDDeviceID> {
+    let set = IOHIDManagerCopyDevices(manager);
+    if set.is_null() {
+        return Vec::new();
+    }
+
+    let mut refs = vec![ptr::null(); CFSetGetCount(set) as usize];
+    CFSetGetValues(set, refs.as_mut_ptr());
+    CFRelease(set as *mut libc::c_void);
+
+    refs.into_iter().map(|device| {
+        IOHIDDeviceID::from_ref(device as IOHIDDeviceRef)
+    }).collect()
 }
 
 pub struct Monitor {
     // Receive events from the thread.
-    rx: Receiver<Event>,
+    events: EventQueue<Event>,
     // Handle to the thread loop.
-    thread: RunLoop
+    thread: RunLoop,
+    // Set to have the thread send a fresh snapshot of all devices.
+    refresh: Arc<AtomicBool>
 }
 
 impl Monitor {
     pub fn new() -> io::Result<Self> {
         let (tx, rx) = channel();
+        let refresh = Arc::new(AtomicBool::new(false));
+        let refresh_ = refresh.clone();
 
         let thread = RunLoop::new(move |alive| -> io::Result<()> {
             let tx_box = Box::new(tx);
             let tx_ptr = Box::into_raw(tx_box) as *mut libc::c_void;
 
             // This will keep `tx` alive only for the scope.
-            let _tx = unsafe { Box::from_raw(tx_ptr) };
+            let tx = unsafe { Box::from_raw(tx_ptr) };
 
             // Create and initialize a scoped HID manager.
             let manager = IOHIDManager::new()?;
@@ -52,6 +81,12 @@ impl Monitor {
             // Run the Event Loop. CFRunLoopRunInMode() will dispatch HID
             // input reports into the various callbacks
             while alive() {
+                // Report all devices, if asked to.
+                if refresh_.swap(false, Ordering::SeqCst) {
+                    let snapshot = unsafe { copy_devices(manager.get()) };
+                    let _ = tx.send(Event::Snapshot(snapshot));
+                }
+
                 trace!("OSX Runloop running, handle={:?}", thread::current());
 
                 if unsafe { CFRunLoopRunInMode(kCFRunLoopDefaultMode, 0.1, 0) } == kCFRunLoopRunStopped {
@@ -63,11 +98,22 @@ impl Monitor {
             Ok(())
         }, 0 /* no timeout */)?;
 
-        Ok(Self { rx, thread })
+        Ok(Self { events: EventQueue::new(rx), thread, refresh })
+    }
+
+    pub fn events<'a>(&'a self) -> EventIter<'a, Event> {
+        self.events.try_iter()
+    }
+
+    // The events that haven't been handled yet, for diagnostics. They're
+    // still handed out by `events()`.
+    pub fn pending(&self) -> Vec<Event> {
+        self.events.pending()
     }
 
-    pub fn events<'a>(&'a self) -> TryIter<'a, Event> {
-        self.rx.try_iter()
+    // Asks for an Event::Snapshot of all devices currently present.
+    pub fn refresh(&self) {
+        self.refresh.store(true, Ordering::SeqCst);
     }
 
     extern "C" fn device_add_cb(context: *mut c_void, _: IOReturn,
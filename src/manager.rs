@@ -1,108 +1,1955 @@
+use rand::Rng;
+use rand::os::OsRng;
+use std::any::Any;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use consts::PARAMETER_SIZE;
-use platform::PlatformManager;
+use consts::{MAX_MESSAGE_SIZE, PARAMETER_SIZE, U2FHID_IF_VERSION};
+use error::U2FError;
+use platform;
+use metrics::MetricEvent;
+use registry;
 use runloop::RunLoop;
-use util::{to_io_err, OnceCallback};
+use session::DeviceSession;
+use statemachine::{count_devices, list_devices, SharedState, StateMachine};
+use stats::DeviceStats;
+#[cfg(feature = "futures")]
+use statemachine::watch_devices;
+#[cfg(feature = "futures")]
+use stream::DeviceEventStream;
+#[cfg(feature = "futures")]
+use futures::sync::mpsc::unbounded;
+use u2fprotocol::check_apdu_data;
+use u2ftypes::{Assertion, AssertionOptions, AttestationObject, CredentialRequest, DeviceFilter, DeviceInfo, Direction, KeyHandle, LibraryInfo, OperationOptions, PinStatus, RegisterResponse, SelectionPolicy, SignProgress, SignResponse, Transport};
+use util::{deadline, io_err, sha256, to_base64url, to_io_err, OnceCallback, SharedRng};
+use webauthn::{register_response_to_webauthn, WebAuthnAttestation};
+use warnings::Warning;
+
+// How long the blocking variants of operations wait for a callback beyond
+// the operation's timeout, in milliseconds.
+const BLOCKING_SLACK: u64 = 1000;
+
+struct Callbacks {
+    // By operation id. `None` once we shut down.
+    pending: Option<HashMap<usize, Box<dyn Any + Send>>>,
+    // How many of them are being called right now.
+    running: usize
+}
+
+// Holds the callbacks of the operations that haven't called back yet, so
+// that `shutdown()` can drop them. The thread of an operation that got stuck
+// is detached and may try to call back much later, this stops it.
+#[derive(Clone)]
+struct CallbackSlots {
+    callbacks: Arc<Mutex<Callbacks>>,
+    // Notified whenever a callback returned.
+    returned: Arc<Condvar>
+}
+
+impl CallbackSlots {
+    fn new() -> Self {
+        let callbacks = Callbacks { pending: Some(HashMap::new()), running: 0 };
+        Self { callbacks: Arc::new(Mutex::new(callbacks)), returned: Arc::new(Condvar::new()) }
+    }
+
+    fn insert(&self, id: usize, callback: Box<dyn Any + Send>) -> io::Result<()> {
+        let mut callbacks = self.callbacks.lock().map_err(|_| io_err("failed to lock"))?;
+        match callbacks.pending {
+            Some(ref mut pending) => { pending.insert(id, callback); Ok(()) }
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "manager was shut down"))
+        }
+    }
+
+    fn remove(&self, id: usize) -> Option<Box<dyn Any + Send>> {
+        let mut callbacks = self.callbacks.lock().ok()?;
+        callbacks.pending.as_mut().and_then(|pending| pending.remove(&id))
+    }
+
+    // Calls the callback with the given id, unless it's gone.
+    fn call<T, E, F>(&self, id: usize, rv: Result<T, E>)
+        where F: FnOnce(Result<T, E>), F: 'static
+    {
+        let callback = match self.callbacks.lock() {
+            Ok(mut callbacks) => {
+                let callback = callbacks.pending.as_mut().and_then(|pending| pending.remove(&id));
+                if callback.is_some() {
+                    callbacks.running += 1;
+                }
+                callback
+            }
+            Err(_) => None
+        };
+
+        if let Some(callback) = callback {
+            // Counted down even if the callback panics.
+            let _running = Running(self);
+            if let Ok(callback) = callback.downcast::<F>() {
+                (*callback)(rv);
+            }
+        }
+    }
+
+    // Drops all callbacks, once those being called returned. Later ones
+    // aren't accepted anymore.
+    fn close(&self) {
+        let pending = match self.callbacks.lock() {
+            Ok(mut callbacks) => {
+                let pending = callbacks.pending.take();
+                while callbacks.running > 0 {
+                    callbacks = match self.returned.wait(callbacks) {
+                        Ok(callbacks) => callbacks,
+                        Err(_) => break
+                    };
+                }
+                pending
+            }
+            Err(_) => None
+        };
+
+        // Not while locked, dropping them might run arbitrary code.
+        drop(pending);
+    }
+}
+
+struct Running<'a>(&'a CallbackSlots);
+
+impl<'a> Drop for Running<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut callbacks) = self.0.callbacks.lock() {
+            callbacks.running -= 1;
+            self.0.returned.notify_all();
+        }
+    }
+}
+
+// Owned by the wrapper `track()` returns. When the wrapper goes away without
+// being called, e.g. because the operation couldn't be queued, it drops the
+// callback and the operation isn't in flight anymore.
+struct CallbackSlot {
+    callbacks: CallbackSlots,
+    current_op: Arc<AtomicUsize>,
+    id: usize
+}
+
+impl CallbackSlot {
+    fn call<T, E, F>(&self, rv: Result<T, E>)
+        where F: FnOnce(Result<T, E>), F: 'static
+    {
+        // Done before calling back, which may start the next operation.
+        self.done();
+        self.callbacks.call::<T, E, F>(self.id, rv);
+    }
+
+    fn done(&self) {
+        let _ = self.current_op.compare_exchange(self.id, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+impl Drop for CallbackSlot {
+    fn drop(&mut self) {
+        self.done();
+        drop(self.callbacks.remove(self.id));
+    }
+}
 
 pub enum QueueAction {
   Register {
-    timeout: u64,
     challenge: Vec<u8>,
     application: Vec<u8>,
-    callback: OnceCallback
+    options: OperationOptions,
+    callback: OnceCallback<(Vec<u8>, DeviceInfo), U2FError>
   },
   Sign {
-    timeout: u64,
     challenge: Vec<u8>,
     application: Vec<u8>,
-    key_handle: Vec<u8>,
-    callback: OnceCallback
+    key_handle: KeyHandle,
+    options: OperationOptions,
+    callback: OnceCallback<(Vec<u8>, DeviceInfo), U2FError>
+  },
+  SignAllKeys {
+    deadline: Option<Instant>,
+    challenge: Vec<u8>,
+    application: Vec<u8>,
+    key_handles: Vec<KeyHandle>,
+    progress: SignProgress,
+    callback: OnceCallback<Vec<Option<Vec<u8>>>>
+  },
+  MakeCredential {
+    timeout: u64,
+    request: CredentialRequest,
+    callback: OnceCallback<AttestationObject>
+  },
+  GetAssertion {
+    timeout: u64,
+    rp_id: String,
+    client_data_hash: Vec<u8>,
+    allow_list: Vec<Vec<u8>>,
+    options: AssertionOptions,
+    callback: OnceCallback<Assertion>
+  },
+  PinStatus {
+    timeout: u64,
+    callback: OnceCallback<PinStatus>
+  },
+  RequiresUv {
+    callback: OnceCallback<bool>
+  },
+  HasCredential {
+    application: Vec<u8>,
+    key_handle: KeyHandle,
+    callback: OnceCallback<bool>
+  },
+  Versions {
+    callback: OnceCallback<Vec<(DeviceInfo, io::Result<String>)>>
+  },
+  ProbeApplications {
+    key_handle: KeyHandle,
+    applications: Vec<[u8; PARAMETER_SIZE]>,
+    callback: OnceCallback<Option<usize>>
   },
-  Cancel
+  SendApdu {
+    timeout: u64,
+    ins: u8,
+    p1: u8,
+    data: Vec<u8>,
+    callback: OnceCallback<(Vec<u8>, u16), U2FError>
+  },
+  TouchTest {
+    timeout: u64,
+    callback: OnceCallback<()>
+  },
+  Ping {
+    timeout: u64,
+    data: Vec<u8>,
+    callback: OnceCallback<Vec<u8>>
+  },
+  Wink {
+    timeout: u64,
+    callback: OnceCallback<DeviceInfo>
+  },
+  Cancel,
+  // Cancels the operation with this id, unless it's done by now.
+  CancelOp {
+    id: usize
+  }
 }
 
+impl QueueAction {
+    // Calls back an operation that won't start with `err`.
+    fn fail(self, err: U2FError) {
+        match self {
+            QueueAction::Register{callback, ..} => callback.call(Err(err)),
+            QueueAction::Sign{callback, ..} => callback.call(Err(err)),
+            QueueAction::SignAllKeys{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::MakeCredential{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::GetAssertion{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::PinStatus{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::RequiresUv{callback} => callback.call(Err(err.into())),
+            QueueAction::HasCredential{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::Versions{callback} => callback.call(Err(err.into())),
+            QueueAction::ProbeApplications{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::SendApdu{callback, ..} => callback.call(Err(err)),
+            QueueAction::TouchTest{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::Ping{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::Wink{callback, ..} => callback.call(Err(err.into())),
+            QueueAction::Cancel | QueueAction::CancelOp{..} => {}
+        }
+    }
+}
+
+// Decides whether `origin` is a trusted facet of `app_id`.
+pub type FacetVerifier = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+// Callbacks are called on a thread of the manager's, never on the calling
+// thread. They may start new operations, as that only queues them. They
+// must not drop the last reference to the manager though, or wait for a new
+// operation to complete: the operation can't start before they return.
 pub struct U2FManager {
     queue: RunLoop,
-    tx: Sender<QueueAction>
+    // With the id of the operation, zero for `Cancel` and `CancelOp`.
+    tx: Sender<(usize, QueueAction)>,
+    shared: SharedState,
+    prompt: Arc<Mutex<Option<String>>>,
+    filter: DeviceFilter,
+    rng: SharedRng,
+    facet_verifier: Option<Box<FacetVerifier>>,
+    // Fail new operations while one is in flight, instead of cancelling it.
+    reject_if_busy: bool,
+    // Let new operations wait for the one in flight, instead of cancelling it.
+    queue_operations: bool,
+    // When the operations still queued fail, if set.
+    queue_deadline: Arc<Mutex<Option<Instant>>>,
+    // The operation in flight, if not zero. While operations are queued, the
+    // queue thread sets it when it starts one.
+    current_op: Arc<AtomicUsize>,
+    next_op: AtomicUsize,
+    callbacks: CallbackSlots
+}
+
+// Sets up a U2FManager with non-default options.
+pub struct U2FManagerBuilder {
+    filter: DeviceFilter,
+    rng: Option<Box<dyn Rng + Send>>,
+    init_settle_delay: Option<Duration>,
+    reject_if_busy: bool,
+    queue_operations: bool
+}
+
+impl U2FManagerBuilder {
+    pub fn new() -> Self {
+        Self { filter: DeviceFilter::default(), rng: None, init_settle_delay: None, reject_if_busy: false, queue_operations: false }
+    }
+
+    // Only talk to devices on the given transport.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.filter.transport = Some(transport);
+        self
+    }
+
+    // Draw INIT nonces and PING payloads from `rng` instead of the OS RNG.
+    // Lets tests use a deterministic source.
+    pub fn rng<R>(mut self, rng: R) -> Self
+        where R: Rng + Send + 'static
+    {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    // Never use devices with the given USB vendor and product ID, e.g. a
+    // model with known-bad firmware. They don't receive any commands.
+    pub fn block_device(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.filter.blocklist.push((vendor_id, product_id));
+        self
+    }
+
+    // Makes every operation ignore the devices that are attached when it
+    // starts, so that the user has to remove and reinsert a device for
+    // each operation. Devices plugged in later are used right away. This
+    // only shows that a device was attached again, not that a person did
+    // it, and a device that's left plugged in is never used at all: the
+    // operation just times out.
+    pub fn require_reinsert(mut self, require: bool) -> Self {
+        self.filter.require_reinsert = require;
+        self
+    }
+
+    // Makes every operation ignore the devices that are plugged in while it
+    // runs, so that a device inserted while the user is prompted can't
+    // complete it. By default, such devices are used right away. Devices
+    // that are removed are still dropped. Doesn't go together with
+    // `require_reinsert()`, no device would ever be used.
+    pub fn only_present_at_start(mut self, only: bool) -> Self {
+        self.filter.present_at_start = only;
+        self
+    }
+
+    // Keeps FIDO2 tokens that don't list U2F_V2 in their getInfo versions
+    // out of register and sign operations. Some of them answer U2F commands
+    // anyway, but not always correctly. Tokens without CTAP2 already had
+    // to answer the version APDU with U2F_V2 before they're used.
+    pub fn require_u2f_support(mut self, require: bool) -> Self {
+        self.filter.require_u2f = require;
+        self
+    }
+
+    // How long to wait after allocating a channel on a device before sending
+    // it commands, 5ms by default. Some tokens reject commands that follow
+    // right away as busy, zero only suits devices known not to.
+    pub fn init_settle_delay(mut self, delay: Duration) -> Self {
+        self.init_settle_delay = Some(delay);
+        self
+    }
+
+    // Which device to favor when several could complete an operation, see
+    // `SelectionPolicy`. Defaults to the first one to respond.
+    pub fn selection_policy(mut self, policy: SelectionPolicy) -> Self {
+        self.filter.selection = policy;
+        self
+    }
+
+    // Talks to devices through the given platform backend only, e.g.
+    // "libusb" to debug it on Linux, rather than using every device any
+    // backend finds. `build()` fails if it isn't compiled in, see
+    // `U2FManager::backends()`.
+    pub fn backend(mut self, backend: &str) -> Self {
+        self.filter.backend = Some(String::from(backend));
+        self
+    }
+
+    // By default, starting an operation cancels the one in flight, if any,
+    // and its callback gets an `Interrupted` error. With this set, the new
+    // operation fails with a `U2FError::Busy` error instead, and the one in
+    // flight carries on. An operation is in flight until its callback was
+    // called.
+    pub fn reject_if_busy(mut self, reject: bool) -> Self {
+        self.reject_if_busy = reject;
+        self
+    }
+
+    // Makes new operations wait until the ones started before them called
+    // back, instead of cancelling them. They run in order then, e.g. a batch
+    // of them, see `U2FManager::set_queue_deadline()` to bound how long that
+    // takes. `cancel()` cancels the waiting ones too. With `reject_if_busy()`
+    // set, operations never wait.
+    pub fn queue_operations(mut self, queue: bool) -> Self {
+        self.queue_operations = queue;
+        self
+    }
+
+    pub fn build(self) -> io::Result<U2FManager> {
+        if let Some(ref backend) = self.filter.backend {
+            let available = platform::backends();
+            if !available.contains(&backend.as_str()) {
+                let msg = format!("backend {:?} isn't compiled in, available: {}", backend, available.join(", "));
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+
+        let rng = match self.rng {
+            Some(rng) => rng,
+            None => Box::new(OsRng::new()?)
+        };
+
+        let mut shared = SharedState::new();
+        shared.init_settle_delay = self.init_settle_delay;
+        U2FManager::start(self.filter, Arc::new(Mutex::new(rng)), shared, self.reject_if_busy, self.queue_operations)
+    }
+}
+
+impl Default for U2FManagerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Fails for options that no operation could run with.
+fn check_options(options: &OperationOptions) -> io::Result<()> {
+    if options.idle_timeout == Some(Duration::from_secs(0)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Idle timeout must not be zero"));
+    }
+    Ok(())
+}
+
+// Pairs a raw response with its parsed form.
+fn with_parsed<T, P>(rv: Result<Vec<u8>, U2FError>, parse: P) -> Result<(Vec<u8>, T), U2FError>
+    where P: FnOnce(&[u8]) -> io::Result<T>
+{
+    let raw = rv?;
+    let parsed = parse(&raw)?;
+    Ok((raw, parsed))
 }
 
 impl U2FManager {
+    // The platform backends compiled in, for `U2FManagerBuilder::backend()`.
+    pub fn backends() -> Vec<&'static str> {
+        platform::backends()
+    }
+
     pub fn new() -> io::Result<Self> {
+        U2FManagerBuilder::new().build()
+    }
+
+    // Creates a manager that only talks to devices on the given transport.
+    // Passing `None` allows any transport.
+    pub fn with_transport(transport: Option<Transport>) -> io::Result<Self> {
+        let mut builder = U2FManagerBuilder::new();
+        builder.filter.transport = transport;
+        builder.build()
+    }
+
+    // Like `with_transport()`, but draws INIT nonces and PING payloads from
+    // `rng` instead of the OS RNG. Lets tests use a deterministic source.
+    pub fn with_rng<R>(transport: Option<Transport>, rng: R) -> io::Result<Self>
+        where R: Rng + Send + 'static
+    {
+        let mut builder = U2FManagerBuilder::new().rng(rng);
+        builder.filter.transport = transport;
+        builder.build()
+    }
+
+    fn start(filter: DeviceFilter, rng: SharedRng, shared: SharedState, reject_if_busy: bool, queue_operations: bool) -> io::Result<Self> {
+        let filter_ = filter.clone();
+        let rng_ = rng.clone();
+        let shared_ = shared.clone();
+        let prompt = Arc::new(Mutex::new(None));
+        let prompt_ = prompt.clone();
+        let queue_deadline = Arc::new(Mutex::new(None));
+        let queue_deadline_ = queue_deadline.clone();
+        let current_op = Arc::new(AtomicUsize::new(0));
+        let current_op_ = current_op.clone();
         let (tx, rx) = channel();
 
         // Start a new work queue thread.
-        let queue = try!(RunLoop::new(move |alive| {
-            let mut pm = PlatformManager::new();
+        let queue = RunLoop::new(move |alive| {
+            let mut sm = StateMachine::new(filter_, rng_, shared_);
+            // Operations that didn't start yet, in order.
+            let mut waiting: VecDeque<(usize, QueueAction)> = VecDeque::new();
 
             while alive() {
                 match rx.recv_timeout(Duration::from_millis(50)) {
-                    Ok(QueueAction::Register{timeout, challenge, application, callback}) => {
-                        // This must not block, otherwise we can't cancel.
-                        pm.register(timeout, challenge, application, callback);
-                    }
-                    Ok(QueueAction::Sign{timeout, challenge, application, key_handle, callback}) => {
-                        // This must not block, otherwise we can't cancel.
-                        pm.sign(timeout, challenge, application, key_handle, callback);
-                    }
-                    Ok(QueueAction::Cancel) => {
+                    Ok((_, QueueAction::Cancel)) => {
                         // Cancelling must block so that we don't start a new
                         // polling thread before the old one has shut down.
-                        pm.cancel();
+                        sm.cancel();
+                        for (_, action) in waiting.drain(..) {
+                            action.fail(U2FError::Cancelled);
+                        }
                     }
+                    Ok((_, QueueAction::CancelOp{id})) => {
+                        // Unless operations are queued, those queued later
+                        // started by now, and are in flight instead.
+                        if current_op_.load(Ordering::SeqCst) == id {
+                            sm.cancel();
+                        } else if let Some(index) = waiting.iter().position(|&(op, _)| op == id) {
+                            if let Some((_, action)) = waiting.remove(index) {
+                                action.fail(U2FError::Cancelled);
+                            }
+                        }
+                    }
+                    Ok(queued) => waiting.push_back(queued),
                     Err(RecvTimeoutError::Disconnected) => {
                         break;
                     }
-                    _ => { /* continue */ }
+                    Err(RecvTimeoutError::Timeout) => { /* continue */ }
+                }
+
+                // Past the queue deadline, nothing may run anymore.
+                let deadline = queue_deadline_.lock().ok().and_then(|deadline| *deadline);
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    sm.cancel();
+                    for (_, action) in waiting.drain(..) {
+                        action.fail(U2FError::QueueDeadline);
+                    }
+                }
+
+                // Unless they're queued, operations start right away and
+                // cancel the one in flight.
+                while !(queue_operations && sm.busy()) {
+                    let action = match waiting.pop_front() {
+                        Some((id, action)) => {
+                            // Otherwise `track()` did, when it was started.
+                            if queue_operations {
+                                current_op_.store(id, Ordering::SeqCst);
+                            }
+                            Some(action)
+                        }
+                        None => None
+                    };
+                    match action {
+                        Some(QueueAction::Register{challenge, application, options, callback}) => {
+                            if let Ok(mut current) = prompt_.lock() {
+                                *current = options.prompt.clone();
+                            }
+                            // This must not block, otherwise we can't cancel.
+                            sm.register(challenge, application, options, callback);
+                        }
+                        Some(QueueAction::Sign{challenge, application, key_handle, options, callback}) => {
+                            if let Ok(mut current) = prompt_.lock() {
+                                *current = options.prompt.clone();
+                            }
+                            // This must not block, otherwise we can't cancel.
+                            sm.sign(challenge, application, key_handle, options, callback);
+                        }
+                        Some(QueueAction::SignAllKeys{deadline, challenge, application, key_handles, progress, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.sign_all_keys(deadline, challenge, application, key_handles, progress, callback);
+                        }
+                        Some(QueueAction::MakeCredential{timeout, request, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.make_credential(timeout, request, callback);
+                        }
+                        Some(QueueAction::GetAssertion{timeout, rp_id, client_data_hash, allow_list, options, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.get_assertion(timeout, rp_id, client_data_hash, allow_list, options, callback);
+                        }
+                        Some(QueueAction::PinStatus{timeout, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.pin_status(timeout, callback);
+                        }
+                        Some(QueueAction::RequiresUv{callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.requires_uv(callback);
+                        }
+                        Some(QueueAction::HasCredential{application, key_handle, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.has_credential(application, key_handle, callback);
+                        }
+                        Some(QueueAction::Versions{callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.versions(callback);
+                        }
+                        Some(QueueAction::ProbeApplications{key_handle, applications, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.probe_applications(key_handle, applications, callback);
+                        }
+                        Some(QueueAction::SendApdu{timeout, ins, p1, data, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.send_apdu(timeout, ins, p1, data, callback);
+                        }
+                        Some(QueueAction::TouchTest{timeout, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.touch_test(timeout, callback);
+                        }
+                        Some(QueueAction::Wink{timeout, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.wink(timeout, callback);
+                        }
+                        Some(QueueAction::Ping{timeout, data, callback}) => {
+                            // This must not block, otherwise we can't cancel.
+                            sm.ping(timeout, data, callback);
+                        }
+                        Some(QueueAction::Cancel) | Some(QueueAction::CancelOp{..}) => { /* handled above */ }
+                        None => break
+                    }
                 }
             }
 
             // Cancel any ongoing activity.
-            pm.cancel();
-        }, 0 /* no timeout */));
+            sm.cancel();
+        }, 0 /* no timeout */)?;
+
+        let next_op = AtomicUsize::new(0);
+        let callbacks = CallbackSlots::new();
+        Ok(Self { queue, tx, shared, prompt, filter, rng, facet_verifier: None, reject_if_busy, queue_operations, queue_deadline, current_op, next_op, callbacks })
+    }
 
-        Ok(Self { queue, tx })
+    // Wraps the callback of a new operation, so that we know when it's done,
+    // and gives it an id. Fails if another one is in flight and we were asked
+    // not to cancel it.
+    fn track<T, E, F>(&self, callback: F) -> io::Result<(usize, OnceCallback<T, E>)>
+        where T: 'static, E: 'static, F: FnOnce(Result<T, E>), F: Send + 'static
+    {
+        let id = self.next_op.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.reject_if_busy {
+            if self.current_op.compare_exchange(0, id, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                return Err(U2FError::Busy.into());
+            }
+        } else if !self.queue_operations {
+            self.current_op.store(id, Ordering::SeqCst);
+        }
+
+        // From here on, the operation is done once `slot` is dropped.
+        let slot = CallbackSlot { callbacks: self.callbacks.clone(), current_op: self.current_op.clone(), id };
+        self.callbacks.insert(id, Box::new(callback))?;
+        Ok((id, OnceCallback::new(move |rv| slot.call::<T, E, F>(rv))))
+    }
+
+    // Hands the operation `track()` gave this id to the queue thread.
+    fn queue(&self, id: usize, action: QueueAction) -> io::Result<()> {
+        self.tx.send((id, action)).map_err(to_io_err)
     }
 
     pub fn register<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, callback: F) -> io::Result<()>
-        where F: FnOnce(io::Result<Vec<u8>>), F: Send + 'static
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        self.register_with_prompt(timeout, challenge, application, None, callback)
+    }
+
+    // Like `register()`, with a message for the user, e.g. "Authenticate to
+    // Example Corp". It would go to the platform's security key dialog, but
+    // none of our backends (hidraw, IOKit, Windows HID) has one. So it's only
+    // kept for the caller's own UI, see `prompt()`.
+    pub fn register_with_prompt<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, prompt: Option<&str>, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let options = OperationOptions { prompt: prompt.map(str::to_owned), ..OperationOptions::with_timeout(timeout) };
+        self.queue_register(challenge, application, options, callback)
+    }
+
+    // Like `register()`, but gives up at the given point in time instead of
+    // after a number of seconds.
+    pub fn register_until<F>(&self, deadline: Instant, challenge: Vec<u8>, application: Vec<u8>, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let options = OperationOptions { deadline: Some(deadline), ..OperationOptions::default() };
+        self.queue_register(challenge, application, options, callback)
+    }
+
+    // Like `register()`, but only with the given devices, e.g. the ones the
+    // user picked from `list_devices()`. They're opened again right away,
+    // devices that are gone by now are skipped. Devices plugged in while the
+    // operation runs are used as well. macOS has no device paths, so all
+    // attached devices are used there.
+    pub fn register_with_devices<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, devices: Vec<DeviceInfo>, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let paths = devices.into_iter().filter_map(|info| info.path).collect();
+        let options = OperationOptions { paths: Some(paths), ..OperationOptions::with_timeout(timeout) };
+        self.queue_register(challenge, application, options, callback)
+    }
+
+    // Like `register()`, but hands over the response base64url-encoded, as
+    // WebAuthn puts it on the wire.
+    pub fn register_base64url<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<String, U2FError>), F: Send + 'static
+    {
+        self.register(timeout, challenge, application, move |rv| {
+            callback(rv.map(|response| to_base64url(&response)))
+        })
+    }
+
+    // Like `register()`, but the callback gets the parsed response along with
+    // the raw one, e.g. to store the raw bytes for verifying them again
+    // later. A response that doesn't parse fails the operation.
+    pub fn register_parsed<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<(Vec<u8>, RegisterResponse), U2FError>), F: Send + 'static
+    {
+        self.register(timeout, challenge, application, move |rv| {
+            callback(with_parsed(rv, RegisterResponse::from_raw))
+        })
+    }
+
+    // Like `register()`, but tags the operation's log messages with
+    // `correlation_id`, e.g. to tell concurrent requests of a daemon apart.
+    pub fn register_with_correlation_id<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, correlation_id: &str, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let options = OperationOptions { correlation_id: Some(correlation_id.to_owned()), ..OperationOptions::with_timeout(timeout) };
+        self.queue_register(challenge, application, options, callback)
+    }
+
+    // Like `register()`, but the callback also learns which device the user
+    // touched, e.g. to warn if a different one is touched to sign later. On
+    // Linux and Windows its `path` identifies it, on macOS its `registry_id`.
+    pub fn register_with_device<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<(Vec<u8>, DeviceInfo), U2FError>), F: Send + 'static
+    {
+        self.register_with_options(challenge, application, OperationOptions::with_timeout(timeout), callback)
+    }
+
+    // Like `register_with_device()`, with any of the above options, e.g. an
+    // idle timeout.
+    pub fn register_with_options<F>(&self, challenge: Vec<u8>, application: Vec<u8>, options: OperationOptions, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<(Vec<u8>, DeviceInfo), U2FError>), F: Send + 'static
+    {
+        self.start_register(challenge, application, options, callback).map(|_| ())
+    }
+
+    // Like `register_with_options()`, but returns the id of the operation,
+    // e.g. for `wait_for()` to cancel it.
+    fn start_register<F>(&self, challenge: Vec<u8>, application: Vec<u8>, options: OperationOptions, callback: F) -> io::Result<usize>
+        where F: FnOnce(Result<(Vec<u8>, DeviceInfo), U2FError>), F: Send + 'static
     {
         if challenge.len() != PARAMETER_SIZE ||
            application.len() != PARAMETER_SIZE {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
         }
+        check_options(&options)?;
 
-        let callback = OnceCallback::new(callback);
-        let action = QueueAction::Register { timeout, challenge, application, callback };
-        self.tx.send(action).map_err(to_io_err)
+        let (id, callback) = self.track(callback)?;
+        let action = QueueAction::Register { challenge, application, options, callback };
+        self.queue(id, action).map(|_| id)
     }
 
-    pub fn sign<F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: Vec<u8>, callback: F) -> io::Result<()>
-        where F: FnOnce(io::Result<Vec<u8>>), F: Send + 'static
+    fn queue_register<F>(&self, challenge: Vec<u8>, application: Vec<u8>, options: OperationOptions, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        self.register_with_options(challenge, application, options, move |rv| {
+            callback(rv.map(|(response, _)| response))
+        })
+    }
+
+    pub fn sign<K, F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        self.sign_with_prompt(timeout, challenge, application, key_handle, None, callback)
+    }
+
+    // Like `sign()`, with a message for the user. See `register_with_prompt()`.
+    pub fn sign_with_prompt<K, F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, prompt: Option<&str>, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let options = OperationOptions { prompt: prompt.map(str::to_owned), ..OperationOptions::with_timeout(timeout) };
+        self.queue_sign(challenge, application, key_handle, options, callback)
+    }
+
+    // Like `sign()`, but gives up at the given point in time. See
+    // `register_until()`.
+    pub fn sign_until<K, F>(&self, deadline: Instant, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let options = OperationOptions { deadline: Some(deadline), ..OperationOptions::default() };
+        self.queue_sign(challenge, application, key_handle, options, callback)
+    }
+
+    // Like `sign()`, but hands over the response base64url-encoded. See
+    // `register_base64url()`.
+    pub fn sign_base64url<K, F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<String, U2FError>), F: Send + 'static
+    {
+        self.sign(timeout, challenge, application, key_handle, move |rv| {
+            callback(rv.map(|response| to_base64url(&response)))
+        })
+    }
+
+    // Like `sign()`, but hands over the parsed response too. See
+    // `register_parsed()`.
+    pub fn sign_parsed<K, F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<(Vec<u8>, SignResponse), U2FError>), F: Send + 'static
+    {
+        self.sign(timeout, challenge, application, key_handle, move |rv| {
+            callback(with_parsed(rv, SignResponse::from_raw))
+        })
+    }
+
+    // Like `sign()`, but tags the operation's log messages. See
+    // `register_with_correlation_id()`.
+    pub fn sign_with_correlation_id<K, F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, correlation_id: &str, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let options = OperationOptions { correlation_id: Some(correlation_id.to_owned()), ..OperationOptions::with_timeout(timeout) };
+        self.queue_sign(challenge, application, key_handle, options, callback)
+    }
+
+    // Like `sign()`, but the callback also learns which device was touched.
+    // See `register_with_device()`.
+    pub fn sign_with_device<K, F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<(Vec<u8>, DeviceInfo), U2FError>), F: Send + 'static
+    {
+        self.sign_with_options(challenge, application, key_handle, OperationOptions::with_timeout(timeout), callback)
+    }
+
+    // Like `sign_with_device()`, with options. See `register_with_options()`.
+    pub fn sign_with_options<K, F>(&self, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, options: OperationOptions, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<(Vec<u8>, DeviceInfo), U2FError>), F: Send + 'static
+    {
+        self.start_sign(challenge, application, key_handle, options, callback).map(|_| ())
+    }
+
+    // Like `sign_with_options()`, but returns the id of the operation. See
+    // `start_register()`.
+    fn start_sign<K, F>(&self, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, options: OperationOptions, callback: F) -> io::Result<usize>
+        where K: Into<KeyHandle>, F: FnOnce(Result<(Vec<u8>, DeviceInfo), U2FError>), F: Send + 'static
     {
         if challenge.len() != PARAMETER_SIZE ||
            application.len() != PARAMETER_SIZE {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
         }
+        check_options(&options)?;
+
+        let key_handle = key_handle.into();
+        key_handle.check()?;
+
+        let (id, callback) = self.track(callback)?;
+        let action = QueueAction::Sign { challenge, application, key_handle, options, callback };
+        self.queue(id, action).map(|_| id)
+    }
+
+    fn queue_sign<K, F>(&self, challenge: Vec<u8>, application: Vec<u8>, key_handle: K, options: OperationOptions, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        self.sign_with_options(challenge, application, key_handle, options, move |rv| {
+            callback(rv.map(|(response, _)| response))
+        })
+    }
+
+    // Signs the challenge with each of the key handles, e.g. to check that
+    // all of a user's keys, backups included, still work. This takes one
+    // touch per key handle, one after the other: whichever device owns a
+    // key handle that wasn't signed with yet waits for the user, the others
+    // don't blink. `progress` is told how many key handles were signed with
+    // so far, out of how many, once at the start and after every touch, so
+    // that the UI can ask for the next key. The callback gets a signature
+    // per key handle, in the same order, once all of them were signed with.
+    // If we time out first, missing signatures are `None`. The signatures
+    // aren't verified, that's up to the caller.
+    pub fn sign_all_keys<P, F>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handles: Vec<Vec<u8>>, progress: P, callback: F) -> io::Result<()>
+        where P: Fn(usize, usize), P: Send + 'static, F: FnOnce(io::Result<Vec<Option<Vec<u8>>>>), F: Send + 'static
+    {
+        if challenge.len() != PARAMETER_SIZE ||
+           application.len() != PARAMETER_SIZE ||
+           key_handles.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameters"));
+        }
+
+        let key_handles: Vec<KeyHandle> = key_handles.into_iter().map(KeyHandle::from).collect();
+        for key_handle in &key_handles {
+            key_handle.check()?;
+        }
+
+        let (id, callback) = self.track(callback)?;
+        let progress = Box::new(progress);
+        let action = QueueAction::SignAllKeys { deadline: deadline(timeout), challenge, application, key_handles, progress, callback };
+        self.queue(id, action)
+    }
+
+    // Creates a CTAP2 credential for the request's user at its relying party
+    // on the first FIDO2 token the user touches, with the first of the COSE
+    // algorithms it supports.
+    // U2F-only tokens are ignored, use `register()` for those. Like with
+    // `get_assertion()`, cancelling takes effect once the token answers.
+    // Unless `options` ask for another `AttestationConveyance`, the token's
+    // attestation is replaced with the "none" format.
+    pub fn make_credential<F>(&self, timeout: u64, request: CredentialRequest, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<AttestationObject>), F: Send + 'static
+    {
+        if request.client_data_hash.len() != PARAMETER_SIZE || request.algorithms.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameters"));
+        }
+
+        let (id, callback) = self.track(callback)?;
+        let action = QueueAction::MakeCredential { timeout, request, callback };
+        self.queue(id, action)
+    }
+
+    // Gets a CTAP2 assertion from the first FIDO2 token that has one of the
+    // credentials in `allow_list`, or a discoverable credential for `rp_id`
+    // if it's empty. U2F-only tokens are ignored, use `sign()` for those.
+    // Tokens wait for the user before they answer, so cancelling takes effect
+    // once the one we're asking does. With `options.hmac_secret` set, only
+    // tokens that support the extension are asked, and the assertion carries
+    // their secrets.
+    pub fn get_assertion<F>(&self, timeout: u64, rp_id: &str, client_data_hash: Vec<u8>, allow_list: Vec<Vec<u8>>, options: AssertionOptions, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<Assertion>), F: Send + 'static
+    {
+        if client_data_hash.len() != PARAMETER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
+        }
+
+        let (id, callback) = self.track(callback)?;
+        let rp_id = rp_id.to_owned();
+        let action = QueueAction::GetAssertion { timeout, rp_id, client_data_hash, allow_list, options, callback };
+        self.queue(id, action)
+    }
 
-        if key_handle.len() > 256 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Key handle too large"));
+    // Tells whether any attached device owns the key handle. Doesn't need
+    // user presence, so the callback is called as soon as we know.
+    pub fn has_credential<K, F>(&self, application: Vec<u8>, key_handle: K, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(io::Result<bool>), F: Send + 'static
+    {
+        if application.len() != PARAMETER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
         }
 
-        let callback = OnceCallback::new(callback);
-        let action = QueueAction::Sign { timeout, challenge, application, key_handle, callback };
-        self.tx.send(action).map_err(to_io_err)
+        let key_handle = key_handle.into();
+        key_handle.check()?;
+
+        let (id, callback) = self.track(callback)?;
+        let action = QueueAction::HasCredential { application, key_handle, callback };
+        self.queue(id, action)
+    }
+
+    // Finds out which of the given application hashes the key handle was
+    // registered under, e.g. when migrating to a new app-id. The callback
+    // gets the index of the application, or `None` if no attached device
+    // knows the key handle. Doesn't need user presence either.
+    pub fn probe_applications<K, F>(&self, key_handle: K, applications: Vec<[u8; PARAMETER_SIZE]>, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(io::Result<Option<usize>>), F: Send + 'static
+    {
+        let key_handle = key_handle.into();
+        key_handle.check()?;
+
+        let (id, callback) = self.track(callback)?;
+        let action = QueueAction::ProbeApplications { key_handle, applications, callback };
+        self.queue(id, action)
+    }
+
+    // Asks the first FIDO2 token that shows up whether it has a PIN set, and
+    // how many attempts are left. U2F-only tokens are ignored.
+    pub fn pin_status<F>(&self, timeout: u64, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<PinStatus>), F: Send + 'static
+    {
+        let (id, callback) = self.track(callback)?;
+        let action = QueueAction::PinStatus { timeout, callback };
+        self.queue(id, action)
+    }
+
+    // Tells whether any attached FIDO2 token will ask for user verification,
+    // i.e. a PIN or a fingerprint, so that the UI can prepare for that.
+    // U2F-only tokens only need user presence and don't count.
+    pub fn requires_uv<F>(&self, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<bool>), F: Send + 'static
+    {
+        let (id, callback) = self.track(callback)?;
+        self.queue(id, QueueAction::RequiresUv { callback })
+    }
+
+    // Asks every attached device for its U2F version string, e.g. for a
+    // diagnostics page. Doesn't need user presence, the callback gets one
+    // entry per device once all of them answered.
+    pub fn versions<F>(&self, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<Vec<(DeviceInfo, io::Result<String>)>>), F: Send + 'static
+    {
+        let (id, callback) = self.track(callback)?;
+        self.queue(id, QueueAction::Versions { callback })
+    }
+
+    // Sends an APDU, e.g. a vendor command, to the first device that answers
+    // it. The callback gets the response data and the status word, which
+    // isn't an error even if it isn't 0x9000. Can be cancelled like any other
+    // operation.
+    pub fn send_apdu<F>(&self, timeout: u64, ins: u8, p1: u8, data: Vec<u8>, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<(Vec<u8>, u16), U2FError>), F: Send + 'static
+    {
+        self.start_send_apdu(timeout, ins, p1, data, callback).map(|_| ())
+    }
+
+    // Like `send_apdu()`, but returns the id of the operation. See
+    // `start_register()`.
+    fn start_send_apdu<F>(&self, timeout: u64, ins: u8, p1: u8, data: Vec<u8>, callback: F) -> io::Result<usize>
+        where F: FnOnce(Result<(Vec<u8>, u16), U2FError>), F: Send + 'static
+    {
+        check_apdu_data(&data)?;
+
+        let (id, callback) = self.track(callback)?;
+        let action = QueueAction::SendApdu { timeout, ins, p1, data, callback };
+        self.queue(id, action).map(|_| id)
+    }
+
+    // Has the first device that answers echo `data` back, to diagnose flaky
+    // links, see `u2f_ping()`. Doesn't need user presence. The callback gets
+    // the echo, devices that garble it are asked again.
+    pub fn ping<F>(&self, timeout: u64, data: Vec<u8>, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<Vec<u8>>), F: Send + 'static
+    {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Ping payload too large"));
+        }
+
+        let (id, callback) = self.track(callback)?;
+        self.queue(id, QueueAction::Ping { timeout, data, callback })
+    }
+
+    // Waits for the user to touch any device, e.g. to check that a token works
+    // before enrolling it. This is a register with throwaway challenge and
+    // application parameters, so the device does create a key pair, which is
+    // then discarded. The callback only learns whether a device was touched.
+    pub fn touch_test<F>(&self, timeout: u64, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<()>), F: Send + 'static
+    {
+        let (id, callback) = self.track(callback)?;
+        self.queue(id, QueueAction::TouchTest { timeout, callback })
+    }
+
+    // Has the first device that answers identify itself, e.g. by blinking,
+    // like `send_apdu()` picks one. The callback learns which device it was.
+    // Fails if that device doesn't support WINK. To wink a device of your
+    // choice, see `open_device()`.
+    pub fn wink<F>(&self, timeout: u64, callback: F) -> io::Result<()>
+        where F: FnOnce(io::Result<DeviceInfo>), F: Send + 'static
+    {
+        let (id, callback) = self.track(callback)?;
+        self.queue(id, QueueAction::Wink { timeout, callback })
+    }
+
+    // Blocks until the operation `start` starts with the given timeout calls
+    // back, for callers without an event loop, e.g. CLI tools. `start`
+    // returns the operation's id. Cancelling from another thread ends the
+    // wait with the cancellation error. The operation times out on its own,
+    // but should its callback not come shortly after, it's cancelled and we
+    // give up anyway.
+    fn wait_for<T, S>(&self, timeout: u64, start: S) -> Result<T, U2FError>
+        where T: Send + 'static, S: FnOnce(Sender<Result<T, U2FError>>) -> io::Result<usize>
+    {
+        let (tx, rx) = channel();
+        // Errors that come right away are ours, e.g. bad arguments.
+        let id = start(tx).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => U2FError::InvalidInput(e),
+            _ => U2FError::from(e)
+        })?;
+        let rv = match deadline(timeout) {
+            Some(_) => rx.recv_timeout(Duration::from_secs(timeout) + Duration::from_millis(BLOCKING_SLACK)),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        match rv {
+            Ok(rv) => rv,
+            Err(RecvTimeoutError::Timeout) => {
+                // Only ours, others might have started in the meantime.
+                let _ = self.tx.send((0, QueueAction::CancelOp { id }));
+                Err(U2FError::Timeout)
+            }
+            // The callback was dropped without being called, see `shutdown()`.
+            Err(RecvTimeoutError::Disconnected) => Err(U2FError::Cancelled)
+        }
+    }
+
+    // Like `register()`, but BLOCKS until it's done and returns the result.
+    pub fn register_blocking(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>) -> Result<Vec<u8>, U2FError> {
+        let options = OperationOptions::with_timeout(timeout);
+        self.wait_for(timeout, |tx| self.start_register(challenge, application, options, move |rv| { let _ = tx.send(rv.map(|(response, _)| response)); }))
+    }
+
+    // Like `sign()`, but BLOCKS until it's done and returns the result.
+    pub fn sign_blocking<K>(&self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: K) -> Result<Vec<u8>, U2FError>
+        where K: Into<KeyHandle>
+    {
+        let options = OperationOptions::with_timeout(timeout);
+        self.wait_for(timeout, |tx| self.start_sign(challenge, application, key_handle, options, move |rv| { let _ = tx.send(rv.map(|(response, _)| response)); }))
+    }
+
+    // Like `send_apdu()`, but BLOCKS until it's done and returns the response
+    // data and the status word.
+    pub fn send_apdu_blocking(&self, timeout: u64, ins: u8, p1: u8, data: Vec<u8>) -> Result<(Vec<u8>, u16), U2FError> {
+        self.wait_for(timeout, |tx| self.start_send_apdu(timeout, ins, p1, data, move |rv| { let _ = tx.send(rv); }))
+    }
+
+    // Has the ongoing register/sign operation enumerate all devices again, to
+    // recover from device arrivals or removals the platform didn't report,
+    // e.g. after resuming from sleep. Operations notice most resumes on their
+    // own, by the wall clock jumping ahead, but not on Windows. Between
+    // operations this is a no-op, as every operation starts with a fresh
+    // enumeration.
+    pub fn refresh_devices(&self) {
+        self.shared.refresh.store(true, Ordering::SeqCst);
+    }
+
+    // Advanced: has the ongoing operation skip the devices left in the current
+    // polling round and start a new one, e.g. once external state says a
+    // particular device should be tried now. The device being talked to
+    // finishes first. Nothing is cancelled, and between operations this is
+    // a no-op. Most callers want `cancel()` or `refresh_devices()` instead.
+    pub fn interrupt_round(&self) {
+        self.shared.interrupt.store(true, Ordering::SeqCst);
+    }
+
+    // Stops sending commands to devices, e.g. while the UI is in the
+    // background, without cancelling the ongoing operation. Its timeout
+    // keeps running. Also applies to operations started while paused.
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::SeqCst);
+    }
+
+    // Continues polling devices after `pause()`.
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::SeqCst);
+    }
+
+    // Limits how many device arrivals/removals are handled between two
+    // rounds of polling devices. Takes effect with the next register/sign.
+    // At least one event is always handled.
+    pub fn set_max_events_per_poll(&self, max: usize) {
+        self.shared.max_events.store(cmp::max(max, 1), Ordering::SeqCst);
+    }
+
+    // Limits how many devices are sent commands in each round of polling,
+    // for machines with lots of HID devices. Recently added devices go
+    // first, any devices beyond the cap aren't tried at all that round.
+    // Unlimited by default, takes effect with the next register/sign. At
+    // least one device is always polled.
+    pub fn set_max_devices_per_poll(&self, max: usize) {
+        self.shared.max_devices.store(cmp::max(max, 1), Ordering::SeqCst);
+    }
+
+    // Calls `observer` with every HID report sent to or received from a
+    // device, for capturing the traffic. Reports are raw and may contain key
+    // handles and other credential data. Takes effect with the next
+    // operation.
+    pub fn set_frame_observer<F>(&self, observer: F) -> io::Result<()>
+        where F: Fn(Direction, &[u8]) + Send + Sync + 'static
+    {
+        let mut current = self.shared.observer.lock().map_err(|_| io_err("failed to lock"))?;
+        *current = Some(Arc::new(observer));
+        Ok(())
+    }
+
+    pub fn clear_frame_observer(&self) -> io::Result<()> {
+        let mut current = self.shared.observer.lock().map_err(|_| io_err("failed to lock"))?;
+        *current = None;
+        Ok(())
+    }
+
+    // Calls `hook` with a `MetricEvent` when an operation starts, for every
+    // device it's tried on, and when it completes, e.g. to feed a monitoring
+    // backend. The hook runs on the operation's thread and should return
+    // quickly. Takes effect with the next operation.
+    pub fn set_metrics_hook<F>(&self, hook: F) -> io::Result<()>
+        where F: Fn(MetricEvent) + Send + Sync + 'static
+    {
+        let mut current = self.shared.metrics.lock().map_err(|_| io_err("failed to lock"))?;
+        *current = Some(Arc::new(hook));
+        Ok(())
+    }
+
+    pub fn clear_metrics_hook(&self) -> io::Result<()> {
+        let mut current = self.shared.metrics.lock().map_err(|_| io_err("failed to lock"))?;
+        *current = None;
+        Ok(())
+    }
+
+    // Calls `progress` with the bytes received so far and the bytes expected
+    // for every HID report of a response that spans several, e.g. a large
+    // CTAP2 one, to show progress during slow transfers. Runs on the
+    // operation's thread. Takes effect with the next operation.
+    pub fn set_read_progress<F>(&self, progress: F) -> io::Result<()>
+        where F: Fn(usize, usize) + Send + Sync + 'static
+    {
+        let mut current = self.shared.progress.lock().map_err(|_| io_err("failed to lock"))?;
+        *current = Some(Arc::new(progress));
+        Ok(())
+    }
+
+    pub fn clear_read_progress(&self) -> io::Result<()> {
+        let mut current = self.shared.progress.lock().map_err(|_| io_err("failed to lock"))?;
+        *current = None;
+        Ok(())
+    }
+
+    // Installs a hook that `register_with_origin()` and `sign_with_origin()`
+    // consult before talking to any device. Fetching and parsing the app-id's
+    // trusted facets list is up to the caller.
+    pub fn set_facet_verifier<F>(&mut self, verifier: F)
+        where F: Fn(&str, &str) -> bool + Send + Sync + 'static
+    {
+        self.facet_verifier = Some(Box::new(verifier));
+    }
+
+    fn check_facet(&self, app_id: &str, origin: &str) -> io::Result<()> {
+        match self.facet_verifier {
+            Some(ref verifier) if !verifier(app_id, origin) => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "facet not allowed"))
+            }
+            _ => Ok(())
+        }
+    }
+
+    // Like `register()`, but takes the app-id itself instead of its hash and
+    // checks that `origin` is allowed to use it.
+    pub fn register_with_origin<F>(&self, timeout: u64, challenge: Vec<u8>, app_id: &str, origin: &str, callback: F) -> io::Result<()>
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        self.check_facet(app_id, origin)?;
+        self.register(timeout, challenge, sha256(app_id.as_bytes()).to_vec(), callback)
+    }
+
+    // Registers a credential for the WebAuthn relying party `rp_id`, with
+    // `client_data` as the clientDataJSON, and hands over the "fido-u2f"
+    // attestationObject and the clientDataJSON to send back. Hashes both, so
+    // `client_data` is passed as is, e.g. from `build_client_data()`. Still
+    // needs the user to touch the device, like `register()`.
+    pub fn register_webauthn<F>(&self, timeout: u64, rp_id: &str, client_data: &[u8], callback: F) -> io::Result<()>
+        where F: FnOnce(Result<WebAuthnAttestation, U2FError>), F: Send + 'static
+    {
+        let rp_id = rp_id.to_owned();
+        let client_data = client_data.to_vec();
+        let challenge = sha256(&client_data).to_vec();
+        let application = sha256(rp_id.as_bytes()).to_vec();
+        self.register(timeout, challenge, application, move |rv| {
+            callback(rv.and_then(|response| Ok(register_response_to_webauthn(&response, &rp_id, &client_data)?)))
+        })
+    }
+
+    // Like `sign()`, but takes the app-id itself instead of its hash and
+    // checks that `origin` is allowed to use it.
+    pub fn sign_with_origin<K, F>(&self, timeout: u64, challenge: Vec<u8>, app_id: &str, origin: &str, key_handle: K, callback: F) -> io::Result<()>
+        where K: Into<KeyHandle>, F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        self.check_facet(app_id, origin)?;
+        self.sign(timeout, challenge, sha256(app_id.as_bytes()).to_vec(), key_handle, callback)
     }
 
     pub fn cancel(&self) -> io::Result<()> {
-        self.tx.send(QueueAction::Cancel).map_err(to_io_err)
+        self.tx.send((0, QueueAction::Cancel)).map_err(to_io_err)
+    }
+
+    // Bounds how long the queued operations take altogether, e.g. to have a
+    // batch of them done within a minute, see
+    // `U2FManagerBuilder::queue_operations()`. Once `deadline` passed, the
+    // operation in flight is cancelled, and those still queued or started
+    // later fail with `U2FError::QueueDeadline` until the deadline is moved
+    // or cleared with `None`. Each operation's own timeout still applies.
+    pub fn set_queue_deadline(&self, deadline: Option<Instant>) -> io::Result<()> {
+        let mut current = self.queue_deadline.lock().map_err(|_| io_err("failed to lock"))?;
+        *current = deadline;
+        Ok(())
+    }
+
+    // Stops the manager for good, e.g. before freeing state the callbacks
+    // use. Unlike `cancel()`, this BLOCKS: it cancels the ongoing operation,
+    // whose callback gets an `Interrupted` error, drops the ones that are
+    // still queued without calling them, and waits for the manager's threads
+    // to finish. Once it returns, no callback passed to this manager will be
+    // called anymore, and new operations fail. Must not be called from a
+    // callback, it would wait for itself.
+    pub fn shutdown(&self) {
+        self.queue.cancel_outer();
+
+        // Operations whose threads got stuck were detached, they must not
+        // call back once they're unstuck.
+        self.callbacks.close();
+    }
+
+    // Opens the device at `path` for a series of commands, bypassing the
+    // queue. Devices that support it are locked to the session until it's
+    // dropped, so that nobody else can talk to them in between.
+    pub fn open_device(&self, path: &str) -> io::Result<DeviceSession> {
+        let observer = self.shared.observer.lock().map_err(|_| io_err("failed to lock"))?.clone();
+        DeviceSession::open(path, &self.rng, observer)
+    }
+
+    // Cancels the operations of all managers in this process that use the
+    // device at `path`, e.g. to hand the device to someone else. The devices
+    // are sent a cancel request and the callbacks get an `Interrupted` error.
+    // Returns whether any operation used the device. Not supported on macOS.
+    pub fn cancel_device(path: &str) -> bool {
+        registry::cancel_device(path)
+    }
+
+    // Reports devices that pass the manager's filter as they're added or
+    // removed, starting with the ones already attached. Works independently
+    // of any ongoing operation. Dropping the stream stops watching.
+    #[cfg(feature = "futures")]
+    pub fn device_event_stream(&self) -> io::Result<DeviceEventStream> {
+        let (tx, rx) = unbounded();
+        let watcher = watch_devices(self.filter.clone(), move |event| {
+            tx.unbounded_send(event).is_ok()
+        })?;
+        Ok(DeviceEventStream::new(rx, watcher))
+    }
+
+    // Returns how many devices the ongoing operation knows about. Without
+    // one, the attached devices are counted, which may take a moment.
+    pub fn device_count(&self) -> io::Result<usize> {
+        let tracked = *self.shared.device_count.lock().map_err(|_| io_err("failed to lock"))?;
+        match tracked {
+            Some(count) => Ok(count),
+            None => count_devices(self.filter.clone())
+        }
+    }
+
+    // Lists the attached devices that pass the manager's filter. Blocks for
+    // at most a second while they're enumerated. Doesn't talk to them, so it
+    // won't get in the way of an ongoing operation. The protocol and device
+    // versions are only known for devices that operations used, as they
+    // come from the INIT response.
+    pub fn list_devices(&self) -> io::Result<Vec<DeviceInfo>> {
+        let mut devices = list_devices(self.filter.clone())?;
+        for info in devices.iter_mut() {
+            self.shared.stats.fill_in(info);
+        }
+        Ok(devices)
+    }
+
+    // Which crate version, protocol version, and platform backend this is.
+    pub fn library_info() -> LibraryInfo {
+        LibraryInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            protocol_version: U2FHID_IF_VERSION,
+            backend: platform::BACKEND
+        }
+    }
+
+    // Returns the warnings that operations left so far, oldest first, and
+    // forgets them. They don't mean that an operation failed, e.g. a sign
+    // response with a signature counter that didn't increase is still
+    // passed on as is. Only the newest ones are kept if nobody asks.
+    pub fn take_warnings(&self) -> Vec<Warning> {
+        self.shared.warnings.take()
+    }
+
+    // The prompt of the register or sign operation that was started last,
+    // `None` if it didn't have one.
+    pub fn prompt(&self) -> Option<String> {
+        self.prompt.lock().ok().and_then(|prompt| prompt.clone())
+    }
+
+    // Returns the ISO 7816-4 status word of the most recent device command
+    // that failed during the current (or last) register/sign operation.
+    pub fn last_status_word(&self) -> Option<u16> {
+        self.shared.last_status.lock().ok().and_then(|last_status| *last_status)
+    }
+
+    // How each device operations talked to fared since the manager was
+    // created, by path, or by registry entry ID on macOS: commands sent,
+    // errors, timeouts and re-INITs. A device with many errors points at bad
+    // hardware or a bad port.
+    pub fn device_stats(&self) -> Vec<(DeviceInfo, DeviceStats)> {
+        self.shared.stats.snapshot()
     }
 }
 
 impl Drop for U2FManager {
     fn drop(&mut self) {
-        self.queue.cancel();
+        self.queue.cancel_outer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueAction, U2FManager, U2FManagerBuilder, with_parsed};
+    use error::U2FError;
+    use consts::{U2FHID_MSG, U2F_REGISTER, U2F_REQUEST_USER_PRESENCE};
+    use metrics::{MetricEvent, OperationKind, Outcome};
+    use p256::{GX, GY};
+    #[cfg(target_os = "linux")]
+    use platform::testbench::{wait_until, TestBench};
+    use testdevice::{apdu, CountingRng, TestDevice};
+    use u2fprotocol::{U2FDevice, u2f_register};
+    use u2ftypes::{DeviceInfo, KeyHandle, OperationOptions, RegisterResponse, Transport};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, TryRecvError};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_facet_verifier_rejects() {
+        let mut manager = U2FManager::new().unwrap();
+        manager.set_facet_verifier(|app_id, origin| {
+            app_id == "https://example.com" && origin == "https://example.com"
+        });
+
+        let rv = manager.register_with_origin(1, vec![0u8; 32], "https://example.com", "https://evil.com", |_| {
+            panic!("the operation must not start");
+        });
+        assert_eq!(rv.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let rv = manager.sign_with_origin(1, vec![0u8; 32], "https://example.com", "https://evil.com", vec![0u8; 64], |_| {
+            panic!("the operation must not start");
+        });
+        assert_eq!(rv.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_backend() {
+        let err = U2FManagerBuilder::new().backend("carrier-pigeon").build().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("\"carrier-pigeon\" isn't compiled in"));
+
+        for backend in U2FManager::backends() {
+            assert!(U2FManagerBuilder::new().backend(backend).build().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_with_parsed() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let mut request = challenge.clone();
+        request.extend(&application);
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, &request));
+        let mut response = vec![0x05, 0x04];
+        response.extend(&GX);
+        response.extend(&GY);
+        response.extend(&[0x01, 0xaa, 0x30, 0x01, 0x07, 0x30, 0x00, 0x90, 0x00]);
+        device.add_message_read(U2FHID_MSG, &response);
+
+        let rv = u2f_register(&mut device, &challenge, &application);
+        let (raw, parsed) = with_parsed(rv, RegisterResponse::from_raw).unwrap();
+        assert_eq!(raw, response);
+        assert_eq!(parsed, RegisterResponse::from_raw(&raw).unwrap());
+        assert_eq!(parsed.key_handle, KeyHandle::from(vec![0xaa]));
+        assert_eq!(parsed.certificate, vec![0x30, 0x01, 0x07]);
+        assert_eq!(parsed.signature, vec![0x30, 0x00]);
+
+        // Errors are passed on, and so are responses that don't parse.
+        let rv = with_parsed(Err(U2FError::Timeout), RegisterResponse::from_raw);
+        match rv.unwrap_err() {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(with_parsed(Ok(vec![0x05, 0x90, 0x00]), RegisterResponse::from_raw).is_err());
+    }
+
+    #[test]
+    fn test_metrics_hook() {
+        let (tx, rx) = channel();
+        let manager = U2FManager::new().unwrap();
+        let tx = Mutex::new(tx);
+        manager.set_metrics_hook(move |event| tx.lock().unwrap().send(event).unwrap()).unwrap();
+
+        // There are no devices in the test environment.
+        manager.register(1, vec![0u8; 32], vec![0u8; 32], |rv| {
+            match rv.unwrap_err() {
+                U2FError::Timeout => {}
+                other => panic!("unexpected {:?}", other)
+            }
+        }).unwrap();
+
+        assert_eq!(rx.recv().unwrap(), MetricEvent::OperationStarted { kind: OperationKind::Register });
+        match rx.recv().unwrap() {
+            MetricEvent::OperationCompleted { kind: OperationKind::Register, outcome: Outcome::TimedOut, duration } => {
+                assert!(duration >= Duration::from_secs(1));
+            }
+            event => panic!("unexpected event {:?}", event)
+        }
+    }
+
+    #[test]
+    fn test_register_from_callback() {
+        let (tx, rx) = channel();
+        let manager = Arc::new(U2FManager::new().unwrap());
+        let manager_ = manager.clone();
+
+        // There are no devices, the first operation times out and starts
+        // another one.
+        manager.register(1, vec![0u8; 32], vec![0u8; 32], move |rv| {
+            match rv.unwrap_err() {
+                U2FError::Timeout => {}
+                other => panic!("unexpected {:?}", other)
+            }
+            manager_.register(1, vec![0u8; 32], vec![0u8; 32], move |rv| {
+                tx.send(rv).unwrap();
+            }).unwrap();
+        }).unwrap();
+
+        let err = rx.recv().unwrap().unwrap_err();
+        match err {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_prompt() {
+        let manager = U2FManager::new().unwrap();
+        assert_eq!(manager.prompt(), None);
+
+        // There are no devices, so this times out.
+        let (tx, rx) = channel();
+        let tx_ = tx.clone();
+        manager.register_with_prompt(1, vec![0u8; 32], vec![0u8; 32], Some("Authenticate to Example Corp"), move |rv| {
+            tx_.send(rv).unwrap();
+        }).unwrap();
+        assert!(rx.recv().unwrap().is_err());
+        assert_eq!(manager.prompt(), Some("Authenticate to Example Corp".to_owned()));
+
+        // Operations without one clear it.
+        manager.sign(1, vec![0u8; 32], vec![0u8; 32], vec![1, 2, 3], move |rv| {
+            tx.send(rv).unwrap();
+        }).unwrap();
+        assert!(rx.recv().unwrap().is_err());
+        assert_eq!(manager.prompt(), None);
+    }
+
+    #[test]
+    fn test_library_info() {
+        let info = U2FManager::library_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.protocol_version, 2);
+        assert!(!info.backend.is_empty());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let manager = U2FManager::new().unwrap();
+        let returned = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+
+        // There are no devices, this would time out after a second.
+        let returned_ = returned.clone();
+        manager.register(1, vec![0u8; 32], vec![0u8; 32], move |rv| {
+            tx.send((rv, returned_.load(Ordering::SeqCst))).unwrap();
+        }).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        manager.shutdown();
+        returned.store(true, Ordering::SeqCst);
+
+        // The callback was called before shutdown() returned, and it's gone
+        // now, so the channel is closed.
+        let calls: Vec<(Result<Vec<u8>, U2FError>, bool)> = rx.iter().collect();
+        assert_eq!(calls.len(), 1);
+        match calls[0].0.as_ref().unwrap_err() {
+            &U2FError::Cancelled => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(!calls[0].1);
+
+        assert!(manager.register(1, vec![0u8; 32], vec![0u8; 32], |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_stuck() {
+        let manager = U2FManager::new().unwrap();
+        let (unstick, stuck) = channel::<()>();
+        let stuck = Mutex::new(stuck);
+        let (tx, rx) = channel();
+
+        // Keeps the operation from stopping once it's cancelled, longer than
+        // shutdown() waits for it.
+        manager.set_metrics_hook(move |event| {
+            if let MetricEvent::OperationStarted { .. } = event {
+                return;
+            }
+            let _ = stuck.lock().unwrap().recv();
+        }).unwrap();
+        manager.register(0, vec![0u8; 32], vec![0u8; 32], move |rv| {
+            let _ = tx.send(rv);
+        }).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        manager.shutdown();
+
+        // The operation was detached, which called back in its stead. It
+        // doesn't call back again once it gets unstuck.
+        match rx.try_recv() {
+            Ok(Err(U2FError::Cancelled)) => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        drop(unstick);
+        thread::sleep(Duration::from_millis(200));
+        match rx.try_recv() {
+            Err(TryRecvError::Disconnected) => {}
+            Ok(rv) => panic!("called back after shutdown: {:?}", rv),
+            Err(e) => panic!("callback wasn't dropped: {:?}", e)
+        }
+        assert!(manager.register(1, vec![0u8; 32], vec![0u8; 32], |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_reject_if_busy() {
+        let manager = U2FManagerBuilder::new().reject_if_busy(true).build().unwrap();
+        let (tx, rx) = channel();
+
+        // There are no devices, the first one runs until it times out.
+        let tx_ = tx.clone();
+        manager.register(1, vec![0u8; 32], vec![0u8; 32], move |rv| {
+            tx_.send(rv).unwrap();
+        }).unwrap();
+        let err = manager.sign(1, vec![0u8; 32], vec![0u8; 32], vec![1, 2, 3], |_| {
+            panic!("the operation must not start");
+        }).unwrap_err();
+        match U2FError::from(err) {
+            U2FError::Busy => {}
+            other => panic!("unexpected {:?}", other)
+        }
+
+        // Once it's done, the next one may start.
+        match rx.recv().unwrap().unwrap_err() {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        manager.register(1, vec![0u8; 32], vec![0u8; 32], move |rv| {
+            tx.send(rv).unwrap();
+        }).unwrap();
+        match rx.recv().unwrap().unwrap_err() {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+
+        // Operations that couldn't be queued aren't in flight.
+        manager.shutdown();
+        assert!(manager.register(1, vec![0u8; 32], vec![0u8; 32], |_| {}).is_err());
+        assert_eq!(manager.current_op.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_queue_deadline() {
+        let manager = U2FManagerBuilder::new().queue_operations(true).build().unwrap();
+        let start = Instant::now();
+        manager.set_queue_deadline(Some(start + Duration::from_millis(600))).unwrap();
+        let (tx, rx) = channel();
+
+        // There are no devices, so they run until they time out, one after
+        // the other. The first one times out on its own, the second one is
+        // cancelled at the queue deadline, the rest never start.
+        for i in 0..4 {
+            let tx = tx.clone();
+            let deadline = start + Duration::from_millis(if i == 0 { 200 } else { 10000 });
+            manager.register_until(deadline, vec![0u8; 32], vec![0u8; 32], move |rv| {
+                tx.send((i, rv.unwrap_err().to_string())).unwrap();
+            }).unwrap();
+        }
+        let results: Vec<_> = (0..4).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(results, vec![
+            (0, "timed out".to_string()),
+            (1, "cancelled".to_string()),
+            (2, "queue deadline elapsed".to_string()),
+            (3, "queue deadline elapsed".to_string())
+        ]);
+        assert!(start.elapsed() >= Duration::from_millis(600));
+
+        // So do operations started later, until the deadline is cleared.
+        let tx_ = tx.clone();
+        manager.register(10, vec![0u8; 32], vec![0u8; 32], move |rv| {
+            tx_.send((4, rv.unwrap_err().to_string())).unwrap();
+        }).unwrap();
+        assert_eq!(rx.recv().unwrap(), (4, "queue deadline elapsed".to_string()));
+        manager.set_queue_deadline(None).unwrap();
+        manager.register_until(Instant::now() + Duration::from_millis(100), vec![0u8; 32], vec![0u8; 32], move |rv| {
+            tx.send((5, rv.unwrap_err().to_string())).unwrap();
+        }).unwrap();
+        assert_eq!(rx.recv().unwrap(), (5, "timed out".to_string()));
+    }
+
+    // Only uses NFC devices, which no backend finds, so that operations never
+    // find a device even if a real token is attached.
+    fn without_devices(builder: U2FManagerBuilder) -> U2FManager {
+        builder.transport(Transport::Nfc).build().unwrap()
+    }
+
+    fn register<F>(manager: &U2FManager, timeout: u64, callback: F) -> usize
+        where F: FnOnce(Result<Vec<u8>, U2FError>), F: Send + 'static
+    {
+        let options = OperationOptions::with_timeout(timeout);
+        manager.start_register(vec![0u8; 32], vec![0u8; 32], options, move |rv| callback(rv.map(|(response, _)| response))).unwrap()
+    }
+
+    #[test]
+    fn test_cancel_op() {
+        let manager = without_devices(U2FManagerBuilder::new());
+        let (tx, rx) = channel();
+
+        // There are no devices, so they run until they time out, unless
+        // they're cancelled. The second one cancels the first one.
+        let tx_ = tx.clone();
+        let first = register(&manager, 1, move |rv| tx_.send(rv).unwrap());
+        let tx_ = tx.clone();
+        let second = register(&manager, 1, move |rv| tx_.send(rv).unwrap());
+        assert!(first != second);
+        match rx.recv().unwrap().unwrap_err() {
+            U2FError::Cancelled => {}
+            other => panic!("unexpected {:?}", other)
+        }
+
+        // Cancelling the first one again leaves the second one alone.
+        manager.tx.send((0, QueueAction::CancelOp { id: first })).unwrap();
+        match rx.recv().unwrap().unwrap_err() {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+
+        let third = register(&manager, 0, move |rv| tx.send(rv).unwrap());
+        manager.tx.send((0, QueueAction::CancelOp { id: third })).unwrap();
+        match rx.recv().unwrap().unwrap_err() {
+            U2FError::Cancelled => {}
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_cancel_queued_op() {
+        let manager = without_devices(U2FManagerBuilder::new().queue_operations(true));
+        let (tx, rx) = channel();
+
+        // There are no devices, so the first one runs until it's cancelled,
+        // the second one waits for it.
+        let tx_ = tx.clone();
+        let first = register(&manager, 0, move |rv| tx_.send((1, rv)).unwrap());
+        let second = register(&manager, 0, move |rv| tx.send((2, rv)).unwrap());
+
+        // The waiting one is dropped from the queue, the one in flight keeps
+        // running until it's cancelled itself.
+        manager.tx.send((0, QueueAction::CancelOp { id: second })).unwrap();
+        match rx.recv().unwrap() {
+            (2, Err(U2FError::Cancelled)) => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+        manager.tx.send((0, QueueAction::CancelOp { id: first })).unwrap();
+        match rx.recv().unwrap() {
+            (1, Err(U2FError::Cancelled)) => {}
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_blocking() {
+        let manager = Arc::new(without_devices(U2FManagerBuilder::new()));
+
+        // There are no devices, so this blocks until the timeout.
+        let start = Instant::now();
+        let err = manager.register_blocking(1, vec![0u8; 32], vec![0u8; 32]).unwrap_err();
+        match err {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // A cancel from elsewhere ends the wait.
+        let manager_ = manager.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            manager_.cancel().unwrap();
+        });
+        let start = Instant::now();
+        match manager.sign_blocking(5, vec![0u8; 32], vec![0u8; 32], vec![1, 2, 3]).unwrap_err() {
+            U2FError::Cancelled => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+
+        // Bad input fails right away.
+        let err = manager.register_blocking(1, vec![0u8; 31], vec![0u8; 32]).unwrap_err();
+        match err {
+            U2FError::InvalidInput(e) => assert_eq!(e.to_string(), "Invalid parameter sizes"),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_register_until() {
+        let manager = U2FManager::new().unwrap();
+        let start = Instant::now();
+        let (tx, rx) = channel();
+
+        // There are no devices, so this runs until the deadline.
+        manager.register_until(start + Duration::from_millis(300), vec![0u8; 32], vec![0u8; 32], move |rv| {
+            tx.send((rv, Instant::now())).unwrap();
+        }).unwrap();
+
+        let (rv, end) = rx.recv().unwrap();
+        match rv.unwrap_err() {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(end - start >= Duration::from_millis(300));
+        assert!(end - start < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_register_with_options() {
+        let manager = U2FManager::new().unwrap();
+        let options = OperationOptions { idle_timeout: Some(Duration::from_secs(0)), ..OperationOptions::default() };
+        let err = manager.register_with_options(vec![0u8; 32], vec![0u8; 32], options, |_| {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // No devices come or go, so the idle timeout ends it long before the
+        // deadline.
+        let start = Instant::now();
+        let (tx, rx) = channel();
+        let options = OperationOptions {
+            idle_timeout: Some(Duration::from_millis(300)),
+            correlation_id: Some(String::from("op-1")),
+            ..OperationOptions::with_timeout(10)
+        };
+        manager.register_with_options(vec![0u8; 32], vec![0u8; 32], options, move |rv| {
+            tx.send((rv, Instant::now())).unwrap();
+        }).unwrap();
+
+        let (rv, end) = rx.recv().unwrap();
+        match rv.unwrap_err() {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(end - start >= Duration::from_millis(300));
+        assert!(end - start < Duration::from_secs(5));
+    }
+
+    // Registers with a device that's touched right away, on the channel 1234.
+    #[cfg(target_os = "linux")]
+    fn touched_device(challenge: &[u8], application: &[u8]) -> TestDevice {
+        let mut request = challenge.to_vec();
+        request.extend(application);
+        let mut device = TestDevice::new();
+        device.expect_init(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, &request));
+        device.add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+        device
+    }
+
+    // Uses the Linux test backend.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_refresh_devices() {
+        let bench = TestBench::new();
+        let manager = U2FManagerBuilder::new().backend("test").rng(CountingRng(0)).block_device(0x1050, 0x0120).build().unwrap();
+
+        // A blocked device tells us when the operation's monitor has looked
+        // at the devices.
+        let mut blocked = TestDevice::new();
+        blocked.info.vendor_id = Some(0x1050);
+        blocked.info.product_id = Some(0x0120);
+        bench.attach("test:0", blocked);
+
+        let (tx, rx) = channel();
+        manager.register(10, vec![0x11; 32], vec![0x22; 32], move |rv| tx.send(rv).unwrap()).unwrap();
+        wait_until(|| bench.opened() == vec!["test:0"]);
+
+        // One plugged in now is missed, until we ask for a refresh.
+        let device = bench.attach("test:1", touched_device(&[0x11; 32], &[0x22; 32]));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(bench.opened(), vec!["test:0"]);
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        manager.refresh_devices();
+        let rv = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(rv.unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+        assert!(device.lock().unwrap().expected_writes.is_empty());
+    }
+
+    // Uses the Linux test backend.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_block_device() {
+        let bench = TestBench::new();
+        let manager = U2FManagerBuilder::new()
+            .backend("test")
+            .rng(CountingRng(0))
+            .block_device(0x1050, 0x0120)
+            .block_device(0x096e, 0x0850)
+            .build().unwrap();
+
+        // The blocked model would panic on any write.
+        let mut blocked = TestDevice::new();
+        blocked.info.vendor_id = Some(0x096e);
+        blocked.info.product_id = Some(0x0850);
+        bench.attach("test:0", blocked);
+        let mut device = touched_device(&[0x11; 32], &[0x22; 32]);
+        device.info.vendor_id = Some(0x096e);
+        device.info.product_id = Some(0x0858);
+        let device = bench.attach("test:1", device);
+
+        let paths: Vec<_> = manager.list_devices().unwrap().into_iter().map(|info| info.path).collect();
+        assert_eq!(paths, vec![Some(String::from("test:1"))]);
+
+        let (tx, rx) = channel();
+        manager.register(10, vec![0x11; 32], vec![0x22; 32], move |rv| tx.send(rv).unwrap()).unwrap();
+        let rv = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(rv.unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+        assert!(device.lock().unwrap().expected_writes.is_empty());
+        assert!(bench.opened().iter().any(|path| path == "test:0"));
+    }
+
+    // Uses the Linux test backend.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_metrics_hook_attempts() {
+        let bench = TestBench::new();
+        let device = bench.attach("test:0", touched_device(&[0x11; 32], &[0x22; 32]));
+        let manager = U2FManagerBuilder::new().backend("test").rng(CountingRng(0)).build().unwrap();
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        manager.set_metrics_hook(move |event| tx.lock().unwrap().send(event).unwrap()).unwrap();
+
+        manager.register(10, vec![0x11; 32], vec![0x22; 32], |rv| {
+            assert_eq!(rv.unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+        }).unwrap();
+
+        assert_eq!(rx.recv().unwrap(), MetricEvent::OperationStarted { kind: OperationKind::Register });
+        match rx.recv().unwrap() {
+            MetricEvent::DeviceCommandAttempt { device, kind: OperationKind::Register } => {
+                assert_eq!(device.path, Some(String::from("test:0")));
+            }
+            event => panic!("unexpected event {:?}", event)
+        }
+        match rx.recv().unwrap() {
+            MetricEvent::OperationCompleted { kind: OperationKind::Register, outcome: Outcome::Success, .. } => {}
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert!(device.lock().unwrap().expected_writes.is_empty());
+    }
+
+    // Uses the Linux test backend.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_register_with_devices() {
+        let bench = TestBench::new();
+        let manager = U2FManagerBuilder::new().backend("test").rng(CountingRng(0)).build().unwrap();
+
+        // The user picked two devices, one of which was unplugged since.
+        // One that's attached but wasn't picked would panic on any write.
+        let device = bench.attach("test:0", touched_device(&[0x11; 32], &[0x22; 32]));
+        bench.attach("test:1", TestDevice::new());
+        let picked = ["test:0", "test:gone"].iter().map(|path| {
+            DeviceInfo { path: Some(path.to_string()), ..DeviceInfo::default() }
+        }).collect();
+
+        let (tx, rx) = channel();
+        manager.register_with_devices(10, vec![0x11; 32], vec![0x22; 32], picked, move |rv| tx.send(rv).unwrap()).unwrap();
+        let rv = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(rv.unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+        assert!(device.lock().unwrap().expected_writes.is_empty());
+        assert_eq!(bench.opened(), vec!["test:0"]);
     }
 }
@@ -0,0 +1,96 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use runloop::StopReason;
+use u2fprotocol::U2FDevice;
+use u2ftypes::DeviceInfo;
+
+// The operations that are reported to the metrics hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OperationKind {
+    Register,
+    Sign,
+    SignAllKeys,
+    MakeCredential,
+    GetAssertion,
+    PinStatus,
+    RequiresUv,
+    HasCredential,
+    ProbeApplications,
+    Versions,
+    SendApdu,
+    TouchTest,
+    Ping,
+    Wink
+}
+
+// How an operation ended. Operations that report a default after timing
+// out, like `has_credential()`, still count as timed out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    Success,
+    Failure,
+    Cancelled,
+    TimedOut
+}
+
+// What the metrics hook is told about. Variants and fields are only ever
+// added.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetricEvent {
+    // An operation started polling devices.
+    OperationStarted { kind: OperationKind },
+    // A device is about to be sent the commands of an operation, once per
+    // polling round.
+    DeviceCommandAttempt { device: Box<DeviceInfo>, kind: OperationKind },
+    // An operation's callback is about to be called.
+    OperationCompleted { kind: OperationKind, outcome: Outcome, duration: Duration }
+}
+
+// Gets to see every `MetricEvent`, e.g. to feed a monitoring backend. Called
+// on the thread of the operation, so it should return quickly.
+pub type MetricsHook = Arc<dyn Fn(MetricEvent) + Send + Sync>;
+
+// Reports a single operation to the hook. Does nothing if there is none,
+// events aren't even built then.
+pub struct Metrics {
+    hook: Option<MetricsHook>,
+    kind: OperationKind,
+    start: Instant
+}
+
+impl Metrics {
+    pub fn started(hook: Option<MetricsHook>, kind: OperationKind) -> Self {
+        if let Some(ref hook) = hook {
+            hook(MetricEvent::OperationStarted { kind });
+        }
+        Self { hook, kind, start: Instant::now() }
+    }
+
+    pub fn attempt<T>(&self, device: &T)
+        where T: U2FDevice
+    {
+        if let Some(ref hook) = self.hook {
+            hook(MetricEvent::DeviceCommandAttempt { device: Box::new(device.get_device_info()), kind: self.kind });
+        }
+    }
+
+    // The operation completed with `rv`.
+    pub fn completed<T>(&self, rv: &io::Result<T>) {
+        self.report(if rv.is_ok() { Outcome::Success } else { Outcome::Failure });
+    }
+
+    pub fn stopped(&self, reason: StopReason) {
+        self.report(match reason {
+            StopReason::Cancelled => Outcome::Cancelled,
+            StopReason::TimedOut => Outcome::TimedOut
+        });
+    }
+
+    fn report(&self, outcome: Outcome) {
+        if let Some(ref hook) = self.hook {
+            hook(MetricEvent::OperationCompleted { kind: self.kind, outcome, duration: self.start.elapsed() });
+        }
+    }
+}
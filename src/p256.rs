@@ -0,0 +1,354 @@
+// Just enough P-256 arithmetic to check that a point is on the curve, to
+// verify ECDSA signatures, and for ECDH with a throwaway key. Numbers are 8
+// little endian 32-bit limbs. This isn't constant time, which is fine for
+// public keys and signatures, and for private keys that are used only once.
+
+type Num = [u32; 8];
+
+// p = 2^256 - 2^224 + 2^192 + 2^96 - 1
+const P: Num = [0xffffffff, 0xffffffff, 0xffffffff, 0x00000000,
+                0x00000000, 0x00000000, 0x00000001, 0xffffffff];
+
+const B: Num = [0x27d2604b, 0x3bce3c3e, 0xcc53b0f6, 0x651d06b0,
+                0x769886bc, 0xb3ebbd55, 0xaa3a93e7, 0x5ac635d8];
+
+// The order of the group, n.
+const N: Num = [0xfc632551, 0xf3b9cac2, 0xa7179e84, 0xbce6faad,
+                0xffffffff, 0xffffffff, 0x00000000, 0xffffffff];
+
+const ZERO: Num = [0; 8];
+const ONE: Num = [1, 0, 0, 0, 0, 0, 0, 0];
+
+fn from_be(bytes: &[u8]) -> Num {
+    let mut num = [0; 8];
+    for (i, chunk) in bytes.chunks(4).rev().enumerate() {
+        num[i] = chunk.iter().fold(0, |n, b| (n << 8) | (*b as u32));
+    }
+    num
+}
+
+fn to_be(num: &Num) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (i, chunk) in bytes.chunks_mut(4).rev().enumerate() {
+        for (j, byte) in chunk.iter_mut().enumerate() {
+            *byte = (num[i] >> (24 - 8 * j)) as u8;
+        }
+    }
+    bytes
+}
+
+fn less_than(a: &Num, b: &Num) -> bool {
+    for i in (0..8).rev() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+    false
+}
+
+// a + b, and whether it overflowed.
+fn add(a: &Num, b: &Num) -> (Num, bool) {
+    let mut sum = [0; 8];
+    let mut carry = 0u64;
+    for i in 0..8 {
+        let s = a[i] as u64 + b[i] as u64 + carry;
+        sum[i] = s as u32;
+        carry = s >> 32;
+    }
+    (sum, carry != 0)
+}
+
+// a - b, and whether it underflowed.
+fn sub(a: &Num, b: &Num) -> (Num, bool) {
+    let mut diff = [0; 8];
+    let mut borrow = 0i64;
+    for i in 0..8 {
+        let d = a[i] as i64 - b[i] as i64 - borrow;
+        diff[i] = d as u32;
+        borrow = if d < 0 { 1 } else { 0 };
+    }
+    (diff, borrow != 0)
+}
+
+// All of these expect a, b < m.
+fn add_mod(a: &Num, b: &Num, m: &Num) -> Num {
+    let (sum, carry) = add(a, b);
+    if carry || !less_than(&sum, m) { sub(&sum, m).0 } else { sum }
+}
+
+fn sub_mod(a: &Num, b: &Num, m: &Num) -> Num {
+    let (diff, borrow) = sub(a, b);
+    if borrow { add(&diff, m).0 } else { diff }
+}
+
+fn mul_mod(a: &Num, b: &Num, m: &Num) -> Num {
+    let mut product = [0u32; 16];
+    for i in 0..8 {
+        let mut carry = 0u64;
+        for j in 0..8 {
+            let t = a[i] as u64 * b[j] as u64 + product[i + j] as u64 + carry;
+            product[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        product[i + 8] = carry as u32;
+    }
+
+    // Reduce bit by bit, from the top.
+    let mut rem = [0; 8];
+    for bit in (0..512).rev() {
+        let overflow = rem[7] >> 31 != 0;
+        for i in (1..8).rev() {
+            rem[i] = (rem[i] << 1) | (rem[i - 1] >> 31);
+        }
+        rem[0] = (rem[0] << 1) | ((product[bit / 32] >> (bit % 32)) & 1);
+
+        if overflow || !less_than(&rem, m) {
+            rem = sub(&rem, m).0;
+        }
+    }
+    rem
+}
+
+fn bit(a: &Num, i: usize) -> bool {
+    (a[i / 32] >> (i % 32)) & 1 != 0
+}
+
+// a^-1 = a^(m - 2) (mod m), for a prime m.
+fn inv_mod(a: &Num, m: &Num) -> Num {
+    let exp = sub(m, &[2, 0, 0, 0, 0, 0, 0, 0]).0;
+    let mut result = ONE;
+    for i in (0..256).rev() {
+        result = mul_mod(&result, &result, m);
+        if bit(&exp, i) {
+            result = mul_mod(&result, a, m);
+        }
+    }
+    result
+}
+
+// A point in Jacobian coordinates: (X / Z^2, Y / Z^3). Z = 0 is the point
+// at infinity.
+#[derive(Clone, Copy)]
+struct Point {
+    x: Num,
+    y: Num,
+    z: Num
+}
+
+const INFINITY: Point = Point { x: ONE, y: ONE, z: ZERO };
+
+impl Point {
+    fn from_affine(x: Num, y: Num) -> Self {
+        Point { x, y, z: ONE }
+    }
+
+    fn is_infinity(&self) -> bool {
+        self.z == ZERO
+    }
+
+    // The affine x coordinate.
+    fn affine_x(&self) -> Num {
+        let z_inv = inv_mod(&self.z, &P);
+        mul_mod(&self.x, &mul_mod(&z_inv, &z_inv, &P), &P)
+    }
+
+    // The affine coordinates.
+    fn affine(&self) -> (Num, Num) {
+        let z_inv = inv_mod(&self.z, &P);
+        let z_inv2 = mul_mod(&z_inv, &z_inv, &P);
+        let z_inv3 = mul_mod(&z_inv2, &z_inv, &P);
+        (mul_mod(&self.x, &z_inv2, &P), mul_mod(&self.y, &z_inv3, &P))
+    }
+
+    // dbl-2001-b, for a = -3.
+    fn double(&self) -> Self {
+        if self.is_infinity() || self.y == ZERO {
+            return INFINITY;
+        }
+
+        let delta = mul_mod(&self.z, &self.z, &P);
+        let gamma = mul_mod(&self.y, &self.y, &P);
+        let beta = mul_mod(&self.x, &gamma, &P);
+        let t = mul_mod(&sub_mod(&self.x, &delta, &P), &add_mod(&self.x, &delta, &P), &P);
+        let alpha = add_mod(&add_mod(&t, &t, &P), &t, &P);
+
+        let beta4 = add_mod(&add_mod(&beta, &beta, &P), &add_mod(&beta, &beta, &P), &P);
+        let beta8 = add_mod(&beta4, &beta4, &P);
+        let x = sub_mod(&mul_mod(&alpha, &alpha, &P), &beta8, &P);
+
+        let yz = add_mod(&self.y, &self.z, &P);
+        let z = sub_mod(&sub_mod(&mul_mod(&yz, &yz, &P), &gamma, &P), &delta, &P);
+
+        let gamma2 = mul_mod(&gamma, &gamma, &P);
+        let gamma2_4 = add_mod(&add_mod(&gamma2, &gamma2, &P), &add_mod(&gamma2, &gamma2, &P), &P);
+        let gamma2_8 = add_mod(&gamma2_4, &gamma2_4, &P);
+        let y = sub_mod(&mul_mod(&alpha, &sub_mod(&beta4, &x, &P), &P), &gamma2_8, &P);
+
+        Point { x, y, z }
+    }
+
+    fn add(&self, other: &Point) -> Self {
+        if self.is_infinity() {
+            return *other;
+        }
+        if other.is_infinity() {
+            return *self;
+        }
+
+        let z1z1 = mul_mod(&self.z, &self.z, &P);
+        let z2z2 = mul_mod(&other.z, &other.z, &P);
+        let u1 = mul_mod(&self.x, &z2z2, &P);
+        let u2 = mul_mod(&other.x, &z1z1, &P);
+        let s1 = mul_mod(&self.y, &mul_mod(&other.z, &z2z2, &P), &P);
+        let s2 = mul_mod(&other.y, &mul_mod(&self.z, &z1z1, &P), &P);
+
+        if u1 == u2 {
+            return if s1 == s2 { self.double() } else { INFINITY };
+        }
+
+        let h = sub_mod(&u2, &u1, &P);
+        let r = sub_mod(&s2, &s1, &P);
+        let hh = mul_mod(&h, &h, &P);
+        let hhh = mul_mod(&h, &hh, &P);
+        let u1hh = mul_mod(&u1, &hh, &P);
+
+        let x = sub_mod(&sub_mod(&mul_mod(&r, &r, &P), &hhh, &P), &add_mod(&u1hh, &u1hh, &P), &P);
+        let y = sub_mod(&mul_mod(&r, &sub_mod(&u1hh, &x, &P), &P), &mul_mod(&s1, &hhh, &P), &P);
+        let z = mul_mod(&h, &mul_mod(&self.z, &other.z, &P), &P);
+        Point { x, y, z }
+    }
+}
+
+// a * p + b * q, both at once.
+fn mul_add(a: &Num, p: &Point, b: &Num, q: &Point) -> Point {
+    let pq = p.add(q);
+    let mut result = INFINITY;
+    for i in (0..256).rev() {
+        result = result.double();
+        match (bit(a, i), bit(b, i)) {
+            (true, true) => result = result.add(&pq),
+            (true, false) => result = result.add(p),
+            (false, true) => result = result.add(q),
+            (false, false) => {}
+        }
+    }
+    result
+}
+
+// The private key as a number, if it's a valid one, i.e. in [1, n).
+fn private_key(k: &[u8]) -> Option<Num> {
+    if k.len() != 32 {
+        return None;
+    }
+
+    let k = from_be(k);
+    if k == ZERO || !less_than(&k, &N) {
+        return None;
+    }
+    Some(k)
+}
+
+// The public key (x, y) for the private key `k`. All numbers are big endian,
+// 32 bytes each. Returns `None` if `k` isn't a valid private key.
+pub fn public_key(k: &[u8]) -> Option<([u8; 32], [u8; 32])> {
+    let k = private_key(k)?;
+    let g = Point::from_affine(from_be(&GX), from_be(&GY));
+    let (x, y) = mul_add(&k, &g, &ZERO, &INFINITY).affine();
+    Some((to_be(&x), to_be(&y)))
+}
+
+// The x coordinate of k * (x, y), the ECDH shared secret of the private key
+// `k` and the other side's public key (x, y). Returns `None` if either key
+// isn't valid.
+pub fn ecdh(k: &[u8], x: &[u8], y: &[u8]) -> Option<[u8; 32]> {
+    let k = private_key(k)?;
+    if !is_on_curve(x, y) {
+        return None;
+    }
+
+    let q = Point::from_affine(from_be(x), from_be(y));
+    Some(to_be(&mul_add(&k, &q, &ZERO, &INFINITY).affine_x()))
+}
+
+// Whether (x, y), both big endian, satisfies y^2 = x^3 - 3x + b.
+pub fn is_on_curve(x: &[u8], y: &[u8]) -> bool {
+    if x.len() != 32 || y.len() != 32 {
+        return false;
+    }
+
+    let (x, y) = (from_be(x), from_be(y));
+    if !less_than(&x, &P) || !less_than(&y, &P) {
+        return false;
+    }
+
+    let three_x = add_mod(&add_mod(&x, &x, &P), &x, &P);
+    let rhs = add_mod(&sub_mod(&mul_mod(&mul_mod(&x, &x, &P), &x, &P), &three_x, &P), &B, &P);
+    mul_mod(&y, &y, &P) == rhs
+}
+
+// Whether (r, s) is an ECDSA signature over the SHA-256 `hash` by the public
+// key (x, y). All numbers are big endian, 32 bytes each.
+pub fn verify(x: &[u8], y: &[u8], hash: &[u8], r: &[u8], s: &[u8]) -> bool {
+    if !is_on_curve(x, y) || hash.len() != 32 || r.len() != 32 || s.len() != 32 {
+        return false;
+    }
+
+    let (r, s) = (from_be(r), from_be(s));
+    if r == ZERO || s == ZERO || !less_than(&r, &N) || !less_than(&s, &N) {
+        return false;
+    }
+
+    // The hash is as long as n, so a single subtraction reduces it.
+    let mut e = from_be(hash);
+    if !less_than(&e, &N) {
+        e = sub(&e, &N).0;
+    }
+
+    let w = inv_mod(&s, &N);
+    let u1 = mul_mod(&e, &w, &N);
+    let u2 = mul_mod(&r, &w, &N);
+
+    let g = Point::from_affine(from_be(&GX), from_be(&GY));
+    let q = Point::from_affine(from_be(x), from_be(y));
+    let point = mul_add(&u1, &g, &u2, &q);
+    if point.is_infinity() {
+        return false;
+    }
+
+    // x mod n, which is below 2n.
+    let mut v = point.affine_x();
+    if !less_than(&v, &N) {
+        v = sub(&v, &N).0;
+    }
+    v == r
+}
+
+// The curve's generator.
+pub const GX: [u8; 32] = [0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40, 0xf2,
+                          0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98, 0xc2, 0x96];
+pub const GY: [u8; 32] = [0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e, 0x16,
+                          0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf, 0x51, 0xf5];
+
+#[cfg(test)]
+mod tests {
+    use super::{from_be, is_on_curve, mul_mod, GX, GY, P};
+
+    #[test]
+    fn test_mul_mod() {
+        // (p - 1)^2 = 1 (mod p)
+        let mut minus_one = P;
+        minus_one[0] -= 1;
+        assert_eq!(mul_mod(&minus_one, &minus_one, &P), from_be(&[&[0u8; 31][..], &[1]].concat()));
+    }
+
+    #[test]
+    fn test_is_on_curve() {
+        // The generator is.
+        assert!(is_on_curve(&GX, &GY));
+
+        let mut y = GY;
+        y[31] ^= 0x01;
+        assert!(!is_on_curve(&GX, &y));
+        assert!(!is_on_curve(&GX[1..], &GY));
+    }
+}
@@ -0,0 +1,102 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use rand::os::OsRng;
+
+use statemachine::{SharedState, StateMachine};
+use u2ftypes::{DeviceFilter, KeyHandle, OperationOptions};
+use util::{OnceCallback, SharedRng};
+
+// Runs one register or sign operation at a time on this platform's devices,
+// without U2FManager's work queue. Starting one cancels the one before, the
+// callback is called on a thread of ours.
+pub struct PlatformManager {
+    // Created on first use, so that `new()` can't fail.
+    sm: Option<StateMachine>
+}
+
+impl PlatformManager {
+    pub fn new() -> Self {
+        Self { sm: None }
+    }
+
+    fn state_machine(&mut self) -> io::Result<&mut StateMachine> {
+        if self.sm.is_none() {
+            let rng: SharedRng = Arc::new(Mutex::new(Box::new(OsRng::new()?)));
+            self.sm = Some(StateMachine::new(DeviceFilter::default(), rng, SharedState::new()));
+        }
+        Ok(self.sm.as_mut().unwrap())
+    }
+
+    pub fn register<F>(&mut self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, callback: F)
+        where F: FnOnce(io::Result<Vec<u8>>), F: Send + 'static
+    {
+        // Abort any prior register/sign calls.
+        self.cancel();
+
+        let callback = OnceCallback::new(callback);
+        let cbc = callback.clone();
+        match self.state_machine() {
+            Ok(sm) => {
+                let callback = OnceCallback::new(move |rv: Result<_, _>| cbc.call(rv.map(|(response, _)| response).map_err(io::Error::from)));
+                sm.register(challenge, application, OperationOptions::with_timeout(timeout), callback);
+            }
+            Err(e) => callback.call(Err(e))
+        }
+    }
+
+    pub fn sign<F>(&mut self, timeout: u64, challenge: Vec<u8>, application: Vec<u8>, key_handle: Vec<u8>, callback: F)
+        where F: FnOnce(io::Result<Vec<u8>>), F: Send + 'static
+    {
+        // Abort any prior register/sign calls.
+        self.cancel();
+
+        let callback = OnceCallback::new(callback);
+        let cbc = callback.clone();
+        let key_handle = KeyHandle::from(key_handle);
+        if let Err(e) = key_handle.check() {
+            return callback.call(Err(e));
+        }
+        match self.state_machine() {
+            Ok(sm) => {
+                let callback = OnceCallback::new(move |rv: Result<_, _>| cbc.call(rv.map(|(response, _)| response).map_err(io::Error::from)));
+                sm.sign(challenge, application, key_handle, OperationOptions::with_timeout(timeout), callback);
+            }
+            Err(e) => callback.call(Err(e))
+        }
+    }
+
+    // This blocks.
+    pub fn cancel(&mut self) {
+        if let Some(ref mut sm) = self.sm {
+            sm.cancel();
+        }
+    }
+}
+
+impl Default for PlatformManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PlatformManager;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_platform_manager() {
+        let mut pm = PlatformManager::new();
+        let (tx, rx) = channel();
+
+        // There are no devices, so it runs until it's cancelled, by the next
+        // one or explicitly.
+        let tx_ = tx.clone();
+        pm.register(0, vec![0u8; 32], vec![0u8; 32], move |rv| tx_.send(rv.unwrap_err().to_string()).unwrap());
+        pm.sign(0, vec![0u8; 32], vec![0u8; 32], vec![0u8; 64], move |rv| tx.send(rv.unwrap_err().to_string()).unwrap());
+        assert_eq!(rx.recv().unwrap(), "cancelled");
+        pm.cancel();
+        assert_eq!(rx.recv().unwrap(), "cancelled");
+    }
+}
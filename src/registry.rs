@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Which operations use which devices, across all managers in the process.
+#[derive(Default)]
+struct Registry {
+    // Device path -> IDs of the operations using it.
+    users: HashMap<String, HashSet<usize>>,
+    // Operation ID -> devices it was asked to let go of.
+    cancelled: HashMap<usize, Vec<String>>
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(Registry::default()))
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The devices an operation uses. They're released when it's dropped.
+pub struct Claims {
+    id: usize
+}
+
+impl Claims {
+    pub fn new() -> Self {
+        Self { id: NEXT_ID.fetch_add(1, Ordering::SeqCst) }
+    }
+
+    // Claims exactly the devices at the given paths.
+    pub fn update(&self, paths: &[String]) {
+        if let Ok(mut registry) = registry().lock() {
+            for (path, users) in registry.users.iter_mut() {
+                if !paths.contains(path) {
+                    users.remove(&self.id);
+                }
+            }
+            for path in paths {
+                registry.users.entry(path.clone()).or_insert_with(HashSet::new).insert(self.id);
+            }
+            registry.users.retain(|_, users| !users.is_empty());
+        }
+    }
+
+    // Paths of the devices someone asked us to stop using, if any.
+    pub fn take_cancelled(&self) -> Vec<String> {
+        match registry().lock() {
+            Ok(mut registry) => registry.cancelled.remove(&self.id).unwrap_or_default(),
+            Err(_) => Vec::new()
+        }
+    }
+}
+
+impl Drop for Claims {
+    fn drop(&mut self) {
+        self.update(&[]);
+        if let Ok(mut registry) = registry().lock() {
+            registry.cancelled.remove(&self.id);
+        }
+    }
+}
+
+// Asks every operation that uses the device at `path` to stop. Returns
+// whether there was any.
+pub fn cancel_device(path: &str) -> bool {
+    let mut registry = match registry().lock() {
+        Ok(registry) => registry,
+        Err(_) => return false
+    };
+
+    let users: Vec<usize> = match registry.users.get(path) {
+        Some(users) => users.iter().cloned().collect(),
+        None => return false
+    };
+
+    for id in users {
+        registry.cancelled.entry(id).or_insert_with(Vec::new).push(path.to_owned());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cancel_device, Claims};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn test_cancel_device() {
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel();
+
+        // An operation in another thread uses two devices.
+        let operation = thread::spawn(move || {
+            let claims = Claims::new();
+            claims.update(&["/test/cancel0".to_owned(), "/test/cancel1".to_owned()]);
+            tx.send(()).unwrap();
+
+            done_rx.recv().unwrap();
+            assert_eq!(claims.take_cancelled(), vec!["/test/cancel1".to_owned()]);
+            assert!(claims.take_cancelled().is_empty());
+        });
+
+        rx.recv().unwrap();
+        assert!(cancel_device("/test/cancel1"));
+        done_tx.send(()).unwrap();
+        operation.join().unwrap();
+
+        // Nobody uses the device anymore.
+        assert!(!cancel_device("/test/cancel1"));
+    }
+
+    #[test]
+    fn test_update_releases() {
+        let claims = Claims::new();
+        claims.update(&["/test/update0".to_owned()]);
+        claims.update(&["/test/update1".to_owned()]);
+        assert!(!cancel_device("/test/update0"));
+        assert!(cancel_device("/test/update1"));
+        assert_eq!(claims.take_cancelled(), vec!["/test/update1".to_owned()]);
+    }
+}
@@ -1,18 +1,93 @@
 use std::io;
 use std::sync::{Arc, Mutex, Weak};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use util::{as_millis, deadline};
+
+// How long `cancel()` waits for the thread to notice, in milliseconds. Well
+// above the longest we block in a single read.
+const STOP_GRACE_PERIOD: u64 = 5000;
+
+// How often the watchdog checks whether a run loop's deadline passed, in
+// milliseconds.
+const WATCHDOG_INTERVAL: u64 = 100;
+
+// How long `cancel_outer()` waits. A thread that cancels run loops of its own
+// on the way out may take up to STOP_GRACE_PERIOD for each of them, so this
+// has to be longer for the inner ones to be detached first.
+const OUTER_GRACE_PERIOD: u64 = 2 * STOP_GRACE_PERIOD;
+
+// Why a run loop's `alive()` callback started returning false.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    Cancelled,
+    TimedOut
+}
+
+// Times out once `idle` passed without a call to `touch()`, e.g. while the
+// user doesn't plug in or remove devices, but never later than `deadline`.
+// `None` means there's no hard limit.
+pub struct IdleTimer {
+    idle: Duration,
+    deadline: Option<Instant>,
+    last: Mutex<Instant>
+}
+
+impl IdleTimer {
+    pub fn new(idle: Duration, deadline: Option<Instant>) -> Self {
+        Self { idle, deadline, last: Mutex::new(Instant::now()) }
+    }
+
+    // Starts the idle clock over.
+    pub fn touch(&self) {
+        if let Ok(mut last) = self.last.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        if self.deadline.is_some_and(|deadline| now >= deadline) {
+            return true;
+        }
+        self.last.lock().map(|last| now >= *last + self.idle).unwrap_or(true)
+    }
+}
+
+// Called once a thread is detached, with the reason it should have stopped.
+pub type OnDetach = Box<dyn Fn(StopReason) + Send>;
 
 struct Canary {
     alive: AtomicBool,
-    thread: Mutex<Option<JoinHandle<()>>>
+    timed_out: AtomicBool,
+    detached: AtomicBool,
+    // The thread, and a channel that disconnects once it's done.
+    thread: Mutex<Option<(JoinHandle<()>, Receiver<()>)>>,
+    on_detach: Mutex<Option<OnDetach>>
 }
 
 impl Canary {
     fn new() -> Self {
-        Self { alive: AtomicBool::new(true), thread: Mutex::new(None) }
+        Self {
+            alive: AtomicBool::new(true),
+            timed_out: AtomicBool::new(false),
+            detached: AtomicBool::new(false),
+            thread: Mutex::new(None),
+            on_detach: Mutex::new(None)
+        }
+    }
+
+    // Gives up on the thread, which exits once it's unstuck. What it holds
+    // is let go of by the `on_detach` callback, if there is one.
+    fn detach(&self, reason: StopReason) {
+        self.detached.store(true, Ordering::Relaxed);
+        let on_detach = self.on_detach.lock().ok().and_then(|mut on_detach| on_detach.take());
+        if let Some(on_detach) = on_detach {
+            on_detach(reason);
+        }
     }
 }
 
@@ -22,41 +97,164 @@ pub struct RunLoop {
 
 impl RunLoop {
     pub fn new<F,T>(fun: F, timeout: u64) -> io::Result<Self>
-        where F: FnOnce(&Fn() -> bool) -> T, F: Send + 'static
+        where F: FnOnce(&dyn Fn() -> bool) -> T, F: Send + 'static
+    {
+        Self::new_with_stop_reason(move |alive: &dyn Fn() -> bool, _: &dyn Fn() -> StopReason| {
+            fun(alive)
+        }, timeout)
+    }
+
+    // Like `new()`, but also passes a callback that tells, once `alive()`
+    // returned false, whether we were cancelled or timed out.
+    pub fn new_with_stop_reason<F,T>(fun: F, timeout: u64) -> io::Result<Self>
+        where F: FnOnce(&dyn Fn() -> bool, &dyn Fn() -> StopReason) -> T, F: Send + 'static
+    {
+        Self::new_with_deadline(fun, deadline(timeout))
+    }
+
+    // Like `new_with_stop_reason()`, but times out at the given point in
+    // time instead of after a number of seconds. `None` means never.
+    pub fn new_with_deadline<F,T>(fun: F, deadline: Option<Instant>) -> io::Result<Self>
+        where F: FnOnce(&dyn Fn() -> bool, &dyn Fn() -> StopReason) -> T, F: Send + 'static
+    {
+        match deadline {
+            Some(deadline) => Self::spawn(fun, Some(move |now| now >= deadline)),
+            None => Self::spawn(fun, None::<fn(Instant) -> bool>)
+        }
+    }
+
+    // Like `new_with_deadline()`, but times out when `timer` says so.
+    pub fn new_with_idle_timer<F,T>(fun: F, timer: Arc<IdleTimer>) -> io::Result<Self>
+        where F: FnOnce(&dyn Fn() -> bool, &dyn Fn() -> StopReason) -> T, F: Send + 'static
+    {
+        Self::spawn(fun, Some(move |now| timer.expired(now)))
+    }
+
+    // Runs `fun` on a new thread. If it may time out, i.e. `expired` is
+    // given, a watchdog detaches the thread should it not stop within a
+    // grace period after it timed out.
+    fn spawn<F,T,E>(fun: F, expired: Option<E>) -> io::Result<Self>
+        where F: FnOnce(&dyn Fn() -> bool, &dyn Fn() -> StopReason) -> T, F: Send + 'static,
+              E: Fn(Instant) -> bool, E: Send + Sync + 'static
     {
         let flag = Arc::new(Canary::new());
         let flag_ = flag.clone();
+        let (done, finished) = channel::<()>();
+        let (watched, watch) = channel::<()>();
+        let expired = expired.map(Arc::new);
+        let expired_ = expired.clone();
 
-        // Spawn the run loop thread.
-        let thread = thread::Builder::new().spawn(move || {
-            let start = Instant::now();
+        // Spawn the run loop thread. Name it so that it can be told apart
+        // in debuggers and crash reports.
+        let thread = thread::Builder::new().name("u2f-runloop".into()).spawn(move || {
+            // Dropped when we return, or panic.
+            let _done = (done, watched);
 
             // A callback to determine whether the thread should terminate.
             let still_alive = || {
                 // `flag.alive` will be false after cancel() was called.
-                flag.alive.load(Ordering::Relaxed) &&
-                // If a timeout was provided, we'll check that too.
-                (timeout == 0 || start.elapsed().as_secs() < timeout)
+                if !flag.alive.load(Ordering::Relaxed) {
+                    return false;
+                }
+
+                // If a deadline was provided, we'll check that too.
+                if expired_.as_ref().is_some_and(|expired| expired(Instant::now())) {
+                    flag.timed_out.store(true, Ordering::Relaxed);
+                    return false;
+                }
+
+                true
+            };
+
+            let stop_reason = || {
+                if flag.timed_out.load(Ordering::Relaxed) {
+                    StopReason::TimedOut
+                } else {
+                    StopReason::Cancelled
+                }
             };
 
             // Ignore errors.
-            let _ = fun(&still_alive);
+            let _ = fun(&still_alive, &stop_reason);
         })?;
 
         // We really should never fail to lock here.
-        let mut guard = (*flag_).thread.lock().map_err(|_| {
-            io::Error::new(io::ErrorKind::Other, "failed to lock")
+        let mut guard = flag_.thread.lock().map_err(|_| {
+            io::Error::other("failed to lock")
         })?;
 
         // Store the thread handle so we can join later.
-        *guard = Some(thread);
+        *guard = Some((thread, finished));
+        drop(guard);
 
-        Ok(Self { flag: Arc::downgrade(&flag_) })
+        let rloop = Self { flag: Arc::downgrade(&flag_) };
+        if let Some(expired) = expired {
+            rloop.watch(watch, expired)?;
+        }
+        Ok(rloop)
+    }
+
+    // Detaches the thread if it didn't stop within STOP_GRACE_PERIOD after
+    // `expired` said it timed out, like `cancel()` would.
+    fn watch<E>(&self, watch: Receiver<()>, expired: Arc<E>) -> io::Result<()>
+        where E: Fn(Instant) -> bool, E: Send + Sync + 'static
+    {
+        let flag = self.flag.clone();
+        let grace = Duration::from_millis(STOP_GRACE_PERIOD);
+        thread::Builder::new().name("u2f-watchdog".into()).spawn(move || {
+            let mut expired_at = None;
+            while watch.recv_timeout(Duration::from_millis(WATCHDOG_INTERVAL)) == Err(RecvTimeoutError::Timeout) {
+                let now = Instant::now();
+                if expired_at.is_none() && expired(now) {
+                    expired_at = Some(now);
+                }
+                if expired_at.is_some_and(|expired_at| now >= expired_at + grace) {
+                    if let Some(flag) = flag.upgrade() {
+                        // Unless `cancel()` took it to wait for the thread.
+                        if let Ok(Some(_)) = flag.thread.lock().map(|mut guard| guard.take()) {
+                            error!("run loop thread didn't stop within {}ms after timing out, detaching it", as_millis(grace));
+                            flag.detach(StopReason::TimedOut);
+                        }
+                    }
+                    return;
+                }
+            }
+        })?;
+        Ok(())
+    }
+
+    // Lets go of what the thread holds, should it be detached, e.g. the
+    // devices of an operation.
+    pub fn on_detach(&self, on_detach: OnDetach) {
+        if let Some(flag) = self.flag.upgrade() {
+            if let Ok(mut guard) = flag.on_detach.lock() {
+                *guard = Some(on_detach);
+            }
+        }
+    }
+
+    // Whether the thread returned, e.g. because its operation called back,
+    // or was detached.
+    pub fn finished(&self) -> bool {
+        self.flag.upgrade().is_none_or(|flag| flag.detached.load(Ordering::Relaxed))
     }
 
     // Cancels the run loop and waits for the thread to terminate.
     // This is a potentially BLOCKING operation.
     pub fn cancel(&self) {
+        self.cancel_within(Duration::from_millis(STOP_GRACE_PERIOD));
+    }
+
+    // Like `cancel()`, for run loops that cancel other run loops before they
+    // return, e.g. the manager's work queue.
+    pub fn cancel_outer(&self) {
+        self.cancel_within(Duration::from_millis(OUTER_GRACE_PERIOD));
+    }
+
+    // Like `cancel()`, but waits at most `grace`. A thread that's stuck,
+    // e.g. in a read that a driver bug keeps from timing out, is detached
+    // then. It exits once it's unstuck.
+    fn cancel_within(&self, grace: Duration) {
         // If thread still exists...
         if let Some(flag) = self.flag.upgrade() {
             // ...let the run loop terminate.
@@ -65,7 +263,13 @@ impl RunLoop {
             // Locking should never fail here either.
             if let Ok(mut guard) = flag.thread.lock() {
                 // This really can't fail.
-                if let Some(handle) = (*guard).take() {
+                if let Some((handle, finished)) = (*guard).take() {
+                    if finished.recv_timeout(grace) == Err(RecvTimeoutError::Timeout) {
+                        error!("run loop thread didn't stop within {}ms, detaching it", as_millis(grace));
+                        flag.detach(StopReason::Cancelled);
+                        return;
+                    }
+
                     // This might fail, ignore.
                     let _ = handle.join();
                 }
@@ -73,3 +277,112 @@ impl RunLoop {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{IdleTimer, RunLoop, StopReason, STOP_GRACE_PERIOD};
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_cancel_stuck() {
+        // Blocks in recv() like in a read that never returns, until the
+        // sender goes away at the end of the test.
+        let (tx, rx) = channel::<()>();
+        let rloop = RunLoop::new(move |_| {
+            let _ = rx.recv();
+        }, 0).unwrap();
+        let (detached_tx, detached) = channel();
+        rloop.on_detach(Box::new(move |reason| detached_tx.send(reason).unwrap()));
+
+        let start = Instant::now();
+        rloop.cancel();
+        assert!(start.elapsed() < Duration::from_millis(STOP_GRACE_PERIOD + 1000));
+        assert_eq!(detached.try_recv(), Ok(StopReason::Cancelled));
+        assert!(rloop.finished());
+        drop(tx);
+
+        // Threads that stop in time are joined, and keep what they hold.
+        let rloop = RunLoop::new(|alive| {
+            while alive() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }, 0).unwrap();
+        let (detached_tx, detached) = channel();
+        rloop.on_detach(Box::new(move |reason| detached_tx.send(reason).unwrap()));
+        rloop.cancel();
+        assert!(rloop.flag.upgrade().is_none());
+        assert!(detached.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_detach_at_deadline() {
+        // Stuck like above, past its deadline.
+        let (tx, rx) = channel::<()>();
+        let start = Instant::now();
+        let rloop = RunLoop::new_with_deadline(move |_, _| {
+            let _ = rx.recv();
+        }, Some(start + Duration::from_millis(100))).unwrap();
+        let (detached_tx, detached) = channel();
+        rloop.on_detach(Box::new(move |reason| detached_tx.send(reason).unwrap()));
+
+        // Nobody cancels it, the watchdog gives up on it on its own.
+        let grace = Duration::from_millis(STOP_GRACE_PERIOD);
+        assert_eq!(detached.recv_timeout(grace * 2), Ok(StopReason::TimedOut));
+        assert!(start.elapsed() >= grace);
+        assert!(rloop.finished());
+
+        // There's nothing left for cancel() to wait for.
+        let start = Instant::now();
+        rloop.cancel();
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        drop(tx);
+    }
+
+    #[test]
+    fn test_thread_name() {
+        let (tx, rx) = channel();
+        let rloop = RunLoop::new(move |_| {
+            tx.send(thread::current().name().map(String::from)).unwrap();
+        }, 0).unwrap();
+
+        assert_eq!(rx.recv().unwrap(), Some("u2f-runloop".to_string()));
+        rloop.cancel();
+    }
+
+    #[test]
+    fn test_idle_timer() {
+        let start = Instant::now();
+        let timer = Arc::new(IdleTimer::new(Duration::from_millis(100), Some(start + Duration::from_millis(600))));
+        let timer_ = timer.clone();
+        let (tx, rx) = channel();
+
+        // Device events keep coming in, so only the hard limit stops us.
+        let rloop = RunLoop::new_with_idle_timer(move |alive, stop_reason| {
+            while alive() {
+                timer_.touch();
+                thread::sleep(Duration::from_millis(20));
+            }
+            tx.send(stop_reason()).unwrap();
+        }, timer).unwrap();
+        assert_eq!(rx.recv().unwrap(), StopReason::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        rloop.cancel();
+
+        // Without them the idle timeout is reached long before.
+        let start = Instant::now();
+        let timer = Arc::new(IdleTimer::new(Duration::from_millis(100), Some(start + Duration::from_secs(10))));
+        let (tx, rx) = channel();
+        let rloop = RunLoop::new_with_idle_timer(move |alive, stop_reason| {
+            while alive() {
+                thread::sleep(Duration::from_millis(20));
+            }
+            tx.send(stop_reason()).unwrap();
+        }, timer).unwrap();
+        assert_eq!(rx.recv().unwrap(), StopReason::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        rloop.cancel();
+    }
+}
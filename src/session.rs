@@ -0,0 +1,163 @@
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use consts::{CAPFLAG_LOCK, SW_CONDITIONS_NOT_SATISFIED};
+use error::U2FError;
+use platform::device::{self, Device};
+use u2fprotocol::{U2FDevice, u2f_init_device, u2f_lock, u2f_register, u2f_send_apdu, u2f_sign, u2f_wink};
+use u2ftypes::{FrameObserver, KeyHandle};
+use util::{io_err, SharedRng};
+
+// How long to lock the device for, in seconds. That's the maximum, the lock
+// is renewed with every command.
+const LOCK_TIMEOUT: u8 = 10;
+
+// A device that's kept open across several commands. If the device supports
+// it, other channels are locked out until the session is dropped.
+pub struct DeviceSession<T: U2FDevice + Read + Write = Device> {
+    device: T,
+    locked: bool
+}
+
+impl DeviceSession<Device> {
+    // Opens the device at `path`.
+    pub fn open(path: &str, rng: &SharedRng, observer: Option<FrameObserver>) -> io::Result<Self> {
+        let mut device = device::open(path)?;
+        device.set_frame_observer(observer);
+        Self::new(device, rng)
+    }
+}
+
+impl<T: U2FDevice + Read + Write> DeviceSession<T> {
+    fn new(mut device: T, rng: &SharedRng) -> io::Result<Self> {
+        {
+            let mut rng = rng.lock().map_err(|_| io_err("failed to lock"))?;
+            u2f_init_device(&mut device, &mut **rng)?;
+        }
+
+        let locked = device.get_device_info().capabilities & CAPFLAG_LOCK != 0;
+        let mut session = Self { device, locked };
+        session.renew_lock()?;
+        Ok(session)
+    }
+
+    fn renew_lock(&mut self) -> io::Result<()> {
+        if self.locked {
+            u2f_lock(&mut self.device, LOCK_TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    // Runs `command` until the user touches the device, or we time out.
+    fn until_present<F, R>(&mut self, timeout: u64, command: F) -> Result<R, U2FError>
+        where F: Fn(&mut T) -> Result<R, U2FError>
+    {
+        let start = Instant::now();
+        let sw_conditions_not_satisfied = (SW_CONDITIONS_NOT_SATISFIED[0] as u16) << 8 |
+                                          SW_CONDITIONS_NOT_SATISFIED[1] as u16;
+
+        loop {
+            self.renew_lock()?;
+
+            match command(&mut self.device) {
+                Err(U2FError::ApduStatus(sw)) if sw == sw_conditions_not_satisfied => {}
+                rv => return rv
+            }
+
+            if start.elapsed().as_secs() >= timeout {
+                return Err(U2FError::Timeout);
+            }
+
+            // Wait a little before trying again.
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    // Like `U2FManager::register()`, but blocks until the user touched the
+    // device.
+    pub fn register(&mut self, timeout: u64, challenge: &[u8], application: &[u8]) -> Result<Vec<u8>, U2FError> {
+        self.until_present(timeout, |device| u2f_register(device, challenge, application))
+    }
+
+    // Like `U2FManager::sign()`, but blocks until the user touched the
+    // device.
+    pub fn sign(&mut self, timeout: u64, challenge: &[u8], application: &[u8], key_handle: &KeyHandle) -> Result<Vec<u8>, U2FError> {
+        self.until_present(timeout, |device| u2f_sign(device, challenge, application, key_handle))
+    }
+
+    // Sends an APDU, e.g. a vendor command, and returns the response data.
+    pub fn send_apdu(&mut self, ins: u8, p1: u8, data: &[u8]) -> Result<Vec<u8>, U2FError> {
+        self.renew_lock()?;
+        u2f_send_apdu(&mut self.device, ins, p1, data)
+    }
+
+    // Makes the device identify itself, e.g. by blinking.
+    pub fn wink(&mut self) -> io::Result<()> {
+        self.renew_lock()?;
+        u2f_wink(&mut self.device)
+    }
+}
+
+impl<T: U2FDevice + Read + Write> Drop for DeviceSession<T> {
+    fn drop(&mut self) {
+        // Let other channels in again. Ignore errors, the device might be
+        // gone already.
+        if self.locked {
+            let _ = u2f_lock(&mut self.device, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceSession;
+    use consts::{CID_BROADCAST, U2FHID_INIT, U2FHID_LOCK, U2FHID_MSG, U2FHID_PING, U2FHID_WINK, U2F_VERSION};
+    use std::sync::{Arc, Mutex};
+    use testdevice::{apdu, CountingRng, TestDevice};
+    use u2fprotocol::U2FDevice;
+    use util::SharedRng;
+
+    #[test]
+    fn test_session() {
+        let cid = [0x00, 0x03, 0x00, 0x14];
+        let mut device = TestDevice::new();
+
+        // INIT, with CAPFLAG_WINK and CAPFLAG_LOCK, again on the new channel, then
+        // PING and VERSION.
+        device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x03]);
+        device.set_cid(&cid);
+        device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x03]);
+        device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+
+        // Lock, then WINK and a vendor APDU. Every command renews the lock.
+        let lock = |device: &mut TestDevice| {
+            device.add_message_write(U2FHID_LOCK, &[10]);
+            device.add_message_read(U2FHID_LOCK, &[]);
+        };
+        lock(&mut device);
+        lock(&mut device);
+        device.add_message_write(U2FHID_WINK, &[]);
+        device.add_message_read(U2FHID_WINK, &[]);
+        lock(&mut device);
+        device.add_message_write(U2FHID_MSG, &apdu(0x40, 0x01, &[0xaa]));
+        device.add_message_read(U2FHID_MSG, &[0xbb, 0xcc, 0x90, 0x00]);
+
+        // Dropping the session releases the lock.
+        device.add_message_write(U2FHID_LOCK, &[0]);
+        device.add_message_read(U2FHID_LOCK, &[]);
+        device.set_cid(&CID_BROADCAST);
+
+        let rng: SharedRng = Arc::new(Mutex::new(Box::new(CountingRng(0))));
+        let mut session = DeviceSession::new(device, &rng).unwrap();
+        session.wink().unwrap();
+        assert_eq!(session.send_apdu(0x40, 0x01, &[0xaa]).unwrap(), vec![0xbb, 0xcc]);
+        assert_eq!(session.device.expected_writes.len(), 1);
+    }
+}
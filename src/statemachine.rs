@@ -0,0 +1,2406 @@
+use std::io;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use counter::{CounterCheck, SignCounters};
+use consts::{CAPFLAG_NMSG, CID_BROADCAST, CTAP2_ERR_NO_CREDENTIALS, CTAP2_ERR_USER_ACTION_TIMEOUT, PARAMETER_SIZE, SW_CONDITIONS_NOT_SATISFIED};
+use error::{cancelled, timed_out, U2FError};
+use hmacsecret::SharedSecret;
+use log;
+use platform::device::{is_disconnect_error, Releaser};
+use platform::devicemap::DeviceMap;
+use platform::monitor::{Event, Monitor};
+use metrics::{Metrics, MetricsHook, OperationKind};
+use registry::Claims;
+use runloop::{IdleTimer, RunLoop, StopReason};
+use stats::{DeviceStatsMap, record_reinit};
+use u2fprotocol::{U2FDevice, ctap2_get_assertion, ctap2_get_info, ctap2_make_credential, ctap2_pin_status, ctap2_shared_secret, ctap2_status, ctap2_requires_uv, status_word, u2f_cancel, u2f_init_channel, u2f_is_keyhandle_valid, u2f_ping, u2f_register, u2f_send_apdu_with_status, u2f_sign, u2f_version, u2f_wink, is_wink_not_supported};
+#[cfg(feature = "futures")]
+use u2ftypes::DeviceEvent;
+use u2ftypes::{Assertion, AssertionOptions, AttestationObject, CredentialRequest, DeviceFilter, DeviceInfo, FrameObserver, KeyHandle, MakeCredentialOptions, OperationContext, OperationOptions, PinStatus, ReadProgress, RelyingParty, SelectionPolicy, SignProgress, User};
+use util::{as_millis, deadline, io_err, to_hex, to_io_err, OnceCallback, SharedRng};
+use warnings::{Warning, Warnings};
+
+// Monitor events handled per polling round, by default.
+const MAX_EVENTS_PER_POLL: usize = 16;
+
+// How long has_credential() gives devices to show up, in seconds.
+const CHECK_TIMEOUT: u64 = 1;
+
+// Time between two polling rounds, in milliseconds.
+const POLL_INTERVAL: u64 = 100;
+
+// Time between two polling rounds while paused, in milliseconds.
+const PAUSED_INTERVAL: u64 = 500;
+
+// How often a device may fail to allocate a channel before an operation
+// gives up on it.
+const MAX_INIT_FAILURES: u32 = 3;
+
+// How far the wall clock may run ahead of the monotonic clock between two
+// polling rounds before we assume the system was suspended, in seconds.
+const RESUME_THRESHOLD: u64 = 2;
+
+// How often to log a polling round in which devices keep waiting for user
+// presence, in milliseconds.
+const WAITING_LOG_INTERVAL: u64 = 1000;
+
+// Drives register/sign operations. Spawns a run loop per operation that adds
+// and removes devices as the platform's monitor reports them and polls all
+// known devices until one of them completes the operation.
+pub struct StateMachine {
+    // Handle to the thread loop.
+    thread: Option<RunLoop>,
+    // Which devices to use.
+    filter: DeviceFilter,
+    // Source for INIT nonces and PING payloads.
+    rng: SharedRng,
+    shared: SharedState,
+    // The highest signature counters seen, across operations.
+    counters: Arc<Mutex<SignCounters>>
+}
+
+// What the manager shares with its state machine: settings the caller may
+// change while operations run, and what operations leave for the caller.
+#[derive(Clone)]
+pub struct SharedState {
+    // Status word of the most recent failed device command.
+    pub last_status: Arc<Mutex<Option<u16>>>,
+    // How many monitor events to handle before polling devices again.
+    pub max_events: Arc<AtomicUsize>,
+    // How many devices to command per round, the newest ones first.
+    pub max_devices: Arc<AtomicUsize>,
+    // Set when the caller asks for devices to be enumerated again.
+    pub refresh: Arc<AtomicBool>,
+    // Set while the caller doesn't want devices to be polled.
+    pub paused: Arc<AtomicBool>,
+    // Set when the caller wants the current polling round to start over.
+    pub interrupt: Arc<AtomicBool>,
+    // How many devices the ongoing operation knows about, if any.
+    pub device_count: Arc<Mutex<Option<usize>>>,
+    // Sees all frames exchanged with devices, if set.
+    pub observer: Arc<Mutex<Option<FrameObserver>>>,
+    // Where operations leave warnings for the caller.
+    pub warnings: Warnings,
+    // Gets to see metric events, if set.
+    pub metrics: Arc<Mutex<Option<MetricsHook>>>,
+    // Told about the progress of long responses, if set.
+    pub progress: Arc<Mutex<Option<ReadProgress>>>,
+    // How each device fared, across operations.
+    pub stats: DeviceStatsMap,
+    // How long devices get to settle after INIT, the default if `None`. Set
+    // when the manager is built.
+    pub init_settle_delay: Option<Duration>
+}
+
+impl SharedState {
+    pub fn new() -> Self {
+        Self {
+            last_status: Arc::new(Mutex::new(None)),
+            max_events: Arc::new(AtomicUsize::new(MAX_EVENTS_PER_POLL)),
+            max_devices: Arc::new(AtomicUsize::new(usize::MAX)),
+            refresh: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            device_count: Arc::new(Mutex::new(None)),
+            observer: Arc::new(Mutex::new(None)),
+            warnings: Warnings::new(),
+            metrics: Arc::new(Mutex::new(None)),
+            progress: Arc::new(Mutex::new(None)),
+            stats: DeviceStatsMap::new(),
+            init_settle_delay: None
+        }
+    }
+}
+
+impl StateMachine {
+    pub fn new(filter: DeviceFilter, rng: SharedRng, shared: SharedState) -> Self {
+        let counters = Arc::new(Mutex::new(SignCounters::new()));
+        Self { thread: None, filter, rng, shared, counters }
+    }
+
+    // What the devices of an operation that starts now get to know.
+    fn context(&self, correlation_id: Option<String>) -> OperationContext {
+        OperationContext {
+            correlation_id,
+            read_progress: self.shared.progress.lock().ok().and_then(|progress| progress.clone()),
+            init_settle_delay: self.shared.init_settle_delay,
+            stats: Some(self.shared.stats.clone()),
+            quiet_round: None
+        }
+    }
+
+    // Runs as `options` say, see `run_with_options()`.
+    pub fn register(&mut self, challenge: Vec<u8>, application: Vec<u8>, options: OperationOptions, callback: OnceCallback<(Vec<u8>, DeviceInfo), U2FError>)
+    {
+        let last_status = self.shared.last_status.clone();
+        let gate = U2fGate::new(&self.filter);
+        self.run_with_options(OperationKind::Register, options, callback.for_io(), move |device| {
+            if !gate.allows(device) {
+                return None;
+            }
+            try_register(device, &challenge, &application, &last_status).map(|rv| with_device(device, rv))
+        }, stopped);
+    }
+
+    // Warns if the signature counter didn't increase. Runs as `options`
+    // say, like `register()`.
+    pub fn sign(&mut self, challenge: Vec<u8>, application: Vec<u8>, key_handle: KeyHandle, options: OperationOptions, callback: OnceCallback<(Vec<u8>, DeviceInfo), U2FError>)
+    {
+        let last_status = self.shared.last_status.clone();
+        let warnings = self.shared.warnings.clone();
+        let counters = self.counters.clone();
+        let gate = U2fGate::new(&self.filter);
+        self.run_with_options(OperationKind::Sign, options, callback.for_io(), move |device| {
+            if !gate.allows(device) {
+                return None;
+            }
+            let rv = try_sign(device, &challenge, &application, &key_handle, &last_status);
+            if let Some(Ok(ref response)) = rv {
+                check_counter(device, &key_handle, response, &counters, &warnings);
+            }
+            rv.map(|rv| with_device(device, rv))
+        }, stopped);
+    }
+
+    // Signs with each of the key handles, on whichever device owns it, one
+    // touch at a time. Completes once all of them were signed with. If we
+    // time out first, reports the signatures we got so far instead.
+    pub fn sign_all_keys(&mut self, deadline: Option<Instant>, challenge: Vec<u8>, application: Vec<u8>, key_handles: Vec<KeyHandle>, progress: SignProgress, callback: OnceCallback<Vec<Option<Vec<u8>>>>)
+    {
+        let last_status = self.shared.last_status.clone();
+        let total = key_handles.len();
+        let signed = Arc::new(Mutex::new(vec![None; total]));
+        let signed_ = signed.clone();
+        progress(0, total);
+
+        let gate = U2fGate::new(&self.filter);
+        self.run(OperationKind::SignAllKeys, deadline, callback, move |device| {
+            if !gate.allows(device) {
+                return None;
+            }
+            let mut signed = match signed.lock() {
+                Ok(signed) => signed,
+                Err(_) => return Some(Err(io_err("failed to lock")))
+            };
+            if try_sign_remaining(device, &challenge, &application, &key_handles, &mut signed, &last_status) {
+                let done = signed.iter().filter(|sig| sig.is_some()).count();
+                progress(done, total);
+                if done == total {
+                    return Some(Ok(signed.clone()));
+                }
+            }
+            None
+        }, move |reason| {
+            match reason {
+                StopReason::TimedOut => signed_.lock().map(|signed| signed.clone()).map_err(|_| io_err("failed to lock")),
+                StopReason::Cancelled => stopped(reason)
+            }
+        });
+    }
+
+    // Creates a credential on the first FIDO2 token the user touches. U2F-only
+    // devices are left alone.
+    pub fn make_credential(&mut self, timeout: u64, request: CredentialRequest, callback: OnceCallback<AttestationObject>)
+    {
+        let last_status = self.shared.last_status.clone();
+        let CredentialRequest { client_data_hash, rp, user, algorithms, options } = request;
+        self.run(OperationKind::MakeCredential, deadline(timeout), callback, move |device| {
+            try_make_credential(device, &client_data_hash, &rp, &user, &algorithms, options, &last_status)
+        }, stopped);
+    }
+
+    // Gets an assertion from the first FIDO2 token that has a matching
+    // credential. U2F-only devices are left alone.
+    pub fn get_assertion(&mut self, timeout: u64, rp_id: String, client_data_hash: Vec<u8>, allow_list: Vec<Vec<u8>>, options: AssertionOptions, callback: OnceCallback<Assertion>)
+    {
+        let last_status = self.shared.last_status.clone();
+        let secrets = SharedSecrets::new(self.rng.clone());
+        self.run(OperationKind::GetAssertion, deadline(timeout), callback, move |device| {
+            try_get_assertion(device, &rp_id, &client_data_hash, &allow_list, options, &secrets, &last_status)
+        }, stopped);
+    }
+
+    // Reports the PIN status of the first FIDO2 token that answers. U2F-only
+    // devices are left alone, we only fail if no token answers in time.
+    pub fn pin_status(&mut self, timeout: u64, callback: OnceCallback<PinStatus>)
+    {
+        let last_status = self.shared.last_status.clone();
+        self.run(OperationKind::PinStatus, deadline(timeout), callback, move |device| {
+            try_pin_status(device, &last_status)
+        }, stopped);
+    }
+
+    // Reports whether any attached device will require user verification.
+    // Devices get a moment to show up first.
+    pub fn requires_uv(&mut self, callback: OnceCallback<bool>)
+    {
+        self.run(OperationKind::RequiresUv, deadline(CHECK_TIMEOUT), callback, |device| {
+            match ctap2_requires_uv(device) {
+                Ok(true) => Some(Ok(true)),
+                // Devices that can't tell us don't count.
+                _ => None
+            }
+        }, |reason| {
+            match reason {
+                StopReason::TimedOut => Ok(false),
+                StopReason::Cancelled => stopped(reason)
+            }
+        });
+    }
+
+    // Reports whether any attached device owns the key handle, without
+    // waiting for user presence. Devices get a moment to show up first.
+    pub fn has_credential(&mut self, application: Vec<u8>, key_handle: KeyHandle, callback: OnceCallback<bool>)
+    {
+        let last_status = self.shared.last_status.clone();
+        self.run(OperationKind::HasCredential, deadline(CHECK_TIMEOUT), callback, move |device| {
+            try_check_credential(device, &application, &key_handle, &last_status)
+        }, |reason| {
+            match reason {
+                StopReason::TimedOut => Ok(false),
+                StopReason::Cancelled => stopped(reason)
+            }
+        });
+    }
+
+    // Reports which of the applications the key handle was registered
+    // under, as an index into `applications`, according to the first device
+    // that knows the key handle. Doesn't wait for user presence.
+    pub fn probe_applications(&mut self, key_handle: KeyHandle, applications: Vec<[u8; PARAMETER_SIZE]>, callback: OnceCallback<Option<usize>>)
+    {
+        let last_status = self.shared.last_status.clone();
+        self.run(OperationKind::ProbeApplications, deadline(CHECK_TIMEOUT), callback, move |device| {
+            try_probe_applications(device, &key_handle, &applications, &last_status)
+        }, |reason| {
+            match reason {
+                StopReason::TimedOut => Ok(None),
+                StopReason::Cancelled => stopped(reason)
+            }
+        });
+    }
+
+    // Sends an APDU to the first device that answers it, and reports the
+    // response data and the status word, whatever it is.
+    pub fn send_apdu(&mut self, timeout: u64, ins: u8, p1: u8, data: Vec<u8>, callback: OnceCallback<(Vec<u8>, u16), U2FError>)
+    {
+        let last_status = self.shared.last_status.clone();
+        self.run(OperationKind::SendApdu, deadline(timeout), callback.for_io(), move |device| {
+            try_send_apdu(device, ins, p1, &data, &last_status)
+        }, stopped);
+    }
+
+    // Has the first device that answers echo `data` back.
+    pub fn ping(&mut self, timeout: u64, data: Vec<u8>, callback: OnceCallback<Vec<u8>>)
+    {
+        let last_status = self.shared.last_status.clone();
+        self.run(OperationKind::Ping, deadline(timeout), callback, move |device| {
+            try_ping(device, &data, &last_status)
+        }, stopped);
+    }
+
+    // Has the first device that answers identify itself, e.g. by blinking.
+    pub fn wink(&mut self, timeout: u64, callback: OnceCallback<DeviceInfo>)
+    {
+        let last_status = self.shared.last_status.clone();
+        self.run(OperationKind::Wink, deadline(timeout), callback, move |device| {
+            try_wink(device, &last_status)
+        }, stopped);
+    }
+
+    // Has the user touch any device, to see whether it works. Devices are
+    // asked to register with throwaway data, and the credential is dropped.
+    // The same devices as for `register()` are asked.
+    pub fn touch_test(&mut self, timeout: u64, callback: OnceCallback<()>)
+    {
+        let last_status = self.shared.last_status.clone();
+        let gate = U2fGate::new(&self.filter);
+        self.run(OperationKind::TouchTest, deadline(timeout), callback, move |device| {
+            try_touch_test(device, &gate, &last_status)
+        }, stopped);
+    }
+
+    // Asks every attached device for its U2F version string, once the monitor
+    // reported all of them. Doesn't need user presence. Devices get a moment
+    // to show up first.
+    pub fn versions(&mut self, callback: OnceCallback<Vec<(DeviceInfo, io::Result<String>)>>)
+    {
+        // Abort any prior register/sign calls.
+        self.cancel();
+
+        let filter = self.filter.clone();
+        let rng = self.rng.clone();
+        let observer = self.shared.observer.lock().ok().and_then(|observer| observer.clone());
+        let hook = self.shared.metrics.lock().ok().and_then(|hook| hook.clone());
+        let cbc = callback.clone();
+        let releaser = Releaser::new();
+        let releaser_ = releaser.clone();
+        let context = self.context(None);
+
+        let thread = RunLoop::new_with_deadline(move |alive, stop_reason| {
+            let mut devices = DeviceMap::new(filter, observer);
+            devices.set_releaser(releaser_);
+            devices.set_context(context);
+            let monitor = try_or!(Monitor::new(), |e| {
+                callback.call(Err(e));
+            });
+            let metrics = Metrics::started(hook, OperationKind::Versions);
+            monitor.refresh();
+
+            while alive() {
+                if process_until_snapshot(&mut devices, monitor.events()) {
+                    let rv = Ok(query_versions(devices.values_mut(), &rng));
+                    metrics.completed(&rv);
+                    callback.call(rv);
+                    return;
+                }
+
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            let reason = stop_reason();
+            metrics.stopped(reason);
+            callback.call(stopped(reason));
+        }, deadline(CHECK_TIMEOUT));
+
+        let thread = try_or!(thread, |_| {
+            cbc.call(Err(io_err("couldn't create runloop")))
+        });
+        release_on_detach(&thread, releaser, cbc);
+        self.thread = Some(thread);
+    }
+
+    // Whether an operation is still running.
+    pub fn busy(&self) -> bool {
+        self.thread.as_ref().is_some_and(|thread| !thread.finished())
+    }
+
+    // This blocks.
+    pub fn cancel(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread.cancel();
+        }
+    }
+
+    // Polls every device with `poll` until it reports that the operation is
+    // complete, or until we're cancelled or time out. `on_stop` then decides
+    // what to report.
+    fn run<T, F, S>(&mut self, kind: OperationKind, deadline: Option<Instant>, callback: OnceCallback<T>, poll: F, on_stop: S)
+        where T: 'static, F: Fn(&mut ::platform::device::Device) -> Option<io::Result<T>>, F: Send + 'static,
+              S: FnOnce(StopReason) -> io::Result<T>, S: Send + 'static
+    {
+        let options = OperationOptions { deadline, ..OperationOptions::default() };
+        self.run_with_options(kind, options, callback, poll, on_stop);
+    }
+
+    // Like `run()`, starting out with the devices at `options.paths` if
+    // given, instead of all attached ones, see `DeviceMap::seed()`. All log
+    // messages of the operation are tagged with the `correlation_id`. With
+    // an `idle_timeout`, devices being added or removed keep the operation
+    // going, up to the `deadline`. The `prompt` is the manager's business.
+    fn run_with_options<T, F, S>(&mut self, kind: OperationKind, options: OperationOptions, callback: OnceCallback<T>, poll: F, on_stop: S)
+        where T: 'static, F: Fn(&mut ::platform::device::Device) -> Option<io::Result<T>>, F: Send + 'static,
+              S: FnOnce(StopReason) -> io::Result<T>, S: Send + 'static
+    {
+        // Abort any prior register/sign calls.
+        self.cancel();
+
+        let OperationOptions { deadline, idle_timeout, paths: seed, correlation_id, .. } = options;
+
+        // Forget status words from previous operations.
+        if let Ok(mut last_status) = self.shared.last_status.lock() {
+            *last_status = None;
+        }
+
+        let filter = self.filter.clone();
+        let selection = filter.selection;
+        let rng = self.rng.clone();
+        let max_events = self.shared.max_events.load(Ordering::SeqCst);
+        let max_devices = self.shared.max_devices.load(Ordering::SeqCst);
+        let observer = self.shared.observer.lock().ok().and_then(|observer| observer.clone());
+        let warnings = self.shared.warnings.clone();
+        let hook = self.shared.metrics.lock().ok().and_then(|hook| hook.clone());
+
+        // We enumerate all devices at the start of every operation anyway.
+        let refresh = self.shared.refresh.clone();
+        refresh.store(false, Ordering::SeqCst);
+        let paused = self.shared.paused.clone();
+        let interrupt = self.shared.interrupt.clone();
+        interrupt.store(false, Ordering::SeqCst);
+        let device_count = self.shared.device_count.clone();
+        let context = self.context(correlation_id);
+        let cbc = callback.clone();
+        let idle = idle_timeout.map(|idle| Arc::new(IdleTimer::new(idle, deadline)));
+        let idle_ = idle.clone();
+        let last_status = self.shared.last_status.clone();
+        let releaser = Releaser::new();
+        let releaser_ = releaser.clone();
+
+        let fun = move |alive: &dyn Fn() -> bool, stop_reason: &dyn Fn() -> StopReason| {
+            op_log!(debug, Some(&context), "{:?} started", kind);
+            let mut rounds = RoundLog::new();
+            let context = OperationContext { quiet_round: Some(rounds.quiet()), ..context };
+            let start = Instant::now();
+            let mut gate = SnapshotGate::new(&filter);
+            let mut devices = DeviceMap::new(filter, observer);
+            devices.set_releaser(releaser_);
+            devices.set_context(context.clone());
+            let mut known = 0;
+            let claims = Claims::new();
+            let mut released = false;
+            let mut resume = ResumeDetector::new(SystemTime::now(), start);
+            let monitor = try_or!(Monitor::new(), |e| {
+                callback.call(Err(e));
+            });
+            let metrics = Metrics::started(hook, kind);
+            let poll = |device: &mut ::platform::device::Device| {
+                metrics.attempt(device);
+                poll(device)
+            };
+
+            // Tells which devices were there from the start, and lets the
+            // monitor add devices beyond the seeded ones.
+            let seeded = seed.is_some();
+            if let Some(seed) = seed {
+                devices.seed(seed);
+            }
+            if gate.required() || seeded {
+                monitor.refresh();
+            }
+
+            while alive() {
+                // Catch up on devices the monitor missed, e.g. while the
+                // system was asleep.
+                let resumed = resume.check(SystemTime::now(), Instant::now());
+                if resumed {
+                    op_log!(debug, Some(&context), "System resumed, enumerating devices again");
+                }
+                if refresh.swap(false, Ordering::SeqCst) || resumed {
+                    monitor.refresh();
+                }
+
+                // Add/remove devices. Leave the rest of an event storm for
+                // the next round so that devices get their turn.
+                process_device_events(&mut devices, &mut gate, idle_.as_deref(), monitor.events(), max_events);
+                set_device_count(&device_count, Some(devices.len()));
+                if log_enabled!(log::LogLevel::Trace) {
+                    let pending = monitor.pending();
+                    if !pending.is_empty() {
+                        op_log!(trace, Some(&context), "{} monitor events left for the next round: {:?}", pending.len(), pending);
+                    }
+                }
+
+                if devices.len() != known {
+                    known = devices.len();
+                    op_log!(debug, Some(&context), "Tracking {} devices after {}ms", known, as_millis(start.elapsed()));
+                }
+
+                // Someone asked us to let go of a device, give up.
+                claims.update(&devices.paths());
+                let cancelled = claims.take_cancelled();
+                if !cancelled.is_empty() {
+                    op_log!(debug, Some(&context), "Cancelled from outside, releasing {:?}", cancelled);
+                    released = true;
+                    break;
+                }
+
+                // Try each device, up to the cap. Others wait for a later
+                // round, or for a newer device to go away.
+                let round = gate.generations().map_or_else(Vec::new, |generations| {
+                    let mut round = preferred_first(devices.newest_first(generations, usize::MAX), selection);
+                    round.truncate(max_devices);
+                    round
+                });
+                rounds.start(Some(&context), Instant::now(), last_status.lock().ok().and_then(|last_status| *last_status));
+                let mut round = Interruptible::new(round.into_iter(), &interrupt);
+                let rv = poll_unless_paused(&paused, &mut round, &rng, &warnings, &poll);
+                let interrupted = round.interrupted();
+                if let Some(rv) = rv {
+                    op_log!(debug, Some(&context), "Operation completed after {}ms", as_millis(start.elapsed()));
+                    set_device_count(&device_count, None);
+                    metrics.completed(&rv);
+                    callback.call(rv);
+                    return;
+                }
+
+                // Forget devices that went away while we talked to them,
+                // whether or not the monitor noticed already.
+                devices.remove_disconnected();
+
+                // Start over right away, the devices wait for their turn.
+                if interrupted {
+                    op_log!(debug, Some(&context), "Polling round interrupted, starting over");
+                    continue;
+                }
+
+                // Wait a little before trying again. The timeout keeps
+                // running while we're paused.
+                let interval = if paused.load(Ordering::SeqCst) { PAUSED_INTERVAL } else { POLL_INTERVAL };
+                thread::sleep(Duration::from_millis(interval));
+            }
+
+            let reason = if released { StopReason::Cancelled } else { stop_reason() };
+
+            // Make the devices stop blinking right away.
+            if reason == StopReason::Cancelled {
+                cancel_pending(devices.values_mut());
+            }
+
+            op_log!(debug, Some(&context), "Operation stopped after {}ms: {:?}", as_millis(start.elapsed()), reason);
+            set_device_count(&device_count, None);
+            metrics.stopped(reason);
+            callback.call(on_stop(reason));
+        };
+
+        let thread = match idle {
+            Some(timer) => RunLoop::new_with_idle_timer(fun, timer),
+            None => RunLoop::new_with_deadline(fun, deadline)
+        };
+
+        let thread = try_or!(thread, |_| {
+            cbc.call(Err(io_err("couldn't create runloop")))
+        });
+        release_on_detach(&thread, releaser, cbc);
+        self.thread = Some(thread);
+    }
+}
+
+// Counts the devices that pass the filter. Waits for the monitor to report
+// a full snapshot, so that we don't miss any.
+pub fn count_devices(filter: DeviceFilter) -> io::Result<usize> {
+    list_devices(filter).map(|devices| devices.len())
+}
+
+// Lists the devices that pass the filter, like `count_devices()`. Blocks for
+// at most CHECK_TIMEOUT seconds.
+pub fn list_devices(filter: DeviceFilter) -> io::Result<Vec<DeviceInfo>> {
+    let mut devices = DeviceMap::new(filter, None);
+    let monitor = Monitor::new()?;
+    monitor.refresh();
+
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(CHECK_TIMEOUT) {
+        if process_until_snapshot(&mut devices, monitor.events()) {
+            return Ok(devices.values_mut().map(|device| device.get_device_info()).collect());
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Err(io::Error::new(io::ErrorKind::TimedOut, "enumeration timed out"))
+}
+
+// Hands events to `devices` until one was a full snapshot. Returns whether
+// there was one, later events are left alone.
+fn process_until_snapshot<I>(devices: &mut DeviceMap, events: I) -> bool
+    where I: Iterator<Item = Event>
+{
+    for event in events {
+        let complete = is_snapshot(&event);
+        devices.process_event(event);
+        if complete {
+            return true;
+        }
+    }
+    false
+}
+
+// Reports devices as they're added or removed, starting with the ones that
+// are already there, until `forward` returns false.
+#[cfg(feature = "futures")]
+pub fn watch_devices<F>(filter: DeviceFilter, forward: F) -> io::Result<RunLoop>
+    where F: Fn(DeviceEvent) -> bool, F: Send + 'static
+{
+    RunLoop::new(move |alive| {
+        let mut devices = DeviceMap::new(filter, None);
+        let monitor = match Monitor::new() {
+            Ok(monitor) => monitor,
+            Err(e) => return debug!("Couldn't watch devices: {}", e)
+        };
+
+        while alive() {
+            for event in monitor.events() {
+                for change in devices.process_event(event) {
+                    if !forward(change) {
+                        return;
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }, 0)
+}
+
+fn is_snapshot(event: &Event) -> bool {
+    matches!(*event, Event::Snapshot(_))
+}
+
+// Tells which devices an operation may use, by when they were added compared
+// to the first full snapshot. When devices have to be reinserted, only those
+// added after it. When they have to be present at the start, only those
+// added before it.
+struct SnapshotGate {
+    reinsert: bool,
+    present_at_start: bool,
+    snapshot: Option<u64>
+}
+
+impl SnapshotGate {
+    fn new(filter: &DeviceFilter) -> Self {
+        Self { reinsert: filter.require_reinsert, present_at_start: filter.present_at_start, snapshot: None }
+    }
+
+    // Whether we have to know which devices were there from the start.
+    fn required(&self) -> bool {
+        self.reinsert || self.present_at_start
+    }
+
+    // Devices that are added later get `generation` or above.
+    fn snapshot_done(&mut self, generation: u64) {
+        if self.snapshot.is_none() {
+            self.snapshot = Some(generation);
+        }
+    }
+
+    // The generations of the devices we may use, if any yet.
+    fn generations(&self) -> Option<Range<u64>> {
+        let end = match self.snapshot {
+            Some(snapshot) if self.present_at_start => snapshot,
+            _ => u64::MAX
+        };
+        match (self.reinsert, self.snapshot) {
+            (true, Some(snapshot)) => Some(snapshot..end),
+            (true, None) => None,
+            (false, _) => Some(0..end)
+        }
+    }
+}
+
+// Guesses that the system was suspended and resumed, when the wall clock ran
+// ahead of the monotonic clock between two checks. The monotonic clock stops
+// during suspend on Linux and macOS, but not on Windows, so resumes aren't
+// noticed there. Setting the wall clock forward looks like a resume too,
+// which only costs an extra enumeration.
+struct ResumeDetector {
+    wall: SystemTime,
+    monotonic: Instant
+}
+
+impl ResumeDetector {
+    fn new(wall: SystemTime, monotonic: Instant) -> Self {
+        Self { wall, monotonic }
+    }
+
+    fn check(&mut self, wall: SystemTime, monotonic: Instant) -> bool {
+        let wall_elapsed = wall.duration_since(self.wall).unwrap_or_default();
+        let monotonic_elapsed = monotonic.duration_since(self.monotonic);
+        self.wall = wall;
+        self.monotonic = monotonic;
+        wall_elapsed > monotonic_elapsed + Duration::from_secs(RESUME_THRESHOLD)
+    }
+}
+
+// Decides which polling rounds log the frames and commands they send.
+// Devices report every round that they wait for the user to touch them, so
+// only one such round is logged per `WAITING_LOG_INTERVAL`. Other rounds, and
+// waiting again later, are logged right away.
+struct RoundLog {
+    quiet: Arc<AtomicBool>,
+    last: Option<Instant>
+}
+
+impl RoundLog {
+    fn new() -> Self {
+        Self { quiet: Arc::new(AtomicBool::new(false)), last: None }
+    }
+
+    // For the devices' `OperationContext`, set while a round isn't logged.
+    fn quiet(&self) -> Arc<AtomicBool> {
+        self.quiet.clone()
+    }
+
+    // Called before each polling round with the last status word seen.
+    fn start(&mut self, context: Option<&OperationContext>, now: Instant, last_status: Option<u16>) {
+        let needs_touch = (SW_CONDITIONS_NOT_SATISFIED[0] as u16) << 8 | SW_CONDITIONS_NOT_SATISFIED[1] as u16;
+        if last_status != Some(needs_touch) {
+            self.last = None;
+            self.quiet.store(false, Ordering::SeqCst);
+            return;
+        }
+
+        let interval = Duration::from_millis(WAITING_LOG_INTERVAL);
+        let logged = match self.last {
+            Some(last) => now.duration_since(last) >= interval,
+            None => true
+        };
+        if logged {
+            self.last = Some(now);
+            op_log!(trace, context, "Waiting for user presence");
+        }
+        self.quiet.store(!logged, Ordering::SeqCst);
+    }
+}
+
+// Keeps devices that don't genuinely speak U2F_V2 out of U2F operations, if
+// the filter asks for that. FIDO2 tokens have to list it in getInfo, which
+// is only asked once per channel. Other devices answered the version APDU
+// with it when they got their channel. Each skipped device is logged once.
+struct U2fGate {
+    required: bool,
+    skipped: Mutex<Vec<DeviceInfo>>
+}
+
+impl U2fGate {
+    fn new(filter: &DeviceFilter) -> Self {
+        Self { required: filter.require_u2f, skipped: Mutex::new(Vec::new()) }
+    }
+
+    fn allows<T>(&self, device: &mut T) -> bool
+        where T: U2FDevice + Read + Write
+    {
+        if !self.required {
+            return true;
+        }
+
+        let info = device.get_device_info();
+        let supported = if info.capabilities & CAPFLAG_NMSG != 0 {
+            false
+        } else if info.supports_cbor() {
+            ctap2_get_info(device).map(|info| info.versions.iter().any(|v| v == "U2F_V2")).unwrap_or(false)
+        } else {
+            true
+        };
+
+        if !supported {
+            if let Ok(mut skipped) = self.skipped.lock() {
+                if !skipped.contains(&info) {
+                    op_log!(info, device.context(), "Skipping {:?}, it doesn't support U2F_V2", info.path);
+                    skipped.push(info);
+                }
+            }
+        }
+        supported
+    }
+}
+
+fn set_device_count(device_count: &Mutex<Option<usize>>, count: Option<usize>) {
+    if let Ok(mut device_count) = device_count.lock() {
+        *device_count = count;
+    }
+}
+
+// Hands at most `max` events to `process`. Whatever is left over stays queued.
+fn process_events<I, F>(events: I, max: usize, mut process: F)
+    where I: Iterator, F: FnMut(I::Item)
+{
+    for event in events.take(max) {
+        process(event);
+    }
+}
+
+// Hands at most `max` of the monitor's events to `devices`. Devices being
+// added or removed mean the user is still plugging them in or out, that
+// starts the idle clock over.
+fn process_device_events<I>(devices: &mut DeviceMap, gate: &mut SnapshotGate, idle: Option<&IdleTimer>, events: I, max: usize)
+    where I: Iterator<Item = Event>
+{
+    process_events(events, max, |event| {
+        let snapshot = is_snapshot(&event);
+        devices.process_event(event);
+        if snapshot {
+            gate.snapshot_done(devices.generation());
+        } else if let Some(idle) = idle {
+            idle.touch();
+        }
+    });
+}
+
+// The result of an operation that was cancelled or timed out.
+// Should an operation's thread get stuck and be detached, closes its devices
+// and calls back in its stead.
+fn release_on_detach<T: 'static>(thread: &RunLoop, releaser: Releaser, callback: OnceCallback<T>) {
+    thread.on_detach(Box::new(move |reason| {
+        releaser.release();
+        callback.call(stopped(reason));
+    }));
+}
+
+fn stopped<T>(reason: StopReason) -> io::Result<T> {
+    Err(match reason {
+        StopReason::Cancelled => cancelled(),
+        StopReason::TimedOut => timed_out()
+    })
+}
+
+// Moves the devices the policy prefers to the front of a polling round, so
+// that they're asked first. Keeps the order otherwise.
+fn preferred_first<T>(mut round: Vec<&mut T>, selection: SelectionPolicy) -> Vec<&mut T>
+    where T: U2FDevice
+{
+    round.sort_by_key(|device| !selection.prefers(&device.get_device_info()));
+    round
+}
+
+// Runs a single polling round over the given devices. Returns the result of
+// the first device that completed the operation, if any. Devices get a channel
+// allocated before they're polled for the first time. Devices that failed to
+// get one MAX_INIT_FAILURES times in a row are skipped, they're most likely
+// not FIDO devices at all and we'd just keep waiting for them. Devices that
+// were unplugged are skipped too, until the caller drops them. Devices that
+// only got a channel on a later try leave a warning.
+fn poll_devices<'a, T, I, F, R>(devices: I, rng: &SharedRng, warnings: &Warnings, poll: &F) -> Option<io::Result<R>>
+    where T: U2FDevice + Read + Write + 'a, I: Iterator<Item = &'a mut T>, F: Fn(&mut T) -> Option<io::Result<R>>
+{
+    for device in devices {
+        if device.disconnected() || device.init_failures() >= MAX_INIT_FAILURES {
+            continue;
+        }
+
+        let start = Instant::now();
+        let needs_init = device.get_cid() == CID_BROADCAST;
+        // Only devices that lost their channel had one before.
+        let reinit = needs_init && device.get_device_info().channel_id.is_some();
+        let rv = match rng.lock() {
+            Ok(mut rng) => u2f_init_channel(device, &mut **rng),
+            Err(_) => return None
+        };
+        if let Err(e) = rv {
+            if is_disconnect_error(&e) {
+                op_log!(debug, device.context(), "Device gone during INIT ({}), dropping it", e);
+                device.set_disconnected();
+                continue;
+            }
+            let failures = device.init_failures() + 1;
+            device.set_init_failures(failures);
+            if failures == MAX_INIT_FAILURES {
+                op_log!(debug, device.context(), "INIT failed {} times ({}), not a FIDO device? Skipping it", failures, e);
+            }
+            continue;
+        }
+        if device.init_failures() > 0 {
+            warnings.push(Warning::InitRetried { info: Box::new(device.get_device_info()), failures: device.init_failures() });
+            device.set_init_failures(0);
+        }
+
+        if reinit {
+            record_reinit(device);
+        }
+        if needs_init {
+            op_log!(debug, device.context(), "{}: initialized in {}ms", to_hex(&device.get_cid()), as_millis(start.elapsed()));
+        }
+
+        if let Some(rv) = poll(device) {
+            return Some(rv);
+        }
+    }
+
+    None
+}
+
+// Asks each device for its version string, allocating channels as needed.
+// Devices that fail to answer report the error instead.
+fn query_versions<'a, T, I>(devices: I, rng: &SharedRng) -> Vec<(DeviceInfo, io::Result<String>)>
+    where T: U2FDevice + Read + Write + 'a, I: Iterator<Item = &'a mut T>
+{
+    devices.map(|device| {
+        let rv = match rng.lock() {
+            Ok(mut rng) => u2f_init_channel(device, &mut **rng),
+            Err(_) => Err(io_err("failed to lock"))
+        };
+        let version = rv.and_then(|_| u2f_version(device))
+                        .and_then(|version| version.into_string().map_err(to_io_err));
+        (device.get_device_info(), version)
+    }).collect()
+}
+
+// Like `poll_devices()`, but doesn't send anything to any device while
+// `paused` is set.
+fn poll_unless_paused<'a, T, I, F, R>(paused: &AtomicBool, devices: I, rng: &SharedRng, warnings: &Warnings, poll: &F) -> Option<io::Result<R>>
+    where T: U2FDevice + Read + Write + 'a, I: Iterator<Item = &'a mut T>, F: Fn(&mut T) -> Option<io::Result<R>>
+{
+    if paused.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    poll_devices(devices, rng, warnings, poll)
+}
+
+// Hands out the devices of a polling round until `interrupt` is set, which
+// is checked before each device and cleared again. The device that was
+// being polled finishes first.
+struct Interruptible<'a, I> {
+    devices: I,
+    interrupt: &'a AtomicBool,
+    interrupted: bool
+}
+
+impl<'a, I> Interruptible<'a, I> {
+    fn new(devices: I, interrupt: &'a AtomicBool) -> Self {
+        Self { devices, interrupt, interrupted: false }
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupted
+    }
+}
+
+impl<'a, I> Iterator for Interruptible<'a, I>
+    where I: Iterator
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        if self.interrupted || self.interrupt.swap(false, Ordering::SeqCst) {
+            self.interrupted = true;
+            return None;
+        }
+        self.devices.next()
+    }
+}
+
+// Asks every device we talked to to abort its pending request. Devices
+// without a channel haven't been asked anything yet.
+fn cancel_pending<'a, T, I>(devices: I)
+    where T: U2FDevice + Read + Write + 'a, I: Iterator<Item = &'a mut T>
+{
+    for device in devices.filter(|device| device.get_cid() != CID_BROADCAST) {
+        // Ignore errors, the device might be gone already.
+        let _ = u2f_cancel(device);
+    }
+}
+
+// Handles a failed device command. If the device answered with a status
+// word, remember it. Anything else means we can't trust the state of the
+// channel anymore, e.g. there might be unread packets left, so drop it and
+// have the device re-initialized before it's used again. This keeps a
+// misbehaving device from affecting later commands. Devices that were
+// unplugged are marked, so that they're dropped right away instead of being
+// initialized again.
+fn handle_error<T>(device: &mut T, last_status: &Mutex<Option<u16>>, err: &io::Error)
+    where T: U2FDevice
+{
+    match status_word(err) {
+        Some(sw) => {
+            if let Ok(mut last_status) = last_status.lock() {
+                *last_status = Some(sw);
+            }
+        }
+        None => {
+            if is_disconnect_error(err) {
+                op_log!(debug, device.context(), "Device gone ({}), dropping it", err);
+                device.set_disconnected();
+            }
+            device.set_cid(&CID_BROADCAST)
+        }
+    }
+}
+
+// Records the counter in a device's sign response, and warns if it didn't
+// increase.
+fn check_counter<T>(device: &T, key_handle: &KeyHandle, response: &[u8], counters: &Mutex<SignCounters>, warnings: &Warnings)
+    where T: U2FDevice
+{
+    let serial_number = device.get_device_info().serial_number;
+    let check = match counters.lock() {
+        Ok(mut counters) => counters.check(serial_number.as_deref(), key_handle, response),
+        Err(_) => return
+    };
+    if let Ok(CounterCheck::NotIncreased { last, current }) = check {
+        warnings.push(Warning::CounterNotIncreased { key_handle: key_handle.clone(), last, current });
+    }
+}
+
+// Pairs a response with the device that gave it, so that callers know
+// which one the user touched.
+fn with_device<T, R>(device: &T, rv: io::Result<R>) -> io::Result<(R, DeviceInfo)>
+    where T: U2FDevice
+{
+    rv.map(|response| (response, device.get_device_info()))
+}
+
+// Asks a device to register. The first device to do so wins.
+fn try_register<T>(device: &mut T, challenge: &[u8], application: &[u8], last_status: &Mutex<Option<u16>>) -> Option<io::Result<Vec<u8>>>
+    where T: U2FDevice + Read + Write
+{
+    match u2f_register(device, challenge, application) {
+        Ok(bytes) => Some(Ok(bytes)),
+        Err(e) => { handle_error(device, last_status, &e.into()); None }
+    }
+}
+
+// Asks a device to sign, if the key handle belongs to it. Other devices are
+// asked to register with bogus data so that they blink too, and touching one
+// of them ends the operation with an error.
+fn try_sign<T>(device: &mut T, challenge: &[u8], application: &[u8], key_handle: &KeyHandle, last_status: &Mutex<Option<u16>>) -> Option<io::Result<Vec<u8>>>
+    where T: U2FDevice + Read + Write
+{
+    // Check if they key handle belongs to the current device.
+    let is_valid = match u2f_is_keyhandle_valid(device, challenge, application, key_handle) {
+        Ok(valid) => valid,
+        Err(e) => { handle_error(device, last_status, &e); return None }
+    };
+
+    if is_valid {
+        // If yes, try to sign.
+        match u2f_sign(device, challenge, application, key_handle) {
+            Ok(bytes) => Some(Ok(bytes)),
+            Err(e) => { handle_error(device, last_status, &e.into()); None }
+        }
+    } else {
+        // If no, keep registering and blinking with bogus data
+        let blank = vec![0u8; PARAMETER_SIZE];
+        match u2f_register(device, &blank, &blank) {
+            Ok(_) => Some(Err(io_err("invalid key"))),
+            Err(e) => { handle_error(device, last_status, &e.into()); None }
+        }
+    }
+}
+
+// Asks a device to sign with the first key handle it owns that wasn't signed
+// with yet, and records the signature. Devices that own none of them are
+// left alone, touching them wouldn't help. Returns whether we got a new
+// signature.
+fn try_sign_remaining<T>(device: &mut T, challenge: &[u8], application: &[u8], key_handles: &[KeyHandle], signed: &mut [Option<Vec<u8>>], last_status: &Mutex<Option<u16>>) -> bool
+    where T: U2FDevice + Read + Write
+{
+    for (key_handle, signature) in key_handles.iter().zip(signed.iter_mut()) {
+        if signature.is_some() {
+            continue;
+        }
+
+        match u2f_is_keyhandle_valid(device, challenge, application, key_handle) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => { handle_error(device, last_status, &e); return false }
+        }
+
+        // Wait for the user to touch this one before we move on.
+        return match u2f_sign(device, challenge, application, key_handle) {
+            Ok(bytes) => { *signature = Some(bytes); true }
+            Err(e) => { handle_error(device, last_status, &e.into()); false }
+        };
+    }
+
+    false
+}
+
+// Handles the result of a CTAP2 command. Tokens without a matching
+// credential, or whose user didn't respond in time, are asked again next
+// round. Any other error the token reports, e.g. that it needs a PIN, ends
+// the operation.
+fn ctap2_result<T, R>(device: &mut T, rv: io::Result<R>, last_status: &Mutex<Option<u16>>) -> Option<io::Result<R>>
+    where T: U2FDevice
+{
+    match rv {
+        Ok(result) => Some(Ok(result)),
+        Err(e) => {
+            match ctap2_status(&e) {
+                Some(CTAP2_ERR_NO_CREDENTIALS) | Some(CTAP2_ERR_USER_ACTION_TIMEOUT) => None,
+                Some(_) => Some(Err(e)),
+                None => { handle_error(device, last_status, &e); None }
+            }
+        }
+    }
+}
+
+// Asks a FIDO2 token to create a credential.
+fn try_make_credential<T>(device: &mut T, client_data_hash: &[u8], rp: &RelyingParty, user: &User, algorithms: &[i64], options: MakeCredentialOptions, last_status: &Mutex<Option<u16>>) -> Option<io::Result<AttestationObject>>
+    where T: U2FDevice + Read + Write
+{
+    if !device.get_device_info().supports_cbor() {
+        return None;
+    }
+
+    let rv = ctap2_make_credential(device, client_data_hash, rp, user, algorithms, options);
+    ctap2_result(device, rv, last_status)
+}
+
+// Asks a FIDO2 token whether a PIN is set.
+fn try_pin_status<T>(device: &mut T, last_status: &Mutex<Option<u16>>) -> Option<io::Result<PinStatus>>
+    where T: U2FDevice + Read + Write
+{
+    if !device.get_device_info().supports_cbor() {
+        return None;
+    }
+
+    let rv = ctap2_pin_status(device);
+    ctap2_result(device, rv, last_status)
+}
+
+// The keys agreed on with each FIDO2 token during an operation, so that the
+// token isn't asked again every polling round. A token that gets a new
+// channel, e.g. because it was power cycled and forgot its key, has a new
+// entry. New keys are made with `rng`.
+struct SharedSecrets {
+    rng: SharedRng,
+    secrets: Mutex<Vec<(DeviceInfo, SharedSecret)>>
+}
+
+impl SharedSecrets {
+    fn new(rng: SharedRng) -> Self {
+        Self { rng, secrets: Mutex::new(Vec::new()) }
+    }
+
+    fn get(&self, info: &DeviceInfo) -> Option<SharedSecret> {
+        let secrets = self.secrets.lock().ok()?;
+        secrets.iter().find(|&(known, _)| known == info).map(|(_, secret)| secret.clone())
+    }
+
+    fn insert(&self, info: DeviceInfo, secret: SharedSecret) {
+        if let Ok(mut secrets) = self.secrets.lock() {
+            secrets.push((info, secret));
+        }
+    }
+}
+
+// Asks a FIDO2 token for an assertion. If hmac-secret outputs are asked for,
+// tokens that don't support the extension are left alone too.
+fn try_get_assertion<T>(device: &mut T, rp_id: &str, client_data_hash: &[u8], allow_list: &[Vec<u8>], options: AssertionOptions, secrets: &SharedSecrets, last_status: &Mutex<Option<u16>>) -> Option<io::Result<Assertion>>
+    where T: U2FDevice + Read + Write
+{
+    if !device.get_device_info().supports_cbor() {
+        return None;
+    }
+
+    let mut shared_secret = None;
+    if options.hmac_secret.is_some() {
+        match ctap2_get_info(device) {
+            Ok(ref info) if info.supports_extension("hmac-secret") => {}
+            Ok(_) => return None,
+            Err(e) => { handle_error(device, last_status, &e); return None }
+        }
+
+        let info = device.get_device_info();
+        shared_secret = secrets.get(&info);
+        if shared_secret.is_none() {
+            // Don't hold on to the RNG while the token waits for the user.
+            let rv = match secrets.rng.lock() {
+                Ok(mut rng) => ctap2_shared_secret(device, &mut **rng),
+                Err(_) => return None
+            };
+            match rv {
+                Ok(secret) => {
+                    secrets.insert(info, secret.clone());
+                    shared_secret = Some(secret);
+                }
+                Err(e) => return ctap2_result(device, Err(e), last_status)
+            }
+        }
+    }
+
+    let rv = ctap2_get_assertion(device, rp_id, client_data_hash, allow_list, options, shared_secret.as_ref());
+    ctap2_result(device, rv, last_status)
+}
+
+// Sends an APDU to a device. Any status word is an answer, only transport
+// errors have the next device asked.
+fn try_send_apdu<T>(device: &mut T, ins: u8, p1: u8, data: &[u8], last_status: &Mutex<Option<u16>>) -> Option<io::Result<(Vec<u8>, u16)>>
+    where T: U2FDevice + Read + Write
+{
+    match u2f_send_apdu_with_status(device, ins, p1, data) {
+        Ok(resp) => Some(Ok(resp)),
+        Err(e) => { handle_error(device, last_status, &e); None }
+    }
+}
+
+// Pings a device. A corrupted echo gets the device a new channel, and it's
+// asked again.
+fn try_ping<T>(device: &mut T, data: &[u8], last_status: &Mutex<Option<u16>>) -> Option<io::Result<Vec<u8>>>
+    where T: U2FDevice + Read + Write
+{
+    match u2f_ping(device, data) {
+        Ok(echo) => Some(Ok(echo)),
+        Err(e) => { handle_error(device, last_status, &e); None }
+    }
+}
+
+// Has a device wink and tells which one it was. A device that can't ends
+// the operation right away, asking it again wouldn't help.
+fn try_wink<T>(device: &mut T, last_status: &Mutex<Option<u16>>) -> Option<io::Result<DeviceInfo>>
+    where T: U2FDevice + Read + Write
+{
+    match u2f_wink(device) {
+        Ok(()) => Some(Ok(device.get_device_info())),
+        Err(ref e) if !is_wink_not_supported(e) => { handle_error(device, last_status, e); None }
+        Err(e) => Some(Err(e))
+    }
+}
+
+// Asks a device to register with blank data, only the touch counts. Devices
+// the gate keeps out of U2F operations aren't asked.
+fn try_touch_test<T>(device: &mut T, gate: &U2fGate, last_status: &Mutex<Option<u16>>) -> Option<io::Result<()>>
+    where T: U2FDevice + Read + Write
+{
+    if !gate.allows(device) {
+        return None;
+    }
+    let blank = vec![0u8; PARAMETER_SIZE];
+    match u2f_register(device, &blank, &blank) {
+        Ok(_) => Some(Ok(())),
+        Err(e) => { handle_error(device, last_status, &e.into()); None }
+    }
+}
+
+// Asks a device whether it owns the key handle. Only a positive answer ends
+// the operation, so that all devices get asked.
+fn try_check_credential<T>(device: &mut T, application: &[u8], key_handle: &KeyHandle, last_status: &Mutex<Option<u16>>) -> Option<io::Result<bool>>
+    where T: U2FDevice + Read + Write
+{
+    let blank = vec![0u8; PARAMETER_SIZE];
+    match u2f_is_keyhandle_valid(device, &blank, application, key_handle) {
+        Ok(true) => Some(Ok(true)),
+        Ok(false) => None,
+        Err(e) => { handle_error(device, last_status, &e); None }
+    }
+}
+
+// Asks a device whether the key handle belongs to any of the applications.
+// Only a positive answer ends the operation.
+fn try_probe_applications<T>(device: &mut T, key_handle: &KeyHandle, applications: &[[u8; PARAMETER_SIZE]], last_status: &Mutex<Option<u16>>) -> Option<io::Result<Option<usize>>>
+    where T: U2FDevice + Read + Write
+{
+    for (index, application) in applications.iter().enumerate() {
+        if let Some(Ok(true)) = try_check_credential(device, application, key_handle, last_status) {
+            return Some(Ok(Some(index)));
+        }
+
+        // Don't keep talking to a device that lost its channel.
+        if device.get_cid() == CID_BROADCAST {
+            break;
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_INIT_FAILURES, ResumeDetector, SharedState, SnapshotGate, StateMachine, U2fGate, cancel_pending, check_counter, process_device_events, process_until_snapshot, poll_devices, poll_unless_paused, preferred_first, process_events, query_versions, try_check_credential, try_get_assertion, try_pin_status, try_probe_applications, try_register, try_send_apdu, try_sign_remaining, try_touch_test, try_wink, with_device, Interruptible, RoundLog, SharedSecrets};
+    use consts::{CAPFLAG_CBOR, CAPFLAG_WINK, CID_BROADCAST, CTAP2_CLIENT_PIN, CTAP2_ERR_USER_ACTION_TIMEOUT, ERR_INVALID_CMD, CTAP2_GET_ASSERTION, CTAP2_GET_INFO, U2FHID_CANCEL, U2FHID_CBOR, U2FHID_ERROR, U2FHID_INIT, U2FHID_MSG, U2FHID_PING, U2FHID_WINK, U2F_AUTHENTICATE, U2F_CHECK_IS_REGISTERED, U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, U2F_VERSION, PARAMETER_SIZE};
+    use error::U2FError;
+    use std::cell::RefCell;
+    use std::sync::{Arc, Mutex};
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+    use std::collections::HashMap;
+    use libc;
+    use log;
+    use util::{disconnected, newest_first, OnceCallback};
+    use platform::devicemap::DeviceMap;
+    use platform::monitor::Event;
+    #[cfg(target_os = "linux")]
+    use platform::testbench::{wait_until, TestBench};
+    use runloop::IdleTimer;
+    use hmacsecret::{extension_input, SharedSecret};
+    use testdevice::{apdu, token_key, CountingRng, TestDevice};
+    use u2fprotocol::U2FDevice;
+    use stats::{DeviceStats, DeviceStatsMap};
+    use u2ftypes::{AssertionOptions, AuthenticatorInfo, DeviceFilter, HmacSecretSalts, KeyHandle, OperationContext, OperationOptions, PinStatus, SelectionPolicy};
+    use util::SharedRng;
+    use cbor::{self, Value};
+    use counter::SignCounters;
+    use warnings::{Warning, Warnings};
+
+    fn counting_rng() -> SharedRng {
+        Arc::new(Mutex::new(Box::new(CountingRng(0))))
+    }
+
+    fn register_apdu(challenge: &[u8], application: &[u8]) -> Vec<u8> {
+        let mut data = challenge.to_vec();
+        data.extend(application);
+        apdu(U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, &data)
+    }
+
+    // Two devices asked to register, of which only the second one is
+    // touched. Their paths are /dev/hidraw0 and /dev/hidraw1.
+    fn second_touched(challenge: &[u8], application: &[u8]) -> Vec<TestDevice> {
+        let mut devices = vec![TestDevice::new(), TestDevice::new()];
+        for (i, device) in devices.iter_mut().enumerate() {
+            device.set_cid(&[1, 2, 3, 4]);
+            device.info.path = Some(format!("/dev/hidraw{}", i));
+            device.add_message_write(U2FHID_MSG, &register_apdu(challenge, application));
+        }
+        devices[0].add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+        devices[1].add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+        devices
+    }
+
+    #[test]
+    fn test_register_records_status_word() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        device.add_message_read(U2FHID_MSG, &[0x6a, 0x80]);
+
+        let last_status = Mutex::new(None);
+        assert!(try_register(&mut device, &challenge, &application, &last_status).is_none());
+        assert_eq!(*last_status.lock().unwrap(), Some(0x6a80));
+    }
+
+    #[test]
+    fn test_resume_detector() {
+        let wall = SystemTime::now();
+        let monotonic = Instant::now();
+        let mut resume = ResumeDetector::new(wall, monotonic);
+
+        // Polling rounds, some of them slow.
+        assert!(!resume.check(wall + Duration::from_millis(100), monotonic + Duration::from_millis(100)));
+        assert!(!resume.check(wall + Duration::from_secs(10), monotonic + Duration::from_secs(10)));
+
+        // A minute asleep, the monotonic clock didn't see it. Only the round
+        // after the jump enumerates again.
+        assert!(resume.check(wall + Duration::from_secs(70), monotonic + Duration::from_secs(10) + Duration::from_millis(100)));
+        assert!(!resume.check(wall + Duration::from_secs(70) + Duration::from_millis(100), monotonic + Duration::from_secs(10) + Duration::from_millis(200)));
+
+        // The wall clock was set back.
+        assert!(!resume.check(wall, monotonic + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn test_u2f_gate() {
+        let mut info = AuthenticatorInfo::default();
+        info.versions.push(String::from("FIDO_2_0"));
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+        device.info.authenticator_info = Some(info.clone());
+
+        let mut filter = DeviceFilter::default();
+        assert!(U2fGate::new(&filter).allows(&mut device));
+        filter.require_u2f = true;
+        let gate = U2fGate::new(&filter);
+        assert!(!gate.allows(&mut device));
+        assert!(!gate.allows(&mut device));
+
+        // Tokens that list it, and ones without CTAP2, are fine.
+        info.versions.push(String::from("U2F_V2"));
+        device.info.authenticator_info = Some(info);
+        assert!(gate.allows(&mut device));
+        assert!(gate.allows(&mut TestDevice::new()));
+    }
+
+    #[test]
+    fn test_send_apdu() {
+        // The first device doesn't answer, the second one rejects the
+        // command. That's still its answer.
+        let mut devices = [TestDevice::new(), TestDevice::new()];
+        for device in devices.iter_mut() {
+            device.set_cid(&[1, 2, 3, 4]);
+        }
+        devices[0].mute = true;
+        devices[1].add_message_write(U2FHID_MSG, &apdu(0x40, 0x01, &[0xaa]));
+        devices[1].add_message_read(U2FHID_MSG, &[0xbb, 0x6d, 0x00]);
+
+        let last_status = Mutex::new(None);
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_send_apdu(device, 0x40, 0x01, &[0xaa], &last_status)
+        });
+        assert_eq!(rv.unwrap().unwrap(), (vec![0xbb], 0x6d00));
+        assert_eq!(devices[0].get_cid(), CID_BROADCAST);
+        assert_eq!(*last_status.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn test_interrupt_round() {
+        let mut devices = [TestDevice::new(), TestDevice::new(), TestDevice::new()];
+        for device in devices.iter_mut() {
+            device.set_cid(&[1, 2, 3, 4]);
+        }
+
+        // Interrupted while the first device is polled.
+        let interrupt = AtomicBool::new(false);
+        let polled = AtomicUsize::new(0);
+        let poll = |_: &mut TestDevice| -> Option<io::Result<()>> {
+            if polled.fetch_add(1, Ordering::SeqCst) == 0 {
+                interrupt.store(true, Ordering::SeqCst);
+            }
+            None
+        };
+        let mut round = Interruptible::new(devices.iter_mut(), &interrupt);
+        assert!(poll_devices(&mut round, &counting_rng(), &Warnings::new(), &poll).is_none());
+        assert!(round.interrupted());
+        assert_eq!(polled.load(Ordering::SeqCst), 1);
+        assert!(!interrupt.load(Ordering::SeqCst));
+
+        // The next round gets to all of them.
+        let mut round = Interruptible::new(devices.iter_mut(), &interrupt);
+        assert!(poll_devices(&mut round, &counting_rng(), &Warnings::new(), &poll).is_none());
+        assert!(!round.interrupted());
+        assert_eq!(polled.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_touch_test() {
+        let blank = vec![0u8; PARAMETER_SIZE];
+        let mut devices = second_touched(&blank, &blank);
+
+        // A FIDO2 token without U2F_V2 comes first, it isn't asked.
+        let mut token = TestDevice::new();
+        token.set_cid(&[1, 2, 3, 4]);
+        token.info.capabilities = CAPFLAG_CBOR;
+        token.info.authenticator_info = Some(AuthenticatorInfo { versions: vec![String::from("FIDO_2_0")], ..AuthenticatorInfo::default() });
+        devices.insert(0, token);
+
+        let filter = DeviceFilter { require_u2f: true, ..DeviceFilter::default() };
+        let gate = U2fGate::new(&filter);
+        let last_status = Mutex::new(None);
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_touch_test(device, &gate, &last_status)
+        });
+        assert!(rv.unwrap().is_ok());
+        assert_eq!(*last_status.lock().unwrap(), Some(0x6985));
+        assert!(devices.iter().all(|device| device.expected_writes.is_empty()));
+    }
+
+    #[test]
+    fn test_wink() {
+        let mut devices = [TestDevice::new(), TestDevice::new()];
+        for device in devices.iter_mut() {
+            device.set_cid(&[1, 2, 3, 4]);
+            device.info.capabilities = CAPFLAG_WINK;
+        }
+        devices[1].info.path = Some(String::from("/dev/hidraw1"));
+        devices[1].add_message_write(U2FHID_WINK, &[]);
+        devices[1].add_message_read(U2FHID_WINK, &[]);
+
+        // The first device answers somebody else, the second one winks.
+        devices[0].add_message_write(U2FHID_WINK, &[]);
+        devices[0].add_read(&[1, 2, 3, 4, U2FHID_MSG, 0x00, 0x00], 0);
+        let last_status = Mutex::new(None);
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_wink(device, &last_status)
+        });
+        assert_eq!(rv.unwrap().unwrap().path, Some(String::from("/dev/hidraw1")));
+
+        // One that can't wink says so instead of timing out.
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        let rv = try_wink(&mut device, &last_status).unwrap();
+        assert_eq!(rv.unwrap_err().to_string(), "WINK not supported");
+    }
+
+    #[test]
+    fn test_pin_status() {
+        // The U2F-only device isn't asked, the FIDO2 token has a PIN set.
+        let mut devices = [TestDevice::new(), TestDevice::new()];
+        for device in devices.iter_mut() {
+            device.set_cid(&[1, 2, 3, 4]);
+        }
+        devices[1].info.capabilities = CAPFLAG_CBOR;
+        let options = Value::Map(vec![(Value::Text("clientPin".to_owned()), Value::Bool(true))]);
+        let info = Value::Map(vec![(Value::Unsigned(0x01), Value::Array(vec![Value::Text("FIDO_2_0".to_owned())])),
+                                   (Value::Unsigned(0x03), Value::Bytes(vec![0xcb; 16])),
+                                   (Value::Unsigned(0x04), options)]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&info));
+        devices[1].add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        devices[1].add_message_read(U2FHID_CBOR, &resp);
+        devices[1].add_message_write(U2FHID_CBOR, &[CTAP2_CLIENT_PIN, 0xa2, 0x01, 0x01, 0x02, 0x01]);
+        devices[1].add_message_read(U2FHID_CBOR, &[0x00, 0xa1, 0x03, 0x08]);
+
+        let last_status = Mutex::new(None);
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_pin_status(device, &last_status)
+        });
+        assert_eq!(rv.unwrap().unwrap(), PinStatus { set: true, retries: 8 });
+        assert!(devices.iter().all(|device| device.expected_writes.is_empty()));
+
+        // Without a token, nobody answers and we keep waiting.
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        assert!(try_pin_status(&mut device, &last_status).is_none());
+    }
+
+    #[test]
+    fn test_get_assertion_shared_secret() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+        let mut info = AuthenticatorInfo::default();
+        info.extensions.push("hmac-secret".to_owned());
+        device.info.authenticator_info = Some(info);
+
+        let options = AssertionOptions { hmac_secret: Some(HmacSecretSalts { salt1: [0x71; 32], salt2: None }), ..AssertionOptions::default() };
+        let secret = SharedSecret::from_cose_key(&token_key(), &mut CountingRng(0)).unwrap();
+        let text = |s: &str| Value::Text(s.to_owned());
+        let extensions = Value::Map(vec![(text("hmac-secret"), extension_input(&secret, options.hmac_secret.as_ref().unwrap()))]);
+        let req = Value::Map(vec![(Value::Unsigned(0x01), text("example.com")),
+                                  (Value::Unsigned(0x02), Value::Bytes(vec![0x11; 32])),
+                                  (Value::Unsigned(0x04), extensions),
+                                  (Value::Unsigned(0x05), Value::Map(vec![(text("up"), Value::Bool(true)), (text("uv"), Value::Bool(false))]))]);
+        let mut get_assertion = vec![CTAP2_GET_ASSERTION];
+        get_assertion.extend(cbor::encode(&req));
+
+        // The key is agreed on in the first round, in which the token isn't
+        // touched, and reused in the second.
+        let mut key_agreement = vec![0x00];
+        key_agreement.extend(cbor::encode(&Value::Map(vec![(Value::Unsigned(0x01), token_key())])));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_CLIENT_PIN, 0xa2, 0x01, 0x01, 0x02, 0x02]);
+        device.add_message_read(U2FHID_CBOR, &key_agreement);
+        device.add_message_write(U2FHID_CBOR, &get_assertion);
+        device.add_message_read(U2FHID_CBOR, &[CTAP2_ERR_USER_ACTION_TIMEOUT]);
+        let mut resp = vec![0x00];
+        let credential = Value::Map(vec![(text("id"), Value::Bytes(vec![0x33; 16])), (text("type"), text("public-key"))]);
+        resp.extend(cbor::encode(&Value::Map(vec![(Value::Unsigned(0x01), credential),
+                                                  (Value::Unsigned(0x02), Value::Bytes(vec![0x44; 37])),
+                                                  (Value::Unsigned(0x03), Value::Bytes(vec![0x55; 70]))])));
+        device.add_message_write(U2FHID_CBOR, &get_assertion);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        let (secrets, last_status) = (SharedSecrets::new(counting_rng()), Mutex::new(None));
+        let poll = |device: &mut TestDevice| {
+            try_get_assertion(device, "example.com", &[0x11; 32], &[], options, &secrets, &last_status)
+        };
+        assert!(poll(&mut device).is_none());
+        assert_eq!(poll(&mut device).unwrap().unwrap().credential_id, vec![0x33; 16]);
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_poll_devices_returns_first_result() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+
+        // The first device isn't touched yet, the second one is.
+        let mut devices = second_touched(&challenge, &application);
+
+        let last_status = Mutex::new(None);
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        });
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+        assert_eq!(*last_status.lock().unwrap(), Some(0x6985));
+    }
+
+    #[test]
+    fn test_with_device() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+
+        // We learn which device was touched.
+        let mut devices = second_touched(&challenge, &application);
+
+        let last_status = Mutex::new(None);
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status).map(|rv| with_device(device, rv))
+        });
+        let (response, info) = rv.unwrap().unwrap();
+        assert_eq!(response, vec![0x05, 0x04, 0x90, 0x00]);
+        assert_eq!(info.path, Some(String::from("/dev/hidraw1")));
+    }
+
+    #[test]
+    fn test_skip_mute_device() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let rng = counting_rng();
+
+        // The first device never answers INIT, the second one has a channel
+        // and waits for user presence.
+        let mut devices = [TestDevice::new(), TestDevice::new()];
+        devices[0].mute = true;
+        devices[1].set_cid(&[1, 2, 3, 4]);
+        for _ in 0..MAX_INIT_FAILURES {
+            devices[1].add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+            devices[1].add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+        }
+
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+        for _ in 0..MAX_INIT_FAILURES {
+            assert!(poll_devices(devices.iter_mut(), &rng, &Warnings::new(), &poll).is_none());
+        }
+        assert_eq!(devices[0].init_failures(), MAX_INIT_FAILURES);
+
+        // From now on it's skipped, any write would panic.
+        devices[0].mute = false;
+        devices[1].add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        devices[1].add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+        let rv = poll_devices(devices.iter_mut(), &rng, &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+        assert!(devices[1].expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_device_stats() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let stats = DeviceStatsMap::new();
+
+        // A macOS device, known by its registry entry ID, that fails the
+        // command, and one we can't tell apart from others. Then two that
+        // have paths, of which the first one lost its channel and the second
+        // one is touched.
+        let mut devices = vec![TestDevice::new(), TestDevice::new()];
+        devices.extend(second_touched(&challenge, &application));
+        devices[0].info.registry_id = Some(0x1000_0521);
+        for device in devices[..2].iter_mut() {
+            device.set_cid(&[1, 2, 3, 4]);
+            device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        }
+        devices[0].add_message_read(U2FHID_ERROR, &[ERR_INVALID_CMD]);
+        devices[1].add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+        {
+            let device = &mut devices[2];
+            device.set_cid(&CID_BROADCAST);
+            device.info.channel_id = Some([0x00, 0x03, 0x00, 0x13]);
+            // It gets a new channel before it's asked.
+            device.expected_writes.clear();
+            device.expected_reads.clear();
+            device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+            device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+            device.set_cid(&[0x00, 0x03, 0x00, 0x14]);
+            device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+            device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+            device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+            device.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+            device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+            device.add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+            device.set_cid(&CID_BROADCAST);
+        }
+        for device in devices.iter_mut() {
+            device.set_context(OperationContext { stats: Some(stats.clone()), ..OperationContext::default() });
+        }
+
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot[0].0.path, Some(String::from("/dev/hidraw0")));
+        assert_eq!(snapshot[0].1, DeviceStats { commands: 5, errors: 0, timeouts: 0, reinits: 1 });
+        assert_eq!(snapshot[1].0.path, Some(String::from("/dev/hidraw1")));
+        assert_eq!(snapshot[1].1, DeviceStats { commands: 1, errors: 0, timeouts: 0, reinits: 0 });
+        assert_eq!(snapshot[2].0.registry_id, Some(0x1000_0521));
+        assert_eq!(snapshot[2].1, DeviceStats { commands: 1, errors: 1, timeouts: 0, reinits: 0 });
+    }
+
+    #[test]
+    fn test_warnings() {
+        let key_handle = KeyHandle::from(vec![0x33; 64]);
+        let counters = Mutex::new(SignCounters::new());
+        let warnings = Warnings::new();
+
+        // A counter that goes backwards leaves a warning, until it's taken.
+        let device = TestDevice::new();
+        check_counter(&device, &key_handle, &[0x01, 0x00, 0x00, 0x00, 0x05, 0x30], &counters, &warnings);
+        check_counter(&device, &key_handle, &[0x01, 0x00, 0x00, 0x00, 0x06, 0x30], &counters, &warnings);
+        assert!(warnings.take().is_empty());
+        check_counter(&device, &key_handle, &[0x01, 0x00, 0x00, 0x00, 0x03, 0x30], &counters, &warnings);
+        assert_eq!(warnings.take(), vec![Warning::CounterNotIncreased { key_handle: key_handle.clone(), last: 6, current: 3 }]);
+        assert!(warnings.take().is_empty());
+
+        // So does a device that needed another INIT.
+        let mut devices = [TestDevice::new()];
+        devices[0].set_cid(&[1, 2, 3, 4]);
+        devices[0].set_init_failures(1);
+        assert!(poll_devices(devices.iter_mut(), &counting_rng(), &warnings, &|_: &mut TestDevice| None::<io::Result<()>>).is_none());
+        assert_eq!(warnings.take(), vec![Warning::InitRetried { info: Box::new(devices[0].get_device_info()), failures: 1 }]);
+    }
+
+    // Uses hidraw's error codes.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_drop_unplugged_device() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+
+        // The device is gone by the time we send INIT.
+        let mut devices = HashMap::new();
+        let mut device = TestDevice::new();
+        device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        device.read_error = Some(libc::ENODEV);
+        devices.insert("hidraw0", device);
+
+        assert!(poll_devices(devices.values_mut(), &counting_rng(), &Warnings::new(), &poll).is_none());
+        assert!(devices["hidraw0"].disconnected());
+        assert_eq!(devices["hidraw0"].init_failures(), 0);
+        assert_eq!(disconnected(&devices), vec!["hidraw0"]);
+
+        // Same for one that goes away in the middle of a command, its
+        // channel is dropped too.
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        device.read_error = Some(libc::ENXIO);
+        devices.insert("hidraw1", device);
+        devices.remove("hidraw0");
+
+        assert!(poll_devices(devices.values_mut(), &counting_rng(), &Warnings::new(), &poll).is_none());
+        assert_eq!(devices["hidraw1"].get_cid(), CID_BROADCAST);
+        assert_eq!(disconnected(&devices), vec!["hidraw1"]);
+        assert_eq!(*last_status.lock().unwrap(), None);
+    }
+
+    // Uses the Linux test backend.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_remove_unplugged_device() {
+        let bench = TestBench::new();
+        let mut device = TestDevice::new();
+        device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        device.read_error = Some(libc::ENODEV);
+        let device = bench.attach("test:0", device);
+
+        // It's gone by the time we send INIT, and dropped from the map while
+        // the operation carries on.
+        let (tx, rx) = channel();
+        let filter = DeviceFilter { backend: Some(String::from("test")), ..DeviceFilter::default() };
+        let mut sm = StateMachine::new(filter, counting_rng(), SharedState::new());
+        sm.register(vec![0x11; 32], vec![0x22; 32], OperationOptions::with_timeout(10), OnceCallback::new(move |rv| {
+            tx.send(rv).unwrap();
+        }));
+        wait_until(|| bench.opened() == vec!["test:0"]);
+        wait_until(|| !bench.is_open("test:0"));
+        assert!(rx.try_recv().is_err());
+        assert!(device.lock().unwrap().expected_writes.is_empty());
+
+        sm.cancel();
+        match rx.recv().unwrap().unwrap_err() {
+            U2FError::Cancelled => {}
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(bench.opened(), vec!["test:0"]);
+    }
+
+    #[test]
+    fn test_query_versions() {
+        let mut devices = [TestDevice::new(), TestDevice::new(), TestDevice::new()];
+        for (device, serial) in devices.iter_mut().zip(&["one", "two", "three"]) {
+            device.set_cid(&[1, 2, 3, 4]);
+            device.info.serial_number = Some(serial.to_string());
+            device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        }
+        devices[0].add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+        devices[1].add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x33, 0x90, 0x00]);
+        devices[2].add_message_read(U2FHID_MSG, &[0x6d, 0x00]);
+
+        let versions = query_versions(devices.iter_mut(), &counting_rng());
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[0].0.serial_number, Some("one".to_owned()));
+        assert_eq!(versions[0].1.as_ref().unwrap(), "U2F_V2");
+        assert_eq!(versions[1].0.serial_number, Some("two".to_owned()));
+        assert_eq!(versions[1].1.as_ref().unwrap(), "U2F_V3");
+        assert!(versions[2].1.is_err());
+        assert!(devices.iter().all(|device| device.expected_writes.is_empty()));
+    }
+
+    #[test]
+    fn test_preferred_first() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+
+        // Both devices would register, the second one is the issued model.
+        let mut devices = [TestDevice::new(), TestDevice::new()];
+        for (device, pid) in devices.iter_mut().zip(&[0x0001, 0x0002]) {
+            device.set_cid(&[1, 2, 3, 4]);
+            device.info.vendor_id = Some(0x1050);
+            device.info.product_id = Some(*pid);
+            device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+            device.add_message_read(U2FHID_MSG, &[0x05, *pid as u8, 0x90, 0x00]);
+        }
+
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+
+        let round = preferred_first(devices.iter_mut().collect(), SelectionPolicy::PreferDevice(0x1050, 0x0002));
+        let rv = poll_devices(round.into_iter(), &counting_rng(), &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x02, 0x90, 0x00]);
+        assert!(devices[1].expected_writes.is_empty());
+
+        // By default the first one asked wins. It wasn't asked before.
+        let round = preferred_first(devices.iter_mut().collect(), SelectionPolicy::default());
+        let rv = poll_devices(round.into_iter(), &counting_rng(), &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x01, 0x90, 0x00]);
+        assert!(devices[0].expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_lazy_init() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let cid = [0x00, 0x03, 0x00, 0x14];
+        let rng = counting_rng();
+
+        // Nothing is sent until the device is first polled, any write would
+        // panic here.
+        let mut devices = [TestDevice::new()];
+
+        // INIT on the broadcast channel, then INIT, PING, VERSION and the
+        // actual command on the allocated one.
+        {
+            let device = &mut devices[0];
+            device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+            device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+            device.set_cid(&cid);
+            device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+            device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+            device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+            device.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+            device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+            device.add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+            device.set_cid(&CID_BROADCAST);
+        }
+
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+        assert!(poll_devices(devices.iter_mut(), &rng, &Warnings::new(), &poll).is_none());
+        assert_eq!(devices[0].get_cid(), cid);
+        assert!(devices[0].expected_writes.is_empty());
+
+        // The channel is reused for later commands.
+        devices[0].add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        devices[0].add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+        let rv = poll_devices(devices.iter_mut(), &rng, &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+        assert!(devices[0].expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_process_events_bounded() {
+        let (tx, rx) = channel();
+        for i in 0..1000 {
+            tx.send(i).unwrap();
+        }
+
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        device.add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+
+        // A single round handles a bounded number of events...
+        let mut processed = Vec::new();
+        process_events(rx.try_iter(), 16, |event| processed.push(event));
+        assert_eq!(processed, (0..16).collect::<Vec<_>>());
+
+        // ... so the device is polled right after.
+        let last_status = Mutex::new(None);
+        let rv = poll_devices([device].iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        });
+        assert!(rv.unwrap().is_ok());
+
+        // The remaining events are still queued for later rounds.
+        assert_eq!(rx.try_iter().next(), Some(16));
+        assert_eq!(rx.try_iter().count(), 983);
+    }
+
+    // Uses hidraw's device paths.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_device_events_reset_idle_timer() {
+        use std::ffi::OsString;
+
+        let filter = DeviceFilter::default();
+        let mut devices = DeviceMap::new(filter.clone(), None);
+        let mut gate = SnapshotGate::new(&filter);
+        let idle = IdleTimer::new(Duration::from_millis(50), None);
+        thread::sleep(Duration::from_millis(60));
+        assert!(idle.expired(Instant::now()));
+
+        // A snapshot is no sign of the user.
+        let events = vec![Event::Snapshot(vec![])];
+        process_device_events(&mut devices, &mut gate, Some(&idle), events.into_iter(), 16);
+        assert!(idle.expired(Instant::now()));
+
+        // A device going away is, even one we didn't use.
+        let events = vec![Event::Remove(OsString::from("/dev/u2f-test-device"))];
+        process_device_events(&mut devices, &mut gate, Some(&idle), events.into_iter(), 16);
+        assert!(!idle.expired(Instant::now()));
+
+        // So is one being added, once the clock ran out again.
+        thread::sleep(Duration::from_millis(60));
+        assert!(idle.expired(Instant::now()));
+        let events = vec![Event::Add(OsString::from("/dev/u2f-test-device"))];
+        process_device_events(&mut devices, &mut gate, Some(&idle), events.into_iter(), 16);
+        assert!(!idle.expired(Instant::now()));
+    }
+
+    #[test]
+    fn test_paused() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+
+        // Any write would panic while paused.
+        let paused = AtomicBool::new(true);
+        let mut devices = [TestDevice::new()];
+        devices[0].set_cid(&[1, 2, 3, 4]);
+        assert!(poll_unless_paused(&paused, devices.iter_mut(), &counting_rng(), &Warnings::new(), &poll).is_none());
+
+        // Polling continues once resumed.
+        paused.store(false, Ordering::SeqCst);
+        devices[0].add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        devices[0].add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+        let rv = poll_unless_paused(&paused, devices.iter_mut(), &counting_rng(), &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+    }
+
+    #[test]
+    fn test_max_devices() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+
+        // Any write to the older devices would panic.
+        let mut devices = HashMap::new();
+        let mut added = HashMap::new();
+        for i in 0..10u8 {
+            let mut device = TestDevice::new();
+            device.set_cid(&[i, 2, 3, 4]);
+            devices.insert(i, device);
+            added.insert(i, i as u64);
+        }
+
+        // Only the newest device is commanded, round after round.
+        for _ in 0..2 {
+            let device = devices.get_mut(&9).unwrap();
+            device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+            device.add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+
+            let round = newest_first(&mut devices, &added, 0..10, 1);
+            assert!(poll_devices(round.into_iter(), &counting_rng(), &Warnings::new(), &poll).is_none());
+            assert!(devices[&9].expected_writes.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_reinsert_gate() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+
+        // Nothing is used before we know which devices were there already.
+        let filter = DeviceFilter { require_reinsert: true, ..DeviceFilter::default() };
+        let mut gate = SnapshotGate::new(&filter);
+        let mut devices = HashMap::new();
+        let mut added = HashMap::new();
+        devices.insert("hidraw0", TestDevice::new());
+        added.insert("hidraw0", 0);
+        assert!(gate.required());
+        assert_eq!(gate.generations(), None);
+
+        // Any write to the device that was there from the start would panic.
+        gate.snapshot_done(1);
+        devices.get_mut("hidraw0").unwrap().set_cid(&[1, 2, 3, 4]);
+        let round = newest_first(&mut devices, &added, gate.generations().unwrap(), usize::MAX);
+        assert!(poll_devices(round.into_iter(), &counting_rng(), &Warnings::new(), &poll).is_none());
+
+        // Once it's removed and added again, it's used.
+        devices.remove("hidraw0");
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        device.add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+        devices.insert("hidraw0", device);
+        added.insert("hidraw0", 1);
+
+        let round = newest_first(&mut devices, &added, gate.generations().unwrap(), usize::MAX);
+        let rv = poll_devices(round.into_iter(), &counting_rng(), &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+
+        // Later snapshots don't matter, and without the option all devices
+        // are used right away.
+        gate.snapshot_done(5);
+        assert_eq!(gate.generations(), Some(1..u64::MAX));
+        let gate = SnapshotGate::new(&DeviceFilter::default());
+        assert!(!gate.required());
+        assert_eq!(gate.generations(), Some(0..u64::MAX));
+    }
+
+    #[test]
+    fn test_present_at_start_gate() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+
+        let filter = DeviceFilter { present_at_start: true, ..DeviceFilter::default() };
+        let mut gate = SnapshotGate::new(&filter);
+        let mut default_gate = SnapshotGate::new(&DeviceFilter::default());
+        assert!(gate.required());
+        gate.snapshot_done(1);
+        default_gate.snapshot_done(1);
+
+        // A device that was plugged in while the user was prompted.
+        let mut devices = HashMap::new();
+        let mut added = HashMap::new();
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        devices.insert("hidraw1", device);
+        added.insert("hidraw1", 1);
+
+        // Any write to it would panic.
+        let round = newest_first(&mut devices, &added, gate.generations().unwrap(), usize::MAX);
+        assert!(poll_devices(round.into_iter(), &counting_rng(), &Warnings::new(), &poll).is_none());
+
+        // By default, it's used.
+        let device = devices.get_mut("hidraw1").unwrap();
+        device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        device.add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+        let round = newest_first(&mut devices, &added, default_gate.generations().unwrap(), usize::MAX);
+        let rv = poll_devices(round.into_iter(), &counting_rng(), &Warnings::new(), &poll);
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+
+        // Devices that were there at the start stay usable.
+        assert_eq!(gate.generations(), Some(0..1));
+    }
+
+    fn state_machine() -> StateMachine {
+        StateMachine::new(DeviceFilter::default(), counting_rng(), SharedState::new())
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (tx, rx) = channel();
+        let mut sm = state_machine();
+        sm.register(vec![0x11; 32], vec![0x22; 32], OperationOptions::with_timeout(1), OnceCallback::new(move |rv| {
+            tx.send(rv).unwrap();
+        }));
+
+        let err = rx.recv().unwrap().unwrap_err();
+        match err {
+            U2FError::Timeout => {}
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    // Keeps the messages logged on each thread, for tests that check what
+    // their own thread logs.
+    thread_local!(static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) });
+
+    fn captured_logs() -> Vec<String> {
+        LOGS.with(|logs| logs.borrow().clone())
+    }
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::LogMetadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::LogRecord) {
+            LOGS.with(|logs| logs.borrow_mut().push(record.args().to_string()));
+        }
+    }
+
+    // Only the first test to get here installs the logger.
+    fn capture_logs() {
+        let _ = log::set_logger(|max_log_level| {
+            max_log_level.set(log::LogLevelFilter::Trace);
+            Box::new(CaptureLogger)
+        });
+    }
+
+    #[test]
+    fn test_correlation_id() {
+        capture_logs();
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+
+        // A device that gets its channel and isn't touched.
+        let mut devices = [TestDevice::new()];
+        {
+            let device = &mut devices[0];
+            device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+            device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+            device.set_cid(&[0x00, 0x03, 0x00, 0x14]);
+            device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+            device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+            device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+            device.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+            device.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+            device.add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+            device.set_cid(&CID_BROADCAST);
+            device.set_context(OperationContext { correlation_id: Some(String::from("op-42")), ..OperationContext::default() });
+        }
+
+        let last_status = Mutex::new(None);
+        let poll = |device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        };
+        assert!(poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &poll).is_none());
+
+        let logs = captured_logs();
+        assert!(logs.iter().any(|msg| msg.starts_with("[op-42] 00030014: initialized in ")));
+        assert!(logs.iter().any(|msg| msg.starts_with("[op-42] 00030014: command 0x83: ")));
+    }
+
+    #[test]
+    fn test_round_log() {
+        let mut rounds = RoundLog::new();
+        let quiet = rounds.quiet();
+        let start = Instant::now();
+
+        // Devices waiting for a touch for 2.5s, polled every 100ms, then
+        // touched, then waiting again.
+        let mut logged = 0;
+        for round in 0..25 {
+            rounds.start(None, start + Duration::from_millis(round * 100), Some(0x6985));
+            if !quiet.load(Ordering::SeqCst) {
+                logged += 1;
+            }
+        }
+        assert_eq!(logged, 3);
+        rounds.start(None, start + Duration::from_millis(2500), Some(0x6a80));
+        assert!(!quiet.load(Ordering::SeqCst));
+        rounds.start(None, start + Duration::from_millis(2600), Some(0x6985));
+        assert!(!quiet.load(Ordering::SeqCst));
+        rounds.start(None, start + Duration::from_millis(2700), Some(0x6985));
+        assert!(quiet.load(Ordering::SeqCst));
+
+        // Nothing from a device yet.
+        rounds.start(None, start + Duration::from_millis(2800), None);
+        assert!(!quiet.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (tx, rx) = channel();
+        let mut sm = state_machine();
+        sm.register(vec![0x11; 32], vec![0x22; 32], OperationOptions::with_timeout(10), OnceCallback::new(move |rv| {
+            tx.send(rv).unwrap();
+        }));
+        sm.cancel();
+
+        let err = rx.recv().unwrap().unwrap_err();
+        match err {
+            U2FError::Cancelled => {}
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_bad_device_is_isolated() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+
+        // The first device answers with garbage: a new message in the middle
+        // of the answer.
+        let mut bad = TestDevice::new();
+        bad.set_cid(&[1, 2, 3, 4]);
+        bad.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        bad.add_read(&[1, 2, 3, 4, U2FHID_MSG, 0x00, 0x80], 0);
+        bad.add_read(&[1, 2, 3, 4, U2FHID_MSG, 0x00, 0x02], 0);
+
+        let mut good = TestDevice::new();
+        good.set_cid(&[1, 2, 3, 5]);
+        good.add_message_write(U2FHID_MSG, &register_apdu(&challenge, &application));
+        good.add_message_read(U2FHID_MSG, &[0x05, 0x04, 0x90, 0x00]);
+
+        let mut devices = [bad, good];
+        let last_status = Mutex::new(None);
+        let rv = poll_devices(devices.iter_mut(), &counting_rng(), &Warnings::new(), &|device: &mut TestDevice| {
+            try_register(device, &challenge, &application, &last_status)
+        });
+        assert_eq!(rv.unwrap().unwrap(), vec![0x05, 0x04, 0x90, 0x00]);
+
+        // The bad device will get a fresh channel next time.
+        assert_eq!(devices[0].get_cid(), CID_BROADCAST);
+        assert_eq!(devices[1].get_cid(), [1, 2, 3, 5]);
+        assert_eq!(*last_status.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn test_cancel_pending() {
+        let mut pending = TestDevice::new();
+        pending.set_cid(&[1, 2, 3, 4]);
+        pending.add_message_write(U2FHID_CANCEL, &[]);
+
+        // This one never got a channel, it would panic if written to.
+        let idle = TestDevice::new();
+
+        let mut devices = [pending, idle];
+        cancel_pending(devices.iter_mut());
+        assert!(devices[0].expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_check_credential() {
+        let application = vec![0x22; 32];
+        let key_handle = KeyHandle::from(vec![0x33; 64]);
+
+        let mut data = vec![0u8; 32];
+        data.extend(&application);
+        data.push(key_handle.len() as u8);
+        data.extend(key_handle.as_bytes());
+        let check = apdu(U2F_AUTHENTICATE, U2F_CHECK_IS_REGISTERED, &data);
+
+        // Owners answer with "test of user presence required".
+        let mut owner = TestDevice::new();
+        owner.set_cid(&[1, 2, 3, 4]);
+        owner.add_message_write(U2FHID_MSG, &check);
+        owner.add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+
+        // Everyone else says "bad key handle".
+        let mut other = TestDevice::new();
+        other.set_cid(&[1, 2, 3, 5]);
+        other.add_message_write(U2FHID_MSG, &check);
+        other.add_message_read(U2FHID_MSG, &[0x6a, 0x80]);
+
+        let last_status = Mutex::new(None);
+        assert!(try_check_credential(&mut other, &application, &key_handle, &last_status).is_none());
+        assert!(try_check_credential(&mut owner, &application, &key_handle, &last_status).unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_sign_remaining() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let key_handles: Vec<KeyHandle> = (0..3).map(|i| KeyHandle::from(vec![0x30 + i; 64])).collect();
+        let request = |key_handle: &KeyHandle, p1| {
+            let mut data = challenge.clone();
+            data.extend(&application);
+            data.push(key_handle.len() as u8);
+            data.extend(key_handle.as_bytes());
+            apdu(U2F_AUTHENTICATE, p1, &data)
+        };
+
+        // The first device owns the first key handle and gets touched right
+        // away.
+        let mut first = TestDevice::new();
+        first.set_cid(&[1, 2, 3, 4]);
+        first.add_message_write(U2FHID_MSG, &request(&key_handles[0], U2F_CHECK_IS_REGISTERED));
+        first.add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+        first.add_message_write(U2FHID_MSG, &request(&key_handles[0], U2F_REQUEST_USER_PRESENCE));
+        first.add_message_read(U2FHID_MSG, &[0x01, 0x02, 0x90, 0x00]);
+
+        // The second one owns the last key handle, and is touched in the
+        // second round. Nobody owns the one in the middle.
+        let mut second = TestDevice::new();
+        second.set_cid(&[1, 2, 3, 5]);
+        for sw in &[&[0x69, 0x85][..], &[0x03, 0x04, 0x90, 0x00]] {
+            second.add_message_write(U2FHID_MSG, &request(&key_handles[1], U2F_CHECK_IS_REGISTERED));
+            second.add_message_read(U2FHID_MSG, &[0x6a, 0x80]);
+            second.add_message_write(U2FHID_MSG, &request(&key_handles[2], U2F_CHECK_IS_REGISTERED));
+            second.add_message_read(U2FHID_MSG, &[0x69, 0x85]);
+            second.add_message_write(U2FHID_MSG, &request(&key_handles[2], U2F_REQUEST_USER_PRESENCE));
+            second.add_message_read(U2FHID_MSG, sw);
+        }
+
+        let mut signed = vec![None; 3];
+        let last_status = Mutex::new(None);
+        assert!(try_sign_remaining(&mut first, &challenge, &application, &key_handles, &mut signed, &last_status));
+        assert!(!try_sign_remaining(&mut second, &challenge, &application, &key_handles, &mut signed, &last_status));
+        assert_eq!(*last_status.lock().unwrap(), Some(0x6985));
+        assert!(try_sign_remaining(&mut second, &challenge, &application, &key_handles, &mut signed, &last_status));
+
+        // Nothing is left for the first device to sign.
+        first.add_message_write(U2FHID_MSG, &request(&key_handles[1], U2F_CHECK_IS_REGISTERED));
+        first.add_message_read(U2FHID_MSG, &[0x6a, 0x80]);
+        assert!(!try_sign_remaining(&mut first, &challenge, &application, &key_handles, &mut signed, &last_status));
+
+        assert!(first.expected_writes.is_empty() && second.expected_writes.is_empty());
+        assert_eq!(signed, vec![Some(vec![0x01, 0x02, 0x90, 0x00]), None, Some(vec![0x03, 0x04, 0x90, 0x00])]);
+    }
+
+    #[test]
+    fn test_process_until_snapshot() {
+        let mut devices = DeviceMap::new(DeviceFilter::default(), None);
+        assert!(!process_until_snapshot(&mut devices, vec![].into_iter()));
+
+        // Events after the snapshot stay queued.
+        let mut events = vec![Event::Snapshot(vec![]), Event::Snapshot(vec![])].into_iter();
+        assert!(process_until_snapshot(&mut devices, &mut events));
+        assert_eq!(events.len(), 1);
+        assert_eq!(devices.len(), 0);
+    }
+
+    #[test]
+    fn test_device_count() {
+        let (tx, rx) = channel();
+        let device_count = Arc::new(Mutex::new(None));
+        let shared = SharedState { device_count: device_count.clone(), ..SharedState::new() };
+        let mut sm = StateMachine::new(DeviceFilter::default(), counting_rng(), shared);
+
+        // There are no devices in the test environment.
+        sm.register(vec![0x11; 32], vec![0x22; 32], OperationOptions::with_timeout(1), OnceCallback::new(move |rv| {
+            tx.send(rv).unwrap();
+        }));
+        while device_count.lock().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*device_count.lock().unwrap(), Some(0));
+
+        // Nothing is tracked once the operation is over.
+        assert!(rx.recv().unwrap().is_err());
+        assert_eq!(*device_count.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn test_probe_applications() {
+        let applications = [[0x21; 32], [0x22; 32], [0x23; 32]];
+        let key_handle = KeyHandle::from(vec![0x33; 64]);
+
+        // The key handle was registered under the second application.
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        for (application, sw) in applications[..2].iter().zip(&[[0x6a, 0x80], [0x69, 0x85]]) {
+            let mut data = vec![0u8; 32];
+            data.extend(&application[..]);
+            data.push(key_handle.len() as u8);
+            data.extend(key_handle.as_bytes());
+            device.add_message_write(U2FHID_MSG, &apdu(U2F_AUTHENTICATE, U2F_CHECK_IS_REGISTERED, &data));
+            device.add_message_read(U2FHID_MSG, sw);
+        }
+
+        let last_status = Mutex::new(None);
+        let rv = try_probe_applications(&mut device, &key_handle, &applications, &last_status);
+        assert_eq!(rv.unwrap().unwrap(), Some(1));
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_has_credential_no_devices() {
+        let (tx, rx) = channel();
+        let mut sm = state_machine();
+        sm.has_credential(vec![0x22; 32], KeyHandle::from(vec![0x33; 64]), OnceCallback::new(move |rv| {
+            tx.send(rv).unwrap();
+        }));
+        assert!(!rx.recv().unwrap().unwrap());
+    }
+}
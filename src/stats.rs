@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use u2fprotocol::U2FDevice;
+use u2ftypes::DeviceInfo;
+
+// How a device fared over the lifetime of a manager, see
+// `U2FManager::device_stats()`. Many errors point at bad hardware or a bad
+// port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    // Commands sent, INITs included.
+    pub commands: u64,
+    // Commands that got no proper answer, timeouts included. Status words
+    // aren't errors, the device did answer.
+    pub errors: u64,
+    // Errors that were timeouts.
+    pub timeouts: u64,
+    // Channels allocated again after the device lost its previous one.
+    pub reinits: u64
+}
+
+// Tells devices apart across operations: by path, or on macOS, where there
+// are none, by IOKit registry entry ID.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum DeviceKey {
+    Path(String),
+    Registry(u64)
+}
+
+impl DeviceKey {
+    fn of(info: &DeviceInfo) -> Option<Self> {
+        match (info.path.as_ref(), info.registry_id) {
+            (Some(path), _) => Some(DeviceKey::Path(path.clone())),
+            (None, Some(id)) => Some(DeviceKey::Registry(id)),
+            (None, None) => None
+        }
+    }
+}
+
+// The stats of every device seen, see `DeviceKey`. Devices we can't tell
+// apart aren't recorded. Shared by the manager and the devices of its
+// operations, through their `OperationContext`.
+#[derive(Clone, Default)]
+pub struct DeviceStatsMap {
+    devices: Arc<Mutex<HashMap<DeviceKey, (DeviceInfo, DeviceStats)>>>
+}
+
+impl DeviceStatsMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update<T, F>(&self, device: &T, update: F)
+        where T: U2FDevice, F: FnOnce(&mut DeviceStats)
+    {
+        let info = device.get_device_info();
+        let key = match DeviceKey::of(&info) {
+            Some(key) => key,
+            None => return
+        };
+        if let Ok(mut devices) = self.devices.lock() {
+            let entry = devices.entry(key).or_insert_with(|| (info.clone(), DeviceStats::default()));
+            // Keep what we know last, e.g. the channel.
+            entry.0 = info;
+            update(&mut entry.1);
+        }
+    }
+
+    // Fills in what INIT told us about the device, if operations talked to
+    // it and it's still the same model. Listing devices doesn't INIT them,
+    // that could disturb an ongoing operation.
+    pub fn fill_in(&self, info: &mut DeviceInfo) {
+        let key = match DeviceKey::of(info) {
+            Some(key) => key,
+            None => return
+        };
+        let devices = match self.devices.lock() {
+            Ok(devices) => devices,
+            Err(_) => return
+        };
+        if let Some((known, _)) = devices.get(&key) {
+            if known.vendor_id == info.vendor_id && known.product_id == info.product_id && known.protocol_version.is_some() {
+                info.capabilities = known.capabilities;
+                info.protocol_version = known.protocol_version;
+                info.device_version = known.device_version;
+            }
+        }
+    }
+
+    // All devices seen so far, by path or registry entry ID.
+    pub fn snapshot(&self) -> Vec<(DeviceInfo, DeviceStats)> {
+        let mut devices: Vec<(DeviceKey, (DeviceInfo, DeviceStats))> = match self.devices.lock() {
+            Ok(devices) => devices.iter().map(|(key, entry)| (key.clone(), entry.clone())).collect(),
+            Err(_) => return Vec::new()
+        };
+        devices.sort_by(|a, b| a.0.cmp(&b.0));
+        devices.into_iter().map(|(_, entry)| entry).collect()
+    }
+}
+
+fn with_device_stats<T, F>(device: &T, fun: F)
+    where T: U2FDevice, F: FnOnce(&DeviceStatsMap)
+{
+    if let Some(stats) = device.context().and_then(|context| context.stats.as_ref()) {
+        fun(stats);
+    }
+}
+
+// Records a command sent to `device`, and whether it was answered.
+pub fn record_command<T, R>(device: &T, rv: &io::Result<R>)
+    where T: U2FDevice
+{
+    with_device_stats(device, |stats| stats.update(device, |stats| {
+        stats.commands += 1;
+        if let Err(ref e) = *rv {
+            stats.errors += 1;
+            if e.kind() == io::ErrorKind::TimedOut {
+                stats.timeouts += 1;
+            }
+        }
+    }));
+}
+
+// Keeps what INIT just told us about `device`, see `DeviceStatsMap::fill_in()`.
+pub fn record_init<T>(device: &T)
+    where T: U2FDevice
+{
+    with_device_stats(device, |stats| stats.update(device, |_| {}));
+}
+
+// Records that `device` got a new channel after losing its previous one.
+pub fn record_reinit<T>(device: &T)
+    where T: U2FDevice
+{
+    with_device_stats(device, |stats| stats.update(device, |stats| stats.reinits += 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceStatsMap;
+    use consts::U2FHID_INIT;
+    use testdevice::TestDevice;
+    use u2fprotocol::{U2FDevice, init_device};
+    use u2ftypes::OperationContext;
+
+    #[test]
+    fn test_fill_in() {
+        let stats = DeviceStatsMap::new();
+
+        let init = [0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01];
+        let mut device = TestDevice::new();
+        device.set_context(OperationContext { stats: Some(stats.clone()), ..OperationContext::default() });
+        let mut info = device.get_device_info();
+        info.path = Some(String::from("/dev/hidraw3"));
+        info.vendor_id = Some(0x1050);
+        device.set_device_info(info.clone());
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        init_device(&mut device, [0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+
+        // Listed again, without INIT.
+        let mut listed = info.clone();
+        stats.fill_in(&mut listed);
+        assert_eq!(listed.protocol_version, Some(0x02));
+        assert_eq!(listed.device_version, Some([0x04, 0x01, 0x08]));
+        assert_eq!(listed.capabilities, 0x01);
+        assert_eq!(listed.channel_id, None);
+
+        // Another device on the same path, or one we never talked to.
+        let mut other = info.clone();
+        other.vendor_id = Some(0x096e);
+        stats.fill_in(&mut other);
+        assert_eq!(other.protocol_version, None);
+        let mut unknown = info.clone();
+        unknown.path = Some(String::from("/dev/hidraw4"));
+        stats.fill_in(&mut unknown);
+        assert_eq!(unknown.protocol_version, None);
+
+        // The same goes for macOS devices, by registry entry ID.
+        let mut device = TestDevice::new();
+        device.set_context(OperationContext { stats: Some(stats.clone()), ..OperationContext::default() });
+        let mut info = device.get_device_info();
+        info.registry_id = Some(0x1000_0521);
+        device.set_device_info(info.clone());
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        init_device(&mut device, [0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        let mut listed = info.clone();
+        stats.fill_in(&mut listed);
+        assert_eq!(listed.protocol_version, Some(0x02));
+        let mut unknown = info.clone();
+        unknown.registry_id = Some(0x1000_0522);
+        stats.fill_in(&mut unknown);
+        assert_eq!(unknown.protocol_version, None);
+    }
+}
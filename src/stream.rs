@@ -0,0 +1,60 @@
+use futures::{Poll, Stream};
+use futures::sync::mpsc::UnboundedReceiver;
+
+use runloop::RunLoop;
+use u2ftypes::DeviceEvent;
+
+// Devices as they're added or removed, see
+// `U2FManager::device_event_stream()`. Dropping the stream stops watching.
+pub struct DeviceEventStream {
+    events: UnboundedReceiver<DeviceEvent>,
+    watcher: RunLoop
+}
+
+impl DeviceEventStream {
+    pub(crate) fn new(events: UnboundedReceiver<DeviceEvent>, watcher: RunLoop) -> Self {
+        Self { events, watcher }
+    }
+}
+
+impl Stream for DeviceEventStream {
+    type Item = DeviceEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<DeviceEvent>, ()> {
+        self.events.poll()
+    }
+}
+
+impl Drop for DeviceEventStream {
+    fn drop(&mut self) {
+        self.watcher.cancel();
+    }
+}
+
+// Uses the Linux test backend.
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use futures::Stream;
+    use manager::U2FManagerBuilder;
+    use platform::testbench::{wait_until, TestBench};
+    use testdevice::TestDevice;
+    use u2ftypes::{DeviceEvent, DeviceInfo};
+
+    #[test]
+    fn test_device_event_stream() {
+        let bench = TestBench::new();
+        bench.attach("test:0", TestDevice::new());
+        let manager = U2FManagerBuilder::new().backend("test").build().unwrap();
+
+        // Devices that are there already are reported first.
+        let mut events = manager.device_event_stream().unwrap().wait();
+        let info = DeviceInfo { path: Some(String::from("test:0")), ..DeviceInfo::default() };
+        assert_eq!(events.next(), Some(Ok(DeviceEvent::Added(info))));
+        assert!(bench.is_open("test:0"));
+
+        // Dropping the stream stops the watcher, which lets go of the device.
+        drop(events);
+        wait_until(|| !bench.is_open("test:0"));
+    }
+}
@@ -0,0 +1,278 @@
+use cbor::Value;
+use consts::{CID_BROADCAST, ERR_CHANNEL_BUSY, HID_RPT_SIZE, TYPE_INIT, U2FAPDUHEADER_SIZE, U2FHID_ERROR, U2FHID_INIT, U2FHID_MSG,
+             U2FHID_PING, U2F_VERSION};
+use rand::Rng;
+use u2fprotocol::U2FDevice;
+use u2ftypes::{DeviceInfo, FrameObserver, OperationContext};
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+// Size of data chunk in U2F Init and Cont USB HID Packets.
+const INIT_DATA_SIZE : usize = HID_RPT_SIZE - 7;
+const CONT_DATA_SIZE : usize = HID_RPT_SIZE - 5;
+
+// A fake device that checks every write against a list of expected packets,
+// and answers reads with a list of canned packets.
+pub struct TestDevice {
+    pub cid: [u8; 4],
+    pub info: DeviceInfo,
+    pub expected_reads: Vec<[u8; HID_RPT_SIZE]>,
+    pub expected_writes: Vec<[u8; HID_RPT_SIZE + 1]>,
+    pub observer: Option<FrameObserver>,
+    pub context: Option<OperationContext>,
+    pub init_failures: u32,
+    // Takes any write and never answers, reads time out.
+    pub mute: bool,
+    pub disconnected: bool,
+    // Reads fail with this OS error, e.g. ENODEV once it's unplugged.
+    pub read_error: Option<i32>,
+    // Commands sent within this long after an INIT are answered with
+    // ERR_CHANNEL_BUSY instead of the canned packets.
+    pub settle_time: Option<Duration>,
+    last_init: Option<Instant>,
+    busy: bool,
+}
+
+impl TestDevice {
+    pub fn new() -> TestDevice {
+        TestDevice {
+            cid: CID_BROADCAST,
+            info: DeviceInfo::default(),
+            expected_reads: Vec::new(),
+            expected_writes: Vec::new(),
+            observer: None,
+            context: None,
+            init_failures: 0,
+            mute: false,
+            disconnected: false,
+            read_error: None,
+            settle_time: None,
+            last_init: None,
+            busy: false
+        }
+    }
+    pub fn add_write(&mut self, packet: &[u8], fill_value: u8) {
+        // Add one to deal with record index check
+        let mut write : [u8; HID_RPT_SIZE + 1] = [fill_value; HID_RPT_SIZE + 1];
+        // Make sure we start with a 0, for HID record index
+        write[0] = 0;
+        // Clone packet data in at 1, since front is padded with HID record index
+        write[1..packet.len() + 1].clone_from_slice(packet);
+        self.expected_writes.push(write);
+    }
+    pub fn add_read(&mut self, packet: &[u8], fill_value: u8) {
+        let mut read : [u8; HID_RPT_SIZE] = [fill_value; HID_RPT_SIZE];
+        read[0..packet.len()].clone_from_slice(packet);
+        self.expected_reads.push(read);
+    }
+
+    // Expect a whole message to be written on the current channel, split into
+    // an init packet and as many continuation packets as needed.
+    pub fn add_message_write(&mut self, cmd: u8, data: &[u8]) {
+        for packet in self.frame(cmd, data) {
+            self.add_write(&packet, 0);
+        }
+    }
+
+    // Answer with a whole message on the current channel.
+    pub fn add_message_read(&mut self, cmd: u8, data: &[u8]) {
+        for packet in self.frame(cmd, data) {
+            self.add_read(&packet, 0);
+        }
+    }
+
+    // Expect a fresh device to be given the channel `cid`, by an RNG that
+    // yields 0, 1, 2, ...: INIT on the broadcast channel, then INIT, PING and
+    // VERSION on the new one. Later messages go to `cid`.
+    pub fn expect_init(&mut self, cid: &[u8; 4]) {
+        let mut init = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        init.extend(cid);
+        init.extend(&[0x02, 0x04, 0x01, 0x08, 0x01]);
+        self.add_message_write(U2FHID_INIT, &init[..8]);
+        self.add_message_read(U2FHID_INIT, &init);
+        self.set_cid(cid);
+        self.add_message_write(U2FHID_INIT, &init[..8]);
+        self.add_message_read(U2FHID_INIT, &init);
+        self.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        self.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        self.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        self.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+    }
+
+    fn frame(&self, cmd: u8, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+
+        let mut packet = self.cid.to_vec();
+        packet.extend(&[cmd, (data.len() >> 8) as u8, data.len() as u8]);
+        let (head, mut tail) = data.split_at(cmp::min(INIT_DATA_SIZE, data.len()));
+        packet.extend(head);
+        packets.push(packet);
+
+        let mut seq = 0;
+        while !tail.is_empty() {
+            let (head, rest) = tail.split_at(cmp::min(CONT_DATA_SIZE, tail.len()));
+            let mut packet = self.cid.to_vec();
+            packet.push(seq);
+            packet.extend(head);
+            packets.push(packet);
+            tail = rest;
+            seq += 1;
+        }
+
+        packets
+    }
+}
+
+// An RNG that yields the bytes 0, 1, 2, ... in order.
+pub struct CountingRng(pub u8);
+
+impl Rng for CountingRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        bytes.iter().fold(0, |num, b| (num << 8) | (*b as u32))
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for byte in dest.iter_mut() {
+            *byte = self.0;
+            self.0 = self.0.wrapping_add(1);
+        }
+    }
+}
+
+// Builds the bytes of an APDU like `send_apdu` puts them on the wire.
+pub fn apdu(ins: u8, p1: u8, data: &[u8]) -> Vec<u8> {
+    let mut apdu = vec![0, ins, p1, 0, 0, (data.len() >> 8) as u8, data.len() as u8];
+    assert_eq!(apdu.len(), U2FAPDUHEADER_SIZE);
+    apdu.extend(data);
+    apdu.extend(&[0, 0]);
+    apdu
+}
+
+impl Write for TestDevice {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.mute {
+            return Ok(bytes.len());
+        }
+        // Pop a vector from the expected writes, check for quality
+        // against bytes array.
+        assert!(!self.expected_writes.is_empty(), "Ran out of expected write values!");
+        let check = self.expected_writes.remove(0);
+        assert_eq!(check.len(), bytes.len());
+        assert_eq!(&check[..], bytes);
+
+        // Skip the report ID and the channel.
+        let cmd = bytes[5];
+        if cmd == U2FHID_INIT {
+            self.last_init = Some(Instant::now());
+        } else if cmd & TYPE_INIT != 0 {
+            if let (Some(settle_time), Some(last_init)) = (self.settle_time, self.last_init) {
+                self.busy = last_init.elapsed() < settle_time;
+            }
+        }
+        Ok(bytes.len())
+    }
+    // nop
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Read for TestDevice {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+        if self.mute {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no answer"));
+        }
+        if let Some(code) = self.read_error {
+            return Err(io::Error::from_raw_os_error(code));
+        }
+        if self.busy {
+            self.busy = false;
+            let mut packet = self.cid.to_vec();
+            packet.extend(&[U2FHID_ERROR, 0x00, 0x01, ERR_CHANNEL_BUSY]);
+            bytes[..packet.len()].clone_from_slice(&packet);
+            return Ok(bytes.len());
+        }
+        // Pop a vector from the expected writes, check for quality
+        // against bytes array.
+        assert!(!self.expected_reads.is_empty(), "Ran out of expected read values!");
+        let check = self.expected_reads.remove(0);
+        bytes.clone_from_slice(&check[..]);
+        Ok(check.len())
+    }
+}
+impl U2FDevice for TestDevice {
+    fn get_cid(&self) -> [u8; 4] {
+        self.cid
+    }
+    fn set_cid(&mut self, cid: &[u8; 4]) {
+        self.cid = *cid;
+    }
+    fn get_device_info(&self) -> DeviceInfo {
+        self.info.clone()
+    }
+    fn set_device_info(&mut self, info: DeviceInfo) {
+        self.info = info;
+    }
+    fn frame_observer(&self) -> Option<&FrameObserver> {
+        self.observer.as_ref()
+    }
+    fn set_frame_observer(&mut self, observer: Option<FrameObserver>) {
+        self.observer = observer;
+    }
+    fn context(&self) -> Option<&OperationContext> {
+        self.context.as_ref()
+    }
+    fn set_context(&mut self, context: OperationContext) {
+        self.context = Some(context);
+    }
+    fn init_failures(&self) -> u32 {
+        self.init_failures
+    }
+    fn set_init_failures(&mut self, failures: u32) {
+        self.init_failures = failures;
+    }
+    fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+    fn set_disconnected(&mut self) {
+        self.disconnected = true;
+    }
+}
+
+// The key agreement key of a FIDO2 token, for the private key 0x5a5a...5a.
+// The vectors below were made with it and the key of ours that CountingRng
+// gives us.
+pub const TOKEN_X: [u8; 32] = [
+    0xf6, 0x8b, 0x1d, 0xe0, 0xf5, 0xce, 0x68, 0x61, 0x92, 0xf4, 0xbd, 0x0f, 0xd3, 0x41, 0x63, 0x86,
+    0x3f, 0x2e, 0x89, 0xd3, 0x85, 0xf8, 0xbc, 0xfb, 0x85, 0x97, 0x95, 0x33, 0xa5, 0x7a, 0xc9, 0xb0];
+pub const TOKEN_Y: [u8; 32] = [
+    0x4f, 0x02, 0x2a, 0xb6, 0xb0, 0x96, 0xc8, 0xbf, 0x42, 0x80, 0x4d, 0x8e, 0x67, 0xd3, 0x8b, 0x3f,
+    0x88, 0x86, 0x69, 0x80, 0xcc, 0x90, 0x3f, 0x68, 0xfd, 0xe9, 0xd2, 0xe1, 0x43, 0xd9, 0x32, 0x67];
+
+// The hmac-secret outputs 0x81...81 and 0x82...82, encrypted by the token.
+pub const OUTPUT_ENC: [u8; 64] = [
+    0xdb, 0x35, 0x8c, 0x27, 0xeb, 0x74, 0xd1, 0x14, 0x4a, 0xed, 0x5f, 0xcd, 0x3c, 0xc0, 0x83, 0x4c,
+    0x0e, 0x24, 0xde, 0xc5, 0x53, 0x8c, 0x0c, 0xb1, 0x5f, 0x44, 0x55, 0x76, 0xc5, 0xd5, 0xc1, 0x50,
+    0x21, 0xae, 0x37, 0xe2, 0x44, 0x50, 0x91, 0x16, 0x6e, 0x39, 0xfe, 0xcb, 0x5a, 0xb6, 0xea, 0x00,
+    0x76, 0x97, 0x11, 0xb2, 0x44, 0xcd, 0x53, 0x53, 0x76, 0xdf, 0x75, 0xc5, 0x60, 0x3a, 0x9e, 0x38];
+
+// The PIN "1234", hashed and encrypted with the shared secret.
+pub const PIN_HASH_ENC: [u8; 16] = [
+    0xa3, 0x36, 0x7d, 0x8f, 0x5d, 0x7c, 0xaa, 0x4f, 0xf7, 0xb0, 0xe6, 0xeb, 0x88, 0x60, 0x5b, 0xfd];
+
+// The pinToken 0x91...91, encrypted by the token.
+pub const PIN_TOKEN_ENC: [u8; 32] = [
+    0x47, 0xf0, 0xfa, 0x29, 0x96, 0x0a, 0x50, 0x5c, 0xb4, 0x1c, 0xf7, 0x4a, 0x70, 0x88, 0x95, 0x87,
+    0xb9, 0x70, 0x2d, 0x8b, 0x9e, 0x0f, 0xdf, 0x22, 0x68, 0xae, 0x5b, 0xe7, 0x19, 0x5f, 0xec, 0xd2];
+
+// The token's key agreement key as a COSE_Key, as clientPin(getKeyAgreement)
+// returns it.
+pub fn token_key() -> Value {
+    Value::Map(vec![(Value::Unsigned(1), Value::Unsigned(2)),
+                    (Value::Unsigned(3), Value::Negative(-25)),
+                    (Value::Negative(-1), Value::Unsigned(1)),
+                    (Value::Negative(-2), Value::Bytes(TOKEN_X.to_vec())),
+                    (Value::Negative(-3), Value::Bytes(TOKEN_Y.to_vec()))])
+}
@@ -12,12 +12,14 @@ extern "C" {
 const uint8_t U2F_RESBUF_ID_REGISTRATION = 0;
 const uint8_t U2F_RESBUF_ID_KEYHANDLE = 1;
 const uint8_t U2F_RESBUF_ID_SIGNATURE = 2;
+const uint8_t U2F_RESBUF_ID_APDU_RESPONSE = 3;
 
 // NOTE: Preconditions
 // * All rust_u2f_mgr* pointers must refer to pointers which are returned
 //   by rust_u2f_mgr_new, and must be freed with rust_u2f_mgr_free.
 // * All rust_u2f_res* pointers must refer to pointers passed to the
-//   register() and sign() callbacks. They can be null on failure.
+//   register(), sign() and send_apdu() callbacks, or returned by
+//   list_devices(). They can be null on failure.
 
 // The `rust_u2f_mgr` opaque type is equivalent to the rust type `U2FManager`
 struct rust_u2f_mgr;
@@ -44,8 +46,19 @@ bool rust_u2f_mgr_sign(rust_u2f_mgr* mgr, uint64_t tid, uint64_t timeout,
                        const uint8_t* application_ptr, size_t application_len,
                        const uint8_t* key_handle_ptr, size_t key_handle_len);
 
+// The callback gets the response data and the status word, see capi.rs.
+bool rust_u2f_mgr_send_apdu(rust_u2f_mgr* mgr, uint64_t tid, uint64_t timeout,
+                            void (*callback)(uint64_t, rust_u2f_res*, uint16_t),
+                            uint8_t ins, uint8_t p1,
+                            const uint8_t* data_ptr, size_t data_len);
+
+// Safe to call when no operation is in flight, that's a no-op.
 void rust_u2f_mgr_cancel(rust_u2f_mgr* mgr);
 
+// Blocks for at most a second. Buffer i of the result describes device i,
+// see capi.rs. Returns null on failure.
+rust_u2f_res* rust_u2f_mgr_list_devices(rust_u2f_mgr* mgr, size_t* count);
+
 }
 
 #endif // __U2FHID_CAPI
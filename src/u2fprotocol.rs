@@ -1,9 +1,19 @@
 extern crate std;
 
+use cbor;
 use consts::*;
-use std::{ffi, mem, io, slice};
+use error::U2FError;
+use hmacsecret::{self, SharedSecret};
+use rand::Rng;
+use stats::{record_command, record_init};
+use u2ftypes::{Assertion, AssertionOptions, AttestationConveyance, AttestationObject, AttestationStatement, AuthenticatorInfo, DeviceInfo, Direction, DryRun, FrameObserver, KeyHandle, MakeCredentialOptions, OperationContext, PinStatus, RelyingParty, ResidentCredential, User};
+use util::{from_u8_array, init_settle_delay, report_read_progress, round_logged, to_hex, to_u8_array, to_u8_vec, PhaseTimer};
+use std::{ffi, fmt, io};
+use std::error::Error;
 use std::io::{Read, Write};
 use std::ffi::CString;
+use std::thread;
+use std::time::Duration;
 
 use log;
 
@@ -17,7 +27,7 @@ const CONT_DATA_SIZE : usize = HID_RPT_SIZE - 5;
 // expect to receive over all.
 //
 // Spec at https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-hid-protocol.html#message--and-packet-structure
-#[repr(packed)]
+#[repr(C, packed)]
 #[allow(dead_code)]
 struct U2FHIDInit {
     // U2F Channel ID
@@ -38,7 +48,7 @@ struct U2FHIDInit {
 // packet, until all data is received.
 //
 // https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-hid-protocol.html#message--and-packet-structure
-#[repr(packed)]
+#[repr(C, packed)]
 struct U2FHIDCont {
     // U2F Channel ID
     cid: [u8; 4],
@@ -53,7 +63,7 @@ struct U2FHIDCont {
 // further requests.
 //
 // https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-hid-protocol.html#u2fhid_init
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Debug)]
 struct U2FHIDInitResp {
     nonce: [u8; INIT_NONCE_SIZE],
@@ -66,121 +76,460 @@ struct U2FHIDInitResp {
 }
 
 // Trait for representing U2F HID Devices. Requires getters/setters for the
-// channel ID, created during device initialization.
+// channel ID, created during device initialization, and for the information
+// gathered during enumeration and initialization.
 pub trait U2FDevice {
     fn get_cid(&self) -> [u8; 4];
     fn set_cid(&mut self, cid: &[u8; 4]);
-}
+    fn get_device_info(&self) -> DeviceInfo;
+    fn set_device_info(&mut self, info: DeviceInfo);
+    fn frame_observer(&self) -> Option<&FrameObserver>;
+    fn set_frame_observer(&mut self, observer: Option<FrameObserver>);
+    // How often allocating a channel failed in a row, see `poll_devices()`.
+    fn init_failures(&self) -> u32;
+    fn set_init_failures(&mut self, failures: u32);
+    // Set once reading or writing failed because the device is gone, so that
+    // it's dropped from the `DeviceMap`.
+    fn disconnected(&self) -> bool;
+    fn set_disconnected(&mut self);
 
-////////////////////////////////////////////////////////////////////////
-// Utility Functions
-////////////////////////////////////////////////////////////////////////
+    // The capability byte from the INIT response, as is. Includes bits we
+    // don't know about, e.g. vendor-specific ones.
+    fn raw_capabilities(&self) -> u8 {
+        self.get_device_info().capabilities
+    }
 
-fn to_u8_array<T>(non_ptr: &T) -> &[u8] {
-    unsafe {
-        slice::from_raw_parts(non_ptr as *const T as *const u8,
-                              mem::size_of::<T>())
+    // The context of the operation the device is used for. Devices that
+    // don't keep one work all the same, just without e.g. stats.
+    fn context(&self) -> Option<&OperationContext> {
+        None
     }
+    fn set_context(&mut self, _context: OperationContext) {}
 }
 
-fn from_u8_array<T>(arr: &[u8]) -> &T {
-    unsafe { &*(arr.as_ptr() as *const T) }
-}
+////////////////////////////////////////////////////////////////////////
+// Utility Functions
+////////////////////////////////////////////////////////////////////////
 
-fn set_data(data: &mut [u8], itr: &mut std::slice::Iter<u8>, max: usize)
+// Copies bytes from `itr` to `data`, at most `max` and no more than fit.
+// Returns how many were copied, bytes beyond that are left in `itr`.
+fn set_data<'a, I>(data: &mut [u8], itr: &mut I, max: usize) -> usize
+    where I: Iterator<Item = &'a u8>
 {
-    let take_amount;
-    let count = itr.size_hint().0;
-    if max < count {
-        take_amount = max;
-    } else {
-        take_amount = count;
-    }
-    // TODO There is a better way to do this :|
-    for i in 0..take_amount {
-        data[i] = *itr.next().unwrap();
+    let mut written = 0;
+    // The slots go first, so that no byte is taken from `itr` once they
+    // ran out.
+    for (slot, byte) in data.iter_mut().take(max).zip(itr) {
+        *slot = *byte;
+        written += 1;
     }
+    written
 }
 
 ////////////////////////////////////////////////////////////////////////
 // Device Commands
 ////////////////////////////////////////////////////////////////////////
 
+// Replies to somebody else's INIT on the broadcast channel can come before
+// ours, INITs are sent up to this many times before giving up.
+const INIT_ATTEMPTS: usize = 3;
+
 pub fn init_device<T>(dev: &mut T, nonce: [u8; 8]) -> io::Result<()>
     where T: U2FDevice + Read + Write
 {
-    let raw = sendrecv(dev, U2FHID_INIT, &nonce)?;
+    let mut attempts = 0;
+    let (cid, cap_flags, protocol_version, device_version) = loop {
+        let raw = sendrecv(dev, U2FHID_INIT, &nonce)?;
+        if raw.len() < std::mem::size_of::<U2FHIDInitResp>() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short INIT response"));
+        }
 
-    let r : &U2FHIDInitResp = from_u8_array(&raw);
-    if r.nonce != nonce {
-        return Err(io::Error::new(io::ErrorKind::Other, "Nonces do not match!"));
-    }
+        let r : &U2FHIDInitResp = from_u8_array(&raw);
+        if r.nonce == nonce {
+            break (r.cid, r.cap_flags, r.version_interface, [r.version_major, r.version_minor, r.version_build]);
+        }
+        attempts += 1;
+        if attempts == INIT_ATTEMPTS {
+            return Err(io::Error::other("Nonces do not match!"));
+        }
+        // The nonce stays the same, the reply to our first INIT may still
+        // be on its way.
+        op_log!(debug, dev.context(), "INIT response for another nonce, trying again");
+    };
+
+    dev.set_cid(&cid);
 
-    dev.set_cid(&r.cid);
+    // A new channel might be a different firmware state, e.g. after an
+    // update, so ask getInfo again.
+    let mut info = dev.get_device_info();
+    info.capabilities = cap_flags;
+    info.protocol_version = Some(protocol_version);
+    info.device_version = Some(device_version);
+    info.init_nonce = Some(nonce);
+    info.channel_id = Some(cid);
+    info.max_msg_size = None;
+    info.authenticator_info = None;
+    dev.set_device_info(info);
+    record_init(dev);
     Ok(())
 }
 
 pub fn ping_device<T>(dev: &mut T, random: [u8; 8]) -> io::Result<()>
     where T: U2FDevice + Read + Write
 {
-    if sendrecv(dev, U2FHID_PING, &random)? != random {
-        return Err(io::Error::new(io::ErrorKind::Other, "Ping was corrupted!"));
+    u2f_ping(dev, &random).map(|_| ())
+}
+
+// Has the device echo `data` back, to test the link independent of any
+// crypto. Payloads beyond the init frame exercise continuation frames, up to
+// the largest message a frame sequence can carry. Returns the echo, which is
+// checked to match.
+pub fn u2f_ping<T>(dev: &mut T, data: &[u8]) -> io::Result<Vec<u8>>
+    where T: U2FDevice + Read + Write
+{
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Ping payload too large"));
+    }
+
+    let echo = sendrecv(dev, U2FHID_PING, data)?;
+    if echo != data {
+        return Err(io::Error::other("Ping was corrupted!"));
+    }
+
+    Ok(echo)
+}
+
+// Asks the device to abort whatever request is pending on the current
+// channel, and to stop waiting for user presence. There's no response.
+pub fn u2f_cancel<T>(dev: &mut T) -> io::Result<()>
+    where T: U2FDevice + Read + Write
+{
+    let uf = U2FHIDInit {
+        cid: dev.get_cid(),
+        cmd: U2FHID_CANCEL,
+        bcnth: 0,
+        bcntl: 0,
+        data: [0; INIT_DATA_SIZE]
+    };
+
+    // Prefix with the report ID, like sendrecv() does.
+    let mut frame : [u8; HID_RPT_SIZE + 1] = [0; HID_RPT_SIZE + 1];
+    frame[1..].clone_from_slice(to_u8_array(&uf));
+    dev.write(&frame).map(|_| ())
+}
+
+// A device was asked to wink that can't.
+#[derive(Debug)]
+struct WinkNotSupported;
+
+impl fmt::Display for WinkNotSupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WINK not supported")
+    }
+}
+
+impl Error for WinkNotSupported {
+    fn description(&self) -> &str {
+        "WINK not supported"
+    }
+}
+
+fn wink_not_supported() -> io::Error {
+    io::Error::other(WinkNotSupported)
+}
+
+// Whether `u2f_wink()` failed because the device can't wink.
+pub(crate) fn is_wink_not_supported(err: &io::Error) -> bool {
+    match err.get_ref() {
+        Some(e) => e.is::<WinkNotSupported>(),
+        None => false
+    }
+}
+
+// Makes the device identify itself, e.g. by blinking. Devices that don't
+// have CAPFLAG_WINK set might never answer, so they aren't asked.
+pub fn u2f_wink<T>(dev: &mut T) -> io::Result<()>
+    where T: U2FDevice + Read + Write
+{
+    if dev.get_device_info().capabilities & CAPFLAG_WINK == 0 {
+        return Err(wink_not_supported());
     }
+    sendrecv(dev, U2FHID_WINK, &[])?;
+    Ok(())
+}
 
+// Gives the current channel exclusive access to the device for the given
+// number of seconds, at most 10. Zero releases the lock. Only supported if
+// the device has CAPFLAG_LOCK set.
+pub fn u2f_lock<T>(dev: &mut T, seconds: u8) -> io::Result<()>
+    where T: U2FDevice + Read + Write
+{
+    sendrecv(dev, U2FHID_LOCK, &[seconds])?;
     Ok(())
 }
 
+// Runs the checks every newly found device has to pass: INIT a channel (twice,
+// see below), PING it, and make sure it speaks U2F_V2. The INIT nonce and PING
+// payload are drawn from `rng`, which lets tests supply a deterministic source.
+pub fn u2f_init_device<T, R>(dev: &mut T, rng: &mut R) -> io::Result<()>
+    where T: U2FDevice + Read + Write, R: Rng + ?Sized
+{
+    let mut nonce = [0u8; 8];
+    rng.fill_bytes(&mut nonce);
+    init_device(dev, nonce)?;
+
+    // INIT once more, on the channel we were just given. That's a no-op
+    // resync per the spec, but some tokens reject every command on a new
+    // channel until it has seen an INIT of its own. The nonce only has to
+    // match the response, so we reuse it.
+    init_device(dev, nonce)?;
+
+    // Give the token a moment to set the channel up, see
+    // `U2FManagerBuilder::init_settle_delay()`.
+    let delay = init_settle_delay(dev.context());
+    if delay > Duration::from_millis(0) {
+        thread::sleep(delay);
+    }
+
+    let mut random = [0u8; 8];
+    rng.fill_bytes(&mut random);
+    ping_device(dev, random)?;
+
+    // CTAP2-only devices don't understand U2F messages.
+    if dev.get_device_info().capabilities & CAPFLAG_NMSG != 0 {
+        return Ok(());
+    }
+
+    // Some of them don't set CAPFLAG_NMSG, but reject the version command.
+    match u2f_version_is_v2(dev) {
+        Err(ref e) if version_unsupported(e) && dev.get_device_info().supports_cbor() => Ok(()),
+        rv => rv
+    }
+}
+
+// Runs `u2f_init_device` unless the device was given a channel already. This
+// lets enumeration skip talking to devices until they're actually used.
+pub fn u2f_init_channel<T, R>(dev: &mut T, rng: &mut R) -> io::Result<()>
+    where T: U2FDevice + Read + Write, R: Rng + ?Sized
+{
+    if dev.get_cid() != CID_BROADCAST {
+        return Ok(());
+    }
+
+    let rv = u2f_init_device(dev, rng);
+    if rv.is_err() {
+        // Start over next time.
+        dev.set_cid(&CID_BROADCAST);
+        let mut info = dev.get_device_info();
+        info.init_nonce = None;
+        info.channel_id = None;
+        dev.set_device_info(info);
+    }
+    rv
+}
+
+// Forgets the device's channel and capabilities and INITs a new channel.
+// Recovers from half-finished transactions, e.g. ones a crashed process left
+// behind: the device drops whatever is pending once it sees the INIT.
+pub fn u2f_reset_channel<T, R>(dev: &mut T, rng: &mut R) -> io::Result<()>
+    where T: U2FDevice + Read + Write, R: Rng + ?Sized
+{
+    let mut info = dev.get_device_info();
+    info.capabilities = 0;
+    info.protocol_version = None;
+    info.device_version = None;
+    info.init_nonce = None;
+    info.channel_id = None;
+    dev.set_device_info(info);
+    dev.set_cid(&CID_BROADCAST);
+
+    u2f_init_channel(dev, rng)
+}
+
+// Error payload for APDUs that failed. Carries the ISO 7816-4 status word the
+// device answered with, so that callers can tell failures apart.
+#[derive(Debug)]
+pub struct StatusWordError {
+    status_word: u16,
+    description: String
+}
+
+impl StatusWordError {
+    pub fn status_word(&self) -> u16 {
+        self.status_word
+    }
+}
+
+impl fmt::Display for StatusWordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl Error for StatusWordError {
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+// Returns the status word carried by an error from a device command, if the
+// device answered with one.
+pub fn status_word(err: &io::Error) -> Option<u16> {
+    err.get_ref()
+       .and_then(|e| e.downcast_ref::<StatusWordError>())
+       .map(|e| e.status_word())
+}
+
+// A U2FHID_ERROR frame a device answered a command with. The command didn't
+// get past the transport layer then.
+#[derive(Debug)]
+struct HidError {
+    code: u8
+}
+
+impl fmt::Display for HidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "U2FHID error {:#04x}: {}", self.code, ctaphid_error_description(self.code))
+    }
+}
+
+impl Error for HidError {
+    fn description(&self) -> &str {
+        ctaphid_error_description(self.code)
+    }
+}
+
+fn hid_error_to_error(code: u8) -> io::Error {
+    let kind = match code {
+        ERR_INVALID_PAR | ERR_INVALID_LEN => io::ErrorKind::InvalidInput,
+        ERR_MSG_TIMEOUT => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other
+    };
+    io::Error::new(kind, HidError { code })
+}
+
+// Returns the U2FHID error code a device answered a command with, see
+// ERR_*, if it did.
+pub fn hid_error(err: &io::Error) -> Option<u8> {
+    err.get_ref()
+       .and_then(|e| e.downcast_ref::<HidError>())
+       .map(|e| e.code)
+}
+
 fn status_word_to_error(status_word_high: u8, status_word_low: u8) -> Option<io::Error>
 {
     let status_word = [status_word_high, status_word_low];
 
-    match status_word {
-        SW_NO_ERROR => None,
-        SW_WRONG_LENGTH => Some(io::Error::new(io::ErrorKind::InvalidInput, "Wrong Length")),
-        SW_WRONG_DATA => Some(io::Error::new(io::ErrorKind::InvalidData, "Wrong Data")),
-        SW_CONDITIONS_NOT_SATISFIED => Some(io::Error::new(io::ErrorKind::TimedOut, "Conditions not satisfied")),
-        _ => Some(io::Error::new(io::ErrorKind::Other, format!("Problem Status: {:?}", status_word))),
+    let (kind, description) = match status_word {
+        SW_NO_ERROR => return None,
+        SW_WRONG_LENGTH => (io::ErrorKind::InvalidInput, String::from("Wrong Length")),
+        SW_WRONG_DATA => (io::ErrorKind::InvalidData, String::from("Wrong Data")),
+        SW_CONDITIONS_NOT_SATISFIED => (io::ErrorKind::TimedOut, String::from("Conditions not satisfied")),
+        SW_INS_NOT_SUPPORTED => (io::ErrorKind::InvalidInput, String::from("Instruction not supported")),
+        _ => (io::ErrorKind::Other, format!("Problem Status: {:?}", status_word)),
+    };
+
+    let status_word = (status_word_high as u16) << 8 | status_word_low as u16;
+    Some(io::Error::new(kind, StatusWordError { status_word, description }))
+}
+
+// The error for an APDU answered with `status_word`, even 0x9000.
+pub(crate) fn status_word_error(status_word: u16) -> io::Error {
+    status_word_to_error((status_word >> 8) as u8, status_word as u8).unwrap_or_else(|| {
+        io::Error::other(StatusWordError { status_word, description: String::from("No error") })
+    })
+}
+
+// Sends an arbitrary APDU, e.g. a vendor command, and returns the response
+// data without the status word. Fails with `U2FError::ApduStatus` if the
+// status word isn't 0x9000.
+pub fn u2f_send_apdu<T>(dev: &mut T, ins: u8, p1: u8, data: &[u8]) -> Result<Vec<u8>, U2FError>
+    where T: U2FDevice + Read + Write
+{
+    let (resp, sw) = u2f_send_apdu_with_status(dev, ins, p1, data)?;
+    match sw {
+        0x9000 => Ok(resp),
+        _ => Err(U2FError::ApduStatus(sw))
     }
 }
 
+// Like `u2f_send_apdu()`, but returns the status word along with the
+// response data instead of failing on anything but 0x9000.
+pub fn u2f_send_apdu_with_status<T>(dev: &mut T, ins: u8, p1: u8, data: &[u8]) -> io::Result<(Vec<u8>, u16)>
+    where T: U2FDevice + Read + Write
+{
+    let mut resp = send_apdu(dev, ins, p1, data)?;
+    let sw_low = resp.pop().unwrap();
+    let sw_high = resp.pop().unwrap();
+    Ok((resp, (sw_high as u16) << 8 | sw_low as u16))
+}
+
 pub fn u2f_version<T>(dev: &mut T) -> io::Result<std::ffi::CString>
     where T: U2FDevice + Read + Write
 {
-    let mut version_resp = try!(send_apdu(dev, U2F_VERSION, 0x00, &vec![]));
+    let mut version_resp = send_apdu(dev, U2F_VERSION, 0x00, &[])?;
     let sw_low = version_resp.pop().unwrap();
     let sw_high = version_resp.pop().unwrap();
 
+    // FIDO2-only devices may not know the command at all.
+    if [sw_high, sw_low] == SW_INS_NOT_SUPPORTED {
+        let description = String::from("Device does not support the U2F version command, it's likely FIDO2-only");
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, StatusWordError { status_word: 0x6d00, description }));
+    }
+
     match status_word_to_error(sw_high, sw_low) {
-        None => Ok(try!(CString::new(version_resp))),
+        None => Ok(CString::new(version_resp)?),
         Some(e) => Err(e),
     }
 }
 
+// Whether `u2f_version()` failed because the device doesn't know the command.
+// Such devices should be asked `ctap2_get_info()` instead.
+pub fn version_unsupported(err: &io::Error) -> bool {
+    status_word(err) == Some(0x6d00)
+}
+
 pub fn u2f_version_is_v2<T>(dev: &mut T) -> io::Result<()>
     where T: U2FDevice + Read + Write
 {
-    let version_string = try!(u2f_version(dev));
+    let version_string = u2f_version(dev)?;
 
-    if version_string != try!(ffi::CString::new("U2F_V2")) {
+    if version_string != ffi::CString::new("U2F_V2")? {
         return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unexpected U2F Version"));
     }
     Ok(())
 }
 
-pub fn u2f_register<T>(dev: &mut T, challenge: &Vec<u8>, application: &Vec<u8>) -> io::Result<Vec<u8>>
-    where T: U2FDevice + Read + Write
-{
+fn register_data(challenge: &[u8], application: &[u8]) -> io::Result<Vec<u8>> {
     if challenge.len() != PARAMETER_SIZE || application.len() != PARAMETER_SIZE {
         return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
     }
 
-    let flags = 0x00;
-
     let mut register_data = Vec::with_capacity(2 * PARAMETER_SIZE);
     register_data.extend(challenge);
     register_data.extend(application);
+    Ok(register_data)
+}
+
+fn sign_data(challenge: &[u8], application: &[u8], key_handle: &KeyHandle) -> io::Result<Vec<u8>> {
+    if challenge.len() != PARAMETER_SIZE || application.len() != PARAMETER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
+    }
+
+    let mut sign_data = Vec::with_capacity(2 * PARAMETER_SIZE + 1 + key_handle.len());
+    sign_data.extend(challenge);
+    sign_data.extend(application);
+    key_handle.encode_into(&mut sign_data)?;
+    Ok(sign_data)
+}
 
-    let register_resp = try!(send_apdu(dev, U2F_REGISTER, flags | U2F_REQUEST_USER_PRESENCE, &register_data));
+// Fails with `U2FError::ApduStatus(0x6985)` until the user touched the device.
+pub fn u2f_register<T>(dev: &mut T, challenge: &[u8], application: &[u8]) -> Result<Vec<u8>, U2FError>
+    where T: U2FDevice + Read + Write
+{
+    let flags = 0x00;
+    let register_data = register_data(challenge, application)?;
+    let register_resp = send_apdu(dev, U2F_REGISTER, flags | U2F_REQUEST_USER_PRESENCE, &register_data)?;
 
     if register_resp.len() != 2 {
         // Real data, we're done
@@ -189,27 +538,15 @@ pub fn u2f_register<T>(dev: &mut T, challenge: &Vec<u8>, application: &Vec<u8>)
 
     match status_word_to_error(register_resp[0], register_resp[1]) {
         None => Ok(Vec::new()),
-        Some(e) => Err(e),
+        Some(e) => Err(e.into()),
     }
 }
 
-pub fn u2f_sign<T>(dev: &mut T, challenge: &Vec<u8>, application: &Vec<u8>, key_handle: &Vec<u8>) -> io::Result<Vec<u8>>
+// Like `u2f_register()`, 0x6a80 means the key handle isn't the device's.
+pub fn u2f_sign<T>(dev: &mut T, challenge: &[u8], application: &[u8], key_handle: &KeyHandle) -> Result<Vec<u8>, U2FError>
     where T: U2FDevice + Read + Write
 {
-    if challenge.len() != PARAMETER_SIZE || application.len() != PARAMETER_SIZE {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
-    }
-
-    if key_handle.len() > 256 {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Key handle too large"));
-    }
-
-    let mut sign_data = Vec::with_capacity(2 * PARAMETER_SIZE + 1 + key_handle.len());
-    sign_data.extend(challenge);
-    sign_data.extend(application);
-    sign_data.push(key_handle.len() as u8);
-    sign_data.extend(key_handle);
-
+    let sign_data = sign_data(challenge, application, key_handle)?;
     let flags = U2F_REQUEST_USER_PRESENCE;
     let sign_resp = send_apdu(dev, U2F_AUTHENTICATE, flags, &sign_data)?;
 
@@ -220,274 +557,1002 @@ pub fn u2f_sign<T>(dev: &mut T, challenge: &Vec<u8>, application: &Vec<u8>, key_
 
     match status_word_to_error(sign_resp[0], sign_resp[1]) {
         None => Ok(Vec::new()),
-        Some(e) => Err(e),
+        Some(e) => Err(e.into()),
     }
 }
 
-pub fn u2f_is_keyhandle_valid<T>(dev: &mut T, challenge: &Vec<u8>, application: &Vec<u8>, key_handle: &Vec<u8>) -> io::Result<bool>
-    where T: U2FDevice + Read + Write
-{
-    if challenge.len() != PARAMETER_SIZE ||
-       application.len() != PARAMETER_SIZE {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid parameter sizes"));
-    }
-
-    if key_handle.len() > 256 {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Key handle too large"));
-    }
+// What `u2f_register()` would send on channel `cid`, without talking to any
+// device: the APDU and the HID frames carrying it.
+pub fn u2f_dry_run_register(cid: &[u8; 4], challenge: &[u8], application: &[u8]) -> io::Result<DryRun> {
+    let apdu = build_apdu(U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, &register_data(challenge, application)?)?;
+    Ok(DryRun { frames: hid_frames(*cid, U2FHID_MSG, &apdu), apdu })
+}
 
-    let mut sign_data = Vec::with_capacity(2 * PARAMETER_SIZE + 1 + key_handle.len());
-    sign_data.extend(challenge);
-    sign_data.extend(application);
-    sign_data.push(key_handle.len() as u8);
-    sign_data.extend(key_handle);
+// Like `u2f_dry_run_register()`, for `u2f_sign()`.
+pub fn u2f_dry_run_sign(cid: &[u8; 4], challenge: &[u8], application: &[u8], key_handle: &KeyHandle) -> io::Result<DryRun> {
+    let apdu = build_apdu(U2F_AUTHENTICATE, U2F_REQUEST_USER_PRESENCE, &sign_data(challenge, application, key_handle)?)?;
+    Ok(DryRun { frames: hid_frames(*cid, U2FHID_MSG, &apdu), apdu })
+}
 
+// Asks a device whether it owns `key_handle`, without needing the user.
+// Owners answer "test of user presence required", everyone else "wrong
+// data". Any other status word means it's not this device either, it
+// shouldn't end an operation that another device can complete.
+pub fn u2f_is_keyhandle_valid<T>(dev: &mut T, challenge: &[u8], application: &[u8], key_handle: &KeyHandle) -> io::Result<bool>
+    where T: U2FDevice + Read + Write
+{
+    let sign_data = sign_data(challenge, application, key_handle)?;
     let flags = U2F_CHECK_IS_REGISTERED;
     let sign_resp = send_apdu(dev, U2F_AUTHENTICATE, flags, &sign_data)?;
 
-    // Need to use `&sign_resp[0..2]` here due to a compiler bug.
-    // https://github.com/rust-lang/rust/issues/42031
-    Ok(&sign_resp[0..2] == SW_CONDITIONS_NOT_SATISFIED)
+    let status = [sign_resp[sign_resp.len() - 2], sign_resp[sign_resp.len() - 1]];
+    match status {
+        SW_CONDITIONS_NOT_SATISFIED => Ok(true),
+        SW_WRONG_DATA => Ok(false),
+        _ => {
+            op_log!(debug, dev.context(), "{}: unexpected status {} checking a key handle", to_hex(&dev.get_cid()), to_hex(&status));
+            Ok(false)
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////
-// Device Communication Functions
+// CTAP2 Commands
 ////////////////////////////////////////////////////////////////////////
 
-fn sendrecv<T>(dev: &mut T, cmd: u8, send: &[u8]) -> io::Result<Vec<u8>>
+fn ctap2_not_supported() -> io::Error {
+    io::Error::other("CTAP2 not supported")
+}
+
+// A CTAP2 command the device answered with an error status.
+#[derive(Debug)]
+struct Ctap2Error {
+    status: u8,
+    description: String
+}
+
+impl fmt::Display for Ctap2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl Error for Ctap2Error {
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+// The CTAP2 status code a device answered with, if it did. The channel is
+// fine in that case, unlike with other errors.
+pub fn ctap2_status(err: &io::Error) -> Option<u8> {
+    err.get_ref()
+       .and_then(|e| e.downcast_ref::<Ctap2Error>())
+       .map(|e| e.status)
+}
+
+// Sends a CTAP2 command with optional CBOR parameters, and returns the decoded
+// response if the device reported success.
+fn ctap2_request<T>(dev: &mut T, cmd: u8, params: Option<&cbor::Value>) -> io::Result<cbor::Value>
     where T: U2FDevice + Read + Write
 {
-    let mut sequence: u8 = 0; // Start at 0
-    let mut data_itr = send.into_iter();
-    let mut init_sent = false;
-    // Write Data.
-    while data_itr.size_hint().0 != 0 {
-        // Add 1 to HID_RPT_SIZE since we need to prefix this with a record
-        // index.
-        let mut frame : [u8; HID_RPT_SIZE + 1] = [0; HID_RPT_SIZE + 1];
-        if !init_sent {
-            let mut uf = U2FHIDInit {
-                cid: dev.get_cid(),
-                cmd: cmd,
-                bcnth: (send.len() >> 8) as u8,
-                bcntl: send.len() as u8,
-                data: [0; INIT_DATA_SIZE]
-            };
-            set_data(&mut uf.data, &mut data_itr, INIT_DATA_SIZE);
-            frame[1..].clone_from_slice(to_u8_array(&uf));
-            init_sent = true;
-        } else {
-            let mut uf = U2FHIDCont {
-                cid: dev.get_cid(),
-                seq: sequence,
-                data: [0; CONT_DATA_SIZE]
-            };
-            set_data(&mut uf.data, &mut data_itr, CONT_DATA_SIZE);
-            sequence += 1;
-            frame[1..].clone_from_slice(to_u8_array(&uf));
-        }
+    if !dev.get_device_info().supports_cbor() {
+        return Err(ctap2_not_supported());
+    }
 
-        if log_enabled!(log::LogLevel::Trace) {
-            let parts: Vec<String> = frame.iter().map(|byte| {
-                format!("{:02x}", byte)
-            }).collect();
-            trace!("USB send: {}", parts.join(""));
+    let mut data = vec![cmd];
+    if let Some(params) = params {
+        data.extend(cbor::encode(params));
+    }
+
+    // Tokens answer requests that are too large with a bare length error, if
+    // at all. Catch that here, e.g. for long allow lists.
+    if let Some(max) = dev.get_device_info().max_msg_size {
+        if data.len() > max {
+            let msg = format!("CTAP2 request of {} bytes exceeds the device's limit of {} bytes", data.len(), max);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
         }
+    }
 
-        if let Err(er) = dev.write(&frame) {
-            return Err(er);
-        };
+    // The first byte is the status code.
+    let resp = sendrecv(dev, U2FHID_CBOR, &data)?;
+    if resp.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty CTAP2 response"));
+    }
+    if resp[0] != CTAP2_OK {
+        let description = format!("CTAP2 error: {:#04x}", resp[0]);
+        return Err(io::Error::other(Ctap2Error { status: resp[0], description }));
     }
 
-    // Now we read. This happens in 2 chunks: The initial packet, which has the
-    // size we expect overall, then continuation packets, which will fill in
-    // data until we have everything.
-    let mut frame : [u8; HID_RPT_SIZE] = [0u8; HID_RPT_SIZE];
-    let datalen: usize;
-    let mut data : Vec<u8>;
+    if resp.len() == 1 {
+        return Ok(cbor::Value::Null);
+    }
+    cbor::decode(&resp[1..])
+}
 
-    // TODO Check the status of the read, figure out how we'll deal with timeouts.
-    dev.read(&mut frame)?;
-    let mut recvlen = INIT_DATA_SIZE;
+// Asks a FIDO2 token about its versions, options and limits. Remembers the
+// answer in the device info until the channel is set up again, it doesn't
+// change in the meantime. Later requests are checked against the maximum
+// message size.
+pub fn ctap2_get_info<T>(dev: &mut T) -> io::Result<AuthenticatorInfo>
+    where T: U2FDevice + Read + Write
+{
+    use cbor::Value;
 
-    // We'll get an init packet back from USB, open it to see how much we'll be
-    // reading overall. (should unpack to an init struct). Scope this to deal
-    // with the lifetime of the frame borrow in from_u8_array.
-    {
-        let info_frame : &U2FHIDInit = from_u8_array(&frame);
+    if let Some(info) = dev.get_device_info().authenticator_info {
+        return Ok(info);
+    }
 
-        // Read until we've exhausted the total read amount or error out
-        datalen = (info_frame.bcnth as usize) << 8 | (info_frame.bcntl as usize);
-        data = Vec::with_capacity(datalen);
+    let resp = ctap2_request(dev, CTAP2_GET_INFO, None)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid getInfo response");
+    match resp {
+        Value::Map(_) => {}
+        _ => return Err(invalid())
+    }
 
-        let clone_len : usize;
-        if datalen < recvlen {
-            clone_len = datalen;
-        } else {
-            clone_len = recvlen;
+    // An array of strings, or nothing.
+    let strings = |key: u64| {
+        match resp.get(&Value::Unsigned(key)) {
+            Some(Value::Array(values)) => {
+                values.iter().map(|value| {
+                    match *value {
+                        Value::Text(ref value) => Ok(value.clone()),
+                        _ => Err(invalid())
+                    }
+                }).collect()
+            }
+            None => Ok(Vec::new()),
+            _ => Err(invalid())
         }
-        data.extend(info_frame.data[0..clone_len].iter().cloned());
+    };
+
+    let mut info = AuthenticatorInfo { versions: strings(0x01)?, extensions: strings(0x02)?, ..AuthenticatorInfo::default() };
+    match resp.get(&Value::Unsigned(0x03)) {
+        Some(Value::Bytes(aaguid)) => info.aaguid = Some(aaguid.clone()),
+        None => {}
+        _ => return Err(invalid())
     }
-    sequence = 0;
-    while recvlen < datalen {
-        // Reset frame value
-        frame = [0u8; HID_RPT_SIZE];
-        dev.read(&mut frame)?;
-        let cont_frame : &U2FHIDCont;
-        cont_frame = from_u8_array(&frame);
-        if cont_frame.cid != dev.get_cid() {
-            return Err(io::Error::new(io::ErrorKind::Other, "Wrong CID!"));
-        }
-        if cont_frame.seq != sequence {
-            return Err(io::Error::new(io::ErrorKind::Other, "Sequence numbers out of order!"));
+    match resp.get(&Value::Unsigned(0x04)) {
+        Some(Value::Map(options)) => {
+            for option in options {
+                match *option {
+                    (Value::Text(ref name), Value::Bool(value)) => info.options.push((name.clone(), value)),
+                    _ => return Err(invalid())
+                }
+            }
         }
-        sequence = cont_frame.seq + 1;
-        if (recvlen + CONT_DATA_SIZE) > datalen {
-            data.extend(cont_frame.data[0..(datalen-recvlen)].iter().cloned());
-        } else {
-            data.extend(cont_frame.data.iter().cloned());
+        None => {}
+        _ => return Err(invalid())
+    }
+    match resp.get(&Value::Unsigned(0x05)) {
+        Some(&Value::Unsigned(size)) => info.max_msg_size = Some(size as usize),
+        None => {}
+        _ => return Err(invalid())
+    }
+    match resp.get(&Value::Unsigned(0x06)) {
+        Some(Value::Array(protocols)) => {
+            for protocol in protocols {
+                match *protocol {
+                    Value::Unsigned(protocol) => info.pin_protocols.push(protocol),
+                    _ => return Err(invalid())
+                }
+            }
         }
-        recvlen += CONT_DATA_SIZE;
+        None => {}
+        _ => return Err(invalid())
     }
-    Ok(data)
+
+    let mut device_info = dev.get_device_info();
+    device_info.max_msg_size = info.max_msg_size;
+    device_info.authenticator_info = Some(info.clone());
+    dev.set_device_info(device_info);
+    Ok(info)
 }
 
-// https://en.wikipedia.org/wiki/Smart_card_application_protocol_data_unit
-// https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-raw-message-formats.html#u2f-message-framing
-#[repr(packed)]
-#[allow(dead_code)]
-struct U2FAPDUHeader {
-    cla : u8,
-    ins : u8,
+// Asks a FIDO2 token whether a PIN is set and how many attempts are left.
+// Devices that don't speak CTAP2 or have no PIN support return an error.
+pub fn ctap2_pin_status<T>(dev: &mut T) -> io::Result<PinStatus>
+    where T: U2FDevice + Read + Write
+{
+    use cbor::Value;
+
+    // The `clientPin` option is only present if the device supports PINs, and
+    // it's true if a PIN was set.
+    let set = match ctap2_get_info(dev)?.option("clientPin") {
+        Some(set) => set,
+        None => return Err(ctap2_not_supported())
+    };
+
+    // pinProtocol: 1, subCommand: getRetries
+    let params = Value::Map(vec![(Value::Unsigned(0x01), Value::Unsigned(1)),
+                                 (Value::Unsigned(0x02), Value::Unsigned(CLIENT_PIN_GET_RETRIES))]);
+    let resp = ctap2_request(dev, CTAP2_CLIENT_PIN, Some(&params))?;
+
+    match resp.get(&Value::Unsigned(0x03)) {
+        Some(&Value::Unsigned(retries)) if retries <= 0xff => {
+            Ok(PinStatus { set, retries: retries as u8 })
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid clientPin response"))
+    }
+}
+
+// Agrees on a key with a FIDO2 token, as needed to ask for hmac-secret
+// outputs. Uses a new key pair of ours, drawn from `rng`, every time.
+pub fn ctap2_shared_secret<T, R>(dev: &mut T, rng: &mut R) -> io::Result<SharedSecret>
+    where T: U2FDevice + Read + Write, R: Rng + ?Sized
+{
+    use cbor::Value;
+
+    // pinProtocol: 1, subCommand: getKeyAgreement
+    let params = Value::Map(vec![(Value::Unsigned(0x01), Value::Unsigned(1)),
+                                 (Value::Unsigned(0x02), Value::Unsigned(CLIENT_PIN_GET_KEY_AGREEMENT))]);
+    let resp = ctap2_request(dev, CTAP2_CLIENT_PIN, Some(&params))?;
+
+    match resp.get(&Value::Unsigned(0x01)) {
+        Some(key_agreement) => SharedSecret::from_cose_key(key_agreement, rng),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid clientPin response"))
+    }
+}
+
+// Trades the PIN for a pinToken, which authenticates commands like
+// `ctap2_enumerate_credentials()` until the token is power cycled. A wrong
+// PIN returns CTAP2_ERR_PIN_INVALID, and uses up an attempt.
+pub fn ctap2_pin_token<T, R>(dev: &mut T, rng: &mut R, pin: &str) -> io::Result<Vec<u8>>
+    where T: U2FDevice + Read + Write, R: Rng + ?Sized
+{
+    use cbor::Value;
+
+    let secret = ctap2_shared_secret(dev, rng)?;
+
+    // pinProtocol: 1, subCommand: getPinToken, keyAgreement, pinHashEnc
+    let params = Value::Map(vec![(Value::Unsigned(0x01), Value::Unsigned(1)),
+                                 (Value::Unsigned(0x02), Value::Unsigned(CLIENT_PIN_GET_PIN_TOKEN)),
+                                 (Value::Unsigned(0x03), secret.cose_key()),
+                                 (Value::Unsigned(0x06), Value::Bytes(secret.encrypt_pin_hash(pin)))]);
+    let resp = ctap2_request(dev, CTAP2_CLIENT_PIN, Some(&params))?;
+
+    match resp.get(&Value::Unsigned(0x02)) {
+        Some(Value::Bytes(pin_token_enc)) => secret.decrypt_pin_token(pin_token_enc),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid clientPin response"))
+    }
+}
+
+// Lists the discoverable credentials a FIDO2 token stores for the RP with
+// the SHA-256 hash `rp_id_hash`, using a `pin_token` from
+// `ctap2_pin_token()`. Tokens that don't advertise credential management in
+// getInfo return an error, ones without matching credentials an empty list.
+pub fn ctap2_enumerate_credentials<T>(dev: &mut T, rp_id_hash: &[u8], pin_token: &[u8]) -> io::Result<Vec<ResidentCredential>>
+    where T: U2FDevice + Read + Write
+{
+    use cbor::Value;
+
+    if rp_id_hash.len() != PARAMETER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid RP ID hash"));
+    }
+
+    // Tokens from before CTAP 2.1 may have a preview of the same commands.
+    let info = ctap2_get_info(dev)?;
+    let cmd = if info.option("credMgmt") == Some(true) {
+        CTAP2_CREDENTIAL_MANAGEMENT
+    } else if info.option("credentialMgmtPreview") == Some(true) {
+        CTAP2_CREDENTIAL_MANAGEMENT_PREVIEW
+    } else {
+        return Err(io::Error::other("Credential management not supported"));
+    };
+
+    // subCommand, subCommandParams: {rpIDHash}, pinProtocol: 1, pinAuth over
+    // the subcommand and its parameters.
+    let sub_params = Value::Map(vec![(Value::Unsigned(0x01), Value::Bytes(rp_id_hash.to_vec()))]);
+    let mut auth_data = vec![CRED_MGMT_ENUMERATE_CREDENTIALS_BEGIN as u8];
+    auth_data.extend(cbor::encode(&sub_params));
+    let params = Value::Map(vec![(Value::Unsigned(0x01), Value::Unsigned(CRED_MGMT_ENUMERATE_CREDENTIALS_BEGIN)),
+                                 (Value::Unsigned(0x02), sub_params),
+                                 (Value::Unsigned(0x03), Value::Unsigned(1)),
+                                 (Value::Unsigned(0x04), Value::Bytes(hmacsecret::authenticate(pin_token, &auth_data)))]);
+    let resp = match ctap2_request(dev, cmd, Some(&params)) {
+        Err(ref e) if ctap2_status(e) == Some(CTAP2_ERR_NO_CREDENTIALS) => return Ok(Vec::new()),
+        resp => resp?
+    };
+
+    // Only the first response has the total.
+    let total = match resp.get(&Value::Unsigned(0x09)) {
+        Some(&Value::Unsigned(total)) => total,
+        _ => return Err(invalid_credential())
+    };
+    let mut credentials = vec![resident_credential(&resp)?];
+    let next = Value::Map(vec![(Value::Unsigned(0x01), Value::Unsigned(CRED_MGMT_ENUMERATE_CREDENTIALS_NEXT))]);
+    for _ in 1..total {
+        let resp = ctap2_request(dev, cmd, Some(&next))?;
+        credentials.push(resident_credential(&resp)?);
+    }
+    Ok(credentials)
+}
+
+fn invalid_credential() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid credentialManagement response")
+}
+
+// Reads the user, the credential ID and the public key from an
+// enumerateCredentials response.
+fn resident_credential(resp: &cbor::Value) -> io::Result<ResidentCredential> {
+    use cbor::Value;
+
+    let text = |s: &str| Value::Text(String::from(s));
+    let string = |map: &Value, key: &str| {
+        match map.get(&text(key)) {
+            Some(Value::Text(value)) => Ok(Some(value.clone())),
+            None => Ok(None),
+            _ => Err(invalid_credential())
+        }
+    };
+
+    let user = match resp.get(&Value::Unsigned(0x06)) {
+        Some(user @ &Value::Map(_)) => {
+            let id = match user.get(&text("id")) {
+                Some(Value::Bytes(id)) => id.clone(),
+                _ => return Err(invalid_credential())
+            };
+            User { id, name: string(user, "name")?, display_name: string(user, "displayName")? }
+        }
+        _ => return Err(invalid_credential())
+    };
+    let credential_id = match resp.get(&Value::Unsigned(0x07)).map(|c| c.get(&text("id"))) {
+        Some(Some(Value::Bytes(id))) => id.clone(),
+        _ => return Err(invalid_credential())
+    };
+    let public_key = match resp.get(&Value::Unsigned(0x08)) {
+        Some(key @ &Value::Map(_)) => cbor::encode(key),
+        _ => return Err(invalid_credential())
+    };
+
+    Ok(ResidentCredential { user, credential_id, public_key })
+}
+
+// Has a FIDO2 token create a credential for `user` at `rp`, with the first
+// of the COSE `algorithms` it supports, e.g. -7 for ES256. The device waits
+// for user presence before it answers. Devices that don't speak CTAP2 return
+// an error, use `u2f_register()` for those.
+pub fn ctap2_make_credential<T>(dev: &mut T, client_data_hash: &[u8], rp: &RelyingParty, user: &User, algorithms: &[i64], options: MakeCredentialOptions) -> io::Result<AttestationObject>
+    where T: U2FDevice + Read + Write
+{
+    use cbor::Value;
+
+    if client_data_hash.len() != PARAMETER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid client data hash"));
+    }
+    if algorithms.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No algorithms"));
+    }
+    // Tokens without storage for credentials don't list the `rk` option.
+    if options.resident_key && ctap2_get_info(dev)?.option("rk") != Some(true) {
+        return Err(io::Error::other("Resident keys not supported"));
+    }
+
+    // Keys are in canonical order, like CTAP2 wants them.
+    let text = |s: &str| Value::Text(String::from(s));
+    let int = |n: i64| if n < 0 { Value::Negative(n) } else { Value::Unsigned(n as u64) };
+
+    let mut rp_entity = vec![(text("id"), text(&rp.id))];
+    if let Some(ref name) = rp.name {
+        rp_entity.push((text("name"), text(name)));
+    }
+    let mut user_entity = vec![(text("id"), Value::Bytes(user.id.clone()))];
+    if let Some(ref name) = user.name {
+        user_entity.push((text("name"), text(name)));
+    }
+    if let Some(ref display_name) = user.display_name {
+        user_entity.push((text("displayName"), text(display_name)));
+    }
+    let params = algorithms.iter().map(|&alg| {
+        Value::Map(vec![(text("alg"), int(alg)), (text("type"), text("public-key"))])
+    }).collect();
+
+    let mut request = vec![
+        (Value::Unsigned(0x01), Value::Bytes(client_data_hash.to_vec())),
+        (Value::Unsigned(0x02), Value::Map(rp_entity)),
+        (Value::Unsigned(0x03), Value::Map(user_entity)),
+        (Value::Unsigned(0x04), Value::Array(params)),
+        (Value::Unsigned(0x07), Value::Map(vec![(text("rk"), Value::Bool(options.resident_key)),
+                                                (text("uv"), Value::Bool(options.user_verification))]))
+    ];
+
+    // A vendor-facilitated enterpriseAttestation, the only kind we can ask
+    // for. Tokens that don't know it would reject the request.
+    if options.attestation == AttestationConveyance::Enterprise && ctap2_get_info(dev)?.option("ep") == Some(true) {
+        request.push((Value::Unsigned(0x0a), Value::Unsigned(1)));
+    }
+    let request = Value::Map(request);
+
+    let resp = ctap2_request(dev, CTAP2_MAKE_CREDENTIAL, Some(&request))?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid makeCredential response");
+
+    let fmt = match resp.get(&Value::Unsigned(0x01)) {
+        Some(Value::Text(fmt)) => fmt.clone(),
+        _ => return Err(invalid())
+    };
+    let auth_data = match resp.get(&Value::Unsigned(0x02)) {
+        Some(Value::Bytes(auth_data)) => auth_data.clone(),
+        _ => return Err(invalid())
+    };
+
+    // Fields we don't know are ignored, the ones we know must be well-formed.
+    let statement = match resp.get(&Value::Unsigned(0x03)) {
+        Some(statement @ &Value::Map(_)) => statement,
+        _ => return Err(invalid())
+    };
+    let mut att_stmt = AttestationStatement::default();
+    match statement.get(&text("alg")) {
+        Some(&Value::Negative(alg)) => att_stmt.alg = Some(alg),
+        Some(&Value::Unsigned(alg)) if alg <= i64::MAX as u64 => att_stmt.alg = Some(alg as i64),
+        None => {}
+        _ => return Err(invalid())
+    }
+    match statement.get(&text("sig")) {
+        Some(Value::Bytes(sig)) => att_stmt.sig = Some(sig.clone()),
+        None => {}
+        _ => return Err(invalid())
+    }
+    match statement.get(&text("x5c")) {
+        Some(Value::Array(certs)) => {
+            for cert in certs {
+                match *cert {
+                    Value::Bytes(ref cert) => att_stmt.x5c.push(cert.clone()),
+                    _ => return Err(invalid())
+                }
+            }
+        }
+        None => {}
+        _ => return Err(invalid())
+    }
+
+    let attestation = AttestationObject { fmt, auth_data, att_stmt };
+    if options.attestation == AttestationConveyance::None {
+        return Ok(without_attestation(attestation));
+    }
+    Ok(attestation)
+}
+
+// Replaces the attestation of a new credential with the "none" format, like
+// a WebAuthn client does, so that the token model can't be told. That
+// includes the AAGUID in the attested credential data, if it's there.
+fn without_attestation(mut attestation: AttestationObject) -> AttestationObject {
+    const FLAG_AT: u8 = 0x40;
+    let auth_data = &mut attestation.auth_data;
+    if auth_data.len() >= 53 && auth_data[32] & FLAG_AT != 0 {
+        for byte in &mut auth_data[37..53] {
+            *byte = 0;
+        }
+    }
+    attestation.fmt = String::from("none");
+    attestation.att_stmt = AttestationStatement::default();
+    attestation
+}
+
+// Asks a FIDO2 token for an assertion by one of the credentials in
+// `allow_list`, or by a discoverable credential for `rp_id` if the list is
+// empty. The device waits for the user as `options` ask for before it
+// answers. Devices that don't speak CTAP2 return an error, use `u2f_sign()`
+// for those. Asking for hmac-secret outputs takes a `shared_secret` from
+// `ctap2_shared_secret()`, and a token that lists the extension in getInfo.
+pub fn ctap2_get_assertion<T>(dev: &mut T, rp_id: &str, client_data_hash: &[u8], allow_list: &[Vec<u8>], options: AssertionOptions, shared_secret: Option<&SharedSecret>) -> io::Result<Assertion>
+    where T: U2FDevice + Read + Write
+{
+    use cbor::Value;
+
+    if client_data_hash.len() != PARAMETER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid client data hash"));
+    }
+
+    let hmac_secret = match (options.hmac_secret, shared_secret) {
+        (Some(salts), Some(secret)) => Some((salts, secret)),
+        (Some(_), None) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "hmac-secret needs a shared secret")),
+        (None, _) => None
+    };
+    if hmac_secret.is_some() && !ctap2_get_info(dev)?.supports_extension("hmac-secret") {
+        return Err(io::Error::other("hmac-secret not supported"));
+    }
+    let silent_not_supported = || io::Error::other("Assertions without user presence not supported");
+    if !options.user_presence && !ctap2_get_info(dev)?.user_presence_configurable() {
+        return Err(silent_not_supported());
+    }
+
+    // Keys are in canonical order, like CTAP2 wants them.
+    let text = |s: &str| Value::Text(String::from(s));
+    let mut params = vec![(Value::Unsigned(0x01), text(rp_id)),
+                          (Value::Unsigned(0x02), Value::Bytes(client_data_hash.to_vec()))];
+    if !allow_list.is_empty() {
+        let credentials = allow_list.iter().map(|id| {
+            Value::Map(vec![(text("id"), Value::Bytes(id.clone())),
+                            (text("type"), text("public-key"))])
+        }).collect();
+        params.push((Value::Unsigned(0x03), Value::Array(credentials)));
+    }
+    if let Some((ref salts, secret)) = hmac_secret {
+        let input = hmacsecret::extension_input(secret, salts);
+        params.push((Value::Unsigned(0x04), Value::Map(vec![(text("hmac-secret"), input)])));
+    }
+    params.push((Value::Unsigned(0x05), Value::Map(vec![(text("up"), Value::Bool(options.user_presence)),
+                                                        (text("uv"), Value::Bool(options.user_verification))])));
+
+    // Some tokens list the option, but refuse to skip the check anyway.
+    let resp = match ctap2_request(dev, CTAP2_GET_ASSERTION, Some(&Value::Map(params))) {
+        Err(ref e) if !options.user_presence && (ctap2_status(e) == Some(CTAP2_ERR_UNSUPPORTED_OPTION) || ctap2_status(e) == Some(CTAP2_ERR_INVALID_OPTION)) => {
+            let status = ctap2_status(e).unwrap_or_default();
+            let description = format!("{} (CTAP2 error: {:#04x})", silent_not_supported(), status);
+            return Err(io::Error::other(Ctap2Error { status, description }));
+        }
+        resp => resp?
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid getAssertion response");
+    let bytes = |key: u64| {
+        match resp.get(&Value::Unsigned(key)) {
+            Some(Value::Bytes(bytes)) => Ok(bytes.clone()),
+            _ => Err(invalid())
+        }
+    };
+
+    // The credential may be left out if there was just one to choose from.
+    let credential_id = match resp.get(&Value::Unsigned(0x01)).map(|c| c.get(&text("id"))) {
+        Some(Some(Value::Bytes(id))) => id.clone(),
+        None if allow_list.len() == 1 => allow_list[0].clone(),
+        _ => return Err(invalid())
+    };
+
+    let auth_data = bytes(0x02)?;
+    let hmac_secret = match hmac_secret {
+        Some((_, secret)) => hmacsecret::extension_output(secret, &auth_data)?,
+        None => None
+    };
+
+    Ok(Assertion { credential_id, auth_data, signature: bytes(0x03)?, hmac_secret })
+}
+
+// Tells whether operations with the device will require user verification,
+// i.e. a PIN or built-in UV like a fingerprint, not just user presence.
+// That's the case if the getInfo options say `uv` or `clientPin` is set up.
+// U2F-only devices never do.
+pub fn ctap2_requires_uv<T>(dev: &mut T) -> io::Result<bool>
+    where T: U2FDevice + Read + Write
+{
+    if !dev.get_device_info().supports_cbor() {
+        return Ok(false);
+    }
+
+    let info = ctap2_get_info(dev)?;
+    let enabled = |name| info.option(name).unwrap_or(false);
+    Ok(enabled("uv") || enabled("clientPin"))
+}
+
+////////////////////////////////////////////////////////////////////////
+// Device Communication Functions
+////////////////////////////////////////////////////////////////////////
+
+// Frames are sent and received through stack buffers, the response is
+// allocated once with its final size. There's nothing per frame to pool.
+// Hands a frame to the device's observer, if there is one.
+fn observe<T: U2FDevice>(dev: &T, direction: Direction, frame: &[u8]) {
+    if let Some(observer) = dev.frame_observer() {
+        observer(direction, frame);
+    }
+}
+
+// Forgets the channel of a device that answers on it for somebody else, so
+// that the next poll round INITs a fresh one.
+fn channel_collision<T: U2FDevice>(dev: &mut T) -> io::Error {
+    op_log!(debug, dev.context(), "{}: channel is in use by someone else, allocating a new one", to_hex(&dev.get_cid()));
+    dev.set_cid(&CID_BROADCAST);
+    io::Error::other("Channel collision")
+}
+
+// Splits a message for channel `cid` into the HID frames that carry it: an
+// init frame, then as many continuation frames as needed. Commands without
+// payload still need an init frame. Each frame is handed to `frame` in turn,
+// until it fails.
+fn for_each_frame<F>(cid: [u8; 4], cmd: u8, send: &[u8], mut frame: F) -> io::Result<()>
+    where F: FnMut(&[u8]) -> io::Result<()>
+{
+    let mut sequence: u8 = 0; // Start at 0
+    let mut data_itr = send.iter();
+    let mut init_sent = false;
+    while !init_sent || data_itr.size_hint().0 != 0 {
+        if !init_sent {
+            let mut uf = U2FHIDInit {
+                cid,
+                cmd,
+                bcnth: (send.len() >> 8) as u8,
+                bcntl: send.len() as u8,
+                data: [0; INIT_DATA_SIZE]
+            };
+            set_data(&mut uf.data, &mut data_itr, INIT_DATA_SIZE);
+            frame(to_u8_array(&uf))?;
+            init_sent = true;
+        } else {
+            let mut uf = U2FHIDCont {
+                cid,
+                seq: sequence,
+                data: [0; CONT_DATA_SIZE]
+            };
+            set_data(&mut uf.data, &mut data_itr, CONT_DATA_SIZE);
+            sequence += 1;
+            frame(to_u8_array(&uf))?;
+        }
+    }
+    Ok(())
+}
+
+// Like `for_each_frame()`, with each frame in a buffer of its own.
+fn hid_frames(cid: [u8; 4], cmd: u8, send: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let _ = for_each_frame(cid, cmd, send, |frame| {
+        frames.push(frame.to_vec());
+        Ok(())
+    });
+    frames
+}
+
+// What a frame read while waiting for the answer to a command is to us.
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    // On another channel, for another process.
+    Foreign,
+    // The device is still working on our request, e.g. waiting for the user.
+    Keepalive,
+    // An error on our channel, with its code.
+    Error(u8),
+    // The init frame of the answer to our command.
+    Response,
+    // A continuation frame, with its sequence number.
+    Continuation(u8),
+    // Anything else on our channel: somebody else's answer.
+    Unexpected
+}
+
+fn classify(frame: &[u8], cid: &[u8; 4], cmd: u8) -> Incoming {
+    if frame[4] & TYPE_MASK == TYPE_CONT {
+        let cont: &U2FHIDCont = from_u8_array(frame);
+        if cont.cid != *cid {
+            return Incoming::Foreign;
+        }
+        return Incoming::Continuation(cont.seq);
+    }
+
+    if frame[0..4] != cid[..] {
+        return Incoming::Foreign;
+    }
+    match frame[4] {
+        U2FHID_KEEPALIVE => Incoming::Keepalive,
+        U2FHID_ERROR => Incoming::Error(frame[7]),
+        c if c == cmd => Incoming::Response,
+        _ => Incoming::Unexpected
+    }
+}
+
+// Whether an error can be waited out. Once the device sent a KEEPALIVE for
+// our request it is working on it, a busy error then is about somebody
+// else's attempt to use our channel. Everything else ends the command.
+fn is_transient(code: u8, processing: bool) -> bool {
+    processing && code == ERR_CHANNEL_BUSY
+}
+
+// Sends a message and reads the answer, counted in the device's stats.
+fn sendrecv<T>(dev: &mut T, cmd: u8, send: &[u8]) -> io::Result<Vec<u8>>
+    where T: U2FDevice + Read + Write
+{
+    let rv = transact(dev, cmd, send);
+    record_command(dev, &rv);
+    rv
+}
+
+fn transact<T>(dev: &mut T, cmd: u8, send: &[u8]) -> io::Result<Vec<u8>>
+    where T: U2FDevice + Read + Write
+{
+    // Only timed if it's logged.
+    let mut timer = if log_enabled!(log::LogLevel::Trace) && round_logged(dev.context()) {
+        Some(PhaseTimer::new())
+    } else {
+        None
+    };
+    // Write Data.
+    let cid = dev.get_cid();
+    for_each_frame(cid, cmd, send, |uf| {
+        // Add 1 to HID_RPT_SIZE since we need to prefix this with a record
+        // index.
+        let mut frame : [u8; HID_RPT_SIZE + 1] = [0; HID_RPT_SIZE + 1];
+        frame[1..].clone_from_slice(uf);
+
+        if log_enabled!(log::LogLevel::Trace) && round_logged(dev.context()) {
+            op_log!(trace, dev.context(), "USB send: {}", to_hex(&frame));
+        }
+        observe(dev, Direction::Write, &frame[1..]);
+
+        dev.write(&frame).map(|_| ())
+    })?;
+
+    // Now we read. This happens in 2 chunks: The initial packet, which has the
+    // size we expect overall, then continuation packets, which will fill in
+    // data until we have everything.
+    let mut frame : [u8; HID_RPT_SIZE] = [0u8; HID_RPT_SIZE];
+    let datalen: usize;
+    let mut data : Vec<u8>;
+    if let Some(ref mut timer) = timer {
+        timer.phase("write");
+    }
+
+    // TODO Check the status of the read, figure out how we'll deal with timeouts.
+    // Devices waiting for the user send KEEPALIVE frames until they answer.
+    // Everyone who has the device open sees every frame, those on other
+    // channels are for other processes.
+    let mut processing = false;
+    loop {
+        dev.read(&mut frame).map(|_| ())?;
+        observe(dev, Direction::Read, &frame);
+        match classify(&frame, &dev.get_cid(), cmd) {
+            Incoming::Foreign => continue,
+            Incoming::Keepalive => processing = true,
+            Incoming::Error(code) if is_transient(code, processing) => {
+                op_log!(debug, dev.context(), "{}: ignoring error {:#04x} while the device is processing", to_hex(&dev.get_cid()), code);
+            }
+            Incoming::Error(code) => return Err(hid_error_to_error(code)),
+            Incoming::Response => break,
+            // The answer to somebody else's command, on our channel.
+            Incoming::Continuation(_) | Incoming::Unexpected => return Err(channel_collision(dev))
+        }
+    }
+    if let Some(ref mut timer) = timer {
+        timer.phase("wait");
+    }
+    let mut recvlen = INIT_DATA_SIZE;
+
+    // We'll get an init packet back from USB, open it to see how much we'll be
+    // reading overall. (should unpack to an init struct). Scope this to deal
+    // with the lifetime of the frame borrow in from_u8_array.
+    {
+        let info_frame : &U2FHIDInit = from_u8_array(&frame);
+
+        // Read until we've exhausted the total read amount or error out
+        datalen = (info_frame.bcnth as usize) << 8 | (info_frame.bcntl as usize);
+        // Sequence numbers run out beyond that, we'd wait for frames that
+        // can't come.
+        if datalen > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Response too long: {} bytes", datalen)));
+        }
+        data = Vec::with_capacity(datalen);
+
+        let clone_len = if datalen < recvlen { datalen } else { recvlen };
+        data.extend(info_frame.data[0..clone_len].iter().cloned());
+    }
+    // Only responses that span several reports are worth reporting.
+    if datalen > recvlen {
+        report_read_progress(dev.context(), data.len(), datalen);
+    }
+    let mut sequence: u8 = 0;
+    while recvlen < datalen {
+        // Reset frame value
+        frame = [0u8; HID_RPT_SIZE];
+        dev.read(&mut frame).map(|_| ())?;
+        observe(dev, Direction::Read, &frame);
+        match classify(&frame, &dev.get_cid(), cmd) {
+            Incoming::Foreign | Incoming::Keepalive => continue,
+            // The device gave up on the rest of the answer.
+            Incoming::Error(code) => return Err(hid_error_to_error(code)),
+            Incoming::Continuation(seq) if seq != sequence => {
+                return Err(io::Error::other("Sequence numbers out of order!"));
+            }
+            Incoming::Continuation(_) => {}
+            // Another message on our channel, in the middle of ours.
+            Incoming::Response | Incoming::Unexpected => return Err(channel_collision(dev))
+        }
+        let cont_frame : &U2FHIDCont = from_u8_array(&frame);
+        sequence = cont_frame.seq + 1;
+        if (recvlen + CONT_DATA_SIZE) > datalen {
+            data.extend(cont_frame.data[0..(datalen-recvlen)].iter().cloned());
+        } else {
+            data.extend(cont_frame.data.iter().cloned());
+        }
+        recvlen += CONT_DATA_SIZE;
+        report_read_progress(dev.context(), data.len(), datalen);
+    }
+    if let Some(mut timer) = timer {
+        timer.phase("read");
+        op_log!(trace, dev.context(), "{}: command {:#04x}: {}", to_hex(&dev.get_cid()), cmd, timer);
+    }
+    Ok(data)
+}
+
+// https://en.wikipedia.org/wiki/Smart_card_application_protocol_data_unit
+// https://fidoalliance.org/specs/fido-u2f-v1.0-nfc-bt-amendment-20150514/fido-u2f-raw-message-formats.html#u2f-message-framing
+#[repr(C, packed)]
+#[allow(dead_code)]
+struct U2FAPDUHeader {
+    cla : u8,
+    ins : u8,
     p1 : u8,
     p2 : u8,
     lc : [u8; 3]
 }
 
-fn send_apdu<T>(dev: &mut T, cmd: u8, p1: u8, send: &Vec<u8>) -> io::Result<Vec<u8>>
-    where T: U2FDevice + Read + Write
-{
-    // TODO: Check send length to make sure it's < 2^16
+// Builds an extended length APDU. Fails if it wouldn't fit into a message,
+// which also keeps the length within the 16 bits Lc has.
+fn build_apdu(cmd: u8, p1: u8, send: &[u8]) -> io::Result<Vec<u8>> {
+    check_apdu_data(send)?;
     let header = U2FAPDUHeader {
         cla: 0,
         ins: cmd,
-        p1: p1,
+        p1,
         p2: 0, // p2 is always 0, at least, for our requirements.
         lc: [0, // lc[0] should always be 0
              (send.len() >> 8) as u8,
              (send.len() & 0xff) as u8]
     };
-    // Size of header, plus data, plus 2 0 bytes at the end for maximum return
-    // size.
-    let mut data_vec : Vec<u8> = vec![0; std::mem::size_of::<U2FAPDUHeader>() + send.len() + 2];
-    let header_raw : &[u8] = to_u8_array(&header);
-    data_vec[0..U2FAPDUHEADER_SIZE].clone_from_slice(&header_raw);
-    data_vec[U2FAPDUHEADER_SIZE..(send.len() + U2FAPDUHEADER_SIZE)].clone_from_slice(&send);
-    sendrecv(dev, U2FHID_MSG, &data_vec)
+    // Header, plus data, plus 2 0 bytes at the end for maximum return size.
+    let mut data_vec = to_u8_vec(&header);
+    data_vec.extend(send);
+    data_vec.extend(&[0, 0]);
+    Ok(data_vec)
+}
+
+// Fails for APDU data that `build_apdu()` can't send.
+pub(crate) fn check_apdu_data(send: &[u8]) -> io::Result<()> {
+    if send.len() > MAX_APDU_DATA_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "APDU data too large"));
+    }
+    Ok(())
+}
+
+fn send_apdu<T>(dev: &mut T, cmd: u8, p1: u8, send: &[u8]) -> io::Result<Vec<u8>>
+    where T: U2FDevice + Read + Write
+{
+    let resp = sendrecv(dev, U2FHID_MSG, &build_apdu(cmd, p1, send)?)?;
+
+    // Every response ends with a status word, callers rely on that.
+    if resp.len() < 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "short response"));
+    }
+
+    Ok(resp)
 }
 
 #[cfg(test)]
     mod tests {
-    use super::{U2FDevice, init_device, ping_device, sendrecv, send_apdu};
-    use std::error::Error;
-    use consts::{U2FHID_PING, U2FHID_MSG};
-    mod platform {
-        use consts::{CID_BROADCAST, HID_RPT_SIZE};
-        use u2fprotocol::U2FDevice;
-        use std::io;
-        use std::io::{Read, Write};
-
-        pub struct TestDevice {
-            pub cid: [u8; 4],
-            pub expected_reads: Vec<[u8; HID_RPT_SIZE]>,
-            pub expected_writes: Vec<[u8; HID_RPT_SIZE + 1]>,
-        }
-
-        impl TestDevice {
-            pub fn new() -> TestDevice {
-                TestDevice {
-                    cid: CID_BROADCAST,
-                    expected_reads: Vec::new(),
-                    expected_writes: Vec::new()
-                }
-            }
-            pub fn add_write(&mut self, packet: &[u8], fill_value: u8) {
-                // Add one to deal with record index check
-                let mut write : [u8; HID_RPT_SIZE + 1] = [fill_value; HID_RPT_SIZE + 1];
-                // Make sure we start with a 0, for HID record index
-                write[0] = 0;
-                // Clone packet data in at 1, since front is padded with HID record index
-                write[1..packet.len() + 1].clone_from_slice(&packet);
-                self.expected_writes.push(write);
-            }
-            pub fn add_read(&mut self, packet: &[u8], fill_value: u8) {
-                let mut read : [u8; HID_RPT_SIZE] = [fill_value; HID_RPT_SIZE];
-                read[0..packet.len()].clone_from_slice(&packet);
-                self.expected_reads.push(read);
-            }
-        }
+    use super::{CONT_DATA_SIZE, INIT_DATA_SIZE, Incoming, U2FDevice, classify, ctap2_enumerate_credentials, ctap2_get_assertion, ctap2_get_info, ctap2_make_credential, ctap2_pin_status, ctap2_pin_token, ctap2_shared_secret, ctap2_status, ctap2_requires_uv, hid_error, init_device, is_transient, ping_device, sendrecv, u2f_ping, send_apdu, set_data, u2f_dry_run_register, u2f_dry_run_sign, u2f_init_channel, u2f_init_device, u2f_is_keyhandle_valid, u2f_register, u2f_reset_channel, u2f_sign, u2f_version, u2f_wink, is_wink_not_supported, version_unsupported};
+    use cbor::{self, Value};
+    use consts::{CAPFLAG_CBOR, CAPFLAG_NMSG, CAPFLAG_WINK, CID_BROADCAST, CTAP2_CLIENT_PIN, CTAP2_CREDENTIAL_MANAGEMENT_PREVIEW, CTAP2_ERR_INVALID_OPTION, CTAP2_ERR_NO_CREDENTIALS, CTAP2_GET_ASSERTION, CTAP2_GET_INFO, CTAP2_MAKE_CREDENTIAL, ERR_CHANNEL_BUSY, ERR_INVALID_SEQ, ERR_MSG_TIMEOUT, HID_RPT_SIZE, MAX_APDU_DATA_SIZE, MAX_MESSAGE_SIZE, U2FHID_CBOR, U2FHID_ERROR, U2FHID_INIT, U2FHID_KEEPALIVE, U2FHID_PING, U2FHID_MSG, U2FHID_WINK, U2F_AUTHENTICATE, U2F_CHECK_IS_REGISTERED, U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, U2F_VERSION};
+    use hmacsecret::{extension_input, SharedSecret};
+    use std::io::{self, Write};
+    use testdevice::{apdu, token_key, CountingRng, TestDevice, OUTPUT_ENC, PIN_HASH_ENC, PIN_TOKEN_ENC};
+    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use u2ftypes::{Assertion, AssertionOptions, AttestationConveyance, AttestationObject, AttestationStatement, AuthenticatorInfo, Direction, HmacSecretSalts, KeyHandle, MakeCredentialOptions, OperationContext, PinStatus, RegisterResponse, RelyingParty, User};
 
-        impl Write for TestDevice {
-            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
-                // Pop a vector from the expected writes, check for quality
-                // against bytes array.
-                assert!(self.expected_writes.len() > 0, "Ran out of expected write values!");
-                let check = self.expected_writes.remove(0);
-                assert_eq!(check.len(), bytes.len());
-                assert_eq!(&check[..], bytes);
-                Ok(bytes.len())
-            }
-            // nop
-            fn flush(&mut self) -> io::Result<()> {
-                Ok(())
-            }
-        }
-        impl Read for TestDevice {
-            fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
-                // Pop a vector from the expected writes, check for quality
-                // against bytes array.
-                assert!(self.expected_reads.len() > 0, "Ran out of expected read values!");
-                let check = self.expected_reads.remove(0);
-                bytes.clone_from_slice(&check[..]);
-                Ok(check.len())
-            }
+    #[test]
+    fn test_init_device() {
+        let mut device = TestDevice::new();
+        let nonce = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+
+        device.add_write(&vec![0xff, 0xff, 0xff, 0xff, 0x86, 0x00, 0x08, 0x08,
+                               0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+                         0);
+        device.add_read(&vec![0xff, 0xff, 0xff, 0xff, 0x86, 0x00, 0x11, 0x08,
+                              0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x00,
+                              0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01],
+                        0);
+        if let Err(e) = init_device(&mut device, nonce) {
+            panic!("Init device returned an error! {:?}", e);
         }
-        impl U2FDevice for TestDevice {
-            fn get_cid(&self) -> [u8; 4] {
-                return self.cid.clone();
-            }
-            fn set_cid(&mut self, cid: &[u8; 4]) {
-                self.cid = cid.clone();
-            }
+        assert_eq!(device.get_cid(), [0x00, 0x03, 0x00, 0x14]);
+        assert_eq!(device.get_device_info().init_nonce, Some(nonce));
+        assert_eq!(device.get_device_info().channel_id, Some([0x00, 0x03, 0x00, 0x14]));
+        assert_eq!(device.get_device_info().protocol_version, Some(0x02));
+        assert_eq!(device.get_device_info().device_version, Some([0x04, 0x01, 0x08]));
+    }
+
+    #[test]
+    fn test_init_device_stale_response() {
+        let nonce = [0, 1, 2, 3, 4, 5, 6, 7];
+        let mut theirs = [7, 6, 5, 4, 3, 2, 1, 0, 0x00, 0x03, 0x00, 0x15, 0x02, 0x04, 0x01, 0x08, 0x01];
+        let ours = [0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01];
+
+        // Somebody else's INIT got answered first, ours on the next try.
+        let mut device = TestDevice::new();
+        device.add_message_write(U2FHID_INIT, &nonce);
+        device.add_message_read(U2FHID_INIT, &theirs);
+        device.add_message_write(U2FHID_INIT, &nonce);
+        device.add_message_read(U2FHID_INIT, &ours);
+        init_device(&mut device, nonce).unwrap();
+        assert_eq!(device.get_cid(), [0x00, 0x03, 0x00, 0x14]);
+
+        // Only so many times.
+        let mut device = TestDevice::new();
+        for i in 0..3 {
+            theirs[0] = i;
+            device.add_message_write(U2FHID_INIT, &nonce);
+            device.add_message_read(U2FHID_INIT, &theirs);
         }
+        assert!(init_device(&mut device, nonce).is_err());
+        assert_eq!(device.get_cid(), CID_BROADCAST);
+        assert_eq!(device.get_device_info().channel_id, None);
+    }
+
+    #[test]
+    fn test_double_init() {
+        let cid = [0x00, 0x03, 0x00, 0x14];
+        let init = [0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01];
+
+        // A token that only takes commands once it saw an INIT on the new
+        // channel answers a PING right after the first one with an error.
+        let mut device = TestDevice::new();
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        device.set_cid(&cid);
+        device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_read(U2FHID_ERROR, &[ERR_CHANNEL_BUSY]);
+        device.set_cid(&CID_BROADCAST);
+        init_device(&mut device, [0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        let err = ping_device(&mut device, [8, 9, 10, 11, 12, 13, 14, 15]).unwrap_err();
+        assert_eq!(hid_error(&err), Some(ERR_CHANNEL_BUSY));
+        assert_eq!(err.to_string(), "U2FHID error 0x06: channel busy");
+
+        // It's fine after the second INIT.
+        let mut device = TestDevice::new();
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        device.set_cid(&cid);
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+        device.set_cid(&CID_BROADCAST);
+        u2f_init_device(&mut device, &mut CountingRng(0)).unwrap();
+        assert_eq!(device.get_cid(), cid);
+        assert!(device.expected_writes.is_empty());
     }
 
     #[test]
-    fn test_init_device() {
-        let mut device = platform::TestDevice::new();
-        let nonce = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+    fn test_reset_channel() {
+        let mut device = TestDevice::new();
+        device.info.capabilities = CAPFLAG_CBOR;
 
-        device.add_write(&vec![0xff, 0xff, 0xff, 0xff, 0x86, 0x00, 0x08, 0x08,
-                               0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
-                         0);
-        device.add_read(&vec![0xff, 0xff, 0xff, 0xff, 0x86, 0x00, 0x11, 0x08,
-                              0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x00,
-                              0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01],
-                        0);
-        if let Err(e) = init_device(&mut device, nonce) {
-            assert!(true, format!("Init device returned an error! {:?}", e.description()));
-        }
+        // INIT on the broadcast channel, then INIT, PING and VERSION on the new one.
+        device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+        device.set_cid(&[0x00, 0x03, 0x00, 0x14]);
+        device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        device.add_message_read(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+        device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+
+        // Even though the device had a channel already.
+        device.set_cid(&[1, 2, 3, 4]);
+        u2f_reset_channel(&mut device, &mut CountingRng(0)).unwrap();
         assert_eq!(device.get_cid(), [0x00, 0x03, 0x00, 0x14]);
+        assert_eq!(device.raw_capabilities(), 0x01);
+        assert!(device.expected_writes.is_empty());
+
+        // Nothing is cached if the device keeps answering with a wrong nonce.
+        device.set_cid(&CID_BROADCAST);
+        for _ in 0..3 {
+            device.add_message_write(U2FHID_INIT, &[0, 1, 2, 3, 4, 5, 6, 7]);
+            device.add_message_read(U2FHID_INIT, &[7, 6, 5, 4, 3, 2, 1, 0, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01]);
+        }
+        device.set_cid(&[0x00, 0x03, 0x00, 0x14]);
+        assert!(u2f_reset_channel(&mut device, &mut CountingRng(0)).is_err());
+        assert_eq!(device.get_cid(), CID_BROADCAST);
+        assert_eq!(device.raw_capabilities(), 0);
+        assert_eq!(device.get_device_info().channel_id, None);
     }
 
     #[test]
     fn test_sendrecv_multiple() {
-        let mut device = platform::TestDevice::new();
+        let mut device = TestDevice::new();
         device.set_cid(&[1, 2, 3, 4]);
-        device.add_write(&vec![0x01, 0x02, 0x03, 0x04, U2FHID_PING, 0x00, 0xe4], 1);
+        device.add_write(&[0x01, 0x02, 0x03, 0x04, U2FHID_PING, 0x00, 0xe4], 1);
         // Need CID and sequence number for CONT packets
-        device.add_write(&vec![0x01, 0x02, 0x03, 0x04, 0x00], 1);
-        device.add_write(&vec![0x01, 0x02, 0x03, 0x04, 0x01], 1);
+        device.add_write(&[0x01, 0x02, 0x03, 0x04, 0x00], 1);
+        device.add_write(&[0x01, 0x02, 0x03, 0x04, 0x01], 1);
         device.add_write(&vec![0x01, 0x02, 0x03, 0x04, 0x02, 0x01, 0x01, 0x01,
                                0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
                                0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
@@ -496,10 +1561,10 @@ fn send_apdu<T>(dev: &mut T, cmd: u8, p1: u8, send: &Vec<u8>) -> io::Result<Vec<
                                0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
                                0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
                                0x01, 0x01], 0);
-        device.add_read(&vec![0x01, 0x02, 0x03, 0x04, U2FHID_PING, 0x00, 0xe4], 1);
+        device.add_read(&[0x01, 0x02, 0x03, 0x04, U2FHID_PING, 0x00, 0xe4], 1);
         // Need CID and sequence number for CONT packets
-        device.add_read(&vec![0x01, 0x02, 0x03, 0x04, 0x00], 1);
-        device.add_read(&vec![0x01, 0x02, 0x03, 0x04, 0x01], 1);
+        device.add_read(&[0x01, 0x02, 0x03, 0x04, 0x00], 1);
+        device.add_read(&[0x01, 0x02, 0x03, 0x04, 0x01], 1);
         device.add_read(&vec![0x01, 0x02, 0x03, 0x04, 0x02, 0x01, 0x01, 0x01,
                               0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
                               0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
@@ -509,17 +1574,24 @@ fn send_apdu<T>(dev: &mut T, cmd: u8, p1: u8, send: &Vec<u8>) -> io::Result<Vec<
                               0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
                               0x01, 0x01], 0);
 
-        let d = match sendrecv(&mut device, U2FHID_PING, &vec![1 as u8; 0xe4]) {
+        // Progress is reported for every frame of the response.
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let progress_ = progress.clone();
+        let read_progress = Arc::new(move |received, expected| progress_.lock().unwrap().push((received, expected)));
+        device.set_context(OperationContext { read_progress: Some(read_progress), ..OperationContext::default() });
+
+        let d = match sendrecv(&mut device, U2FHID_PING, &[1u8; 0xe4]) {
             Ok(c) => c,
-            Err(e) => panic!(format!("Init device returned an error! {:?}", e.description()))
+            Err(e) => panic!("Init device returned an error! {:?}", e)
         };
         assert_eq!(d.len(), 0xe4);
-        assert_eq!(d, vec![1 as u8; 0xe4]);
+        assert_eq!(d, vec![1u8; 0xe4]);
+        assert_eq!(*progress.lock().unwrap(), vec![(57, 0xe4), (116, 0xe4), (175, 0xe4), (0xe4, 0xe4)]);
     }
 
     #[test]
     fn test_sendapdu() {
-        let mut device = platform::TestDevice::new();
+        let mut device = TestDevice::new();
         device.set_cid(&[1, 2, 3, 4]);
         device.add_write(&vec![// sendrecv header
                                0x01, 0x02, 0x03, 0x04, U2FHID_MSG, 0x00, 0x0e,
@@ -528,27 +1600,1094 @@ fn send_apdu<T>(dev: &mut T, cmd: u8, p1: u8, send: &Vec<u8>) -> io::Result<Vec<
                                // apdu data
                                0x01, 0x02, 0x03, 0x04, 0x05], 0);
         // Only expect data from APDU back
-        device.add_read(&vec![0x01, 0x02, 0x03, 0x04, U2FHID_MSG, 0x00, 0x05,
+        device.add_read(&[0x01, 0x02, 0x03, 0x04, U2FHID_MSG, 0x00, 0x05,
                               0x01, 0x02, 0x03, 0x04, 0x05], 0);
-        assert!(send_apdu(&mut device, U2FHID_PING, 0xaa, &vec![1, 2, 3, 4, 5]).is_ok());
+        assert!(send_apdu(&mut device, U2FHID_PING, 0xaa, &[1, 2, 3, 4, 5]).is_ok());
+
+        // Data that wouldn't fit into one message is rejected before anything
+        // is sent: beyond 65535 bytes Lc would be truncated, beyond 128
+        // continuation frames the sequence number would wrap.
+        for &len in &[MAX_APDU_DATA_SIZE + 1, 20000, 0x10000] {
+            let err = send_apdu(&mut device, U2FHID_PING, 0xaa, &vec![0; len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let dry_run = u2f_dry_run_register(&[1, 2, 3, 4], &challenge, &application).unwrap();
+
+        let mut expected = vec![0x00, U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, 0x00, 0x00, 0x00, 0x40];
+        expected.extend(&challenge);
+        expected.extend(&application);
+        expected.extend(&[0x00, 0x00]);
+        assert_eq!(dry_run.apdu, expected);
+
+        // 73 bytes: 57 in the init frame, the rest in a continuation frame.
+        let mut init = vec![0x01, 0x02, 0x03, 0x04, U2FHID_MSG, 0x00, 0x49];
+        init.extend(&[0x00, U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, 0x00, 0x00, 0x00, 0x40]);
+        init.extend(vec![0x11; 32]);
+        init.extend(vec![0x22; 18]);
+        let mut cont = vec![0x01, 0x02, 0x03, 0x04, 0x00];
+        cont.extend(vec![0x22; 14]);
+        cont.extend(vec![0x00; 45]);
+        assert_eq!(dry_run.frames, vec![init, cont]);
+
+        // A sign is sent the same way u2f_sign() sends it.
+        let key_handle = KeyHandle::from(vec![0x33; 64]);
+        let dry_run = u2f_dry_run_sign(&[1, 2, 3, 4], &challenge, &application, &key_handle).unwrap();
+        let mut data = challenge.clone();
+        data.extend(&application);
+        data.push(0x40);
+        data.extend(vec![0x33; 64]);
+        let apdu = apdu(U2F_AUTHENTICATE, U2F_REQUEST_USER_PRESENCE, &data);
+        assert_eq!(dry_run.apdu, apdu);
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu);
+        for frame in &dry_run.frames {
+            device.write_all(&[&[0][..], frame].concat()).unwrap();
+        }
+
+        assert!(u2f_dry_run_register(&[1, 2, 3, 4], &challenge[..31], &application).is_err());
+    }
+
+    #[test]
+    fn test_sign_key_handle() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let key_handle = KeyHandle::from_bytes(vec![0x33; 255]).unwrap();
+
+        // The length byte precedes the key handle.
+        let mut data = challenge.clone();
+        data.extend(&application);
+        data.push(0xff);
+        data.extend(vec![0x33; 255]);
+
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_AUTHENTICATE, U2F_REQUEST_USER_PRESENCE, &data));
+        device.add_message_read(U2FHID_MSG, &[0x01, 0x90, 0x00]);
+        assert_eq!(u2f_sign(&mut device, &challenge, &application, &key_handle).unwrap(), vec![0x01, 0x90, 0x00]);
+
+        // 256 bytes don't fit, nothing is sent.
+        let key_handle = KeyHandle::from(vec![0x33; 256]);
+        assert!(u2f_sign(&mut device, &challenge, &application, &key_handle).is_err());
+    }
+
+    #[test]
+    fn test_frame_observer() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00]);
+
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let frames_ = frames.clone();
+        device.set_frame_observer(Some(Arc::new(move |direction, frame: &[u8]| {
+            frames_.lock().unwrap().push((direction, frame.to_vec()));
+        })));
+        assert!(u2f_version(&mut device).is_ok());
+
+        // Both frames, as they went over the wire.
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, Direction::Write);
+        assert_eq!(&frames[0].1[..5], &[1, 2, 3, 4, U2FHID_MSG]);
+        assert_eq!(frames[1].0, Direction::Read);
+        assert_eq!(&frames[1].1[..7], &[1, 2, 3, 4, U2FHID_MSG, 0x00, 0x08]);
     }
 
     #[test]
     fn test_ping_device() {
-        let mut device = platform::TestDevice::new();
+        let mut device = TestDevice::new();
         device.set_cid(&[1, 2, 3, 4]);
         device.add_write(&vec![// apdu header
                                0x01, 0x02, 0x03, 0x04, U2FHID_PING, 0x00, 0x08,
                                // ping nonce
                                0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08], 0);
         // Only expect data from APDU back
-        device.add_read(&vec![0x01, 0x02, 0x03, 0x04, U2FHID_MSG, 0x00, 0x08,
+        device.add_read(&vec![0x01, 0x02, 0x03, 0x04, U2FHID_PING, 0x00, 0x08,
                               0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08], 0);
 
         let random = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
 
         if let Err(e) = ping_device(&mut device, random) {
-            assert!(true, format!("Init device returned an error! {:?}", e.description()));
+            panic!("Init device returned an error! {:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        assert_eq!(MAX_MESSAGE_SIZE, INIT_DATA_SIZE + 128 * CONT_DATA_SIZE);
+    }
+
+    #[test]
+    fn test_u2f_ping() {
+        // 200 bytes take an init frame and three continuation frames.
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_PING, &data);
+        device.add_message_read(U2FHID_PING, &data);
+        assert_eq!(u2f_ping(&mut device, &data).unwrap(), data);
+
+        // A byte flipped along the way.
+        let mut corrupted = data.clone();
+        corrupted[150] ^= 0x01;
+        device.add_message_write(U2FHID_PING, &data);
+        device.add_message_read(U2FHID_PING, &corrupted);
+        assert!(u2f_ping(&mut device, &data).is_err());
+
+        // Nothing is sent if it can't be.
+        assert_eq!(u2f_ping(&mut device, &vec![0; 7610]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // Yields 0x00, 0x01, 0x02, ... so tests know exactly which bytes to expect.
+    #[test]
+    fn test_init_device_deterministic_rng() {
+        let mut device = TestDevice::new();
+        let cid = [0x00, 0x03, 0x00, 0x14];
+
+        // INIT on the broadcast channel, with the first eight bytes as nonce.
+        device.add_write(&vec![0xff, 0xff, 0xff, 0xff, U2FHID_INIT, 0x00, 0x08,
+                               0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07], 0);
+        device.add_read(&vec![0xff, 0xff, 0xff, 0xff, U2FHID_INIT, 0x00, 0x11,
+                              0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+                              0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01], 0);
+        // INIT again on the allocated channel, with the same nonce.
+        device.add_write(&vec![0x00, 0x03, 0x00, 0x14, U2FHID_INIT, 0x00, 0x08,
+                               0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07], 0);
+        device.add_read(&vec![0x00, 0x03, 0x00, 0x14, U2FHID_INIT, 0x00, 0x11,
+                              0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+                              0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x01], 0);
+        // PING on the allocated channel, with the next eight bytes.
+        device.add_write(&vec![0x00, 0x03, 0x00, 0x14, U2FHID_PING, 0x00, 0x08,
+                               0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f], 0);
+        device.add_read(&vec![0x00, 0x03, 0x00, 0x14, U2FHID_PING, 0x00, 0x08,
+                              0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f], 0);
+        // VERSION, answered with "U2F_V2" and SW_NO_ERROR.
+        device.add_write(&vec![0x00, 0x03, 0x00, 0x14, U2FHID_MSG, 0x00, 0x09,
+                               0x00, U2F_VERSION, 0x00, 0x00, 0x00, 0x00, 0x00], 0);
+        device.add_read(&vec![0x00, 0x03, 0x00, 0x14, U2FHID_MSG, 0x00, 0x08,
+                              0x55, 0x32, 0x46, 0x5f, 0x56, 0x32, 0x90, 0x00], 0);
+
+        let mut rng = CountingRng(0);
+        if let Err(e) = u2f_init_device(&mut device, &mut rng) {
+            panic!("u2f_init_device returned an error! {:?}", e);
+        }
+        assert_eq!(device.get_cid(), cid);
+        assert_eq!(device.get_device_info().capabilities, 0x01);
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_pin_status() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        // getInfo, with a PIN set.
+        let options = Value::Map(vec![(Value::Text("rk".to_owned()), Value::Bool(true)),
+                                      (Value::Text("clientPin".to_owned()), Value::Bool(true))]);
+        let info = Value::Map(vec![(Value::Unsigned(0x01), Value::Array(vec![Value::Text("FIDO_2_0".to_owned())])),
+                                   (Value::Unsigned(0x03), Value::Bytes(vec![0xcb; 16])),
+                                   (Value::Unsigned(0x04), options)]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&info));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        // clientPin(pinProtocol: 1, subCommand: getRetries), 5 retries left.
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_CLIENT_PIN, 0xa2, 0x01, 0x01, 0x02, 0x01]);
+        device.add_message_read(U2FHID_CBOR, &[0x00, 0xa1, 0x03, 0x05]);
+
+        let status = ctap2_pin_status(&mut device).unwrap();
+        assert_eq!(status, PinStatus { set: true, retries: 5 });
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_requires_uv() {
+        // U2F-only devices aren't asked, they only need user presence.
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        assert!(!ctap2_requires_uv(&mut device).unwrap());
+
+        // getInfo, with built-in UV set up but no PIN.
+        device.info.capabilities = CAPFLAG_CBOR;
+        for &(uv, expected) in &[(true, true), (false, false)] {
+            let options = Value::Map(vec![(Value::Text("uv".to_owned()), Value::Bool(uv)),
+                                          (Value::Text("clientPin".to_owned()), Value::Bool(false))]);
+            let info = Value::Map(vec![(Value::Unsigned(0x01), Value::Array(vec![Value::Text("FIDO_2_0".to_owned())])),
+                                       (Value::Unsigned(0x04), options)]);
+            let mut resp = vec![0x00];
+            resp.extend(cbor::encode(&info));
+            device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+            device.add_message_read(U2FHID_CBOR, &resp);
+            assert_eq!(ctap2_requires_uv(&mut device).unwrap(), expected);
+
+            // Start over with a new channel, the answer is cached otherwise.
+            device.info.authenticator_info = None;
+        }
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_max_msg_size() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        // getInfo, with a tiny maxMsgSize.
+        let options = Value::Map(vec![(Value::Text("rk".to_owned()), Value::Bool(true))]);
+        let info = Value::Map(vec![(Value::Unsigned(0x01), Value::Array(vec![Value::Text("FIDO_2_0".to_owned())])),
+                                   (Value::Unsigned(0x02), Value::Array(vec![Value::Text("hmac-secret".to_owned())])),
+                                   (Value::Unsigned(0x03), Value::Bytes(vec![0xcb; 16])),
+                                   (Value::Unsigned(0x04), options),
+                                   (Value::Unsigned(0x05), Value::Unsigned(128))]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&info));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        let info = ctap2_get_info(&mut device).unwrap();
+        assert_eq!(info.versions, vec!["FIDO_2_0".to_owned()]);
+        assert_eq!(info.extensions, vec!["hmac-secret".to_owned()]);
+        assert_eq!(info.aaguid, Some(vec![0xcb; 16]));
+        assert_eq!(info.option("rk"), Some(true));
+        assert_eq!(info.option("uv"), None);
+        assert_eq!(info.max_msg_size, Some(128));
+        assert_eq!(device.get_device_info().max_msg_size, Some(128));
+
+        // Two credentials don't fit, nothing is sent.
+        let allow_list = vec![vec![0x33; 64], vec![0x44; 64]];
+        let err = ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &allow_list, AssertionOptions::default(), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_get_info_pin_protocols() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        let protocols = Value::Array(vec![Value::Unsigned(2), Value::Unsigned(1)]);
+        let info = Value::Map(vec![(Value::Unsigned(0x01), Value::Array(vec![Value::Text("FIDO_2_1".to_owned())])),
+                                   (Value::Unsigned(0x06), protocols)]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&info));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+        assert_eq!(ctap2_get_info(&mut device).unwrap().pin_protocols, vec![2, 1]);
+
+        // Protocols have to be numbers.
+        device.info.authenticator_info = None;
+        let info = Value::Map(vec![(Value::Unsigned(0x06), Value::Array(vec![Value::Text("1".to_owned())]))]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&info));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+        assert!(ctap2_get_info(&mut device).is_err());
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_get_info_cached() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        let options = Value::Map(vec![(Value::Text("clientPin".to_owned()), Value::Bool(false))]);
+        let info = Value::Map(vec![(Value::Unsigned(0x01), Value::Array(vec![Value::Text("FIDO_2_0".to_owned())])),
+                                   (Value::Unsigned(0x04), options)]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&info));
+
+        // getInfo is sent once, for all of these.
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+        assert!(!ctap2_requires_uv(&mut device).unwrap());
+        let info = ctap2_get_info(&mut device).unwrap();
+        assert_eq!(info.option("clientPin"), Some(false));
+        assert!(info.extensions.is_empty());
+        assert!(info.pin_protocols.is_empty());
+        assert!(!ctap2_requires_uv(&mut device).unwrap());
+        assert!(device.expected_writes.is_empty());
+
+        // A new channel forgets it.
+        let nonce = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+        device.add_message_write(U2FHID_INIT, &nonce);
+        let mut init_resp = nonce.to_vec();
+        init_resp.extend(&[0x01, 0x02, 0x03, 0x04, 0x02, 0x04, 0x01, 0x08, CAPFLAG_CBOR]);
+        device.add_message_read(U2FHID_INIT, &init_resp);
+        init_device(&mut device, nonce).unwrap();
+        assert_eq!(device.get_device_info().authenticator_info, None);
+
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+        assert!(!ctap2_requires_uv(&mut device).unwrap());
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_get_assertion() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        // {1: "example.com", 2: h'11..', 3: [{"id": h'33..', "type": "public-key"}],
+        //  5: {"up": true, "uv": false}}
+        let mut req = vec![CTAP2_GET_ASSERTION, 0xa4, 0x01, 0x6b];
+        req.extend(b"example.com");
+        req.extend(&[0x02, 0x58, 0x20]);
+        req.extend(&[0x11; 32]);
+        req.extend(&[0x03, 0x81, 0xa2, 0x62, b'i', b'd', 0x50]);
+        req.extend(&[0x33; 16]);
+        req.extend(&[0x64, b't', b'y', b'p', b'e', 0x6a]);
+        req.extend(b"public-key");
+        req.extend(&[0x05, 0xa2, 0x62, b'u', b'p', 0xf5, 0x62, b'u', b'v', 0xf4]);
+
+        // {1: {"id": h'33..', "type": "public-key"}, 2: h'44..', 3: h'55..'},
+        // after the device kept us waiting for a bit.
+        let mut resp = vec![0x00, 0xa3, 0x01, 0xa2, 0x62, b'i', b'd', 0x50];
+        resp.extend(&[0x33; 16]);
+        resp.extend(&[0x64, b't', b'y', b'p', b'e', 0x6a]);
+        resp.extend(b"public-key");
+        resp.extend(&[0x02, 0x58, 0x25]);
+        resp.extend(&[0x44; 37]);
+        resp.extend(&[0x03, 0x58, 0x46]);
+        resp.extend(&[0x55; 70]);
+
+        device.add_message_write(U2FHID_CBOR, &req);
+        device.add_message_read(U2FHID_KEEPALIVE, &[0x02]);
+        device.add_message_read(U2FHID_KEEPALIVE, &[0x02]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        let assertion = ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[vec![0x33; 16]], AssertionOptions::default(), None).unwrap();
+        assert_eq!(assertion, Assertion { credential_id: vec![0x33; 16], auth_data: vec![0x44; 37], signature: vec![0x55; 70], hmac_secret: None });
+        assert!(device.expected_reads.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_get_assertion_silent() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+        let options = AssertionOptions { user_presence: false, ..AssertionOptions::default() };
+
+        // Tokens that can't test for presence.
+        let mut info = AuthenticatorInfo::default();
+        info.options.push((String::from("up"), false));
+        device.info.authenticator_info = Some(info);
+        assert!(ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[vec![0x33; 16]], options, None).is_err());
+
+        // {1: "example.com", 2: h'11..', 3: [{"id": h'33..', "type": "public-key"}],
+        //  5: {"up": false, "uv": false}}
+        let mut info = AuthenticatorInfo::default();
+        info.options.push((String::from("up"), true));
+        device.info.authenticator_info = Some(info);
+        let mut req = vec![CTAP2_GET_ASSERTION, 0xa4, 0x01, 0x6b];
+        req.extend(b"example.com");
+        req.extend(&[0x02, 0x58, 0x20]);
+        req.extend(&[0x11; 32]);
+        req.extend(&[0x03, 0x81, 0xa2, 0x62, b'i', b'd', 0x50]);
+        req.extend(&[0x33; 16]);
+        req.extend(&[0x64, b't', b'y', b'p', b'e', 0x6a]);
+        req.extend(b"public-key");
+        req.extend(&[0x05, 0xa2, 0x62, b'u', b'p', 0xf4, 0x62, b'u', b'v', 0xf4]);
+
+        // The user presence flag isn't set.
+        let mut resp = vec![0x00, 0xa2, 0x02, 0x58, 0x25];
+        resp.extend(&[0x44; 32]);
+        resp.extend(&[0x00, 0x00, 0x00, 0x00, 0x07]);
+        resp.extend(&[0x03, 0x58, 0x46]);
+        resp.extend(&[0x55; 70]);
+        device.add_message_write(U2FHID_CBOR, &req);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        let assertion = ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[vec![0x33; 16]], options, None).unwrap();
+        assert_eq!(assertion.auth_data[32], 0x00);
+
+        // Refusing it anyway gets a clear error.
+        device.add_message_write(U2FHID_CBOR, &req);
+        device.add_message_read(U2FHID_CBOR, &[CTAP2_ERR_INVALID_OPTION]);
+        let err = ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[vec![0x33; 16]], options, None).unwrap_err();
+        assert_eq!(ctap2_status(&err), Some(CTAP2_ERR_INVALID_OPTION));
+        assert!(err.to_string().contains("without user presence"));
+        assert!(device.expected_reads.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_pin_token() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        // clientPin(pinProtocol: 1, subCommand: getKeyAgreement)
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&Value::Map(vec![(Value::Unsigned(0x01), token_key())])));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_CLIENT_PIN, 0xa2, 0x01, 0x01, 0x02, 0x02]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        // clientPin(pinProtocol: 1, subCommand: getPinToken, ...)
+        let platform_key = SharedSecret::from_cose_key(&token_key(), &mut CountingRng(0)).unwrap().cose_key();
+        let req = Value::Map(vec![(Value::Unsigned(0x01), Value::Unsigned(1)),
+                                  (Value::Unsigned(0x02), Value::Unsigned(0x05)),
+                                  (Value::Unsigned(0x03), platform_key),
+                                  (Value::Unsigned(0x06), Value::Bytes(PIN_HASH_ENC.to_vec()))]);
+        let mut data = vec![CTAP2_CLIENT_PIN];
+        data.extend(cbor::encode(&req));
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&Value::Map(vec![(Value::Unsigned(0x02), Value::Bytes(PIN_TOKEN_ENC.to_vec()))])));
+        device.add_message_write(U2FHID_CBOR, &data);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        assert_eq!(ctap2_pin_token(&mut device, &mut CountingRng(0), "1234").unwrap(), vec![0x91; 32]);
+        assert!(device.expected_reads.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_enumerate_credentials() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+        let pin_token = [0x91; 32];
+
+        // Tokens that don't advertise it.
+        device.info.authenticator_info = Some(AuthenticatorInfo::default());
+        assert!(ctap2_enumerate_credentials(&mut device, &[0x44; 32], &pin_token).is_err());
+
+        let mut info = AuthenticatorInfo::default();
+        info.options.push((String::from("credentialMgmtPreview"), true));
+        device.info.authenticator_info = Some(info);
+
+        // enumerateCredentialsBegin(rpIDHash), with the pinAuth for the
+        // pinToken 0x91...91.
+        let mut begin = vec![CTAP2_CREDENTIAL_MANAGEMENT_PREVIEW, 0xa4, 0x01, 0x04, 0x02, 0xa1, 0x01, 0x58, 0x20];
+        begin.extend(&[0x44; 32]);
+        begin.extend(&[0x03, 0x01, 0x04, 0x50]);
+        begin.extend(&[0xbe, 0xfd, 0x0a, 0x05, 0x3a, 0x60, 0x0e, 0x4c, 0xe2, 0x6b, 0x99, 0x26, 0x63, 0x67, 0xa0, 0xa5]);
+
+        let text = |s: &str| Value::Text(s.to_owned());
+        let public_key = Value::Map(vec![(Value::Unsigned(1), Value::Unsigned(2)), (Value::Unsigned(3), Value::Negative(-7))]);
+        let credential = |id: u8, name: Option<&str>, total: Option<u64>| {
+            let mut user = vec![(text("id"), Value::Bytes(vec![id]))];
+            if let Some(name) = name {
+                user.push((text("name"), text(name)));
+            }
+            let mut resp = vec![(Value::Unsigned(0x06), Value::Map(user)),
+                                (Value::Unsigned(0x07), Value::Map(vec![(text("id"), Value::Bytes(vec![id; 16])), (text("type"), text("public-key"))])),
+                                (Value::Unsigned(0x08), public_key.clone())];
+            if let Some(total) = total {
+                resp.push((Value::Unsigned(0x09), Value::Unsigned(total)));
+            }
+            let mut data = vec![0x00];
+            data.extend(cbor::encode(&Value::Map(resp)));
+            data
+        };
+
+        // Two credentials, the second one from getNextCredential.
+        device.add_message_write(U2FHID_CBOR, &begin);
+        device.add_message_read(U2FHID_CBOR, &credential(0x01, Some("alice"), Some(2)));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_CREDENTIAL_MANAGEMENT_PREVIEW, 0xa1, 0x01, 0x05]);
+        device.add_message_read(U2FHID_CBOR, &credential(0x02, None, None));
+
+        let credentials = ctap2_enumerate_credentials(&mut device, &[0x44; 32], &pin_token).unwrap();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials[0].user, User { id: vec![0x01], name: Some(String::from("alice")), display_name: None });
+        assert_eq!(credentials[0].credential_id, vec![0x01; 16]);
+        assert_eq!(credentials[0].public_key, cbor::encode(&public_key));
+        assert_eq!(credentials[1].user.id, vec![0x02]);
+        assert_eq!(credentials[1].credential_id, vec![0x02; 16]);
+
+        // None for the RP. A response without the total is malformed.
+        device.add_message_write(U2FHID_CBOR, &begin);
+        device.add_message_read(U2FHID_CBOR, &[CTAP2_ERR_NO_CREDENTIALS]);
+        assert!(ctap2_enumerate_credentials(&mut device, &[0x44; 32], &pin_token).unwrap().is_empty());
+        device.add_message_write(U2FHID_CBOR, &begin);
+        device.add_message_read(U2FHID_CBOR, &credential(0x01, None, None));
+        let err = ctap2_enumerate_credentials(&mut device, &[0x44; 32], &pin_token).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(device.expected_reads.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_get_assertion_hmac_secret() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        let mut info = AuthenticatorInfo::default();
+        info.extensions.push("hmac-secret".to_owned());
+        device.info.authenticator_info = Some(info);
+
+        // clientPin(pinProtocol: 1, subCommand: getKeyAgreement)
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&Value::Map(vec![(Value::Unsigned(0x01), token_key())])));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_CLIENT_PIN, 0xa2, 0x01, 0x01, 0x02, 0x02]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+        let secret = ctap2_shared_secret(&mut device, &mut CountingRng(0)).unwrap();
+
+        let options = AssertionOptions { hmac_secret: Some(HmacSecretSalts { salt1: [0x71; 32], salt2: Some([0x72; 32]) }), ..AssertionOptions::default() };
+        let text = |s: &str| Value::Text(s.to_owned());
+        let credential = Value::Map(vec![(text("id"), Value::Bytes(vec![0x33; 16])), (text("type"), text("public-key"))]);
+        let extensions = Value::Map(vec![(text("hmac-secret"), extension_input(&secret, options.hmac_secret.as_ref().unwrap()))]);
+        let req = Value::Map(vec![(Value::Unsigned(0x01), text("example.com")),
+                                  (Value::Unsigned(0x02), Value::Bytes(vec![0x11; 32])),
+                                  (Value::Unsigned(0x03), Value::Array(vec![credential])),
+                                  (Value::Unsigned(0x04), extensions),
+                                  (Value::Unsigned(0x05), Value::Map(vec![(text("up"), Value::Bool(true)), (text("uv"), Value::Bool(false))]))]);
+        let mut data = vec![CTAP2_GET_ASSERTION];
+        data.extend(cbor::encode(&req));
+
+        // The authenticator data has the extension data flag set, and the
+        // encrypted outputs at the end.
+        let mut auth_data = vec![0x44; 32];
+        auth_data.extend(&[0x81, 0x00, 0x00, 0x00, 0x01]);
+        auth_data.extend(cbor::encode(&Value::Map(vec![(text("hmac-secret"), Value::Bytes(OUTPUT_ENC.to_vec()))])));
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&Value::Map(vec![(Value::Unsigned(0x02), Value::Bytes(auth_data)),
+                                                  (Value::Unsigned(0x03), Value::Bytes(vec![0x55; 70]))])));
+
+        device.add_message_write(U2FHID_CBOR, &data);
+        device.add_message_read(U2FHID_CBOR, &resp);
+        let assertion = ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[vec![0x33; 16]], options, Some(&secret)).unwrap();
+        assert_eq!(assertion.hmac_secret, Some([[0x81; 32], [0x82; 32]].concat()));
+        assert!(device.expected_writes.is_empty());
+
+        // Nothing is sent to tokens without the extension, or without a
+        // shared secret.
+        device.info.authenticator_info = Some(AuthenticatorInfo::default());
+        assert!(ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[], options, Some(&secret)).is_err());
+        let err = ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[], options, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_ctap2_make_credential() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+
+        let rp = RelyingParty { id: String::from("example.com"), name: Some(String::from("Example")) };
+        let user = User { id: vec![0x01, 0x02], name: Some(String::from("jo")), display_name: None };
+
+        // {1: h'11..', 2: {"id": "example.com", "name": "Example"},
+        //  3: {"id": h'0102', "name": "jo"}, 4: [{"alg": -7, "type": "public-key"}],
+        //  7: {"rk": false, "uv": false}}
+        let mut req = vec![CTAP2_MAKE_CREDENTIAL, 0xa5, 0x01, 0x58, 0x20];
+        req.extend(&[0x11; 32]);
+        req.extend(&[0x02, 0xa2, 0x62, b'i', b'd', 0x6b]);
+        req.extend(b"example.com");
+        req.extend(&[0x64, b'n', b'a', b'm', b'e', 0x67]);
+        req.extend(b"Example");
+        req.extend(&[0x03, 0xa2, 0x62, b'i', b'd', 0x42, 0x01, 0x02]);
+        req.extend(&[0x64, b'n', b'a', b'm', b'e', 0x62, b'j', b'o']);
+        req.extend(&[0x04, 0x81, 0xa2, 0x63, b'a', b'l', b'g', 0x26]);
+        req.extend(&[0x64, b't', b'y', b'p', b'e', 0x6a]);
+        req.extend(b"public-key");
+        req.extend(&[0x07, 0xa2, 0x62, b'r', b'k', 0xf4, 0x62, b'u', b'v', 0xf4]);
+
+        // {1: "packed", 2: h'44..', 3: {"alg": -7, "sig": h'55..', "x5c": [h'66..']}}
+        let mut resp = vec![0x00, 0xa3, 0x01, 0x66];
+        resp.extend(b"packed");
+        resp.extend(&[0x02, 0x58, 0x40]);
+        resp.extend(&[0x44; 64]);
+        resp.extend(&[0x03, 0xa3, 0x63, b'a', b'l', b'g', 0x26]);
+        resp.extend(&[0x63, b's', b'i', b'g', 0x58, 0x46]);
+        resp.extend(&[0x55; 70]);
+        resp.extend(&[0x63, b'x', b'5', b'c', 0x81, 0x58, 0x20]);
+        resp.extend(&[0x66; 32]);
+
+        device.add_message_write(U2FHID_CBOR, &req);
+        device.add_message_read(U2FHID_KEEPALIVE, &[0x02]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+
+        let options = MakeCredentialOptions { attestation: AttestationConveyance::Direct, ..MakeCredentialOptions::default() };
+        let attestation = ctap2_make_credential(&mut device, &[0x11; 32], &rp, &user, &[-7], options).unwrap();
+        assert_eq!(attestation.fmt, "packed");
+        assert_eq!(attestation.auth_data, vec![0x44; 64]);
+        assert_eq!(attestation.att_stmt, AttestationStatement { alg: Some(-7), sig: Some(vec![0x55; 70]), x5c: vec![vec![0x66; 32]] });
+        assert!(device.expected_reads.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_make_credential_none() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        let rp = RelyingParty { id: String::from("example.com"), name: None };
+        let user = User { id: vec![0x01], name: None, display_name: None };
+        let options = MakeCredentialOptions { resident_key: true, user_verification: false, attestation: AttestationConveyance::Direct };
+
+        // U2F-only devices.
+        assert!(ctap2_make_credential(&mut device, &[0x11; 32], &rp, &user, &[-7], options).is_err());
+
+        // Tokens that can't store it.
+        device.info.capabilities = CAPFLAG_CBOR;
+        device.info.authenticator_info = Some(AuthenticatorInfo::default());
+        assert!(ctap2_make_credential(&mut device, &[0x11; 32], &rp, &user, &[-7], options).is_err());
+
+        // The "none" format has an empty statement. A malformed one is an
+        // error.
+        let mut info = AuthenticatorInfo::default();
+        info.options.push((String::from("rk"), true));
+        device.info.authenticator_info = Some(info);
+        let mut req = vec![CTAP2_MAKE_CREDENTIAL, 0xa5, 0x01, 0x58, 0x20];
+        req.extend(&[0x11; 32]);
+        req.extend(&[0x02, 0xa1, 0x62, b'i', b'd', 0x6b]);
+        req.extend(b"example.com");
+        req.extend(&[0x03, 0xa1, 0x62, b'i', b'd', 0x41, 0x01]);
+        req.extend(&[0x04, 0x81, 0xa2, 0x63, b'a', b'l', b'g', 0x26]);
+        req.extend(&[0x64, b't', b'y', b'p', b'e', 0x6a]);
+        req.extend(b"public-key");
+        req.extend(&[0x07, 0xa2, 0x62, b'r', b'k', 0xf5, 0x62, b'u', b'v', 0xf4]);
+        for statement in &[vec![0xa0], vec![0xa1, 0x63, b's', b'i', b'g', 0x01]] {
+            let mut resp = vec![0x00, 0xa3, 0x01, 0x64];
+            resp.extend(b"none");
+            resp.extend(&[0x02, 0x41, 0x44, 0x03]);
+            resp.extend(statement);
+            device.add_message_write(U2FHID_CBOR, &req);
+            device.add_message_read(U2FHID_CBOR, &resp);
+        }
+
+        let attestation = ctap2_make_credential(&mut device, &[0x11; 32], &rp, &user, &[-7], options).unwrap();
+        assert_eq!(attestation.fmt, "none");
+        assert_eq!(attestation.att_stmt, AttestationStatement::default());
+        let err = ctap2_make_credential(&mut device, &[0x11; 32], &rp, &user, &[-7], options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_ctap2_make_credential_attestation() {
+        let text = |s: &str| Value::Text(String::from(s));
+        let rp = RelyingParty { id: String::from("example.com"), name: None };
+        let user = User { id: vec![0x01], name: None, display_name: None };
+        let request = |enterprise: bool| {
+            let mut request = vec![
+                (Value::Unsigned(0x01), Value::Bytes(vec![0x11; 32])),
+                (Value::Unsigned(0x02), Value::Map(vec![(text("id"), text("example.com"))])),
+                (Value::Unsigned(0x03), Value::Map(vec![(text("id"), Value::Bytes(vec![0x01]))])),
+                (Value::Unsigned(0x04), Value::Array(vec![Value::Map(vec![(text("alg"), Value::Negative(-7)), (text("type"), text("public-key"))])])),
+                (Value::Unsigned(0x07), Value::Map(vec![(text("rk"), Value::Bool(false)), (text("uv"), Value::Bool(false))]))
+            ];
+            if enterprise {
+                request.push((Value::Unsigned(0x0a), Value::Unsigned(1)));
+            }
+            let mut req = vec![CTAP2_MAKE_CREDENTIAL];
+            req.extend(cbor::encode(&Value::Map(request)));
+            req
+        };
+
+        // Attested credential data: rpIdHash, flags, counter, AAGUID, and
+        // the rest.
+        let mut auth_data = vec![0x44; 32];
+        auth_data.extend(&[0x41, 0x00, 0x00, 0x00, 0x07]);
+        auth_data.extend(&[0xcb; 16]);
+        auth_data.extend(&[0x00, 0x01, 0x33]);
+        let statement = Value::Map(vec![(text("alg"), Value::Negative(-7)), (text("sig"), Value::Bytes(vec![0x55; 8]))]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&Value::Map(vec![(Value::Unsigned(0x01), text("packed")),
+                                                  (Value::Unsigned(0x02), Value::Bytes(auth_data.clone())),
+                                                  (Value::Unsigned(0x03), statement)])));
+        let packed = AttestationObject { fmt: String::from("packed"), auth_data: auth_data.clone(), att_stmt: AttestationStatement { alg: Some(-7), sig: Some(vec![0x55; 8]), x5c: vec![] } };
+
+        let make_credential = |device: &mut TestDevice, attestation: AttestationConveyance, enterprise: bool| {
+            device.add_message_write(U2FHID_CBOR, &request(enterprise));
+            device.add_message_read(U2FHID_CBOR, &resp);
+            let options = MakeCredentialOptions { attestation, ..MakeCredentialOptions::default() };
+            let rv = ctap2_make_credential(device, &[0x11; 32], &rp, &user, &[-7], options).unwrap();
+            assert!(device.expected_writes.is_empty());
+            rv
+        };
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.info.capabilities = CAPFLAG_CBOR;
+        device.info.authenticator_info = Some(AuthenticatorInfo::default());
+
+        // By default, the token's attestation is dropped.
+        let none = make_credential(&mut device, AttestationConveyance::default(), false);
+        assert_eq!(none.fmt, "none");
+        assert_eq!(none.att_stmt, AttestationStatement::default());
+        assert_eq!(&none.auth_data[37..53], &[0; 16]);
+        assert_eq!(&none.auth_data[..37], &auth_data[..37]);
+        assert_eq!(&none.auth_data[53..], &auth_data[53..]);
+
+        assert_eq!(make_credential(&mut device, AttestationConveyance::Indirect, false), packed);
+        assert_eq!(make_credential(&mut device, AttestationConveyance::Direct, false), packed);
+
+        // Enterprise attestation is only asked for if the token has it.
+        assert_eq!(make_credential(&mut device, AttestationConveyance::Enterprise, false), packed);
+        let mut info = AuthenticatorInfo::default();
+        info.options.push((String::from("ep"), true));
+        device.info.authenticator_info = Some(info);
+        assert_eq!(make_credential(&mut device, AttestationConveyance::Enterprise, true), packed);
+    }
+
+    #[test]
+    fn test_ctap2_get_assertion_errors() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        let options = AssertionOptions::default();
+
+        // U2F-only devices.
+        assert!(ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[], options, None).is_err());
+
+        // A discoverable credential, but there is none.
+        device.info.capabilities = CAPFLAG_CBOR;
+        let mut req = vec![CTAP2_GET_ASSERTION, 0xa3, 0x01, 0x6b];
+        req.extend(b"example.com");
+        req.extend(&[0x02, 0x58, 0x20]);
+        req.extend(&[0x11; 32]);
+        req.extend(&[0x05, 0xa2, 0x62, b'u', b'p', 0xf5, 0x62, b'u', b'v', 0xf4]);
+        device.add_message_write(U2FHID_CBOR, &req);
+        device.add_message_read(U2FHID_CBOR, &[CTAP2_ERR_NO_CREDENTIALS]);
+
+        let err = ctap2_get_assertion(&mut device, "example.com", &[0x11; 32], &[], options, None).unwrap_err();
+        assert_eq!(ctap2_status(&err), Some(CTAP2_ERR_NO_CREDENTIALS));
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_ctap2_pin_status_not_supported() {
+        // U2F-only devices aren't even asked.
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        assert!(ctap2_pin_status(&mut device).is_err());
+
+        // FIDO2 devices without PIN support.
+        device.info.capabilities = CAPFLAG_CBOR;
+        let info = Value::Map(vec![(Value::Unsigned(0x01), Value::Array(vec![Value::Text("FIDO_2_0".to_owned())]))]);
+        let mut resp = vec![0x00];
+        resp.extend(cbor::encode(&info));
+        device.add_message_write(U2FHID_CBOR, &[CTAP2_GET_INFO]);
+        device.add_message_read(U2FHID_CBOR, &resp);
+        assert!(ctap2_pin_status(&mut device).is_err());
+        assert!(device.expected_writes.is_empty());
+    }
+
+    #[test]
+    fn test_raw_capabilities() {
+        let mut device = TestDevice::new();
+        let nonce = [0x08, 0x04, 0x02, 0x01, 0x10, 0x20, 0x40, 0x80];
+
+        // WINK, CBOR and an unknown bit.
+        device.add_write(&vec![0xff, 0xff, 0xff, 0xff, U2FHID_INIT, 0x00, 0x08,
+                               0x08, 0x04, 0x02, 0x01, 0x10, 0x20, 0x40, 0x80], 0);
+        device.add_read(&vec![0xff, 0xff, 0xff, 0xff, U2FHID_INIT, 0x00, 0x11,
+                              0x08, 0x04, 0x02, 0x01, 0x10, 0x20, 0x40, 0x80,
+                              0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, 0x45], 0);
+
+        init_device(&mut device, nonce).unwrap();
+        assert_eq!(device.raw_capabilities(), 0x45);
+        assert!(device.get_device_info().supports_cbor());
+    }
+
+    #[test]
+    fn test_short_response() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x90]);
+
+        let err = u2f_version(&mut device).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "short response");
+    }
+
+    #[test]
+    fn test_channel_collision() {
+        let mut device = TestDevice::new();
+
+        // Frames on other channels are for other processes.
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_PING, &[1; 60]);
+        device.add_read(&[5, 6, 7, 8, U2FHID_PING, 0x00, 0x03], 9);
+        device.add_read(&[1, 2, 3, 4, U2FHID_PING, 0x00, 60], 1);
+        device.add_read(&[5, 6, 7, 8, 0x00], 9);
+        device.add_read(&[1, 2, 3, 4, 0x00], 1);
+        assert_eq!(sendrecv(&mut device, U2FHID_PING, &[1; 60]).unwrap(), vec![1; 60]);
+        assert_eq!(device.get_cid(), [1, 2, 3, 4]);
+
+        // Somebody else's answer on our channel makes us INIT a new one.
+        device.add_message_write(U2FHID_PING, &[1, 2, 3]);
+        device.add_message_read(U2FHID_WINK, &[]);
+        assert!(sendrecv(&mut device, U2FHID_PING, &[1, 2, 3]).is_err());
+        assert_eq!(device.get_cid(), CID_BROADCAST);
+
+        let init = [0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, CAPFLAG_NMSG];
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        device.set_cid(&[0x00, 0x03, 0x00, 0x14]);
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.set_cid(&CID_BROADCAST);
+        u2f_init_channel(&mut device, &mut CountingRng(0)).unwrap();
+        assert_eq!(device.get_cid(), [0x00, 0x03, 0x00, 0x14]);
+    }
+
+    #[test]
+    fn test_u2f_is_keyhandle_valid() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let key_handle = KeyHandle::from(vec![0x33; 64]);
+        let mut data = challenge.clone();
+        data.extend(&application);
+        data.push(64);
+        data.extend(key_handle.as_bytes());
+        let check = apdu(U2F_AUTHENTICATE, U2F_CHECK_IS_REGISTERED, &data);
+
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        let answers: [(&[u8], bool); 4] = [
+            (&[0x69, 0x85], true),
+            (&[0x6a, 0x80], false),
+            // Unexpected ones aren't errors, just not this device.
+            (&[0x6d, 0x00], false),
+            (&[0x90, 0x00], false)
+        ];
+        for &(answer, valid) in answers.iter() {
+            device.add_message_write(U2FHID_MSG, &check);
+            device.add_message_read(U2FHID_MSG, answer);
+            assert_eq!(u2f_is_keyhandle_valid(&mut device, &challenge, &application, &key_handle).unwrap(), valid);
         }
+
+        // Without a status word there is nothing to go by.
+        device.add_message_write(U2FHID_MSG, &check);
+        device.add_message_read(U2FHID_MSG, &[0x69]);
+        assert!(u2f_is_keyhandle_valid(&mut device, &challenge, &application, &key_handle).is_err());
+    }
+
+    #[test]
+    fn test_register_long_certificate() {
+        let challenge = vec![0x11; 32];
+        let application = vec![0x22; 32];
+        let mut request = challenge.clone();
+        request.extend(&application);
+
+        // A typical attestation certificate with a 2048-bit RSA key, 1000
+        // bytes or so, and an ECDSA signature.
+        let mut certificate = vec![0x30, 0x82, 0x03, 0xe4];
+        certificate.extend((0..0x3e4).map(|i| i as u8));
+        let mut response = vec![0x05, 0x04];
+        response.extend(&[0x44; 64]);
+        response.extend(&[0x40]);
+        response.extend(&[0x55; 64]);
+        response.extend(&certificate);
+        response.extend(&[0x30, 0x45]);
+        response.extend(&[0x66; 0x45]);
+
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, &request));
+        let mut answer = response.clone();
+        answer.extend(&[0x90, 0x00]);
+        device.add_message_read(U2FHID_MSG, &answer);
+        let raw = u2f_register(&mut device, &challenge, &application).unwrap();
+        assert_eq!(raw, answer);
+        let parsed = RegisterResponse::parse(&response).unwrap();
+        assert_eq!(parsed.certificate, certificate);
+        assert_eq!(parsed.signature.len(), 0x47);
+    }
+
+    #[test]
+    fn test_response_gaps_and_length() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+
+        // A lost continuation frame.
+        device.add_message_write(U2FHID_PING, &[1; 200]);
+        device.add_read(&[1, 2, 3, 4, U2FHID_PING, 0x00, 200], 1);
+        device.add_read(&[1, 2, 3, 4, 0x00], 1);
+        device.add_read(&[1, 2, 3, 4, 0x02], 1);
+        assert!(sendrecv(&mut device, U2FHID_PING, &[1; 200]).is_err());
+
+        // More than fits into the sequence numbers.
+        device.add_message_write(U2FHID_PING, &[1; 8]);
+        device.add_read(&[1, 2, 3, 4, U2FHID_PING, 0xff, 0xff], 1);
+        let err = sendrecv(&mut device, U2FHID_PING, &[1; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(device.expected_reads.is_empty());
+    }
+
+    #[test]
+    fn test_u2f_wink() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+
+        // Nothing is sent without CAPFLAG_WINK.
+        let err = u2f_wink(&mut device).unwrap_err();
+        assert_eq!(err.to_string(), "WINK not supported");
+        assert!(is_wink_not_supported(&err));
+        assert!(!is_wink_not_supported(&io::Error::other("WINK not supported")));
+
+        device.info.capabilities = CAPFLAG_WINK;
+        device.add_message_write(U2FHID_WINK, &[]);
+        device.add_message_read(U2FHID_WINK, &[]);
+        u2f_wink(&mut device).unwrap();
+        assert!(device.expected_reads.is_empty());
+    }
+
+    #[test]
+    fn test_set_data() {
+        // Shorter than `max`.
+        let mut data = [0u8; 8];
+        let bytes = [1, 2, 3];
+        let mut itr = bytes.iter();
+        assert_eq!(set_data(&mut data, &mut itr, 8), 3);
+        assert_eq!(data, [1, 2, 3, 0, 0, 0, 0, 0]);
+
+        // Longer than `data`, the rest is left.
+        let mut data = [0u8; 4];
+        let bytes = [1, 2, 3, 4, 5, 6];
+        let mut itr = bytes.iter();
+        assert_eq!(set_data(&mut data, &mut itr, 8), 4);
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(itr.as_slice(), &[5, 6]);
+
+        // `max` is smaller than both.
+        let mut data = [0u8; 4];
+        let mut itr = bytes.iter();
+        assert_eq!(set_data(&mut data, &mut itr, 2), 2);
+        assert_eq!(data, [1, 2, 0, 0]);
+        assert_eq!(itr.as_slice(), &[3, 4, 5, 6]);
+
+        // Exactly as many.
+        let mut data = [0u8; 6];
+        let mut itr = bytes.iter();
+        assert_eq!(set_data(&mut data, &mut itr, 6), 6);
+        assert_eq!(data, bytes);
+        assert!(itr.next().is_none());
+    }
+
+    #[test]
+    fn test_interleaved_frames() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        let request = apdu(U2F_VERSION, 0, &[]);
+        let mut response = vec![0x55; 70];
+        response.extend(&[0x90, 0x00]);
+
+        // Keepalives, others' traffic and a busy error for somebody poking at
+        // our channel, with the answer split up by more of the same.
+        device.add_message_write(U2FHID_MSG, &request);
+        device.add_read(&[1, 2, 3, 4, U2FHID_KEEPALIVE, 0x00, 0x01, 0x01], 0);
+        device.add_read(&[5, 6, 7, 8, U2FHID_ERROR, 0x00, 0x01, ERR_CHANNEL_BUSY], 0);
+        device.add_read(&[5, 6, 7, 8, U2FHID_MSG, 0x00, 0x02, 0x6a, 0x80], 0);
+        device.add_read(&[1, 2, 3, 4, U2FHID_ERROR, 0x00, 0x01, ERR_CHANNEL_BUSY], 0);
+        device.add_read(&[1, 2, 3, 4, U2FHID_KEEPALIVE, 0x00, 0x01, 0x02], 0);
+        device.add_read(&[1, 2, 3, 4, U2FHID_MSG, 0x00, 72], 0x55);
+        device.add_read(&[5, 6, 7, 8, 0x00], 0x66);
+        device.add_read(&[1, 2, 3, 4, U2FHID_KEEPALIVE, 0x00, 0x01, 0x02], 0);
+        let mut cont = vec![1, 2, 3, 4, 0x00];
+        cont.extend(&response[57..]);
+        device.add_read(&cont, 0);
+        assert_eq!(sendrecv(&mut device, U2FHID_MSG, &request).unwrap(), response);
+        assert_eq!(device.get_cid(), [1, 2, 3, 4]);
+
+        // Before any keepalive, busy means our request was turned down.
+        device.add_message_write(U2FHID_MSG, &request);
+        device.add_read(&[1, 2, 3, 4, U2FHID_ERROR, 0x00, 0x01, ERR_CHANNEL_BUSY], 0);
+        assert_eq!(hid_error(&sendrecv(&mut device, U2FHID_MSG, &request).unwrap_err()), Some(ERR_CHANNEL_BUSY));
+
+        // Other errors end the command, even halfway through the answer.
+        device.add_message_write(U2FHID_MSG, &request);
+        device.add_read(&[1, 2, 3, 4, U2FHID_KEEPALIVE, 0x00, 0x01, 0x01], 0);
+        device.add_read(&[1, 2, 3, 4, U2FHID_ERROR, 0x00, 0x01, ERR_MSG_TIMEOUT], 0);
+        assert_eq!(hid_error(&sendrecv(&mut device, U2FHID_MSG, &request).unwrap_err()), Some(ERR_MSG_TIMEOUT));
+        device.add_message_write(U2FHID_MSG, &request);
+        device.add_read(&[1, 2, 3, 4, U2FHID_MSG, 0x00, 72], 0x55);
+        device.add_read(&[1, 2, 3, 4, U2FHID_ERROR, 0x00, 0x01, ERR_MSG_TIMEOUT], 0);
+        assert_eq!(hid_error(&sendrecv(&mut device, U2FHID_MSG, &request).unwrap_err()), Some(ERR_MSG_TIMEOUT));
+        assert_eq!(device.get_cid(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_classify() {
+        let cid = [1, 2, 3, 4];
+        let frame = |bytes: &[u8]| {
+            let mut frame = [0u8; HID_RPT_SIZE];
+            frame[..bytes.len()].copy_from_slice(bytes);
+            frame
+        };
+        assert_eq!(classify(&frame(&[5, 6, 7, 8, U2FHID_MSG]), &cid, U2FHID_MSG), Incoming::Foreign);
+        assert_eq!(classify(&frame(&[1, 2, 3, 4, U2FHID_KEEPALIVE, 0, 1, 2]), &cid, U2FHID_MSG), Incoming::Keepalive);
+        assert_eq!(classify(&frame(&[1, 2, 3, 4, U2FHID_ERROR, 0, 1, ERR_INVALID_SEQ]), &cid, U2FHID_MSG), Incoming::Error(ERR_INVALID_SEQ));
+        assert_eq!(classify(&frame(&[1, 2, 3, 4, U2FHID_MSG]), &cid, U2FHID_MSG), Incoming::Response);
+        assert_eq!(classify(&frame(&[1, 2, 3, 4, 0x03]), &cid, U2FHID_MSG), Incoming::Continuation(3));
+        assert_eq!(classify(&frame(&[5, 6, 7, 8, 0x03]), &cid, U2FHID_MSG), Incoming::Foreign);
+        assert_eq!(classify(&frame(&[1, 2, 3, 4, U2FHID_WINK]), &cid, U2FHID_MSG), Incoming::Unexpected);
+        assert!(is_transient(ERR_CHANNEL_BUSY, true));
+        assert!(!is_transient(ERR_CHANNEL_BUSY, false));
+        assert!(!is_transient(ERR_MSG_TIMEOUT, true));
+    }
+
+    #[test]
+    fn test_init_settle_delay() {
+        let init = [0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, CAPFLAG_NMSG];
+        let script = |device: &mut TestDevice| {
+            device.set_cid(&CID_BROADCAST);
+            device.add_message_write(U2FHID_INIT, &init[..8]);
+            device.add_message_read(U2FHID_INIT, &init);
+            device.set_cid(&[0x00, 0x03, 0x00, 0x14]);
+            device.add_message_write(U2FHID_INIT, &init[..8]);
+            device.add_message_read(U2FHID_INIT, &init);
+            device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+            device.set_cid(&CID_BROADCAST);
+        };
+
+        // The token needs a moment after INIT, which we give it.
+        let mut device = TestDevice::new();
+        device.settle_time = Some(Duration::from_millis(2));
+        script(&mut device);
+        device.set_context(OperationContext { init_settle_delay: Some(Duration::from_millis(10)), ..OperationContext::default() });
+        u2f_init_device(&mut device, &mut CountingRng(0)).unwrap();
+
+        // Without it, the first command is rejected.
+        script(&mut device);
+        device.set_context(OperationContext { init_settle_delay: Some(Duration::from_millis(0)), ..OperationContext::default() });
+        device.settle_time = Some(Duration::from_secs(10));
+        let err = u2f_init_device(&mut device, &mut CountingRng(0)).unwrap_err();
+        assert_eq!(hid_error(&err), Some(ERR_CHANNEL_BUSY));
     }
+
+    #[test]
+    fn test_version_unsupported() {
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x6d, 0x00]);
+
+        let err = u2f_version(&mut device).unwrap_err();
+        assert!(version_unsupported(&err));
+        assert_eq!(err.to_string(), "Device does not support the U2F version command, it's likely FIDO2-only");
+
+        // Other failures aren't mistaken for it.
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x6a, 0x80]);
+        assert!(!version_unsupported(&u2f_version(&mut device).unwrap_err()));
+
+        // A FIDO2 device that rejects the command is still initialized.
+        let init = [0, 1, 2, 3, 4, 5, 6, 7, 0x00, 0x03, 0x00, 0x14, 0x02, 0x04, 0x01, 0x08, CAPFLAG_CBOR];
+        device.set_cid(&CID_BROADCAST);
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        device.set_cid(&[0x00, 0x03, 0x00, 0x14]);
+        device.add_message_write(U2FHID_INIT, &init[..8]);
+        device.add_message_read(U2FHID_INIT, &init);
+        device.add_message_write(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_read(U2FHID_PING, &[8, 9, 10, 11, 12, 13, 14, 15]);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_VERSION, 0, &[]));
+        device.add_message_read(U2FHID_MSG, &[0x6d, 0x00]);
+        device.set_cid(&CID_BROADCAST);
+        u2f_init_device(&mut device, &mut CountingRng(0)).unwrap();
+        assert!(device.expected_writes.is_empty());
+    }
+
 }
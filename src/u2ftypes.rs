@@ -0,0 +1,782 @@
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use consts::{CAPFLAG_CBOR, INIT_NONCE_SIZE, SW_NO_ERROR};
+use counter::sign_counter;
+use p256;
+use stats::DeviceStatsMap;
+use util::{constant_time_eq, deadline, der_length, from_base64url, to_base64url};
+
+// Transports a U2F token can be reached over. Only USB HID is implemented for
+// now, but tokens may show up over NFC as well once support for it lands, so
+// callers can already restrict operations to one of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Transport {
+    #[default]
+    UsbHid,
+    Nfc
+}
+
+// How an authenticator is attached, as WebAuthn's AuthenticatorAttachment.
+// Relying parties may ask for either kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AuthenticatorAttachment {
+    // Built into the client device, e.g. a TPM.
+    Platform,
+    // Roaming between clients, like every token we talk to.
+    CrossPlatform
+}
+
+// Information about a device, as gathered during enumeration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub transport: Transport,
+    // Many tokens deliberately don't expose a serial number.
+    pub serial_number: Option<String>,
+    // The capability byte from the U2FHID_INIT response, see CAPFLAG_*.
+    pub capabilities: u8,
+    // The U2FHID protocol version and the device's major, minor and build
+    // version from the U2FHID_INIT response.
+    pub protocol_version: Option<u8>,
+    pub device_version: Option<[u8; 3]>,
+    // USB vendor and product IDs, if the platform tells us.
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    // The largest CTAP2 request the device takes, once getInfo told us.
+    pub max_msg_size: Option<usize>,
+    // The getInfo response, kept until the device's channel is set up again.
+    pub authenticator_info: Option<AuthenticatorInfo>,
+    // Where the device was found, as passed to `U2FManager::cancel_device()`.
+    // Not known on macOS.
+    pub path: Option<String>,
+    // The IOKit registry entry ID of the device, which identifies it on
+    // macOS instead of a path. Only known on macOS.
+    pub registry_id: Option<u64>,
+    // The nonce of the last U2FHID_INIT and the channel it allocated, for
+    // binding a session to the device. Channels are ephemeral: every INIT,
+    // e.g. after an error or `refresh_devices()`, allocates a new one.
+    pub init_nonce: Option<[u8; INIT_NONCE_SIZE]>,
+    pub channel_id: Option<[u8; 4]>,
+    // The USB interface of a composite device the token's HID is on, and
+    // its interrupt endpoint addresses. Only known on Linux.
+    pub usb_interface: Option<u8>,
+    pub usb_endpoint_in: Option<u8>,
+    pub usb_endpoint_out: Option<u8>,
+    // How often the IN endpoint's descriptor asks the host to poll it for
+    // reports. That's up to the kernel's HID driver, it doesn't change how
+    // often commands are sent to the device.
+    pub usb_poll_interval: Option<Duration>
+}
+
+impl DeviceInfo {
+    pub fn new(transport: Transport) -> Self {
+        Self { transport, serial_number: None, capabilities: 0, protocol_version: None, device_version: None, vendor_id: None, product_id: None, max_msg_size: None, authenticator_info: None, path: None, registry_id: None, init_nonce: None, channel_id: None, usb_interface: None, usb_endpoint_in: None, usb_endpoint_out: None, usb_poll_interval: None }
+    }
+
+    // Whether the device speaks CTAP2, i.e. is a FIDO2 token.
+    pub fn supports_cbor(&self) -> bool {
+        self.capabilities & CAPFLAG_CBOR != 0
+    }
+
+    // Whether the device may be used for an operation that was restricted to
+    // the given transport. `None` means any transport will do.
+    pub fn matches_transport(&self, transport: Option<Transport>) -> bool {
+        transport.is_none_or(|t| t == self.transport)
+    }
+
+    // All our transports reach roaming tokens.
+    pub fn attachment(&self) -> AuthenticatorAttachment {
+        match self.transport {
+            Transport::UsbHid | Transport::Nfc => AuthenticatorAttachment::CrossPlatform
+        }
+    }
+}
+
+impl Default for DeviceInfo {
+    fn default() -> Self {
+        Self::new(Transport::default())
+    }
+}
+
+// Which build of the library is in use, for bug reports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LibraryInfo {
+    // The crate version.
+    pub version: &'static str,
+    // The U2FHID/CTAPHID interface version we implement.
+    pub protocol_version: u32,
+    // The platform backend compiled in, e.g. "linux-hidraw".
+    pub backend: &'static str
+}
+
+// A device that showed up or went away.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    Added(DeviceInfo),
+    Removed(DeviceInfo)
+}
+
+// How to pick among several devices that could complete an operation, e.g.
+// when more than one accepts a register request. Devices are asked one after
+// the other in every polling round, so this only decides who's asked first:
+// it's best effort, a device touched a round earlier still wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    // Whichever device answers first.
+    #[default]
+    FirstToRespond,
+    // Ask devices with this USB vendor and product ID first, e.g. the ones
+    // an organization issued.
+    PreferDevice(u16, u16)
+}
+
+impl SelectionPolicy {
+    pub fn prefers(&self, info: &DeviceInfo) -> bool {
+        match *self {
+            SelectionPolicy::FirstToRespond => false,
+            SelectionPolicy::PreferDevice(vid, pid) => {
+                info.vendor_id == Some(vid) && info.product_id == Some(pid)
+            }
+        }
+    }
+}
+
+// Decides which devices an operation may use.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceFilter {
+    // Only use devices on this transport, if given.
+    pub transport: Option<Transport>,
+    // (vendor ID, product ID) pairs of device models to stay away from.
+    pub blocklist: Vec<(u16, u16)>,
+    // Only use devices that were plugged in after the operation started.
+    pub require_reinsert: bool,
+    // Only use devices that were attached when the operation started.
+    pub present_at_start: bool,
+    // Only use devices for U2F register/sign that list U2F_V2 in getInfo,
+    // if they speak CTAP2.
+    pub require_u2f: bool,
+    // Which devices to ask first.
+    pub selection: SelectionPolicy,
+    // Talk to devices through this platform backend only, one of
+    // `U2FManager::backends()`. Picked per device if `None`.
+    pub backend: Option<String>
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        info.matches_transport(self.transport) && !self.is_blocked(info)
+    }
+
+    fn is_blocked(&self, info: &DeviceInfo) -> bool {
+        match (info.vendor_id, info.product_id) {
+            (Some(vid), Some(pid)) => self.blocklist.contains(&(vid, pid)),
+            _ => false
+        }
+    }
+}
+
+// Whether a frame went to the device or came from it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Write,
+    Read
+}
+
+// Gets to see every HID report exchanged with a device, e.g. to capture the
+// traffic. Reports are passed as is, without the leading report ID byte.
+pub type FrameObserver = Arc<dyn Fn(Direction, &[u8]) + Send + Sync>;
+
+// What an operation hands to each device it uses, for the protocol code to
+// pick up. Devices used outside of an operation have none.
+#[derive(Clone, Default)]
+pub struct OperationContext {
+    // Tags the operation's log messages, see `OperationOptions`.
+    pub(crate) correlation_id: Option<String>,
+    // Told about the progress of long responses.
+    pub(crate) read_progress: Option<ReadProgress>,
+    // How long to wait after allocating a channel before sending commands
+    // on it. A few milliseconds if `None`.
+    pub(crate) init_settle_delay: Option<Duration>,
+    // Where commands sent to the device are counted.
+    pub(crate) stats: Option<DeviceStatsMap>,
+    // Set during polling rounds that only repeat the one before, whose
+    // frames and commands aren't logged.
+    pub(crate) quiet_round: Option<Arc<AtomicBool>>
+}
+
+// Told how many bytes of a response that spans several HID reports were
+// received so far, and how many there are in total. Called once per report.
+pub type ReadProgress = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+// Told how many of the key handles were signed with so far, and how many
+// there are, whenever that changes. See `U2FManager::sign_all_keys()`.
+pub type SignProgress = Box<dyn Fn(usize, usize) + Send>;
+
+// Authenticate requests prefix the key handle with a single length byte.
+pub const MAX_KEY_HANDLE_SIZE: usize = 255;
+
+// A key handle, as returned by a token on registration. Converting from a
+// `Vec<u8>` can't fail, so the length is checked again once the key handle is
+// used, `from_bytes()` checks it right away.
+#[derive(Clone, Debug, Eq)]
+pub struct KeyHandle(Vec<u8>);
+
+// Key handles may wrap the private key, see `constant_time_eq()`.
+impl PartialEq for KeyHandle {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Hash for KeyHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl KeyHandle {
+    pub fn from_bytes(bytes: Vec<u8>) -> io::Result<Self> {
+        let key_handle = KeyHandle(bytes);
+        key_handle.check()?;
+        Ok(key_handle)
+    }
+
+    // Decodes a credential id as WebAuthn sends it, and checks the length.
+    pub fn from_base64url(encoded: &str) -> io::Result<Self> {
+        Self::from_bytes(from_base64url(encoded)?)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_base64url(&self) -> String {
+        to_base64url(&self.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn check(&self) -> io::Result<()> {
+        if self.0.len() > MAX_KEY_HANDLE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Key handle too large"));
+        }
+        Ok(())
+    }
+
+    // Whether the first byte is the length of the rest, as if the caller had
+    // passed the length prefix from a register response along. The prefix
+    // is added again when encoding, and the token won't know the handle.
+    fn looks_length_prefixed(&self) -> bool {
+        self.0.first().is_some_and(|&len| len as usize == self.0.len() - 1)
+    }
+
+    // Appends the length byte and the key handle itself, as they appear in
+    // authenticate requests. The length is always that of the handle, even
+    // if it looks like it has one already.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.check()?;
+        if self.looks_length_prefixed() {
+            warn!("key handle of {} bytes starts with its own length, is it prefixed twice?", self.0.len());
+        }
+        buf.push(self.0.len() as u8);
+        buf.extend(&self.0);
+        Ok(())
+    }
+}
+
+impl From<Vec<u8>> for KeyHandle {
+    fn from(bytes: Vec<u8>) -> Self {
+        KeyHandle(bytes)
+    }
+}
+
+// What a relying party keeps from a raw register response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterResponse {
+    // An uncompressed P-256 point: 0x04, x, y.
+    pub public_key: Vec<u8>,
+    pub key_handle: KeyHandle,
+    // The DER encoded X.509 attestation certificate.
+    pub certificate: Vec<u8>,
+    // The DER encoded ECDSA signature over the registration.
+    pub signature: Vec<u8>
+}
+
+fn invalid_register_response() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid register response")
+}
+
+// Returns the length of the DER SEQUENCE `der` starts with, header included,
+// as its length field says. Certificates are longer than 127 bytes, so the
+// long forms are accepted too, see `der_length()`.
+fn der_sequence_len(der: &[u8]) -> io::Result<usize> {
+    match der.split_first() {
+        Some((&0x30, rest)) => match der_length(rest) {
+            Some((len, contents)) => Ok(der.len() - contents.len() + len),
+            None => Err(invalid_register_response())
+        },
+        _ => Err(invalid_register_response())
+    }
+}
+
+// Leaves out the trailing 0x9000 of a response, as the manager's callbacks
+// get it. The status word isn't part of the signed data.
+fn without_status_word(response: &[u8]) -> &[u8] {
+    match response.len().checked_sub(2) {
+        Some(len) if response[len..] == SW_NO_ERROR => &response[..len],
+        _ => response
+    }
+}
+
+impl RegisterResponse {
+    // Parses a response as the `U2FManager::register()` callback gets it,
+    // status word and all.
+    pub fn from_raw(response: &[u8]) -> io::Result<Self> {
+        Self::parse(without_status_word(response))
+    }
+
+    // The response starts with 0x05, the public key, the key handle length
+    // and the key handle. The attestation certificate follows, its DER
+    // length tells where the signature starts. The signature is the rest.
+    pub fn parse(response: &[u8]) -> io::Result<Self> {
+        if response.len() < 67 || response[0] != 0x05 {
+            return Err(invalid_register_response());
+        }
+
+        let key_handle_len = response[66] as usize;
+        if response.len() < 67 + key_handle_len {
+            return Err(invalid_register_response());
+        }
+
+        let public_key = response[1..66].to_vec();
+        let key_handle = KeyHandle(response[67..67 + key_handle_len].to_vec());
+        let attestation = &response[67 + key_handle_len..];
+        let (certificate, signature) = attestation.split_at(der_sequence_len(attestation)?);
+        if signature.is_empty() {
+            return Err(invalid_register_response());
+        }
+        Ok(Self { public_key, key_handle, certificate: certificate.to_vec(), signature: signature.to_vec() })
+    }
+
+    // Checks that the public key is a point on P-256, so that garbage from a
+    // buggy or malicious token doesn't get stored.
+    pub fn validate_public_key(&self) -> io::Result<()> {
+        let key = &self.public_key;
+        if key.len() != 65 || key[0] != 0x04 || !p256::is_on_curve(&key[1..33], &key[33..]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid public key"));
+        }
+        Ok(())
+    }
+}
+
+// A parsed authenticate response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignResponse {
+    pub user_present: bool,
+    pub counter: u32,
+    // The DER encoded ECDSA signature.
+    pub signature: Vec<u8>
+}
+
+impl SignResponse {
+    // Parses a response as the `U2FManager::sign()` callback gets it, status
+    // word and all.
+    pub fn from_raw(response: &[u8]) -> io::Result<Self> {
+        Self::parse(without_status_word(response))
+    }
+
+    // A user presence byte, the counter in big endian byte order and the
+    // signature.
+    pub fn parse(response: &[u8]) -> io::Result<Self> {
+        let counter = sign_counter(response);
+        match counter {
+            Some(counter) if response.len() > 5 => {
+                Ok(Self { user_present: response[0] & 0x01 != 0, counter, signature: response[5..].to_vec() })
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid sign response"))
+        }
+    }
+}
+
+// What a command would put on the wire, for inspecting the format without a
+// device. Frames are HID reports as devices see them, without the report id
+// the platform prepends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DryRun {
+    pub apdu: Vec<u8>,
+    pub frames: Vec<Vec<u8>>
+}
+
+// The relying party a CTAP2 credential is created for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: Option<String>
+}
+
+// The user account a CTAP2 credential is created for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: Vec<u8>,
+    pub name: Option<String>,
+    pub display_name: Option<String>
+}
+
+// Which attestation a relying party wants for a new credential, as in
+// WebAuthn. CTAP 2.0 tokens always attest in their own format, so all but
+// `Enterprise` are up to us: `None` replaces the token's attestation with
+// the "none" format and zeroes the AAGUID, `Indirect` and `Direct` keep it
+// as is. `Enterprise` asks tokens that list the "ep" option for enterprise
+// attestation, others attest as with `Direct`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AttestationConveyance {
+    #[default]
+    None,
+    Indirect,
+    Direct,
+    Enterprise
+}
+
+// What a CTAP2 makeCredential asks for besides user presence. By default,
+// nothing, and no attestation.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MakeCredentialOptions {
+    // Store the credential on the token, so that it can be discovered.
+    pub resident_key: bool,
+    pub user_verification: bool,
+    pub attestation: AttestationConveyance
+}
+
+// What a CTAP2 makeCredential is about, see `U2FManager::make_credential()`.
+// The client data hash is 32 bytes, and there is at least one COSE
+// algorithm, the ones preferred first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CredentialRequest {
+    pub client_data_hash: Vec<u8>,
+    pub rp: RelyingParty,
+    pub user: User,
+    pub algorithms: Vec<i64>,
+    pub options: MakeCredentialOptions
+}
+
+// How a register or sign operation runs, see
+// `U2FManager::register_with_options()`. By default, it never times out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OperationOptions {
+    // When to give up.
+    pub deadline: Option<Instant>,
+    // Only time out once no device was plugged in or removed for this long,
+    // e.g. for a prompt that should stay open while the user is busy finding
+    // the right key. Gives up at the `deadline` regardless.
+    pub idle_timeout: Option<Duration>,
+    // A message for the user, see `U2FManager::register_with_prompt()`.
+    pub prompt: Option<String>,
+    // Only use the devices at these paths, see
+    // `U2FManager::register_with_devices()`.
+    pub paths: Option<Vec<String>>,
+    // Tags the operation's log messages, e.g. to tell concurrent requests of
+    // a daemon apart.
+    pub correlation_id: Option<String>
+}
+
+impl OperationOptions {
+    // Gives up after `timeout` seconds, zero means never.
+    pub fn with_timeout(timeout: u64) -> Self {
+        Self { deadline: deadline(timeout), ..Self::default() }
+    }
+}
+
+// The attestation statement of a new credential. Which fields are set
+// depends on the format: "packed" and "fido-u2f" have a signature and
+// usually a certificate chain, "none" has nothing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AttestationStatement {
+    // A COSE algorithm identifier, e.g. -7 for ES256.
+    pub alg: Option<i64>,
+    pub sig: Option<Vec<u8>>,
+    // DER certificates, the attestation certificate first.
+    pub x5c: Vec<Vec<u8>>
+}
+
+// The answer to a CTAP2 makeCredential.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttestationObject {
+    pub fmt: String,
+    // Contains the credential id and public key.
+    pub auth_data: Vec<u8>,
+    pub att_stmt: AttestationStatement
+}
+
+// A discoverable credential stored on a FIDO2 token, as listed by
+// `ctap2_enumerate_credentials()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResidentCredential {
+    pub user: User,
+    pub credential_id: Vec<u8>,
+    // The credential's COSE_Key, CBOR encoded.
+    pub public_key: Vec<u8>
+}
+
+// Salts for the hmac-secret extension. The token derives a secret from each
+// one and the credential, the same every time, e.g. to unlock a disk. The
+// second salt lets callers rotate secrets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HmacSecretSalts {
+    pub salt1: [u8; 32],
+    pub salt2: Option<[u8; 32]>
+}
+
+// What a CTAP2 getAssertion asks the user for. By default, just presence.
+// Tokens that don't support the hmac-secret extension are left alone if it's
+// asked for. Without `user_presence`, the token signs without a touch, if
+// it's `user_presence_configurable()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AssertionOptions {
+    pub user_presence: bool,
+    pub user_verification: bool,
+    pub hmac_secret: Option<HmacSecretSalts>
+}
+
+impl Default for AssertionOptions {
+    fn default() -> Self {
+        Self { user_presence: true, user_verification: false, hmac_secret: None }
+    }
+}
+
+// The answer to a CTAP2 getAssertion: which credential was used, the
+// authenticator data, and the signature over it and the client data hash.
+// If hmac-secret was asked for, the token's secrets follow, 32 bytes per
+// salt.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assertion {
+    pub credential_id: Vec<u8>,
+    pub auth_data: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub hmac_secret: Option<Vec<u8>>
+}
+
+// What a FIDO2 token tells about itself in its getInfo response. Fields we
+// don't know are left out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuthenticatorInfo {
+    // e.g. "U2F_V2" and "FIDO_2_0".
+    pub versions: Vec<String>,
+    // Extensions the token understands, e.g. "hmac-secret". Others would be
+    // ignored.
+    pub extensions: Vec<String>,
+    pub aaguid: Option<Vec<u8>>,
+    // Options like "rk", "uv" or "clientPin". Options that aren't listed
+    // aren't supported.
+    pub options: Vec<(String, bool)>,
+    // The largest request the token takes, in bytes.
+    pub max_msg_size: Option<usize>,
+    // The PIN/UV auth protocols the token speaks, 1 and/or 2, in its order
+    // of preference.
+    pub pin_protocols: Vec<u64>
+}
+
+impl AuthenticatorInfo {
+    pub fn option(&self, name: &str) -> Option<bool> {
+        self.options.iter().find(|&(key, _)| key == name).map(|&(_, value)| value)
+    }
+
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|extension| extension == name)
+    }
+    // Whether the token can test for user presence, and so can be told not
+    // to, see `AssertionOptions`. The `up` option is true if it's left out.
+    pub fn user_presence_configurable(&self) -> bool {
+        self.option("up").unwrap_or(true)
+    }
+}
+
+// Whether a FIDO2 token has a PIN set, and how many attempts are left.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PinStatus {
+    pub set: bool,
+    pub retries: u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthenticatorAttachment, DeviceFilter, DeviceInfo, KeyHandle, RegisterResponse, SignResponse, Transport, der_sequence_len};
+    use p256::{GX, GY};
+
+    #[test]
+    fn test_default_transport() {
+        assert_eq!(DeviceInfo::default().transport, Transport::UsbHid);
+    }
+
+    #[test]
+    fn test_attachment() {
+        assert_eq!(DeviceInfo::new(Transport::UsbHid).attachment(), AuthenticatorAttachment::CrossPlatform);
+        assert_eq!(DeviceInfo::new(Transport::Nfc).attachment(), AuthenticatorAttachment::CrossPlatform);
+    }
+
+    #[test]
+    fn test_transport_filter() {
+        let usb = DeviceInfo::new(Transport::UsbHid);
+        let nfc = DeviceInfo::new(Transport::Nfc);
+        let devices = [usb.clone(), nfc.clone()];
+
+        let filtered: Vec<&DeviceInfo> = devices.iter().filter(|info| {
+            info.matches_transport(Some(Transport::UsbHid))
+        }).collect();
+        assert_eq!(filtered, vec![&usb]);
+
+        // No filter means all devices are used.
+        assert!(usb.matches_transport(None));
+        assert!(nfc.matches_transport(None));
+        assert!(nfc.matches_transport(Some(Transport::Nfc)));
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let device = |vid, pid| {
+            let mut info = DeviceInfo::new(Transport::UsbHid);
+            info.vendor_id = Some(vid);
+            info.product_id = Some(pid);
+            info
+        };
+
+        let filter = DeviceFilter { blocklist: vec![(0x1050, 0x0120)], ..DeviceFilter::default() };
+        assert!(!filter.matches(&device(0x1050, 0x0120)));
+        assert!(filter.matches(&device(0x1050, 0x0407)));
+        assert!(filter.matches(&device(0x096e, 0x0120)));
+
+        // Devices we don't know the IDs of can't be blocked.
+        assert!(filter.matches(&DeviceInfo::new(Transport::UsbHid)));
+    }
+
+    #[test]
+    fn test_key_handle_size() {
+        assert_eq!(KeyHandle::from_bytes(vec![0x33; 255]).unwrap().len(), 255);
+        assert!(KeyHandle::from_bytes(vec![0x33; 256]).is_err());
+
+        // Unchecked key handles are rejected once they're encoded.
+        let mut buf = Vec::new();
+        assert!(KeyHandle::from(vec![0x33; 256]).encode_into(&mut buf).is_err());
+        assert!(buf.is_empty());
+
+        KeyHandle::from(vec![0x01, 0x02]).encode_into(&mut buf).unwrap();
+        assert_eq!(buf, vec![0x02, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn test_key_handle_length_prefixed() {
+        let mut key_handle = vec![0x40];
+        key_handle.extend(&[0x33; 64]);
+
+        let mut buf = Vec::new();
+        KeyHandle::from(vec![0x33; 64]).encode_into(&mut buf).unwrap();
+        assert_eq!(buf, key_handle);
+        assert!(!KeyHandle::from(vec![0x33; 64]).looks_length_prefixed());
+
+        // A handle that includes its prefix gets another one, for its actual
+        // length.
+        let prefixed = KeyHandle::from(key_handle.clone());
+        assert!(prefixed.looks_length_prefixed());
+        let mut buf = Vec::new();
+        prefixed.encode_into(&mut buf).unwrap();
+        assert_eq!(buf[0], 0x41);
+        assert_eq!(&buf[1..], &key_handle[..]);
+        assert!(!KeyHandle::from(Vec::new()).looks_length_prefixed());
+    }
+
+    #[test]
+    fn test_key_handle_base64url() {
+        let key_handle = KeyHandle::from_base64url("-_8").unwrap();
+        assert_eq!(key_handle.as_bytes(), &[0xfb, 0xff]);
+        assert_eq!(key_handle.to_base64url(), "-_8");
+        assert!(KeyHandle::from_base64url("+/8").is_err());
+    }
+
+    #[test]
+    fn test_register_response() {
+        let mut response = vec![0x05, 0x04];
+        response.extend(&GX);
+        response.extend(&GY);
+        response.extend(&[0x02, 0xaa, 0xbb]);
+        response.extend(&[0x30, 0x01, 0x07]); // Certificate.
+        response.extend(&[0x30, 0x02, 0x05, 0x06]); // Signature.
+
+        let parsed = RegisterResponse::parse(&response).unwrap();
+        assert_eq!(parsed.key_handle, KeyHandle::from(vec![0xaa, 0xbb]));
+        assert_eq!(parsed.certificate, vec![0x30, 0x01, 0x07]);
+        assert_eq!(parsed.signature, vec![0x30, 0x02, 0x05, 0x06]);
+        assert!(parsed.validate_public_key().is_ok());
+
+        // Without a signature, or with the certificate cut short.
+        assert!(RegisterResponse::parse(&response[..response.len() - 4]).is_err());
+        assert!(RegisterResponse::parse(&response[..response.len() - 5]).is_err());
+
+        // Tampering with the point moves it off the curve.
+        response[40] ^= 0x01;
+        assert!(RegisterResponse::parse(&response).unwrap().validate_public_key().is_err());
+
+        assert!(RegisterResponse::parse(&response[..68]).is_err());
+        assert!(RegisterResponse::parse(&[0x05; 10]).is_err());
+    }
+
+    #[test]
+    fn test_sign_response() {
+        let raw = [0x01, 0x00, 0x00, 0x01, 0x02, 0x30, 0x00, 0x90, 0x00];
+        let parsed = SignResponse::from_raw(&raw).unwrap();
+        assert_eq!(parsed, SignResponse { user_present: true, counter: 0x102, signature: vec![0x30, 0x00] });
+        assert_eq!(SignResponse::parse(&raw[..7]).unwrap(), parsed);
+
+        // No signature.
+        assert!(SignResponse::from_raw(&raw[..5]).is_err());
+        assert!(SignResponse::parse(&[0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_register_response_long_certificate() {
+        let mut head = vec![0x05, 0x04];
+        head.extend(&GX);
+        head.extend(&GY);
+        head.extend(&[0x01, 0xaa]);
+        let signature = vec![0x30, 0x02, 0x05, 0x06];
+
+        // One, two and three length bytes. The signature must start where
+        // the length field says the certificate ends.
+        for &(ref length, len) in &[(vec![0x81, 0x80], 0x80), (vec![0x82, 0x01, 0x2c], 0x12c), (vec![0x83, 0x01, 0x00, 0x00], 0x10000)] {
+            let mut certificate = vec![0x30];
+            certificate.extend(length);
+            certificate.extend(vec![0x30; len]);
+            let mut response = head.clone();
+            response.extend(&certificate);
+            response.extend(&signature);
+
+            let parsed = RegisterResponse::parse(&response).unwrap();
+            assert_eq!(parsed.certificate, certificate);
+            assert_eq!(parsed.signature, signature);
+        }
+    }
+
+    #[test]
+    fn test_der_sequence_len() {
+        assert_eq!(der_sequence_len(&[0x30, 0x01, 0x00, 0xff]).unwrap(), 3);
+        let mut short = vec![0x30, 0x7f];
+        short.extend(vec![0; 0x7f]);
+        assert_eq!(der_sequence_len(&short).unwrap(), 0x81);
+        let mut long = vec![0x30, 0x82, 0x01, 0x00];
+        long.extend(vec![0; 0x100]);
+        assert_eq!(der_sequence_len(&long).unwrap(), 0x104);
+        assert!(der_sequence_len(&long[..0x103]).is_err());
+        assert!(der_sequence_len(&long[..3]).is_err());
+        assert!(der_sequence_len(&[0x31, 0x00]).is_err());
+        // Indefinite and overly long lengths aren't DER.
+        assert!(der_sequence_len(&[0x30, 0x80, 0x00, 0x00]).is_err());
+        assert!(der_sequence_len(&[0x30, 0x84, 0x00, 0x00, 0x00, 0x01, 0x00]).is_err());
+    }
+}
@@ -1,10 +1,34 @@
 extern crate libc;
 
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
 use std::io;
+use std::ops::Range;
+use std::{mem, slice};
 use std::sync::{Arc,Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
 use boxfnonce::SendBoxFnOnce;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::Rng;
+
+use u2fprotocol::U2FDevice;
+use u2ftypes::OperationContext;
+
+// Logs like `debug!()` and friends, the level given as the first argument,
+// tagged with the correlation id of the operation `$context` belongs to,
+// an `Option<&OperationContext>`. See `log_tag()`.
+macro_rules! op_log {
+    ($level:ident, $context:expr, $($arg:tt)+) => {
+        $level!("{}{}", ::util::log_tag($context), format_args!($($arg)+))
+    }
+}
 
 macro_rules! try_or {
     ($val:expr, $or:expr) => {
@@ -21,17 +45,17 @@ pub trait Signed {
 
 impl Signed for i32 {
     fn is_negative(&self) -> bool {
-        *self < (0 as i32)
+        *self < 0_i32
     }
 }
 
 impl Signed for usize {
     fn is_negative(&self) -> bool {
-        (*self as isize) < (0 as isize)
+        (*self as isize) < 0_isize
     }
 }
 
-#[cfg(any(target_os = "linux"))]
+#[cfg(target_os = "linux")]
 pub fn from_unix_result<T: Signed>(rv: T) -> io::Result<T> {
     if rv.is_negative() {
         let errno = unsafe { *libc::__errno_location() };
@@ -42,38 +66,658 @@ pub fn from_unix_result<T: Signed>(rv: T) -> io::Result<T> {
 }
 
 pub fn io_err(msg: &str) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, msg)
+    io::Error::other(msg)
 }
 
 pub fn to_io_err<T: Error>(err: T) -> io::Error {
-    io_err(err.description())
+    io_err(&err.to_string())
+}
+
+// Compares the devices a DeviceMap knows about against the ones currently
+// present. Returns the ones that vanished and the ones that are new.
+pub fn diff_devices<K>(known: &[K], current: &[K]) -> (Vec<K>, Vec<K>)
+    where K: Clone + PartialEq
+{
+    let removed = known.iter().filter(|k| !current.contains(k)).cloned().collect();
+    let added = current.iter().filter(|k| !known.contains(k)).cloned().collect();
+    (removed, added)
+}
+
+// Returns at most `max` of the devices in `map`, the most recently added ones
+// first. `added` holds an increasing sequence number per key, devices with a
+// number outside of `generations` are left out.
+pub fn newest_first<'a, K, T>(map: &'a mut HashMap<K, T>, added: &HashMap<K, u64>, generations: Range<u64>, max: usize) -> Vec<&'a mut T>
+    where K: Eq + Hash
+{
+    let mut devices: Vec<(u64, &mut T)> = map.iter_mut().map(|(key, device)| {
+        (added.get(key).cloned().unwrap_or(0), device)
+    }).filter(|&(seq, _)| seq >= generations.start && seq < generations.end).collect();
+
+    devices.sort_by_key(|&(seq, _)| Reverse(seq));
+    devices.into_iter().take(max).map(|(_, device)| device).collect()
+}
+
+// Keys of the devices in `map` that were unplugged while we talked to them.
+pub fn disconnected<K, T>(map: &HashMap<K, T>) -> Vec<K>
+    where K: Clone + Eq + Hash, T: U2FDevice
+{
+    map.iter().filter(|&(_, device)| device.disconnected()).map(|(key, _)| key.clone()).collect()
+}
+
+// Merges the device lists of several backends, which may each report the
+// same physical device. Drops the entries whose id `stable_id` returns an
+// earlier backend had too, so that no device is used twice. A backend's own
+// entries are all kept, they may be several interfaces of one device, and so
+// are entries without an id.
+pub fn merge_backends<T, K, F>(backends: Vec<Vec<T>>, stable_id: F) -> Vec<T>
+    where K: Eq + Hash, F: Fn(&T) -> Option<K>
+{
+    let mut seen = HashSet::new();
+    let mut merged = Vec::new();
+    for devices in backends {
+        let (devices, ids): (Vec<T>, Vec<Option<K>>) = devices.into_iter().map(|device| {
+            let id = stable_id(&device);
+            (device, id)
+        }).unzip();
+        merged.extend(devices.into_iter().zip(ids.iter()).filter(|&(_, id)| {
+            match *id {
+                Some(ref id) => !seen.contains(id),
+                None => true
+            }
+        }).map(|(device, _)| device));
+        seen.extend(ids.into_iter().flatten());
+    }
+    merged
+}
+
+// The events a monitor's thread sent, until they're handled. Unlike the
+// channel itself, it lets us look at them without taking them.
+pub struct EventQueue<E> {
+    rx: Receiver<E>,
+    // Events taken off the channel by `pending()`, oldest first.
+    peeked: Mutex<VecDeque<E>>
+}
+
+impl<E> EventQueue<E> where E: Clone {
+    pub fn new(rx: Receiver<E>) -> Self {
+        Self { rx, peeked: Mutex::new(VecDeque::new()) }
+    }
+
+    // The events that are waiting, oldest first. They stay queued.
+    pub fn pending(&self) -> Vec<E> {
+        match self.peeked.lock() {
+            Ok(mut peeked) => {
+                peeked.extend(self.rx.try_iter());
+                peeked.iter().cloned().collect()
+            }
+            Err(_) => Vec::new()
+        }
+    }
+
+    // Takes the waiting events, oldest first. Never blocks.
+    pub fn try_iter(&self) -> EventIter<'_, E> {
+        EventIter { queue: self }
+    }
+}
+
+pub struct EventIter<'a, E: 'a> {
+    queue: &'a EventQueue<E>
+}
+
+impl<'a, E> Iterator for EventIter<'a, E> {
+    type Item = E;
+
+    fn next(&mut self) -> Option<E> {
+        let peeked = self.queue.peeked.lock().ok().and_then(|mut peeked| peeked.pop_front());
+        peeked.or_else(|| self.queue.rx.try_recv().ok())
+    }
+}
+
+// Which physical device each key of a DeviceMap stands for, so that one that
+// shows up under several keys, e.g. once per interface, is only added once.
+// Devices without an id are told apart by their keys only.
+pub struct DeviceIds<K> {
+    ids: HashMap<K, Option<String>>
+}
+
+impl<K> DeviceIds<K> where K: Eq + Hash {
+    pub fn new() -> Self {
+        Self { ids: HashMap::new() }
+    }
+
+    // Whether `key` is new, and no other key stands for the device with `id`.
+    pub fn is_new(&self, key: &K, id: &Option<String>) -> bool {
+        !self.ids.contains_key(key) && id.as_ref().is_none_or(|id| {
+            !self.ids.values().any(|known| known.as_ref() == Some(id))
+        })
+    }
+
+    pub fn insert(&mut self, key: K, id: Option<String>) {
+        self.ids.insert(key, id);
+    }
+
+    // Returns whether `key` was known.
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.ids.remove(key).is_some()
+    }
+}
+
+// The devices an operation was started with, by path. Until the monitor's
+// first snapshot only these are used, not the others that were attached
+// already. Devices plugged in later are used as usual.
+pub struct Seed<K> {
+    keys: Option<Vec<K>>
+}
+
+impl<K> Seed<K> where K: Clone + PartialEq {
+    // `add` opens one of the devices, or returns `None` if it's gone. Returns
+    // what it returned for the others.
+    pub fn new<T, F>(keys: Vec<K>, add: F) -> (Self, Vec<T>)
+        where F: FnMut(K) -> Option<T>
+    {
+        let added = keys.iter().cloned().filter_map(add).collect();
+        (Self { keys: Some(keys) }, added)
+    }
+
+    // Whether the monitor reporting `key` should be heeded.
+    pub fn allows(&self, key: &K) -> bool {
+        self.keys.as_ref().is_none_or(|keys| keys.contains(key))
+    }
+
+    // Leaves the devices out of the first snapshot that weren't seeded, and
+    // lifts the restriction for all later events.
+    pub fn snapshot(&mut self, keys: Vec<K>) -> Vec<K> {
+        match self.keys.take() {
+            Some(seeded) => keys.into_iter().filter(|key| seeded.contains(key)).collect(),
+            None => keys
+        }
+    }
+}
+
+impl<K> Default for Seed<K> {
+    fn default() -> Self {
+        Self { keys: None }
+    }
+}
+
+// Whether `a` and `b` are equal, taking as long for any pair of the same
+// length. Key handles and other secrets are compared with this, so that the
+// time taken doesn't tell how many leading bytes matched. The length isn't
+// secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Reads the DER length field `der` starts with. Returns the length and what
+// follows the field, which holds at least that many bytes. Long forms of up
+// to three bytes are accepted, in their shortest encoding only, as DER has it.
+pub fn der_length(der: &[u8]) -> Option<(usize, &[u8])> {
+    let (&first, rest) = der.split_first()?;
+    let (len, rest) = match first {
+        len if len < 0x80 => (len as usize, rest),
+        0x81..=0x83 => {
+            let count = (first & 0x7f) as usize;
+            if rest.len() < count || rest[0] == 0 {
+                return None;
+            }
+            let (bytes, rest) = rest.split_at(count);
+            match bytes.iter().fold(0, |len, &b| len << 8 | b as usize) {
+                len if len < 0x80 => return None,
+                len => (len, rest)
+            }
+        }
+        _ => return None
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    Some((len, rest))
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    hash
 }
 
-type Callback = SendBoxFnOnce<(io::Result<Vec<u8>>,)>;
+// The raw bytes of `value`, which should be a #[repr(packed)] struct. The
+// slice borrows `value`, use `to_u8_vec()` to keep the bytes around longer.
+pub fn to_u8_array<T>(value: &T) -> &[u8] {
+    unsafe {
+        slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+    }
+}
+
+// Like `to_u8_array()`, but copies the bytes.
+pub fn to_u8_vec<T>(value: &T) -> Vec<u8> {
+    to_u8_array(value).to_vec()
+}
+
+// Reads a #[repr(packed)] struct from the start of `arr`, which must be large
+// enough to hold one.
+pub fn from_u8_array<T>(arr: &[u8]) -> &T {
+    assert!(arr.len() >= mem::size_of::<T>());
+    unsafe { &*(arr.as_ptr() as *const T) }
+}
 
-pub struct OnceCallback {
-    callback: Arc<Mutex<Option<Callback>>>
+// Formats bytes for logging, in a single allocation.
+pub fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
 }
 
-impl OnceCallback {
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// Encodes bytes the way WebAuthn puts them on the wire: base64 with the URL
+// safe alphabet, without padding.
+pub fn to_base64url(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 4).div_ceil(3));
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..chunk.len() + 1 {
+            encoded.push(BASE64URL[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+// The reverse of `to_base64url()`. Padding isn't accepted, neither are the
+// '+' and '/' of plain base64.
+pub fn from_base64url(encoded: &str) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid base64url");
+    if encoded.len() % 4 == 1 {
+        return Err(invalid());
+    }
+
+    let mut bytes = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.as_bytes().chunks(4) {
+        let mut group = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = BASE64URL.iter().position(|b| b == c).ok_or_else(invalid)?;
+            group |= (value as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((group >> (16 - 8 * i)) as u8);
+        }
+        // Leftover bits have to be zero, so that every input has one encoding.
+        if group & ((1 << (32 - 8 * chunk.len())) - 1) != 0 {
+            return Err(invalid());
+        }
+    }
+    Ok(bytes)
+}
+
+// When an operation that starts now and may take `timeout` seconds is over.
+// Zero means never.
+pub fn deadline(timeout: u64) -> Option<Instant> {
+    if timeout > 0 {
+        Some(Instant::now() + Duration::from_secs(timeout))
+    } else {
+        None
+    }
+}
+
+pub fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+// Some tokens answer ERR_CHANNEL_BUSY to commands that follow INIT right
+// away. A few milliseconds are enough for them, others don't notice.
+pub const DEFAULT_INIT_SETTLE_DELAY: u64 = 5;
+
+// "[id] " for the correlation id of the operation `context` belongs to, to
+// prefix log messages with. Empty if there is none, which doesn't allocate.
+pub fn log_tag(context: Option<&OperationContext>) -> String {
+    context.and_then(|context| context.correlation_id.as_ref()).map_or_else(String::new, |id| format!("[{}] ", id))
+}
+
+// Whether the frames and commands sent to a device right now should be
+// logged. Not during polling rounds that only repeat the one before.
+pub fn round_logged(context: Option<&OperationContext>) -> bool {
+    match context.and_then(|context| context.quiet_round.as_ref()) {
+        Some(quiet) => !quiet.load(Ordering::SeqCst),
+        None => true
+    }
+}
+
+// Tells the operation's `ReadProgress`, if any, how much of a long response
+// was received.
+pub fn report_read_progress(context: Option<&OperationContext>, received: usize, expected: usize) {
+    if let Some(progress) = context.and_then(|context| context.read_progress.as_ref()) {
+        progress(received, expected);
+    }
+}
+
+// How long `u2f_init_device()` waits after INIT for the operation, a few
+// milliseconds unless it says otherwise.
+pub fn init_settle_delay(context: Option<&OperationContext>) -> Duration {
+    context.and_then(|context| context.init_settle_delay)
+           .unwrap_or_else(|| Duration::from_millis(DEFAULT_INIT_SETTLE_DELAY))
+}
+
+// Measures how long each of a sequence of phases takes, for trace logs.
+// Displays as e.g. "write 1ms, wait 830ms, read 2ms".
+pub struct PhaseTimer {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>
+}
+
+impl PhaseTimer {
+    pub fn new() -> Self {
+        Self { last: Instant::now(), phases: Vec::new() }
+    }
+
+    // Ends the phase with the given name, the next one starts now.
+    pub fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push((name, now.duration_since(self.last)));
+        self.last = now;
+    }
+}
+
+impl fmt::Display for PhaseTimer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phases: Vec<String> = self.phases.iter().map(|&(name, duration)| {
+            format!("{} {}ms", name, as_millis(duration))
+        }).collect();
+        write!(f, "{}", phases.join(", "))
+    }
+}
+
+// An RNG that can be handed to the threads that talk to devices.
+pub type SharedRng = Arc<Mutex<Box<dyn Rng + Send>>>;
+
+type Callback<T, E> = SendBoxFnOnce<(Result<T, E>,)>;
+
+pub struct OnceCallback<T, E = io::Error> {
+    callback: Arc<Mutex<Option<Callback<T, E>>>>
+}
+
+impl<T: 'static, E: 'static> OnceCallback<T, E> {
     pub fn new<F>(cb: F) -> Self
-        where F: FnOnce(io::Result<Vec<u8>>), F: Send + 'static
+        where F: FnOnce(Result<T, E>), F: Send + 'static
     {
         let cb = Some(SendBoxFnOnce::from(cb));
         Self { callback: Arc::new(Mutex::new(cb)) }
     }
 
-    pub fn call(&self, rv: io::Result<Vec<u8>>) {
-        if let Ok(mut cb) = self.callback.lock() {
-            if let Some(cb) = cb.take() {
-                cb.call(rv);
-            }
+    // Don't hold the lock while calling, the callback might end up calling
+    // (a clone of) us again. That's a no-op then.
+    pub fn call(&self, rv: Result<T, E>) {
+        let cb = match self.callback.lock() {
+            Ok(mut cb) => cb.take(),
+            Err(_) => return
+        };
+
+        if let Some(cb) = cb {
+            cb.call(rv);
         }
     }
+
+    // A callback for code that fails with `io::Error`s, which are converted
+    // before they're passed on to us.
+    pub fn for_io(self) -> OnceCallback<T>
+        where E: From<io::Error>
+    {
+        OnceCallback::new(move |rv: io::Result<T>| self.call(rv.map_err(E::from)))
+    }
 }
 
-impl Clone for OnceCallback {
+impl<T, E> Clone for OnceCallback<T, E> {
     fn clone(&self) -> Self {
         Self { callback: self.callback.clone() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, der_length, diff_devices, from_base64url, from_u8_array, log_tag, merge_backends, newest_first, round_logged, to_base64url, to_hex, to_u8_vec, DeviceIds, EventQueue, OnceCallback, PhaseTimer, Seed};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::channel;
+    use u2ftypes::OperationContext;
+
+    #[test]
+    fn test_diff_devices() {
+        // A device showed up without an event, another one vanished.
+        let known = vec!["/dev/hidraw0", "/dev/hidraw1"];
+        let current = vec!["/dev/hidraw1", "/dev/hidraw2"];
+
+        let (removed, added) = diff_devices(&known, &current);
+        assert_eq!(removed, vec!["/dev/hidraw0"]);
+        assert_eq!(added, vec!["/dev/hidraw2"]);
+
+        assert_eq!(diff_devices(&current, &current), (vec![], vec![]));
+    }
+
+    #[repr(C, packed)]
+    struct Header {
+        cmd: u8,
+        len: [u8; 2]
+    }
+
+    #[test]
+    fn test_to_u8_vec() {
+        // The bytes outlive the struct.
+        let bytes = to_u8_vec(&Header { cmd: 0x83, len: [0x00, 0x07] });
+        assert_eq!(bytes, vec![0x83, 0x00, 0x07]);
+
+        let header: &Header = from_u8_array(&bytes);
+        assert_eq!(header.cmd, 0x83);
+        assert_eq!(header.len, [0x00, 0x07]);
+    }
+
+    #[test]
+    fn test_merge_backends() {
+        // Both backends see the token on usb-1, the second one also sees a
+        // device without an id.
+        // device without an id. The first one has two interfaces on usb-2.
+        let hidraw = vec![("/dev/hidraw0", Some("usb-1")), ("/dev/hidraw1", Some("usb-2")), ("/dev/hidraw2", Some("usb-2"))];
+        let other = vec![("other:1", Some("usb-1")), ("other:2", None), ("other:3", None)];
+
+        let merged = merge_backends(vec![hidraw, other], |&(_, id)| id);
+        let paths: Vec<&str> = merged.iter().map(|&(path, _)| path).collect();
+        assert_eq!(paths, vec!["/dev/hidraw0", "/dev/hidraw1", "/dev/hidraw2", "other:2", "other:3"]);
+    }
+
+    #[test]
+    fn test_event_queue() {
+        let (tx, rx) = channel();
+        let queue = EventQueue::new(rx);
+        assert!(queue.pending().is_empty());
+
+        // Looking doesn't take anything, events come out in order.
+        tx.send("add /dev/hidraw0").unwrap();
+        tx.send("remove /dev/hidraw0").unwrap();
+        assert_eq!(queue.pending(), vec!["add /dev/hidraw0", "remove /dev/hidraw0"]);
+        tx.send("add /dev/hidraw1").unwrap();
+        assert_eq!(queue.pending().len(), 3);
+
+        assert_eq!(queue.try_iter().next(), Some("add /dev/hidraw0"));
+        assert_eq!(queue.pending(), vec!["remove /dev/hidraw0", "add /dev/hidraw1"]);
+        let rest: Vec<&str> = queue.try_iter().collect();
+        assert_eq!(rest, vec!["remove /dev/hidraw0", "add /dev/hidraw1"]);
+        assert!(queue.pending().is_empty());
+    }
+
+    #[test]
+    fn test_device_ids() {
+        let mut ids = DeviceIds::new();
+        let usb1 = Some("usb-1".to_string());
+        assert!(ids.is_new(&"/dev/hidraw0", &usb1));
+        ids.insert("/dev/hidraw0", usb1.clone());
+
+        // The same add event again, and the token's second interface.
+        assert!(!ids.is_new(&"/dev/hidraw0", &usb1));
+        assert!(!ids.is_new(&"/dev/hidraw1", &usb1));
+
+        // Devices without an id only clash by key.
+        assert!(ids.is_new(&"/dev/hidraw2", &None));
+        ids.insert("/dev/hidraw2", None);
+        assert!(ids.is_new(&"/dev/hidraw3", &None));
+        assert!(!ids.is_new(&"/dev/hidraw2", &None));
+
+        // Spurious removes change nothing.
+        assert!(!ids.remove(&"/dev/hidraw1"));
+        assert!(!ids.is_new(&"/dev/hidraw1", &usb1));
+
+        // Once unplugged, the token may come back under another key.
+        assert!(ids.remove(&"/dev/hidraw0"));
+        assert!(!ids.remove(&"/dev/hidraw0"));
+        assert!(ids.is_new(&"/dev/hidraw1", &usb1));
+    }
+
+    #[test]
+    fn test_seed() {
+        // The second device was unplugged since it was listed.
+        let present = vec!["/dev/hidraw0", "/dev/hidraw2"];
+        let (mut seed, added) = Seed::new(vec!["/dev/hidraw0", "/dev/hidraw1"], |path| {
+            if present.contains(&path) { Some(path) } else { None }
+        });
+        assert_eq!(added, vec!["/dev/hidraw0"]);
+
+        // Other attached devices are ignored until the first snapshot.
+        assert!(seed.allows(&"/dev/hidraw0"));
+        assert!(!seed.allows(&"/dev/hidraw2"));
+        assert_eq!(seed.snapshot(present.clone()), vec!["/dev/hidraw0"]);
+        assert!(seed.allows(&"/dev/hidraw2"));
+        assert_eq!(seed.snapshot(present.clone()), present);
+    }
+
+    #[test]
+    fn test_newest_first() {
+        let mut map = HashMap::new();
+        let mut added = HashMap::new();
+        for (seq, name) in ["hidraw0", "hidraw1", "hidraw2"].iter().enumerate() {
+            map.insert(*name, seq);
+            added.insert(*name, seq as u64);
+        }
+
+        let devices: Vec<usize> = newest_first(&mut map, &added, 0..3, 2).into_iter().map(|d| *d).collect();
+        assert_eq!(devices, vec![2, 1]);
+        assert_eq!(newest_first(&mut map, &added, 0..3, usize::MAX).len(), 3);
+        assert_eq!(newest_first(&mut map, &added, 2..3, usize::MAX).len(), 1);
+        assert_eq!(newest_first(&mut map, &added, 0..2, usize::MAX).len(), 2);
+    }
+
+    #[test]
+    fn test_once_callback_reentrant() {
+        let (tx, rx) = channel();
+        let slot: Arc<Mutex<Option<OnceCallback<u8>>>> = Arc::new(Mutex::new(None));
+        let slot_ = slot.clone();
+
+        let callback = OnceCallback::new(move |rv| {
+            tx.send(rv.unwrap()).unwrap();
+
+            // Calling ourselves again must neither deadlock nor call twice.
+            if let Some(callback) = slot_.lock().unwrap().take() {
+                callback.call(Ok(2));
+            }
+        });
+        *slot.lock().unwrap() = Some(callback.clone());
+
+        callback.call(Ok(1));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_base64url() {
+        // The challenge from the clientdata tests.
+        let challenge = [0xd6, 0xf4, 0x3d, 0x9b, 0x18, 0xa8, 0x9e, 0xad, 0x27, 0x80, 0x29, 0xe3, 0x0f, 0xec, 0x13, 0xb2,
+                         0xfd, 0x73, 0x51, 0x2a, 0xc6, 0x46, 0xd1, 0x6a, 0x1b, 0x6c, 0x4f, 0xd3, 0xd4, 0x9b, 0x67, 0xbd];
+        assert_eq!(to_base64url(&challenge), "1vQ9mxionq0ngCnjD-wTsv1zUSrGRtFqG2xP09SbZ70");
+        assert_eq!(from_base64url("1vQ9mxionq0ngCnjD-wTsv1zUSrGRtFqG2xP09SbZ70").unwrap(), challenge.to_vec());
+
+        for len in 0..7 {
+            let bytes: Vec<u8> = (0..len).map(|i| 0xfb - i as u8).collect();
+            assert_eq!(from_base64url(&to_base64url(&bytes)).unwrap(), bytes);
+        }
+        assert_eq!(to_base64url(b"fo"), "Zm8");
+        assert_eq!(to_base64url(&[0xfb, 0xff]), "-_8");
+
+        // Padding, plain base64, truncated input, and stray bits.
+        assert!(from_base64url("Zm8=").is_err());
+        assert!(from_base64url("+/8").is_err());
+        assert!(from_base64url("Zm9vY").is_err());
+        assert!(from_base64url("Zm9").is_err());
+    }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xa0, 0xff]), "000fa0ff");
+        assert_eq!(to_hex(&[]), "");
+    }
+
+    #[test]
+    fn test_phase_timer() {
+        let mut timer = PhaseTimer::new();
+        assert_eq!(timer.to_string(), "");
+
+        timer.phase("write");
+        timer.phase("wait");
+        timer.phase("read");
+
+        let phases: Vec<&str> = timer.phases.iter().map(|&(name, _)| name).collect();
+        assert_eq!(phases, vec!["write", "wait", "read"]);
+        assert!(timer.to_string().starts_with("write "));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(&[], &[]));
+        assert!(constant_time_eq(&[0x01, 0x02, 0x03], &[0x01, 0x02, 0x03]));
+        assert!(!constant_time_eq(&[0x01, 0x02, 0x03], &[0x01, 0x02, 0x04]));
+        assert!(!constant_time_eq(&[0x81, 0x02, 0x03], &[0x01, 0x02, 0x03]));
+        assert!(!constant_time_eq(&[0x01, 0x02], &[0x01, 0x02, 0x03]));
+        assert!(!constant_time_eq(&[], &[0x00]));
+    }
+
+    #[test]
+    fn test_der_length() {
+        assert_eq!(der_length(&[0x02, 0xaa, 0xbb, 0xcc]), Some((2, &[0xaa, 0xbb, 0xcc][..])));
+        let mut long = vec![0x82, 0x01, 0x00];
+        long.extend(vec![0; 0x100]);
+        assert_eq!(der_length(&long), Some((0x100, &long[3..])));
+        assert_eq!(der_length(&long[..0x102]), None);
+        assert_eq!(der_length(&[]), None);
+
+        // Indefinite, overly long and needlessly long forms aren't DER.
+        assert_eq!(der_length(&[0x80, 0x00]), None);
+        assert_eq!(der_length(&[0x84, 0x00, 0x00, 0x01, 0x00]), None);
+        assert_eq!(der_length(&[0x81, 0x05, 0, 0, 0, 0, 0]), None);
+        assert_eq!(der_length(&[0x82, 0x00, 0x80]), None);
+    }
+
+    #[test]
+    fn test_log_tag() {
+        assert_eq!(log_tag(None), "");
+        let mut context = OperationContext::default();
+        assert_eq!(log_tag(Some(&context)), "");
+        context.correlation_id = Some(String::from("op-7"));
+        assert_eq!(log_tag(Some(&context)), "[op-7] ");
+    }
+
+    #[test]
+    fn test_round_logged() {
+        assert!(round_logged(None));
+        let mut context = OperationContext::default();
+        assert!(round_logged(Some(&context)));
+        let quiet = Arc::new(AtomicBool::new(false));
+        context.quiet_round = Some(quiet.clone());
+        assert!(round_logged(Some(&context)));
+        quiet.store(true, Ordering::SeqCst);
+        assert!(!round_logged(Some(&context)));
+    }
+}
@@ -0,0 +1,132 @@
+use std::io;
+
+use p256;
+use util::{der_length, sha256};
+
+fn invalid_signature() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid signature encoding")
+}
+
+// Reads a DER INTEGER as a 32-byte big endian number. Returns it and the rest.
+fn der_integer(der: &[u8]) -> io::Result<([u8; 32], &[u8])> {
+    if der.first() != Some(&0x02) {
+        return Err(invalid_signature());
+    }
+
+    let (len, rest) = der_length(&der[1..]).ok_or_else(invalid_signature)?;
+    let (bytes, rest) = rest.split_at(len);
+
+    // Positive numbers with the top bit set get a leading zero.
+    let bytes = match bytes.iter().position(|&b| b != 0) {
+        Some(start) => &bytes[start..],
+        None => &[]
+    };
+    if bytes.len() > 32 {
+        return Err(invalid_signature());
+    }
+
+    let mut num = [0u8; 32];
+    num[32 - bytes.len()..].copy_from_slice(bytes);
+    Ok((num, rest))
+}
+
+// Splits a DER encoded ECDSA signature, SEQUENCE { r INTEGER, s INTEGER },
+// into r and s.
+fn parse_der_signature(der: &[u8]) -> io::Result<([u8; 32], [u8; 32])> {
+    if der.first() != Some(&0x30) {
+        return Err(invalid_signature());
+    }
+
+    let (len, rest) = der_length(&der[1..]).ok_or_else(invalid_signature)?;
+    if rest.len() != len {
+        return Err(invalid_signature());
+    }
+
+    let (r, rest) = der_integer(rest)?;
+    let (s, rest) = der_integer(rest)?;
+    if !rest.is_empty() {
+        return Err(invalid_signature());
+    }
+    Ok((r, s))
+}
+
+// Verifies the signature in an authenticate response against the public key
+// from the matching register response, as a relying party would. The token
+// signs the application parameter, the user presence byte, the counter in
+// big endian byte order, and the challenge parameter. Returns an error if
+// the key or the signature are malformed, `false` if the signature doesn't
+// match.
+pub fn verify_signature(public_key: &[u8; 65], application: &[u8; 32], user_presence: u8, counter: u32, challenge: &[u8; 32], signature_der: &[u8]) -> io::Result<bool> {
+    if public_key[0] != 0x04 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid public key"));
+    }
+    let (r, s) = parse_der_signature(signature_der)?;
+
+    let mut message = application.to_vec();
+    message.push(user_presence);
+    message.extend(&[(counter >> 24) as u8, (counter >> 16) as u8, (counter >> 8) as u8, counter as u8]);
+    message.extend(challenge);
+
+    Ok(p256::verify(&public_key[1..33], &public_key[33..], &sha256(&message), &r, &s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_der_signature, verify_signature};
+
+    // Made with a throwaway key, over application 0x40..0x5f, user presence
+    // set, counter 42, and challenge 0x80..0x9f.
+    const PUBLIC_KEY: [u8; 65] = [
+        0x04, 0xa9, 0x72, 0x2a, 0xee, 0x79, 0xf5, 0xc0, 0xea, 0x43, 0x5a, 0x1e, 0x81, 0xdf, 0xae, 0xf0,
+        0xc3, 0x03, 0x59, 0xe3, 0x72, 0x48, 0x5d, 0x73, 0x1a, 0x2f, 0xf3, 0xad, 0x26, 0x40, 0x6e, 0xf4,
+        0xf3, 0x0a, 0x51, 0xaa, 0x28, 0xd6, 0xc5, 0xa4, 0x71, 0x57, 0x0a, 0xf6, 0x17, 0x7f, 0x39, 0xe8,
+        0x8c, 0xac, 0xdd, 0xef, 0x24, 0x57, 0x89, 0x1b, 0x8d, 0x69, 0xa1, 0xd8, 0xd0, 0x8f, 0x45, 0xce,
+        0x83];
+    const SIGNATURE: [u8; 70] = [
+        0x30, 0x44, 0x02, 0x20, 0x36, 0x58, 0xc4, 0x35, 0x89, 0xf2, 0xf6, 0xac, 0x30, 0x1c, 0xe5, 0x78,
+        0x0f, 0xda, 0x79, 0x5a, 0x64, 0xc3, 0xd6, 0xdb, 0xf0, 0x4a, 0x37, 0x78, 0x0b, 0x88, 0x3a, 0x23,
+        0x74, 0xdc, 0x67, 0xde, 0x02, 0x20, 0x4e, 0x00, 0x80, 0x94, 0x93, 0xb6, 0x76, 0xab, 0xd4, 0xe1,
+        0x17, 0xd5, 0x20, 0x11, 0x9b, 0xad, 0x32, 0x3d, 0x8a, 0x85, 0x84, 0x51, 0x64, 0x87, 0xda, 0x5d,
+        0xc0, 0xe4, 0x96, 0xf2, 0x84, 0xfd];
+
+    fn params() -> ([u8; 32], [u8; 32]) {
+        let mut application = [0u8; 32];
+        let mut challenge = [0u8; 32];
+        for i in 0..32 {
+            application[i] = 0x40 + i as u8;
+            challenge[i] = 0x80 + i as u8;
+        }
+        (application, challenge)
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let (application, challenge) = params();
+        assert!(verify_signature(&PUBLIC_KEY, &application, 0x01, 42, &challenge, &SIGNATURE).unwrap());
+
+        // Any change to the signed data, or to the signature, breaks it.
+        assert!(!verify_signature(&PUBLIC_KEY, &application, 0x01, 43, &challenge, &SIGNATURE).unwrap());
+        assert!(!verify_signature(&PUBLIC_KEY, &application, 0x00, 42, &challenge, &SIGNATURE).unwrap());
+        let mut signature = SIGNATURE;
+        signature[10] ^= 0x01;
+        assert!(!verify_signature(&PUBLIC_KEY, &application, 0x01, 42, &challenge, &signature).unwrap());
+
+        // So does a key that isn't on the curve.
+        let mut public_key = PUBLIC_KEY;
+        public_key[64] ^= 0x01;
+        assert!(!verify_signature(&public_key, &application, 0x01, 42, &challenge, &SIGNATURE).unwrap());
+    }
+
+    #[test]
+    fn test_parse_der_signature() {
+        // Leading zeros are dropped, short numbers are padded.
+        let (r, s) = parse_der_signature(&[0x30, 0x08, 0x02, 0x02, 0x00, 0x80, 0x02, 0x02, 0x01, 0x02]).unwrap();
+        assert_eq!(r[31], 0x80);
+        assert_eq!(&s[30..], &[0x01, 0x02]);
+
+        assert!(parse_der_signature(&[]).is_err());
+        assert!(parse_der_signature(&SIGNATURE[..69]).is_err());
+        assert!(parse_der_signature(&[0x30, 0x03, 0x02, 0x01, 0x01]).is_err());
+        assert!(parse_der_signature(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01, 0x00]).is_err());
+    }
+}
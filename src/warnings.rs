@@ -0,0 +1,69 @@
+use std::sync::{Arc, Mutex};
+
+use u2ftypes::{DeviceInfo, KeyHandle};
+
+// How many warnings to keep until the caller takes them. Older ones are
+// dropped first.
+const MAX_WARNINGS: usize = 64;
+
+// Something odd that didn't make an operation fail, but that the caller
+// might want to know about.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    // The signature counter for the key handle didn't increase. The token
+    // might have been cloned, or its counter wrapped around.
+    CounterNotIncreased { key_handle: KeyHandle, last: u32, current: u32 },
+    // The device only got a channel after INIT failed `failures` times.
+    InitRetried { info: Box<DeviceInfo>, failures: u32 }
+}
+
+// Collects warnings from operations until the caller takes them. Clones
+// share the same list.
+#[derive(Clone, Default)]
+pub struct Warnings(Arc<Mutex<Vec<Warning>>>);
+
+impl Warnings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, warning: Warning) {
+        if let Ok(mut warnings) = self.0.lock() {
+            if warnings.len() == MAX_WARNINGS {
+                warnings.remove(0);
+            }
+            warnings.push(warning);
+        }
+    }
+
+    // Returns the warnings collected so far, oldest first, and forgets them.
+    pub fn take(&self) -> Vec<Warning> {
+        self.0.lock().map(|mut warnings| warnings.drain(..).collect()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_WARNINGS, Warning, Warnings};
+    use u2ftypes::KeyHandle;
+
+    fn warning(current: u32) -> Warning {
+        Warning::CounterNotIncreased { key_handle: KeyHandle::from(vec![0x33; 64]), last: 1000, current }
+    }
+
+    #[test]
+    fn test_take() {
+        let warnings = Warnings::new();
+        warnings.clone().push(warning(1));
+        assert_eq!(warnings.take(), vec![warning(1)]);
+        assert!(warnings.take().is_empty());
+
+        // Only the newest ones are kept.
+        for current in 0..MAX_WARNINGS as u32 + 1 {
+            warnings.push(warning(current));
+        }
+        let taken = warnings.take();
+        assert_eq!(taken.len(), MAX_WARNINGS);
+        assert_eq!(taken[0], warning(1));
+    }
+}
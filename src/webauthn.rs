@@ -0,0 +1,175 @@
+use std::io;
+
+use cbor::{self, Value};
+use u2ftypes::RegisterResponse;
+use util::sha256;
+
+// Authenticator data flags: user present, attested credential data included.
+const FLAGS_UP_AT: u8 = 0x41;
+
+// A U2F registration as WebAuthn relying parties store it, with an
+// attestation object in the "fido-u2f" format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebAuthnRegistration {
+    // The key handle.
+    pub credential_id: Vec<u8>,
+    // Contains the credential id and the public key as a COSE_Key.
+    pub auth_data: Vec<u8>,
+    // The CBOR encoded attestation object, authData included.
+    pub attestation_object: Vec<u8>
+}
+
+// What `U2FManager::register_webauthn()` hands over, ready to be sent to a
+// relying party.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebAuthnAttestation {
+    pub credential_id: Vec<u8>,
+    pub attestation_object: Vec<u8>,
+    pub client_data_json: Vec<u8>
+}
+
+// The credential's public key as a COSE_Key: an EC2 key (1) on P-256 (-1: 1)
+// for ES256 (3: -7), keys in CTAP2 canonical order.
+fn cose_key(public_key: &[u8]) -> Vec<u8> {
+    cbor::encode(&Value::Map(vec![
+        (Value::Unsigned(1), Value::Unsigned(2)),
+        (Value::Unsigned(3), Value::Negative(-7)),
+        (Value::Negative(-1), Value::Unsigned(1)),
+        (Value::Negative(-2), Value::Bytes(public_key[1..33].to_vec())),
+        (Value::Negative(-3), Value::Bytes(public_key[33..].to_vec()))
+    ]))
+}
+
+// Carries a U2F registration over to the WebAuthn data model, e.g. to move
+// legacy credentials to WebAuthn storage. `rp_id_hash` is the application
+// parameter the credential was registered with, `counter` the signature
+// counter to start out with. U2F tokens have no AAGUID, it's all zeroes.
+pub fn u2f_register_to_webauthn(response: &RegisterResponse, rp_id_hash: &[u8; 32], counter: u32) -> io::Result<WebAuthnRegistration> {
+    response.validate_public_key()?;
+    let credential_id = response.key_handle.as_bytes().to_vec();
+    let mut auth_data = Vec::new();
+    auth_data.extend(rp_id_hash);
+    auth_data.push(FLAGS_UP_AT);
+    auth_data.extend(&[(counter >> 24) as u8, (counter >> 16) as u8, (counter >> 8) as u8, counter as u8]);
+    auth_data.extend(&[0u8; 16]);
+    auth_data.extend(&[(credential_id.len() >> 8) as u8, credential_id.len() as u8]);
+    auth_data.extend(&credential_id);
+    auth_data.extend(cose_key(&response.public_key));
+
+    let text = |s: &str| Value::Text(String::from(s));
+    let att_stmt = Value::Map(vec![
+        (text("sig"), Value::Bytes(response.signature.clone())),
+        (text("x5c"), Value::Array(vec![Value::Bytes(response.certificate.clone())]))
+    ]);
+    let attestation_object = cbor::encode(&Value::Map(vec![
+        (text("fmt"), text("fido-u2f")),
+        (text("attStmt"), att_stmt),
+        (text("authData"), Value::Bytes(auth_data.clone()))
+    ]));
+
+    Ok(WebAuthnRegistration { credential_id, auth_data, attestation_object })
+}
+
+// Maps the register response for `rp_id` and `client_data`, as the manager
+// hands it over, to what a relying party expects. New credentials start out
+// with a zero counter.
+pub fn register_response_to_webauthn(response: &[u8], rp_id: &str, client_data: &[u8]) -> io::Result<WebAuthnAttestation> {
+    let response = RegisterResponse::from_raw(response)?;
+    let registration = u2f_register_to_webauthn(&response, &sha256(rp_id.as_bytes()), 0)?;
+    Ok(WebAuthnAttestation {
+        credential_id: registration.credential_id,
+        attestation_object: registration.attestation_object,
+        client_data_json: client_data.to_vec()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{register_response_to_webauthn, u2f_register_to_webauthn};
+    use cbor::{self, Value};
+    use consts::{U2FHID_MSG, U2F_REGISTER, U2F_REQUEST_USER_PRESENCE};
+    use p256::{GX, GY};
+    use testdevice::{apdu, TestDevice};
+    use u2fprotocol::{U2FDevice, u2f_register};
+    use u2ftypes::RegisterResponse;
+    use util::sha256;
+
+    #[test]
+    fn test_u2f_register_to_webauthn() {
+        let mut raw = vec![0x05, 0x04];
+        raw.extend(&GX);
+        raw.extend(&GY);
+        raw.extend(&[0x02, 0xaa, 0xbb]);
+        raw.extend(&[0x30, 0x03, 0x01, 0x02, 0x03]); // Certificate.
+        raw.extend(&[0x30, 0x02, 0x05, 0x06]); // Signature.
+        let response = RegisterResponse::parse(&raw).unwrap();
+
+        let registration = u2f_register_to_webauthn(&response, &[0x11; 32], 1).unwrap();
+        assert_eq!(registration.credential_id, vec![0xaa, 0xbb]);
+
+        let mut auth_data = vec![0x11; 32];
+        auth_data.extend(&[0x41, 0x00, 0x00, 0x00, 0x01]);
+        auth_data.extend(&[0x00; 16]);
+        auth_data.extend(&[0x00, 0x02, 0xaa, 0xbb]);
+        auth_data.extend(&[0xa5, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01, 0x21, 0x58, 0x20]);
+        auth_data.extend(&GX);
+        auth_data.extend(&[0x22, 0x58, 0x20]);
+        auth_data.extend(&GY);
+        assert_eq!(registration.auth_data, auth_data);
+
+        // {"fmt": "fido-u2f", "attStmt": {"sig": h'3002..', "x5c": [h'3003..']}, "authData": h'11..'}
+        let mut expected = vec![0xa3, 0x63];
+        expected.extend(b"fmt");
+        expected.push(0x68);
+        expected.extend(b"fido-u2f");
+        expected.push(0x67);
+        expected.extend(b"attStmt");
+        expected.extend(&[0xa2, 0x63]);
+        expected.extend(b"sig");
+        expected.extend(&[0x44, 0x30, 0x02, 0x05, 0x06, 0x63]);
+        expected.extend(b"x5c");
+        expected.extend(&[0x81, 0x45, 0x30, 0x03, 0x01, 0x02, 0x03, 0x68]);
+        expected.extend(b"authData");
+        expected.extend(&[0x58, 0x86]);
+        expected.extend(&auth_data);
+        assert_eq!(registration.attestation_object, expected);
+    }
+
+    #[test]
+    fn test_register_response_to_webauthn() {
+        let client_data = br#"{"type":"webauthn.create","challenge":"AAAA","origin":"https://example.com"}"#;
+        let challenge = sha256(client_data);
+        let application = sha256(b"example.com");
+
+        let mut device = TestDevice::new();
+        device.set_cid(&[1, 2, 3, 4]);
+        let mut request = challenge.to_vec();
+        request.extend(&application);
+        device.add_message_write(U2FHID_MSG, &apdu(U2F_REGISTER, U2F_REQUEST_USER_PRESENCE, &request));
+        let mut response = vec![0x05, 0x04];
+        response.extend(&GX);
+        response.extend(&GY);
+        response.extend(&[0x02, 0xaa, 0xbb, 0x30, 0x01, 0x07, 0x30, 0x00, 0x90, 0x00]);
+        device.add_message_read(U2FHID_MSG, &response);
+        let response = u2f_register(&mut device, &challenge, &application).unwrap();
+
+        let attestation = register_response_to_webauthn(&response, "example.com", client_data).unwrap();
+        assert_eq!(attestation.credential_id, vec![0xaa, 0xbb]);
+        assert_eq!(attestation.client_data_json, client_data.to_vec());
+
+        let text = |s: &str| Value::Text(String::from(s));
+        let object = cbor::decode(&attestation.attestation_object).unwrap();
+        assert_eq!(object.get(&text("fmt")), Some(&text("fido-u2f")));
+        let att_stmt = object.get(&text("attStmt")).unwrap();
+        assert_eq!(att_stmt.get(&text("sig")), Some(&Value::Bytes(vec![0x30, 0x00])));
+        assert_eq!(att_stmt.get(&text("x5c")), Some(&Value::Array(vec![Value::Bytes(vec![0x30, 0x01, 0x07])])));
+        match object.get(&text("authData")) {
+            Some(Value::Bytes(auth_data)) => {
+                assert_eq!(&auth_data[..32], &application[..]);
+                assert_eq!(&auth_data[33..37], &[0, 0, 0, 0]);
+                assert_eq!(&auth_data[53..57], &[0x00, 0x02, 0xaa, 0xbb]);
+            }
+            other => panic!("unexpected authData {:?}", other)
+        }
+    }
+}
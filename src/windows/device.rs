@@ -1,24 +1,93 @@
 use std::fs::{File, OpenOptions};
+use std::fmt;
 use std::io;
 use std::io::{Read, Write};
 use std::os::windows::io::AsRawHandle;
 
 use ::consts::{CID_BROADCAST, HID_RPT_SIZE, FIDO_USAGE_PAGE, FIDO_USAGE_U2FHID};
-use super::winapi::DeviceCapabilities;
+use super::winapi::{serial_number, vendor_product_id, DeviceCapabilities};
 
 use u2fprotocol::{U2FDevice};
+use u2ftypes::{DeviceInfo, FrameObserver, OperationContext, Transport};
+
+// Device interface paths handed out by SetupAPI aren't stable across
+// enumerations, they may differ in case and in the `\\.\` vs. `\\?\`
+// prefix. Normalize them so that the same device yields the same path: fold
+// to lowercase, use backslashes only, use the `\\?\` prefix and drop any
+// trailing NULs.
+pub fn normalize_path(path: &str) -> String {
+    let path = path.trim_end_matches('\0').replace('/', "\\").to_lowercase();
+
+    if path.starts_with("\\\\.\\") {
+        format!("\\\\?\\{}", &path[4..])
+    } else {
+        path
+    }
+}
+
+// ERROR_GEN_FAILURE and ERROR_DEVICE_NOT_CONNECTED, from winerror.h.
+const ERROR_GEN_FAILURE: i32 = 31;
+const ERROR_DEVICE_NOT_CONNECTED: i32 = 1167;
+
+// Whether reading or writing failed because the device was unplugged. The HID
+// class driver fails pending and new requests with ERROR_GEN_FAILURE then.
+pub fn is_disconnect_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(ERROR_GEN_FAILURE) | Some(ERROR_DEVICE_NOT_CONNECTED) => true,
+        _ => false
+    }
+}
+
+// Opens the U2F device with the given device interface path.
+pub fn open(path: &str) -> io::Result<Device> {
+    let dev = Device::new(path.to_owned())?;
+    if !dev.is_u2f() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a U2F device"));
+    }
+    Ok(dev)
+}
+
+// Would let go of an operation's devices should its thread be detached, see
+// `RunLoop::on_detach()`. A handle that another thread is reading from can't
+// be closed safely here, so the devices are kept until the thread exits.
+#[derive(Clone, Default)]
+pub struct Releaser;
+
+impl Releaser {
+    pub fn new() -> Self {
+        Releaser
+    }
+
+    pub fn release(&self) {}
+}
 
-#[derive(Debug)]
 pub struct Device {
     path: String,
     file: File,
-    cid: [u8; 4]
+    cid: [u8; 4],
+    info: DeviceInfo,
+    observer: Option<FrameObserver>,
+    context: Option<OperationContext>,
+    init_failures: u32,
+    disconnected: bool
 }
 
 impl Device {
     pub fn new(path: String) -> io::Result<Self> {
-        let file = OpenOptions::new().read(true).write(true).open(&path)?;
-        Ok(Self { path, file, cid: CID_BROADCAST })
+        let normalized = normalize_path(&path);
+        let file = OpenOptions::new().read(true).write(true).open(&normalized)?;
+        let mut info = DeviceInfo::new(Transport::UsbHid);
+        info.path = Some(path);
+        info.serial_number = serial_number(file.as_raw_handle());
+        if let Some((vid, pid)) = vendor_product_id(file.as_raw_handle()) {
+            info.vendor_id = Some(vid);
+            info.product_id = Some(pid);
+        }
+        Ok(Self { path: normalized, file, cid: CID_BROADCAST, info, observer: None, context: None, init_failures: 0, disconnected: false })
+    }
+
+    pub fn serial_number(&self) -> Option<String> {
+        self.info.serial_number.clone()
     }
 
     pub fn is_u2f(&self) -> bool {
@@ -27,11 +96,28 @@ impl Device {
                 caps.usage() == FIDO_USAGE_U2FHID &&
                 caps.usage_page() == FIDO_USAGE_PAGE
             }
-            _ => false
+            // Some devices work fine with the default 64-byte reports, but
+            // their capabilities can't be read. Give them a try.
+            Err(e) => {
+                debug!("Couldn't read HID capabilities, using defaults: {}", e);
+                true
+            }
         }
     }
 }
 
+impl fmt::Debug for Device {
+    // Leaves out the observer, closures aren't Debug.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Device")
+           .field("path", &self.path)
+           .field("file", &self.file)
+           .field("cid", &self.cid)
+           .field("info", &self.info)
+           .finish()
+    }
+}
+
 impl PartialEq for Device {
     fn eq(&self, other: &Device) -> bool {
         self.path == other.path
@@ -40,9 +126,14 @@ impl PartialEq for Device {
 
 impl Read for Device {
     fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
-        // Windows always includes the report ID.
+        // Windows always includes the report ID. Anything shorter than a
+        // full report would leave the frame padded with zeros we'd take for
+        // data.
         let mut input = [0u8; HID_RPT_SIZE + 1];
-        let _ = self.file.read(&mut input)?;
+        let read = self.file.read(&mut input)?;
+        if read < input.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("short HID report: {} bytes", read)));
+        }
         bytes.clone_from_slice(&input[1..]);
         Ok(bytes.len() as usize)
     }
@@ -60,10 +151,74 @@ impl Write for Device {
 
 impl U2FDevice for Device {
     fn get_cid(&self) -> [u8; 4] {
-        self.cid.clone()
+        self.cid
     }
 
     fn set_cid(&mut self, cid: &[u8; 4]) {
         self.cid.clone_from(cid);
     }
+
+    fn get_device_info(&self) -> DeviceInfo {
+        self.info.clone()
+    }
+
+    fn set_device_info(&mut self, info: DeviceInfo) {
+        self.info = info;
+    }
+    fn frame_observer(&self) -> Option<&FrameObserver> {
+        self.observer.as_ref()
+    }
+
+    fn set_frame_observer(&mut self, observer: Option<FrameObserver>) {
+        self.observer = observer;
+    }
+    fn context(&self) -> Option<&OperationContext> {
+        self.context.as_ref()
+    }
+    fn set_context(&mut self, context: OperationContext) {
+        self.context = Some(context);
+    }
+
+    fn init_failures(&self) -> u32 {
+        self.init_failures
+    }
+
+    fn set_init_failures(&mut self, failures: u32) {
+        self.init_failures = failures;
+    }
+    fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+    fn set_disconnected(&mut self) {
+        self.disconnected = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_path;
+    use super::super::winapi::from_wide_serial;
+
+    #[test]
+    fn test_normalize_path() {
+        // The same device, as returned by two different enumerations.
+        let first = "\\\\?\\HID#VID_1050&PID_0120#6&2B5F4E3A&0&0000#{4d1e55b2-f16f-11cf-88cb-001111000030}";
+        let second = "\\\\.\\hid#vid_1050&pid_0120#6&2b5f4e3a&0&0000#{4D1E55B2-F16F-11CF-88CB-001111000030}\0";
+
+        assert_eq!(normalize_path(first), normalize_path(second));
+        assert_eq!(normalize_path(first), "\\\\?\\hid#vid_1050&pid_0120#6&2b5f4e3a&0&0000#{4d1e55b2-f16f-11cf-88cb-001111000030}");
+
+        // Normalizing is idempotent.
+        assert_eq!(normalize_path(&normalize_path(second)), normalize_path(second));
+    }
+
+    #[test]
+    fn test_serial_number() {
+        let mut buf = [0u16; 127];
+        for (c, b) in buf.iter_mut().zip("4711".encode_utf16()) {
+            *c = b;
+        }
+        assert_eq!(from_wide_serial(&buf), Some("4711".to_owned()));
+        assert_eq!(from_wide_serial(&[0u16; 127]), None);
+    }
 }
@@ -1,63 +1,147 @@
-use rand::{thread_rng, Rng};
 use std::collections::hash_map::ValuesMut;
 use std::collections::HashMap;
+use std::ops::Range;
 
-use ::platform::device::Device;
+use ::platform::device::{Device, Releaser};
 use ::platform::monitor::Event;
+use u2fprotocol::U2FDevice;
+use u2ftypes::{DeviceEvent, DeviceFilter, DeviceInfo, FrameObserver, OperationContext};
+use util::{diff_devices, disconnected, newest_first, Seed};
 
 pub struct DeviceMap {
-    map: HashMap<String, Device>
+    map: HashMap<String, Device>,
+    // When each device was added, to favor new ones.
+    added: HashMap<String, u64>,
+    next: u64,
+    filter: DeviceFilter,
+    // Handed to every device we add.
+    observer: Option<FrameObserver>,
+    // The devices the caller picked, if any.
+    seed: Seed<String>,
+    // Handed to every device we add, if set.
+    context: Option<OperationContext>
 }
 
 impl DeviceMap {
-    pub fn new() -> Self {
-        Self { map: HashMap::new() }
+    pub fn new(filter: DeviceFilter, observer: Option<FrameObserver>) -> Self {
+        Self { map: HashMap::new(), added: HashMap::new(), next: 0, filter, observer, seed: Seed::default(), context: None }
+    }
+
+    // Devices are kept until a detached thread exits, see `Releaser`.
+    pub fn set_releaser(&mut self, _: Releaser) {}
+
+    // Hands `context` to the devices added from now on.
+    pub fn set_context(&mut self, context: OperationContext) {
+        self.context = Some(context);
     }
 
     pub fn values_mut(&mut self) -> ValuesMut<String, Device> {
         self.map.values_mut()
     }
 
-    pub fn process_event(&mut self, event: Event) {
+    // At most `max` devices, the most recently added ones first. Leaves out
+    // the ones added outside of `generations`, see `generation()`.
+    pub fn newest_first(&mut self, generations: Range<u64>, max: usize) -> Vec<&mut Device> {
+        newest_first(&mut self.map, &self.added, generations, max)
+    }
+
+    // Devices added from now on are newer than this.
+    pub fn generation(&self) -> u64 {
+        self.next
+    }
+
+    // Paths of all devices, as passed to `U2FManager::cancel_device()`.
+    pub fn paths(&self) -> Vec<String> {
+        self.map.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Opens the devices at the given paths right away, instead of waiting
+    // for the monitor to find them. Skips the ones that are gone. Others the
+    // monitor reports are ignored until its next snapshot. Returns the
+    // devices that were added.
+    pub fn seed(&mut self, paths: Vec<String>) -> Vec<DeviceEvent> {
+        let (seed, added) = Seed::new(paths, |path| self.add(path));
+        self.seed = seed;
+        added.into_iter().map(DeviceEvent::Added).collect()
+    }
+
+    // Returns the devices that were actually added or removed.
+    pub fn process_event(&mut self, event: Event) -> Vec<DeviceEvent> {
         match event {
-            Event::Add(path) => self.add(path),
-            Event::Remove(path) => self.remove(path)
+            Event::Add(ref path) if !self.seed.allows(path) => Vec::new(),
+            Event::Add(path) => self.add(path).map(DeviceEvent::Added).into_iter().collect(),
+            Event::Remove(path) => self.remove(path).map(DeviceEvent::Removed).into_iter().collect(),
+            Event::Snapshot(paths) => {
+                let paths = self.seed.snapshot(paths);
+                self.reconcile(paths)
+            }
+        }
+    }
+
+    // Drops the devices that were unplugged while we talked to them. Returns
+    // the ones that were removed.
+    pub fn remove_disconnected(&mut self) -> Vec<DeviceEvent> {
+        disconnected(&self.map).into_iter().filter_map(|key| self.remove(key)).map(DeviceEvent::Removed).collect()
+    }
+
+    // Adds and removes devices so that we know exactly the given ones.
+    fn reconcile(&mut self, paths: Vec<String>) -> Vec<DeviceEvent> {
+        let known: Vec<String> = self.map.keys().cloned().collect();
+        let (removed, added) = diff_devices(&known, &paths);
+
+        let mut events = Vec::new();
+        for path in removed {
+            events.extend(self.remove(path).map(DeviceEvent::Removed));
+        }
+        for path in added {
+            events.extend(self.add(path).map(DeviceEvent::Added));
         }
+        events
     }
 
-    fn add(&mut self, path: String) {
+    fn add(&mut self, path: String) -> Option<DeviceInfo> {
         if self.map.contains_key(&path) {
-            return;
+            return None;
         }
 
         // Create and try to open the device.
-        if let Ok(mut dev) = Device::new(path.clone()) {
-            if !dev.is_u2f() {
-                return;
-            }
-
-            // Do a few U2F device checks.
-            let mut nonce = [0u8; 8];
-            thread_rng().fill_bytes(&mut nonce);
-            if let Err(_) = ::init_device(&mut dev, nonce) {
-                return;
-            }
+        let mut dev = match Device::new(path.clone()) {
+            Ok(dev) => dev,
+            Err(_) => return None
+        };
+        if !dev.is_u2f() {
+            return None;
+        }
 
-            let mut random = [0u8; 8];
-            thread_rng().fill_bytes(&mut random);
-            if let Err(_) = ::ping_device(&mut dev, random) {
-                return;
-            }
-            if let Err(_) = ::u2f_version_is_v2(&mut dev) {
-                return;
-            }
+        // Skip devices on transports the caller didn't ask for, and blocked
+        // models.
+        let info = dev.get_device_info();
+        if !self.filter.matches(&info) {
+            return None;
+        }
 
-            self.map.insert(path, dev);
+        // The channel is allocated once the device is first used.
+        dev.set_frame_observer(self.observer.clone());
+        if let Some(ref context) = self.context {
+            dev.set_context(context.clone());
         }
+        op_log!(debug, self.context.as_ref(), "added U2F device {:?} (serial number: {:?})", path, dev.serial_number());
+        self.added.insert(path.clone(), self.next);
+        self.next += 1;
+        self.map.insert(path, dev);
+        Some(info)
     }
 
-    fn remove(&mut self, path: String) {
-        // Ignore errors.
-        let _ = self.map.remove(&path);
+    fn remove(&mut self, path: String) -> Option<DeviceInfo> {
+        let _ = self.added.remove(&path);
+        self.map.remove(&path).map(|dev| dev.get_device_info())
     }
 }
@@ -2,43 +2,55 @@ use std::collections::HashSet;
 use std::error::Error;
 use std::io;
 use std::iter::FromIterator;
-use std::sync::mpsc::{channel, Receiver, TryIter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
 use std::thread;
 use std::time::Duration;
 
 use runloop::RunLoop;
+use util::{EventIter, EventQueue};
+use super::device::normalize_path;
 use super::winapi::DeviceInfoSet;
 
 pub fn io_err(msg: &str) -> io::Error {
-  io::Error::new(io::ErrorKind::Other, msg)
+  io::Error::other(msg)
 }
 
 pub fn to_io_err<T: Error>(err: T) -> io::Error {
-  io_err(err.description())
+  io_err(&err.to_string())
 }
 
+#[derive(Clone, Debug)]
 pub enum Event {
     Add(String),
-    Remove(String)
+    Remove(String),
+    // All devices currently present, after a refresh was requested.
+    Snapshot(Vec<String>)
 }
 
 pub struct Monitor {
     // Receive events from the thread.
-    rx: Receiver<Event>,
+    events: EventQueue<Event>,
     // Handle to the thread loop.
-    thread: RunLoop
+    thread: RunLoop,
+    // Set to have the thread send a fresh snapshot of all devices.
+    refresh: Arc<AtomicBool>
 }
 
 impl Monitor {
     pub fn new() -> io::Result<Self> {
         let (tx, rx) = channel();
+        let refresh = Arc::new(AtomicBool::new(false));
+        let refresh_ = refresh.clone();
 
-        let thread = RunLoop::new(move |alive| {
+        let thread = RunLoop::new(move |alive| -> io::Result<()> {
             let mut stored = HashSet::new();
 
             while alive() {
                 let device_info_set = DeviceInfoSet::new()?;
-                let devices = HashSet::from_iter(device_info_set.devices());
+                let paths = device_info_set.devices().map(|path| normalize_path(&path));
+                let devices = HashSet::from_iter(paths);
 
                 // Remove devices that are gone.
                 for path in stored.difference(&devices) {
@@ -50,6 +62,12 @@ impl Monitor {
                     tx.send(Event::Add(path.clone())).map_err(to_io_err)?;
                 }
 
+                // Report all devices, if asked to.
+                if refresh_.swap(false, Ordering::SeqCst) {
+                    let snapshot = devices.iter().cloned().collect();
+                    tx.send(Event::Snapshot(snapshot)).map_err(to_io_err)?;
+                }
+
                 // Remember the new set.
                 stored = devices;
 
@@ -60,15 +78,28 @@ impl Monitor {
             Ok(())
         }, 0 /* no timeout */)?;
 
-        Ok(Self { rx, thread })
+        Ok(Self { events: EventQueue::new(rx), thread, refresh })
+    }
+
+    pub fn events<'a>(&'a self) -> EventIter<'a, Event> {
+        self.events.try_iter()
     }
 
-    pub fn events<'a>(&'a self) -> TryIter<'a, Event> {
-        self.rx.try_iter()
+    // The events that haven't been handled yet, for diagnostics. They're
+    // still handed out by `events()`.
+    pub fn pending(&self) -> Vec<Event> {
+        self.events.pending()
     }
 
-    // This might block.
-    pub fn stop(&mut self) {
+    // Asks for an Event::Snapshot of all devices currently present.
+    pub fn refresh(&self) {
+        self.refresh.store(true, Ordering::SeqCst);
+    }
+
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
         self.thread.cancel();
     }
 }
@@ -40,6 +40,15 @@ extern "stdcall" {
     ) -> BOOL;
 }
 
+#[repr(C)]
+#[allow(non_snake_case)]
+struct HiddAttributes {
+    Size: ULONG,
+    VendorID: USHORT,
+    ProductID: USHORT,
+    VersionNumber: USHORT
+}
+
 #[link(name = "hid")]
 extern "stdcall" {
     fn HidD_GetPreparsedData(HidDeviceObject: HANDLE,
@@ -48,18 +57,27 @@ extern "stdcall" {
 
     fn HidD_FreePreparsedData(PreparsedData: PHIDP_PREPARSED_DATA) -> BOOLEAN;
 
+    fn HidD_GetAttributes(HidDeviceObject: HANDLE,
+                          Attributes: *mut HiddAttributes
+    ) -> BOOLEAN;
+
+    fn HidD_GetSerialNumberString(HidDeviceObject: HANDLE,
+                                  Buffer: PVOID,
+                                  BufferLength: ULONG
+    ) -> BOOLEAN;
+
     fn HidP_GetCaps(PreparsedData: PHIDP_PREPARSED_DATA,
                     Capabilities: PHIDP_CAPS
     ) -> NTSTATUS;
 }
 
 fn io_err(msg: &str) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, msg)
+    io::Error::other(msg)
 }
 
 macro_rules! offset_of {
     ($ty:ty, $field:ident) => {
-        unsafe { &(*(0 as *const $ty)).$field as *const _ as usize }
+        ::std::mem::offset_of!($ty, $field)
     }
 }
 
@@ -210,6 +228,46 @@ impl Drop for DeviceInterfaceDetailData {
     }
 }
 
+// Returns the USB serial number string of the device, if it has one.
+pub fn serial_number(handle: HANDLE) -> Option<String> {
+    // USB string descriptors hold at most 126 UTF-16 code units.
+    let mut buf = [0u16; 127];
+    let len = (buf.len() * mem::size_of::<u16>()) as ULONG;
+
+    let rv = unsafe {
+        HidD_GetSerialNumberString(handle, buf.as_mut_ptr() as PVOID, len)
+    };
+    if rv == 0 {
+        return None;
+    }
+
+    from_wide_serial(&buf)
+}
+
+// The NUL-terminated serial number string in `buf`, unless it's empty.
+pub fn from_wide_serial(buf: &[u16]) -> Option<String> {
+    let end = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    let serial = OsString::from_wide(&buf[..end]).to_string_lossy().into_owned();
+    if serial.is_empty() { None } else { Some(serial) }
+}
+
+// Returns the vendor and product IDs of the device.
+pub fn vendor_product_id(handle: HANDLE) -> Option<(u16, u16)> {
+    let mut attrs = HiddAttributes {
+        Size: mem::size_of::<HiddAttributes>() as ULONG,
+        VendorID: 0,
+        ProductID: 0,
+        VersionNumber: 0
+    };
+
+    let rv = unsafe { HidD_GetAttributes(handle, &mut attrs) };
+    if rv == 0 {
+        return None;
+    }
+
+    Some((attrs.VendorID, attrs.ProductID))
+}
+
 pub struct DeviceCapabilities {
     caps: HIDP_CAPS
 }